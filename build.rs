@@ -0,0 +1,77 @@
+// Captures build provenance for the /_wagi/version route.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    watch_git_head();
+
+    let git_sha = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=WAGI_GIT_SHA={}", git_sha);
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=WAGI_BUILD_TIMESTAMP={}", build_timestamp);
+
+    let wasmtime_version = std::fs::read_to_string("Cargo.lock")
+        .ok()
+        .and_then(|lock| locked_version(&lock, "wasmtime"))
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=WAGI_WASMTIME_VERSION={}", wasmtime_version);
+}
+
+// A new commit on the current branch changes the branch's ref, not HEAD, so the
+// ref is watched too. It may be loose under `.git/refs` or only in `packed-refs`,
+// and a packed ref becomes loose on the next commit, so then its directory is
+// watched instead. Only paths that exist are watched, since a missing one would
+// make every build rerun this script; outside a git checkout that is none.
+fn watch_git_head() {
+    let git_dir = Path::new(".git");
+    let head = match std::fs::read_to_string(git_dir.join("HEAD")) {
+        Ok(head) => head,
+        Err(_) => return,
+    };
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(branch_ref) = head.trim().strip_prefix("ref: ") {
+        let loose_ref = git_dir.join(branch_ref);
+        let ref_path = if loose_ref.exists() {
+            Some(loose_ref)
+        } else {
+            loose_ref.parent().filter(|dir| dir.exists()).map(Path::to_path_buf)
+        };
+        for path in ref_path.into_iter().chain(Some(git_dir.join("packed-refs"))) {
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+}
+
+// Cargo.lock lists each package as `name = "..."` immediately followed by `version = "..."`.
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name_line = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() == name_line {
+            let version_line = lines.next()?;
+            return version_line
+                .trim()
+                .strip_prefix("version = \"")
+                .and_then(|v| v.strip_suffix('"'))
+                .map(|v| v.to_owned());
+        }
+    }
+    None
+}
@@ -0,0 +1,36 @@
+//! Helpers for writing WAGI modules in Rust.
+//!
+//! WAGI runs a module once per request, passing the request in environment
+//! variables and on standard input, and reads the response from standard output
+//! as a CGI header block followed by the body. This crate wraps those conventions:
+//!
+//! ```no_run
+//! use wagi_guest::{Request, Response};
+//!
+//! fn main() {
+//!     wagi_guest::serve(|req: Request| {
+//!         let name = req.query_param("name").unwrap_or_else(|| "world".to_owned());
+//!         Response::text(format!("Hello, {}!\n", name))
+//!     });
+//! }
+//! ```
+//!
+//! A module can serve several routes from different entrypoints, and list them in
+//! a `_routes` function with the [`routes!`] macro.
+
+mod request;
+mod response;
+mod routes;
+
+pub use request::Request;
+pub use response::Response;
+
+/// Reads the request WAGI passed to the module, runs the handler, and writes its
+/// response to standard output.
+pub fn serve(handler: impl FnOnce(Request) -> Response) {
+    let response = handler(Request::from_env());
+    if let Err(e) = response.send() {
+        // Nothing more can be written to the client, but the server logs stderr
+        eprintln!("Error writing response: {}", e);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+/// A request, as WAGI describes it to the module.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Request {
+    vars: HashMap<String, String>,
+    body: Option<Vec<u8>>,
+}
+
+impl Request {
+    /// The request passed to this run of the module. The body is read from
+    /// standard input the first time it is asked for.
+    pub fn from_env() -> Self {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// A request built from the given environment variables, for example to test
+    /// a handler outside WAGI.
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            vars: vars.into_iter().collect(),
+            body: None,
+        }
+    }
+
+    /// Sets the body, rather than reading it from standard input.
+    pub fn with_body(self, body: impl Into<Vec<u8>>) -> Self {
+        Self { body: Some(body.into()), ..self }
+    }
+
+    /// An environment variable WAGI set for the request.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|v| v.as_str())
+    }
+
+    pub fn method(&self) -> &str {
+        self.var("REQUEST_METHOD").unwrap_or("GET")
+    }
+
+    /// The route that matched, as written in the configuration, such as `/api/...`.
+    pub fn matched_route(&self) -> &str {
+        self.var("X_MATCHED_ROUTE").unwrap_or_default()
+    }
+
+    /// The decoded part of the path after the matched route.
+    pub fn path_info(&self) -> &str {
+        self.var("PATH_INFO").unwrap_or_default()
+    }
+
+    pub fn query_string(&self) -> &str {
+        self.var("QUERY_STRING").unwrap_or_default()
+    }
+
+    /// The decoded value of the first query parameter with the given name.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_string()
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(key, _)| decode_query_component(key) == name)
+            .map(|(_, value)| decode_query_component(value))
+    }
+
+    /// A request header, by its HTTP name, such as `User-Agent`. WAGI does not pass
+    /// `Authorization` or `Connection` to modules.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        match name.to_ascii_lowercase().as_str() {
+            "content-type" => self.var("CONTENT_TYPE").filter(|v| !v.is_empty()),
+            other => self.var(&format!("HTTP_{}", other.to_ascii_uppercase().replace('-', "_"))),
+        }
+    }
+
+    /// A cookie the client sent, by the name WAGI gives its variable: upper-cased,
+    /// with anything but letters and digits replaced by `_`.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.var(&format!("COOKIE_{}", name))
+    }
+
+    /// The client's IP address.
+    pub fn client_addr(&self) -> Option<&str> {
+        self.var("REMOTE_ADDR")
+    }
+
+    /// The request body. The first call reads it from standard input.
+    pub fn body(&mut self) -> std::io::Result<&[u8]> {
+        if self.body.is_none() {
+            let mut body = Vec::new();
+            std::io::stdin().read_to_end(&mut body)?;
+            self.body = Some(body);
+        }
+        Ok(self.body.as_deref().unwrap_or_default())
+    }
+}
+
+fn decode_query_component(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some([high, low]) if bytes[i] == b'%' => hex_value(*high).zip(hex_value(*low)),
+            _ => None,
+        };
+        match (bytes[i], escaped) {
+            (_, Some((high, low))) => {
+                decoded.push(high * 16 + low);
+                i += 2;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (b, None) => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(vars: &[(&str, &str)]) -> Request {
+        Request::from_vars(vars.iter().map(|(k, v)| (k.to_string(), v.to_string())))
+    }
+
+    #[test]
+    fn request_is_read_from_wagi_variables() {
+        let req = request(&[
+            ("REQUEST_METHOD", "POST"),
+            ("X_MATCHED_ROUTE", "/api/..."),
+            ("PATH_INFO", "/users/1"),
+            ("QUERY_STRING", "name=J%C3%BCrgen+K&flag&name=second"),
+            ("HTTP_USER_AGENT", "curl/7.79"),
+            ("CONTENT_TYPE", "application/json"),
+            ("COOKIE_SESSION", "abc"),
+        ]);
+        assert_eq!("POST", req.method());
+        assert_eq!("/api/...", req.matched_route());
+        assert_eq!("/users/1", req.path_info());
+        assert_eq!(Some("Jürgen K".to_owned()), req.query_param("name"));
+        assert_eq!(Some("".to_owned()), req.query_param("flag"));
+        assert_eq!(None, req.query_param("missing"));
+        assert_eq!(Some("curl/7.79"), req.header("User-Agent"));
+        assert_eq!(Some("application/json"), req.header("content-type"));
+        assert_eq!(Some("abc"), req.cookie("SESSION"));
+    }
+
+    #[test]
+    fn given_body_is_not_read_from_stdin() {
+        let mut req = request(&[]).with_body("hello");
+        assert_eq!(b"hello", req.body().unwrap());
+        assert_eq!("GET", req.method());
+    }
+}
@@ -0,0 +1,119 @@
+use std::io::Write;
+
+/// A response, written to WAGI as a CGI header block followed by the body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// An empty response with the given status.
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    /// A `200 OK` response with a `text/plain` body.
+    pub fn text(body: impl Into<String>) -> Self {
+        Self::new(200)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    /// A `200 OK` response with a `text/html` body.
+    pub fn html(body: impl Into<String>) -> Self {
+        Self::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    /// A `200 OK` response with a body that is already JSON.
+    pub fn json(body: impl Into<String>) -> Self {
+        Self::new(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(body.into())
+    }
+
+    /// A redirect to the given URL, with status 302.
+    pub fn redirect(location: impl Into<String>) -> Self {
+        Self::new(302).with_header("Location", location)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body("Not Found\n")
+    }
+
+    pub fn with_status(self, status: u16) -> Self {
+        Self { status, ..self }
+    }
+
+    /// Adds a header. A header can be added more than once, as `Set-Cookie` often is.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(self, body: impl Into<Vec<u8>>) -> Self {
+        Self { body: body.into(), ..self }
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Writes the header block and body. Header values are cut at the first line
+    /// break, so that a value taken from the request cannot add headers of its own.
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "Status: {}", self.status)?;
+        for (name, value) in &self.headers {
+            let value = value.split(['\r', '\n']).next().unwrap_or_default();
+            writeln!(out, "{}: {}", name, value)?;
+        }
+        writeln!(out)?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+
+    /// Writes the response to standard output, where WAGI reads it from.
+    pub fn send(&self) -> std::io::Result<()> {
+        self.write_to(&mut std::io::stdout().lock())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn response_is_written_as_a_header_block_and_body() {
+        let mut out = vec![];
+        Response::text("hi\n")
+            .with_status(201)
+            .with_header("Set-Cookie", "a=1")
+            .with_header("Set-Cookie", "b=2\r\nX-Injected: yes")
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(
+            "Status: 201\nContent-Type: text/plain; charset=utf-8\nSet-Cookie: a=1\nSet-Cookie: b=2\n\nhi\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn redirect_has_a_location_and_no_body() {
+        let mut out = vec![];
+        Response::redirect("/login").write_to(&mut out).unwrap();
+        assert_eq!("Status: 302\nLocation: /login\n\n", String::from_utf8(out).unwrap());
+    }
+}
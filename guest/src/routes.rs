@@ -0,0 +1,61 @@
+/// Declares the module's `_routes` function, which tells WAGI which entrypoint
+/// serves each route under the module's own route, and defines each entrypoint to
+/// serve requests with the given handler.
+///
+/// ```no_run
+/// use wagi_guest::{Request, Response};
+///
+/// wagi_guest::routes! {
+///     "/users/..." => on_users(users),
+///     "/health" => on_health(health),
+/// }
+///
+/// fn users(req: Request) -> Response {
+///     Response::text(format!("User {}\n", req.path_info()))
+/// }
+///
+/// fn health(_req: Request) -> Response {
+///     Response::text("OK\n")
+/// }
+///
+/// fn main() {
+///     // Serves the module's own route
+///     wagi_guest::serve(|_req| Response::text("Home\n"));
+/// }
+/// ```
+#[macro_export]
+macro_rules! routes {
+    ($($route:literal => $entrypoint:ident($handler:path)),* $(,)?) => {
+        #[no_mangle]
+        pub fn _routes() {
+            $( println!("{} {}", $route, stringify!($entrypoint)); )*
+        }
+
+        $(
+            #[no_mangle]
+            pub fn $entrypoint() {
+                $crate::serve($handler);
+            }
+        )*
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Request, Response};
+
+    fn hello(_req: Request) -> Response {
+        Response::text("hello")
+    }
+
+    crate::routes! {
+        "/hello" => on_hello(hello),
+    }
+
+    #[test]
+    fn entrypoints_are_defined_for_each_route() {
+        // The generated functions exist and have the signature WAGI calls
+        let _routes: fn() = _routes;
+        let _entrypoint: fn() = on_hello;
+    }
+}
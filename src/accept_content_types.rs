@@ -0,0 +1,99 @@
+//! Routes that only take some kinds of request body.
+//!
+//! A route with `accept_content_types = ["application/json", "multipart/form-data"]`
+//! answers request bodies of any other media type, or with no `Content-Type`, with
+//! `415 Unsupported Media Type` without running the module. Entries can end in
+//! `/*` to accept a whole type, such as `text/*`. Requests without a body, such as
+//! a GET, are let through.
+
+use hyper::{header::CONTENT_TYPE, http::request::Parts, Body, Response};
+
+use crate::http_util::unsupported_media_type;
+
+/// The media types a route accepts request bodies in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AcceptedContentTypes {
+    media_types: Vec<String>,
+}
+
+impl AcceptedContentTypes {
+    pub fn parse(media_types: &[String]) -> anyhow::Result<Self> {
+        if media_types.is_empty() {
+            anyhow::bail!("at least one media type is required");
+        }
+        for media_type in media_types {
+            let valid = match media_type.split_once('/') {
+                Some((ty, subtype)) => !ty.is_empty() && ty != "*" && !subtype.is_empty() && !subtype.contains(['/', ';', ' ']),
+                None => false,
+            };
+            if !valid {
+                anyhow::bail!("'{}' is not a media type such as application/json or text/*", media_type);
+            }
+        }
+        Ok(Self {
+            media_types: media_types.iter().map(|t| t.to_ascii_lowercase()).collect(),
+        })
+    }
+
+    /// Checks the request, returning the response to reject it with if its body
+    /// is not of an accepted type.
+    pub fn check(&self, req: &Parts, body: &[u8]) -> Result<(), Response<Body>> {
+        let content_type = req.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        if body.is_empty() && content_type.is_none() {
+            return Ok(());
+        }
+        match content_type {
+            Some(ct) if self.accepts(ct) => Ok(()),
+            _ => Err(unsupported_media_type(&self.media_types.join(" or "))),
+        }
+    }
+
+    // Parameters such as `charset` or `boundary` are allowed, and media types are
+    // case-insensitive
+    fn accepts(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.media_types.iter().any(|accepted| match accepted.strip_suffix("/*") {
+            Some(ty) => media_type.split_once('/').map(|(t, _)| t == ty).unwrap_or(false),
+            None => *accepted == media_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{Request, StatusCode};
+
+    fn parts(content_type: Option<&str>) -> Parts {
+        let mut builder = Request::post("/upload");
+        if let Some(ct) = content_type {
+            builder = builder.header(CONTENT_TYPE, ct);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn accepted(types: &[&str]) -> AcceptedContentTypes {
+        AcceptedContentTypes::parse(&types.iter().map(|t| t.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn only_listed_media_types_are_accepted() {
+        let types = accepted(&["application/json", "multipart/form-data", "Text/*"]);
+        assert!(types.check(&parts(Some("application/JSON; charset=utf-8")), b"{}").is_ok());
+        assert!(types.check(&parts(Some("multipart/form-data; boundary=x")), b"--x").is_ok());
+        assert!(types.check(&parts(Some("text/csv")), b"a,b").is_ok());
+        assert!(types.check(&parts(None), b"").is_ok());
+
+        let rejection = types.check(&parts(Some("application/xml")), b"<a/>").unwrap_err();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, rejection.status());
+        let rejection = types.check(&parts(None), b"data").unwrap_err();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, rejection.status());
+    }
+
+    #[test]
+    fn lists_must_hold_media_types() {
+        for invalid in [vec![], vec!["json".to_owned()], vec!["*/*".to_owned()], vec!["text/plain; charset=utf-8".to_owned()]] {
+            AcceptedContentTypes::parse(&invalid).expect_err("should be invalid");
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Client IP allow and deny lists for routes.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address
+/// is treated as a single-host network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.trim();
+        let (address_text, prefix_text) = match text.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (text, None),
+        };
+        let address: IpAddr = address_text
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid IP address in '{}': {}", s, e))?;
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_text {
+            None => max_prefix_len,
+            Some(p) => p
+                .parse::<u8>()
+                .map_err(|e| anyhow::anyhow!("Invalid prefix length in '{}': {}", s, e))?,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(anyhow::anyhow!(
+                "Prefix length in '{}' is larger than {}",
+                s,
+                max_prefix_len
+            ));
+        }
+        Ok(Self { address, prefix_len })
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, normalize(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, self.prefix_len, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = (bits - prefix_len) as u32;
+    (net >> shift) == (ip >> shift)
+}
+
+// A dual-stack listener reports IPv4 clients as IPv4-mapped IPv6 addresses
+// (::ffff:a.b.c.d). Treat those as the IPv4 addresses they are.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match ipv4_mapped(&v6) {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        _ => ip,
+    }
+}
+
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// The client networks a route accepts requests from.
+///
+/// A client matching any `deny` network is always refused. Otherwise, if there are
+/// any `allow` networks, the client must match one of them.
+#[derive(Clone, Debug, Default)]
+pub struct IpAccessList {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl IpAccessList {
+    pub fn parse(allow_from: &Option<Vec<String>>, deny_from: &Option<Vec<String>>) -> anyhow::Result<Self> {
+        Ok(Self {
+            allow: parse_networks(allow_from)?,
+            deny: parse_networks(deny_from)?,
+        })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|n| n.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip))
+    }
+}
+
+fn parse_networks(networks: &Option<Vec<String>>) -> anyhow::Result<Vec<IpNetwork>> {
+    networks
+        .as_deref()
+        .unwrap_or_default()
+        .iter()
+        .map(|n| n.parse())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().expect("Test IP should have parsed")
+    }
+
+    fn list(allow: &[&str], deny: &[&str]) -> IpAccessList {
+        let to_vec = |v: &[&str]| Some(v.iter().map(|s| s.to_string()).collect());
+        IpAccessList::parse(&to_vec(allow), &to_vec(deny)).expect("Test access list should have parsed")
+    }
+
+    #[test]
+    fn networks_match_by_prefix() {
+        let net: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+
+        let host: IpNetwork = "192.168.0.1".parse().unwrap();
+        assert!(host.contains(ip("192.168.0.1")));
+        assert!(!host.contains(ip("192.168.0.2")));
+
+        let v6: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+
+        let everything: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_match_ipv4_networks() {
+        let net: IpNetwork = "127.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("fd00::/129".parse::<IpNetwork>().is_err());
+        assert!("not-an-ip/8".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn empty_list_permits_everyone() {
+        assert!(IpAccessList::default().permits(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let acl = list(&["10.0.0.0/8"], &["10.0.0.13"]);
+        assert!(acl.permits(ip("10.0.0.12")));
+        assert!(!acl.permits(ip("10.0.0.13")));
+        assert!(!acl.permits(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn deny_only_permits_others() {
+        let acl = list(&[], &["192.168.0.0/16"]);
+        assert!(acl.permits(ip("10.0.0.1")));
+        assert!(!acl.permits(ip("192.168.4.4")));
+    }
+}
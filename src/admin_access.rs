@@ -0,0 +1,104 @@
+//! Who may use the inbuilt routes that change how WAGI serves, or that list its
+//! routes.
+//!
+//! Without `--admin-token`, only clients on the same machine may. That is not enough
+//! on its own behind a reverse proxy on the same host, where every request comes from
+//! a loopback address, so a request that came through a trusted proxy, or that
+//! carries forwarding headers, is refused too. With `--admin-token`, a request must
+//! instead carry the token as `Authorization: Bearer <token>`, from any address.
+
+use std::net::SocketAddr;
+
+use hyper::header::{HeaderName, AUTHORIZATION, FORWARDED};
+use hyper::http::request::Parts;
+
+use crate::access_control::IpNetwork;
+
+// Headers that only a proxy adds. Their presence can only get a request refused,
+// so there is no harm in a client adding them itself.
+const FORWARDING_HEADERS: &[&str] = &["x-forwarded-for", "x-forwarded-host", "x-forwarded-proto", "x-real-ip"];
+
+#[derive(Clone, Default)]
+pub struct AdminAccess {
+    token: Option<String>,
+    trusted_proxies: Vec<IpNetwork>,
+}
+
+impl AdminAccess {
+    pub fn new(token: Option<String>, trusted_proxies: Vec<IpNetwork>) -> Self {
+        Self { token, trusted_proxies }
+    }
+
+    /// Whether the request may use the admin routes.
+    pub fn allows(&self, parts: &Parts, client_addr: SocketAddr) -> bool {
+        match &self.token {
+            Some(token) => parts
+                .headers
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map_or(false, |presented| constant_time_eq(presented.trim().as_bytes(), token.as_bytes())),
+            None => {
+                let ip = client_addr.ip();
+                ip.is_loopback()
+                    && !self.trusted_proxies.iter().any(|n| n.contains(ip))
+                    && !parts.headers.contains_key(FORWARDED)
+                    && !FORWARDING_HEADERS.iter().any(|h| parts.headers.contains_key(HeaderName::from_static(h)))
+            }
+        }
+    }
+}
+
+// The token is not printed in debug output.
+impl std::fmt::Debug for AdminAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminAccess")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("trusted_proxies", &self.trusted_proxies)
+            .finish()
+    }
+}
+
+// Compares without stopping at the first difference, so that response times don't
+// reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parts(headers: &[(&str, &str)]) -> Parts {
+        let mut builder = hyper::Request::post("/_wagi/routes");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn without_a_token_only_direct_local_clients_are_allowed() {
+        let local: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "203.0.113.9:5000".parse().unwrap();
+        let access = AdminAccess::default();
+        assert!(access.allows(&parts(&[]), local));
+        assert!(!access.allows(&parts(&[]), remote));
+        assert!(!access.allows(&parts(&[("X-Forwarded-For", "203.0.113.9")]), local));
+        assert!(!access.allows(&parts(&[("Forwarded", "for=203.0.113.9")]), local));
+
+        let behind_proxy = AdminAccess::new(None, vec!["127.0.0.1/32".parse().unwrap()]);
+        assert!(!behind_proxy.allows(&parts(&[]), local));
+    }
+
+    #[test]
+    fn with_a_token_the_token_is_required() {
+        let local: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "203.0.113.9:5000".parse().unwrap();
+        let access = AdminAccess::new(Some("s3cret".to_owned()), vec![]);
+        assert!(access.allows(&parts(&[("Authorization", "Bearer s3cret")]), remote));
+        assert!(!access.allows(&parts(&[("Authorization", "Bearer s3cre")]), remote));
+        assert!(!access.allows(&parts(&[("Authorization", "Basic s3cret")]), remote));
+        assert!(!access.allows(&parts(&[]), local));
+    }
+}
@@ -0,0 +1,345 @@
+//! An audit log of requests to routes with `audit = true`.
+//!
+//! Each audited request is appended to the log as lines of JSON, written by WAGI
+//! rather than by the module, so the trail does not depend on guest code. A
+//! `started` entry is written before the module runs, and the module does not run
+//! if it can't be. A `completed` entry follows once the response is ready, or an
+//! `abandoned` one if the client disconnects first.
+//! The entries are hash chained: each one carries the SHA-256 of the line before
+//! it in `prev_hash`, so removing or editing an entry breaks the chain from that
+//! point on. `verify_chain` checks a log.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use hyper::{http::request::Parts, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub const AUDIT_LOG_FILE: &str = "audit.log";
+// The `prev_hash` of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// How a route is audited.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditSettings {
+    /// How many bytes of each request body to record. Bodies are not recorded if
+    /// this is zero; their size and SHA-256 are recorded either way.
+    pub body_limit: usize,
+}
+
+/// The point in a request that an entry records.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEvent {
+    /// The module is about to run.
+    Started,
+    /// The response is ready.
+    Completed,
+    /// The client disconnected before the response was ready.
+    Abandoned,
+}
+
+/// What is recorded about one request.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    pub event: AuditEvent,
+    /// For a `completed` or `abandoned` entry, the `seq` of the request's `started`
+    /// entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_seq: Option<u64>,
+    pub route: String,
+    pub module: String,
+    pub client_addr: String,
+    pub method: String,
+    pub uri: String,
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+    pub content_type: Option<String>,
+    pub body_bytes: usize,
+    pub body_sha256: String,
+    /// The start of the body, if it is UTF-8 text...
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// ...or in hex if it is not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_hex: Option<String>,
+    pub body_truncated: bool,
+    pub status: u16,
+    pub duration_ms: u128,
+}
+
+impl AuditEntry {
+    /// The `started` entry for the request.
+    pub fn new(route: String, module: String, client_addr: String, parts: &Parts, body: &[u8], settings: &AuditSettings) -> Self {
+        let header = |name: hyper::header::HeaderName| parts.headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_owned());
+        let recorded = &body[..body.len().min(settings.body_limit)];
+        let (text, hex) = match recorded {
+            [] => (None, None),
+            bytes => match std::str::from_utf8(bytes) {
+                Ok(text) => (Some(text.to_owned()), None),
+                // Cutting the body may have split the last character
+                Err(e) if e.error_len().is_none() => (Some(String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned()), None),
+                Err(_) => (None, Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())),
+            },
+        };
+        Self {
+            event: AuditEvent::Started,
+            started_seq: None,
+            route,
+            module,
+            client_addr,
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            host: header(hyper::header::HOST),
+            user_agent: header(hyper::header::USER_AGENT),
+            content_type: header(hyper::header::CONTENT_TYPE),
+            body_bytes: body.len(),
+            body_sha256: format!("{:x}", Sha256::digest(body)),
+            body: text,
+            body_hex: hex,
+            body_truncated: body.len() > recorded.len(),
+            status: 0,
+            duration_ms: 0,
+        }
+    }
+
+    /// The entry that ends the request whose `started` entry was `started_seq`.
+    /// An abandoned request has no status.
+    pub fn finished(self, started_seq: u64, status: Option<StatusCode>, duration: Duration) -> Self {
+        Self {
+            event: if status.is_some() { AuditEvent::Completed } else { AuditEvent::Abandoned },
+            started_seq: Some(started_seq),
+            status: status.map_or(0, |s| s.as_u16()),
+            duration_ms: duration.as_millis(),
+            ..self
+        }
+    }
+}
+
+/// An audited request whose `started` entry has been written. If it is dropped
+/// before `completed` is called, because hyper dropped the request's future when the
+/// client disconnected, it writes an `abandoned` entry, in the way
+/// `AbandonedRequestGuard` counts such requests.
+pub struct AuditedRequest {
+    log: AuditLog,
+    entry: Option<AuditEntry>,
+    started_seq: u64,
+    started: Instant,
+}
+
+impl AuditedRequest {
+    /// Writes the request's `started` entry. The module must not run if this fails.
+    pub async fn start(log: &AuditLog, entry: AuditEntry) -> anyhow::Result<Self> {
+        let started = Instant::now();
+        let writer = log.clone();
+        let (started_seq, entry) = tokio::task::spawn_blocking(move || writer.record(&entry).map(|seq| (seq, entry)))
+            .await?
+            .with_context(|| format!("Failed to record request in {}", log.path().display()))?;
+        Ok(Self {
+            log: log.clone(),
+            entry: Some(entry),
+            started_seq,
+            started,
+        })
+    }
+
+    /// Writes the request's `completed` entry.
+    pub async fn completed(mut self, status: StatusCode) -> anyhow::Result<()> {
+        let entry = self.finish(Some(status));
+        let writer = self.log.clone();
+        tokio::task::spawn_blocking(move || writer.record(&entry))
+            .await?
+            .with_context(|| format!("Failed to record request completion in {}", self.log.path().display()))?;
+        Ok(())
+    }
+
+    fn finish(&mut self, status: Option<StatusCode>) -> AuditEntry {
+        // Only `completed` and `drop` call this, and each only once
+        self.entry.take().unwrap().finished(self.started_seq, status, self.started.elapsed())
+    }
+}
+
+impl Drop for AuditedRequest {
+    fn drop(&mut self) {
+        if self.entry.is_none() {
+            return;
+        }
+        let entry = self.finish(None);
+        let log = self.log.clone();
+        let record = move || {
+            if let Err(e) = log.record(&entry) {
+                tracing::error!(error = %e, route = %entry.route, path = %log.path().display(), "Failed to record abandoned request in audit log");
+            }
+        };
+        // The write waits for the disk, so is kept off the async threads if there are any
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(record);
+            }
+            Err(_) => record(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditLine<'a> {
+    seq: u64,
+    time: String,
+    #[serde(flatten)]
+    entry: &'a AuditEntry,
+    prev_hash: &'a str,
+}
+
+/// The audit log file. It is opened, and its chain picked up, when the first
+/// entry is written, so there is no file unless some route is audited.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    state: Arc<Mutex<Option<ChainState>>>,
+}
+
+#[derive(Debug)]
+struct ChainState {
+    file: File,
+    next_seq: u64,
+    prev_hash: String,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            state: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the entry, and waits for it to reach the disk. Returns the entry's
+    /// `seq`.
+    pub fn record(&self, entry: &AuditEntry) -> anyhow::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_none() {
+            *state = Some(self.open()?);
+        }
+        let chain = state.as_mut().unwrap();
+        let line = serde_json::to_string(&AuditLine {
+            seq: chain.next_seq,
+            time: chrono::Utc::now().to_rfc3339(),
+            entry,
+            prev_hash: &chain.prev_hash,
+        })?;
+        chain.file.write_all(format!("{}\n", line).as_bytes())?;
+        chain.file.sync_data()?;
+        let seq = chain.next_seq;
+        chain.next_seq += 1;
+        chain.prev_hash = line_hash(&line);
+        Ok(seq)
+    }
+
+    // Carries on the chain from the last entry already in the file, if any
+    fn open(&self) -> anyhow::Result<ChainState> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).read(true).open(&self.path)?;
+        let mut last = None;
+        for line in BufReader::new(&file).lines() {
+            last = Some(line?);
+        }
+        let (next_seq, prev_hash) = match last {
+            None => (0, GENESIS_HASH.to_owned()),
+            Some(line) => {
+                let seq = serde_json::from_str::<serde_json::Value>(&line)
+                    .ok()
+                    .and_then(|v| v["seq"].as_u64())
+                    .ok_or_else(|| anyhow::anyhow!("Last entry of audit log {} is not a valid entry", self.path.display()))?;
+                (seq + 1, line_hash(&line))
+            }
+        };
+        Ok(ChainState { file, next_seq, prev_hash })
+    }
+}
+
+fn line_hash(line: &str) -> String {
+    format!("{:x}", Sha256::digest(line.as_bytes()))
+}
+
+/// Checks that every entry in the audit log carries the hash of the one before it
+/// and the next sequence number. Returns how many entries there are.
+pub fn verify_chain(path: impl AsRef<Path>) -> anyhow::Result<u64> {
+    let file = File::open(path.as_ref())?;
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let value: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("Audit log line {} is not valid JSON: {}", index + 1, e))?;
+        if value["seq"].as_u64() != Some(count) {
+            anyhow::bail!("Audit log line {} is out of sequence: expected entry {}", index + 1, count);
+        }
+        if value["prev_hash"].as_str() != Some(prev_hash.as_str()) {
+            anyhow::bail!("Audit log line {} does not follow from the line before it", index + 1);
+        }
+        prev_hash = line_hash(&line);
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(body: &[u8], body_limit: usize) -> AuditEntry {
+        let parts = hyper::Request::post("/pay?x=1")
+            .header("Host", "example.com")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        AuditEntry::new("/pay".to_owned(), "pay.wasm".to_owned(), "127.0.0.1".to_owned(), &parts, body, &AuditSettings { body_limit })
+    }
+
+    #[test]
+    fn bodies_are_recorded_up_to_the_limit() {
+        let e = entry("amount=€10".as_bytes(), 9);
+        // The euro sign is cut in the middle, so is left out
+        assert_eq!(Some("amount="), e.body.as_deref());
+        assert!(e.body_truncated);
+        assert_eq!(12, e.body_bytes);
+
+        let e = entry(&[0xff, 0x00], 10);
+        assert_eq!(Some("ff00"), e.body_hex.as_deref());
+        assert!(!e.body_truncated);
+
+        let e = entry(b"secret", 0);
+        assert_eq!(None, e.body);
+        assert!(e.body_truncated);
+        assert_eq!(format!("{:x}", Sha256::digest(b"secret")), e.body_sha256);
+    }
+
+    #[test]
+    fn log_is_chained_across_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(AUDIT_LOG_FILE);
+        let log = AuditLog::new(&path);
+        log.record(&entry(b"one", 100)).unwrap();
+        log.record(&entry(b"two", 100)).unwrap();
+        // As after a restart
+        AuditLog::new(&path).record(&entry(b"three", 100)).unwrap();
+        assert_eq!(3, verify_chain(&path).unwrap());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, text.replacen("\"two\"", "\"TWO\"", 1)).unwrap();
+        verify_chain(&path).expect_err("an edited entry should break the chain");
+    }
+}
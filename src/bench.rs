@@ -0,0 +1,171 @@
+//! In-process load testing (`wagi bench`).
+//!
+//! Requests are sent straight to the routing table, bypassing the network, so the
+//! results reflect WAGI and the module rather than the HTTP stack or the client.
+//! Module instantiation and execution times come from the metrics the handlers
+//! record, so they are totals across all modules the route runs.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request, StatusCode};
+
+use crate::diagnostics::resident_memory_kb;
+use crate::dispatcher::LiveRoutingTable;
+use crate::handlers::{EXECUTION_TIME_METRIC, INSTANTIATION_TIME_METRIC};
+
+#[derive(Clone, Debug)]
+pub struct BenchSettings {
+    pub route: String,
+    pub requests: usize,
+    pub concurrency: usize,
+}
+
+pub struct BenchReport {
+    requests: usize,
+    concurrency: usize,
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+    failures: usize,
+    instantiation_time: Duration,
+    execution_time: Duration,
+    memory: Option<MemoryUsage>,
+}
+
+struct MemoryUsage {
+    start_kb: u64,
+    end_kb: u64,
+    peak_kb: u64,
+}
+
+/// Sends `requests` GET requests to the route, `concurrency` at a time.
+pub async fn run(settings: &BenchSettings, routing_table: LiveRoutingTable) -> anyhow::Result<BenchReport> {
+    if settings.requests == 0 || settings.concurrency == 0 {
+        anyhow::bail!("The number of requests and the concurrency must both be at least 1");
+    }
+    let uri = format!("http://{}{}", routing_table.current().global_context().default_host, settings.route);
+    uri.parse::<hyper::Uri>()
+        .map_err(|e| anyhow::anyhow!("Invalid route {}: {}", settings.route, e))?;
+
+    let metrics = routing_table.current().global_context().metrics.clone();
+    let instantiation_before = metrics.counter_total(INSTANTIATION_TIME_METRIC);
+    let execution_before = metrics.counter_total(EXECUTION_TIME_METRIC);
+    let memory_before = resident_memory_kb();
+
+    let client_addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let next_request = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..settings.concurrency.min(settings.requests))
+        .map(|_| {
+            let routing_table = routing_table.clone();
+            let next_request = next_request.clone();
+            let uri = uri.clone();
+            let total = settings.requests;
+            tokio::spawn(async move {
+                let mut results = vec![];
+                while next_request.fetch_add(1, Ordering::Relaxed) < total {
+                    let request = Request::get(&uri).body(Body::empty()).unwrap();
+                    let request_started = Instant::now();
+                    let status = match routing_table.handle_request(request, client_addr).await {
+                        Ok(response) => {
+                            let status = response.status();
+                            // Include reading the body, as a real client would
+                            let _ = hyper::body::to_bytes(response.into_body()).await;
+                            status
+                        }
+                        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                    results.push((request_started.elapsed(), status));
+                }
+                results
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(settings.requests);
+    let mut failures = 0;
+    for worker in workers {
+        for (latency, status) in worker.await? {
+            latencies.push(latency);
+            if !status.is_success() {
+                failures += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+
+    let memory = match (memory_before, resident_memory_kb()) {
+        (Some((start_kb, _)), Some((end_kb, peak_kb))) => Some(MemoryUsage { start_kb, end_kb, peak_kb }),
+        _ => None,
+    };
+
+    Ok(BenchReport {
+        requests: settings.requests,
+        concurrency: settings.concurrency,
+        elapsed,
+        latencies,
+        failures,
+        instantiation_time: Duration::from_secs_f64(metrics.counter_total(INSTANTIATION_TIME_METRIC) - instantiation_before),
+        execution_time: Duration::from_secs_f64(metrics.counter_total(EXECUTION_TIME_METRIC) - execution_before),
+        memory,
+    })
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let per_request = |total: Duration| total / self.requests as u32;
+        writeln!(f, "Requests:      {} ({} concurrent), {} failed", self.requests, self.concurrency, self.failures)?;
+        writeln!(f, "Elapsed:       {:.2?}", self.elapsed)?;
+        writeln!(f, "Throughput:    {:.1} requests/second", self.requests as f64 / self.elapsed.as_secs_f64())?;
+        writeln!(
+            f,
+            "Latency:       p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(&self.latencies, 50),
+            percentile(&self.latencies, 90),
+            percentile(&self.latencies, 99),
+            self.latencies.last().copied().unwrap_or_default(),
+        )?;
+        writeln!(
+            f,
+            "Per request:   {:.2?} instantiating, {:.2?} executing",
+            per_request(self.instantiation_time),
+            per_request(self.execution_time),
+        )?;
+        match &self.memory {
+            Some(m) => write!(
+                f,
+                "Memory (RSS):  {} MiB at start, {} MiB at end, {} MiB peak",
+                m.start_kb / 1024,
+                m.end_kb / 1024,
+                m.peak_kb / 1024,
+            ),
+            None => write!(f, "Memory (RSS):  not available on this platform"),
+        }
+    }
+}
+
+// `sorted` must be in ascending order.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() * percent + 99) / 100).max(1) - 1;
+    sorted[index.min(sorted.len() - 1)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let latencies: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(Duration::from_millis(5), percentile(&latencies, 50));
+        assert_eq!(Duration::from_millis(9), percentile(&latencies, 90));
+        assert_eq!(Duration::from_millis(10), percentile(&latencies, 99));
+        assert_eq!(Duration::ZERO, percentile(&[], 50));
+    }
+}
@@ -1,7 +1,16 @@
-use std::{collections::{HashMap, HashSet}, iter::FromIterator};
+use std::{collections::{HashMap, HashSet}, iter::FromIterator, time::Duration};
 
 use bindle::{Invoice, Parcel};
 
+use crate::accept_content_types::AcceptedContentTypes;
+use crate::audit::AuditSettings;
+use crate::experiment::ExperimentVariant;
+use crate::handlers::ArgsMode;
+use crate::json_request::JsonRequestSettings;
+use crate::stderr::StderrDestination;
+use crate::volume_overlay::VolumeOverlay;
+use crate::wagi_config::timeout_from_secs;
+
 // TODO: this file is a bit of a cop-out but will be useful during
 // the transition.  Find better homes for these things!
 
@@ -42,33 +51,109 @@ impl InvoiceUnderstander {
             .collect()
     }
 
-    pub fn classify_parcel(&self, parcel: &Parcel) -> Option<InterestingParcel> {
+    /// A parcel is mounted at its `route` feature, and at each of the `route=entrypoint`
+    /// pairs in its `routes` feature, so one module can serve several routes with
+    /// different entrypoints.
+    pub fn classify_parcel(&self, parcel: &Parcel) -> Vec<InterestingParcel> {
         // Currently only handlers but we have talked of scheduled tasks etc.
-        parcel.label.feature.as_ref().and_then(|features| {
-            features.get("wagi").and_then(|wagi_features| {
-                match wagi_features.get("route") {
-                    Some(route) => {
-                        let handler_info = WagiHandlerInfo {
-                            invoice_id: self.id(),
-                            parcel: parcel.clone(),
-                            route: route.to_owned(),
-                            entrypoint: wagi_features.get("entrypoint").map(|s| s.to_owned()),
-                            allowed_hosts: wagi_features.get("allowed_hosts").map(|h| parse_csv(h)),
-                            argv: wagi_features.get("argv").map(|s| s.to_owned()),
-                            required_parcels: parcels_required_for(parcel, &self.group_dependency_map),
-                        };
-                        Some(InterestingParcel::WagiHandler(handler_info))
-                    },
-                    None => None,
+        let wagi_features = match parcel.label.feature.as_ref().and_then(|features| features.get("wagi")) {
+            Some(f) => f,
+            None => return vec![],
+        };
+
+        let default_entrypoint = wagi_features.get("entrypoint").map(|s| s.to_owned());
+        let mut routes = vec![];
+        if let Some(route) = wagi_features.get("route") {
+            routes.push((route.to_owned(), default_entrypoint.clone()));
+        }
+        if let Some(route_list) = wagi_features.get("routes") {
+            routes.extend(parse_route_list(route_list, &default_entrypoint));
+        }
+        if routes.is_empty() {
+            return vec![];
+        }
+
+        let required_parcels = parcels_required_for(parcel, &self.group_dependency_map);
+        let group_mounts = self.group_mounts(parcel, wagi_features.get("asset_mounts"));
+        routes
+            .into_iter()
+            .map(|(route, entrypoint)| {
+                let handler_info = WagiHandlerInfo {
+                    invoice_id: self.id(),
+                    parcel: parcel.clone(),
+                    route,
+                    entrypoint,
+                    allowed_hosts: wagi_features.get("allowed_hosts").map(|h| parse_csv(h)),
+                    args_mode: parse_args_mode_feature(parcel, wagi_features.get("args_mode"), wagi_features.get("argv"), wagi_features.get("argv_from_query")),
+                    argv: wagi_features.get("argv").map(|s| s.to_owned()),
+                    argv_from_query: wagi_features.get("argv_from_query").map(|s| parse_csv(s)),
+                    preinstantiate: wagi_features.get("preinstantiate").map(|s| s == "true").unwrap_or(false),
+                    fallback: wagi_features.get("fallback").map(|s| s == "true").unwrap_or(false),
+                    max_instances: wagi_features.get("max_instances").and_then(|s| parse_max_instances_feature(parcel, s)),
+                    enabled: wagi_features.get("enabled").map(|s| s != "false").unwrap_or(true),
+                    allow_from: wagi_features.get("allow_from").map(|h| parse_csv(h)),
+                    deny_from: wagi_features.get("deny_from").map(|h| parse_csv(h)),
+                    default_content_type: wagi_features.get("default_content_type").map(|s| s.to_owned()),
+                    default_charset: wagi_features.get("default_charset").map(|s| s.to_owned()),
+                    decode_path_info: wagi_features.get("decode_path_info").map(|s| s != "false").unwrap_or(true),
+                    query_env_vars: wagi_features.get("query_env_vars").map(|s| s == "true").unwrap_or(false),
+                    byte_ranges: wagi_features.get("byte_ranges").map(|s| s == "true").unwrap_or(false),
+                    group_mounts: group_mounts.clone(),
+                    volume_overlay: wagi_features.get("volume_overlay").and_then(|s| parse_volume_overlay_feature(parcel, s)),
+                    timeout: wagi_features.get("timeout").and_then(|s| parse_timeout_feature(parcel, s)),
+                    stderr: wagi_features.get("stderr").map(|s| parse_stderr_feature(parcel, s)).unwrap_or_default(),
+                    json: parse_json_feature(wagi_features.get("json"), wagi_features.get("json_fields")),
+                    accept_content_types: wagi_features.get("accept_content_types").and_then(|s| parse_accept_content_types_feature(parcel, s)),
+                    experiment: parse_experiment_feature(parcel, wagi_features.get("experiment"), wagi_features.get("variant"), wagi_features.get("variant_weight")),
+                    audit: parse_audit_feature(parcel, wagi_features.get("audit"), wagi_features.get("audit_body_bytes")),
+                    required_parcels: required_parcels.clone(),
+                };
+                InterestingParcel::WagiHandler(handler_info)
+            })
+            .collect()
+    }
+
+    // The `asset_mounts` feature is a comma-separated list of `group=/guest/path`
+    // pairs. The file parcels in each group are mounted at that path rather than
+    // with the parcel's other assets at the root.
+    fn group_mounts(&self, parcel: &Parcel, text: Option<&String>) -> Vec<GroupMount> {
+        let text = match text {
+            Some(t) => t,
+            None => return vec![],
+        };
+        text.split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+            .filter_map(|item| {
+                let (group, guest_path) = match item.split_once('=') {
+                    Some((group, path)) if path.trim().starts_with('/') => (group.trim(), path.trim()),
+                    _ => {
+                        tracing::warn!(parcel = %parcel.label.name, value = item, "Ignoring invalid asset mount: must be group=/guest/path");
+                        return None;
+                    }
+                };
+                let parcels: Vec<Parcel> = self
+                    .group_dependency_map
+                    .get(group)
+                    .map(|members| members.iter().filter(|p| is_file(p)).cloned().collect())
+                    .unwrap_or_default();
+                if parcels.is_empty() {
+                    tracing::warn!(parcel = %parcel.label.name, group, "Ignoring asset mount: the group has no file parcels");
+                    return None;
                 }
+                Some(GroupMount {
+                    group: group.to_owned(),
+                    guest_path: guest_path.to_owned(),
+                    parcels,
+                })
             })
-        })
+            .collect()
     }
 
     pub fn parse_wagi_handlers(&self) -> Vec<WagiHandlerInfo> {
         self
             .top_modules().iter()
-            .filter_map(|parcel| self.classify_parcel(parcel))
+            .flat_map(|parcel| self.classify_parcel(parcel))
             .map(|parcel| match parcel {    // If there are other cases of InterestingParcel this may need to become a filter_map, but right now that makes Clippy mad
                 InterestingParcel::WagiHandler(h) => h,
             })
@@ -88,12 +173,138 @@ pub struct WagiHandlerInfo {
     pub entrypoint: Option<String>,
     pub allowed_hosts: Option<Vec<String>>,
     pub required_parcels: Vec<Parcel>,
+    pub args_mode: ArgsMode,
     pub argv: Option<String>,
+    pub argv_from_query: Option<Vec<String>>,
+    pub preinstantiate: bool,
+    pub fallback: bool,
+    pub max_instances: Option<usize>,
+    pub enabled: bool,
+    pub allow_from: Option<Vec<String>>,
+    pub deny_from: Option<Vec<String>>,
+    pub default_content_type: Option<String>,
+    pub default_charset: Option<String>,
+    pub decode_path_info: bool,
+    pub query_env_vars: bool,
+    pub byte_ranges: bool,
+    pub group_mounts: Vec<GroupMount>,
+    pub volume_overlay: Option<VolumeOverlay>,
+    pub timeout: Option<Duration>,
+    pub stderr: StderrDestination,
+    pub json: Option<JsonRequestSettings>,
+    pub accept_content_types: Option<AcceptedContentTypes>,
+    pub experiment: Option<ExperimentVariant>,
+    pub audit: Option<AuditSettings>,
+}
+
+/// The file parcels of a group, mounted together at a guest path.
+#[derive(Clone, Debug)]
+pub struct GroupMount {
+    pub group: String,
+    pub guest_path: String,
+    pub parcels: Vec<Parcel>,
 }
 
 impl WagiHandlerInfo {
+    /// The file parcels to mount at the root. Parcels in a mounted group are
+    /// mounted with their group instead.
     pub fn asset_parcels(&self) -> Vec<Parcel> {
-        self.required_parcels.iter().filter(|p| is_file(p)).cloned().collect()
+        let grouped: HashSet<&str> = self
+            .group_mounts
+            .iter()
+            .flat_map(|m| m.parcels.iter().map(|p| p.label.sha256.as_str()))
+            .collect();
+        self.required_parcels
+            .iter()
+            .filter(|p| is_file(p) && !grouped.contains(p.label.sha256.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+fn parse_timeout_feature(parcel: &Parcel, text: &str) -> Option<Duration> {
+    let timeout = text
+        .parse::<f64>()
+        .map_err(anyhow::Error::from)
+        .and_then(timeout_from_secs);
+    match timeout {
+        Ok(t) => Some(t),
+        Err(e) => {
+            tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid timeout");
+            None
+        }
+    }
+}
+
+// The `accept_content_types` feature is a comma-separated list of media types
+fn parse_accept_content_types_feature(parcel: &Parcel, text: &str) -> Option<AcceptedContentTypes> {
+    let types: Vec<String> = text.split(',').map(|v| v.trim().to_owned()).filter(|v| !v.is_empty()).collect();
+    match AcceptedContentTypes::parse(&types) {
+        Ok(t) => Some(t),
+        Err(e) => {
+            tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid accept_content_types");
+            None
+        }
+    }
+}
+
+fn parse_experiment_feature(parcel: &Parcel, experiment: Option<&String>, variant: Option<&String>, weight: Option<&String>) -> Option<ExperimentVariant> {
+    let parsed = match (experiment, variant) {
+        (None, None) => return None,
+        (Some(experiment), Some(variant)) => weight
+            .map(|w| w.parse::<u32>())
+            .transpose()
+            .map_err(anyhow::Error::from)
+            .and_then(|weight| ExperimentVariant::parse(experiment, variant, weight)),
+        _ => Err(anyhow::anyhow!("experiment and variant must be set together")),
+    };
+    match parsed {
+        Ok(v) => Some(v),
+        Err(e) => {
+            tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid experiment");
+            None
+        }
+    }
+}
+
+fn parse_max_instances_feature(parcel: &Parcel, text: &str) -> Option<usize> {
+    match text.parse::<usize>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            tracing::warn!(parcel = %parcel.label.name, value = text, "Ignoring invalid max_instances: must be a whole number of at least 1");
+            None
+        }
+    }
+}
+
+fn parse_args_mode_feature(parcel: &Parcel, mode: Option<&String>, argv: Option<&String>, argv_from_query: Option<&String>) -> ArgsMode {
+    let mode = match mode.map(|m| m.parse::<ArgsMode>()).transpose() {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid args_mode");
+            None
+        }
+    };
+    ArgsMode::resolve(mode, &argv.cloned(), &argv_from_query.map(|s| parse_csv(s))).unwrap_or_else(|e| {
+        tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid args_mode; using cgi");
+        ArgsMode::Cgi
+    })
+}
+
+fn parse_stderr_feature(parcel: &Parcel, text: &str) -> StderrDestination {
+    text.parse().unwrap_or_else(|e| {
+        tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid stderr destination");
+        StderrDestination::default()
+    })
+}
+
+fn parse_volume_overlay_feature(parcel: &Parcel, text: &str) -> Option<VolumeOverlay> {
+    match text.parse() {
+        Ok(overlay) => Some(overlay),
+        Err(e) => {
+            tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid volume overlay");
+            None
+        }
     }
 }
 
@@ -209,6 +420,47 @@ impl ParcelUtils for Parcel {
     }
 }
 
+// A comma-separated list of `route=entrypoint` pairs. A route without an
+// entrypoint uses the parcel's default one.
+fn parse_route_list(text: &str, default_entrypoint: &Option<String>) -> Vec<(String, Option<String>)> {
+    text.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| match item.split_once('=') {
+            Some((route, entrypoint)) => (route.trim().to_owned(), Some(entrypoint.trim().to_owned())),
+            None => (item.to_owned(), default_entrypoint.clone()),
+        })
+        .collect()
+}
+
+// The `json_fields` feature is a comma-separated list, only used with `json: true`
+fn parse_json_feature(json: Option<&String>, fields: Option<&String>) -> Option<JsonRequestSettings> {
+    match json.map(|s| s.as_str()) {
+        Some("true") => Some(JsonRequestSettings {
+            fields: fields
+                .map(|f| f.split(',').map(|v| v.trim().to_owned()).filter(|v| !v.is_empty()).collect())
+                .unwrap_or_default(),
+        }),
+        _ => None,
+    }
+}
+
+fn parse_audit_feature(parcel: &Parcel, audit: Option<&String>, body_bytes: Option<&String>) -> Option<AuditSettings> {
+    if audit.map(|s| s.as_str()) != Some("true") {
+        return None;
+    }
+    let body_limit = match body_bytes.map(|s| s.parse::<usize>()) {
+        None => 0,
+        Some(Ok(limit)) => limit,
+        Some(Err(e)) => {
+            // Auditing stays on; it is only the bodies that are left out
+            tracing::warn!(parcel = %parcel.label.name, error = %e, "Ignoring invalid audit_body_bytes");
+            0
+        }
+    };
+    Some(AuditSettings { body_limit })
+}
+
 fn parse_csv(text: &str) -> Vec<String> {
     text.split(',').map(|v| v.to_owned()).collect()  // TODO: trim etc.?
 }
@@ -386,6 +638,57 @@ mod test {
         assert!(super::is_file(&p));
     }
 
+    #[test]
+    fn test_routes_feature_maps_one_parcel_to_several_entrypoints() {
+        let mut wagifeatures = BTreeMap::new();
+        wagifeatures.insert("route".to_owned(), "/".to_owned());
+        wagifeatures.insert("routes".to_owned(), "/admin=admin_main, /api/...=api_main, /about".to_owned());
+        wagifeatures.insert("entrypoint".to_owned(), "site_main".to_owned());
+        let mut features = BTreeMap::new();
+        features.insert("wagi".to_owned(), wagifeatures);
+
+        let inv = InvoiceUnderstander::new(&Invoice {
+            bindle_version: "v1".to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            signature: None,
+            annotations: None,
+            bindle: BindleSpec {
+                id: "site/1.0.0"
+                    .to_owned()
+                    .try_into()
+                    .expect("This should parse"),
+                description: None,
+                authors: None,
+            },
+            group: None,
+            parcel: Some(vec![Parcel {
+                label: Label {
+                    sha256: "abc123".to_owned(),
+                    name: "site.wasm".to_owned(),
+                    media_type: WASM_MEDIA_TYPE.to_owned(),
+                    size: 1234,
+                    annotations: None,
+                    feature: Some(features),
+                    origin: None,
+                },
+                conditions: None,
+            }]),
+        });
+
+        let handlers: Vec<(String, Option<String>)> = inv
+            .parse_wagi_handlers()
+            .into_iter()
+            .map(|h| (h.route, h.entrypoint))
+            .collect();
+        assert_eq!(vec![
+            ("/".to_owned(), Some("site_main".to_owned())),
+            ("/admin".to_owned(), Some("admin_main".to_owned())),
+            ("/api/...".to_owned(), Some("api_main".to_owned())),
+            ("/about".to_owned(), Some("site_main".to_owned())),
+        ], handlers);
+    }
+
     #[test]
     fn test_group_members() {
         let inv = Invoice {
@@ -460,4 +763,82 @@ mod test {
         let members = membership_map.get("coffee").expect("there should have been a group called 'coffee'");
         assert_eq!(2, members.len());
     }
+
+    fn file_parcel(name: &str, group: &str) -> Parcel {
+        let mut wagifeatures = BTreeMap::new();
+        wagifeatures.insert("file".to_owned(), "true".to_owned());
+        let mut features = BTreeMap::new();
+        features.insert("wagi".to_owned(), wagifeatures);
+        Parcel {
+            label: Label {
+                sha256: format!("sha-of-{}", name),
+                name: name.to_owned(),
+                media_type: "text/plain".to_owned(),
+                size: 1234,
+                annotations: None,
+                feature: Some(features),
+                origin: None,
+            },
+            conditions: Some(Condition {
+                member_of: Some(vec![group.to_owned()]),
+                requires: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_asset_mounts_feature_mounts_groups_separately() {
+        let mut wagifeatures = BTreeMap::new();
+        wagifeatures.insert("route".to_owned(), "/".to_owned());
+        wagifeatures.insert("asset_mounts".to_owned(), "templates=/templates, missing=/nothing, bad".to_owned());
+        let mut features = BTreeMap::new();
+        features.insert("wagi".to_owned(), wagifeatures);
+
+        let inv = InvoiceUnderstander::new(&Invoice {
+            bindle_version: "v1".to_owned(),
+            yanked: None,
+            yanked_signature: None,
+            signature: None,
+            annotations: None,
+            bindle: BindleSpec {
+                id: "site/1.0.0"
+                    .to_owned()
+                    .try_into()
+                    .expect("This should parse"),
+                description: None,
+                authors: None,
+            },
+            group: None,
+            parcel: Some(vec![
+                Parcel {
+                    label: Label {
+                        sha256: "abc123".to_owned(),
+                        name: "site.wasm".to_owned(),
+                        media_type: WASM_MEDIA_TYPE.to_owned(),
+                        size: 1234,
+                        annotations: None,
+                        feature: Some(features),
+                        origin: None,
+                    },
+                    conditions: Some(Condition {
+                        member_of: None,
+                        requires: Some(vec!["static".to_owned(), "templates".to_owned()]),
+                    }),
+                },
+                file_parcel("style.css", "static"),
+                file_parcel("page.html", "templates"),
+            ]),
+        });
+
+        let handlers = inv.parse_wagi_handlers();
+        assert_eq!(1, handlers.len());
+        let root_assets: Vec<_> = handlers[0].asset_parcels().into_iter().map(|p| p.label.name).collect();
+        assert_eq!(vec!["style.css".to_owned()], root_assets);
+
+        let mounts = &handlers[0].group_mounts;
+        assert_eq!(1, mounts.len(), "only the valid mount of a group with files should be kept");
+        assert_eq!("templates", mounts[0].group);
+        assert_eq!("/templates", mounts[0].guest_path);
+        assert_eq!("page.html", mounts[0].parcels[0].label.name);
+    }
 }
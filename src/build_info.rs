@@ -0,0 +1,160 @@
+//! What this server is running: build provenance, the bindle it serves and the
+//! loaded modules.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::TimeZone;
+use serde::Serialize;
+
+use crate::http_util::env_var_name;
+
+/// The path at which the inbuilt version handler is mounted.
+pub const VERSION_ROUTE: &str = "/_wagi/version";
+
+pub const WAGI_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const WASMTIME_VERSION: &str = env!("WAGI_WASMTIME_VERSION");
+pub const GIT_SHA: &str = env!("WAGI_GIT_SHA");
+const BUILD_TIMESTAMP: &str = env!("WAGI_BUILD_TIMESTAMP");
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ModuleInventoryEntry {
+    pub name: String,
+    pub route: String,
+    /// The SHA256 digest of the module as loaded, before compilation.
+    pub digest: String,
+}
+
+/// What the invoice of the bindle being served says about it. Only the
+/// annotations WAGI was asked to pass on are kept.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BindleInfo {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl BindleInfo {
+    pub fn from_invoice(invoice: &bindle::Invoice, annotation_keys: &[String]) -> Self {
+        let annotations = invoice.annotations.as_ref();
+        Self {
+            id: invoice.bindle.id.to_string(),
+            name: invoice.bindle.id.name().to_owned(),
+            version: invoice.bindle.id.version_string(),
+            description: invoice.bindle.description.clone(),
+            annotations: annotation_keys
+                .iter()
+                .filter_map(|key| annotations.and_then(|a| a.get(key)).map(|value| (key.clone(), value.clone())))
+                .collect(),
+        }
+    }
+
+    /// `X_BINDLE_ID`, `X_BINDLE_NAME`, `X_BINDLE_VERSION`, `X_BINDLE_DESCRIPTION`
+    /// if the invoice has one, and `X_BINDLE_ANNOTATION_<KEY>` for each annotation.
+    pub fn env_vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+        vars.insert("X_BINDLE_ID".to_owned(), self.id.clone());
+        vars.insert("X_BINDLE_NAME".to_owned(), self.name.clone());
+        vars.insert("X_BINDLE_VERSION".to_owned(), self.version.clone());
+        if let Some(description) = &self.description {
+            vars.insert("X_BINDLE_DESCRIPTION".to_owned(), description.clone());
+        }
+        for (key, value) in &self.annotations {
+            vars.insert(env_var_name("X_BINDLE_ANNOTATION_", key), value.clone());
+        }
+        vars
+    }
+}
+
+#[derive(Serialize)]
+struct VersionInfo<'a> {
+    wagi_version: &'a str,
+    wasmtime_version: &'a str,
+    git_sha: &'a str,
+    build_timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bindle: Option<&'a BindleInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modules: Option<&'a [ModuleInventoryEntry]>,
+}
+
+/// The build time in RFC 3339 format.
+pub fn build_timestamp() -> String {
+    let seconds = BUILD_TIMESTAMP.parse().unwrap_or_default();
+    chrono::Utc
+        .timestamp_opt(seconds, 0)
+        .single()
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// The build details, and, if `modules` is given, what is being served. The module
+/// inventory and bindle are for operators, like the route list, so are left out
+/// for other clients.
+pub fn render_version_json(modules: Option<&[ModuleInventoryEntry]>, bindle: Option<&BindleInfo>) -> String {
+    let info = VersionInfo {
+        wagi_version: WAGI_VERSION,
+        wasmtime_version: WASMTIME_VERSION,
+        git_sha: GIT_SHA,
+        build_timestamp: build_timestamp(),
+        bindle,
+        modules,
+    };
+    // Serializing plain strings cannot fail.
+    serde_json::to_string_pretty(&info).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version_json_lists_modules() {
+        let modules = vec![ModuleInventoryEntry {
+            name: "hello.wasm".to_owned(),
+            route: "/hello".to_owned(),
+            digest: "abc123".to_owned(),
+        }];
+        let json: serde_json::Value = serde_json::from_str(&render_version_json(Some(&modules), None)).unwrap();
+        assert_eq!(WAGI_VERSION, json["wagi_version"]);
+        assert_eq!("hello.wasm", json["modules"][0]["name"]);
+        assert_eq!("abc123", json["modules"][0]["digest"]);
+        assert!(json["build_timestamp"].as_str().unwrap().starts_with(char::is_numeric));
+        assert!(json.get("bindle").is_none());
+
+        let json: serde_json::Value = serde_json::from_str(&render_version_json(None, None)).unwrap();
+        assert_eq!(WAGI_VERSION, json["wagi_version"]);
+        assert!(json.get("modules").is_none());
+    }
+
+    #[test]
+    fn bindle_info_keeps_only_the_chosen_annotations() {
+        let invoice: bindle::Invoice = toml::from_str(r#"
+            bindleVersion = "1.0.0"
+
+            [bindle]
+            name = "example.com/shop"
+            version = "1.4.2"
+            description = "The shop front"
+
+            [annotations]
+            "git-commit" = "4f2a9c1"
+            owner = "team-shop"
+        "#).unwrap();
+        let bindle = BindleInfo::from_invoice(&invoice, &["git-commit".to_owned(), "missing".to_owned()]);
+
+        let vars = bindle.env_vars();
+        assert_eq!("example.com/shop/1.4.2", vars["X_BINDLE_ID"]);
+        assert_eq!("1.4.2", vars["X_BINDLE_VERSION"]);
+        assert_eq!("The shop front", vars["X_BINDLE_DESCRIPTION"]);
+        assert_eq!("4f2a9c1", vars["X_BINDLE_ANNOTATION_GIT_COMMIT"]);
+        assert_eq!(5, vars.len());
+
+        let json: serde_json::Value = serde_json::from_str(&render_version_json(Some(&[]), Some(&bindle))).unwrap();
+        assert_eq!("1.4.2", json["bindle"]["version"]);
+        assert_eq!("4f2a9c1", json["bindle"]["annotations"]["git-commit"]);
+    }
+}
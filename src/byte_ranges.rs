@@ -0,0 +1,223 @@
+//! Byte range requests for module responses.
+//!
+//! A module's whole response is collected, in memory or spilled to a file, before
+//! it is sent, so WAGI can answer a `Range` request from it even though the module
+//! knows nothing about ranges. This is for modules that serve media, so that clients
+//! can seek. Modules opt in with `byte_ranges = true`; their `200 OK` responses to
+//! `GET` requests then advertise `Accept-Ranges: bytes`.
+//!
+//! Only single ranges are served. A request for several ranges, or with a `Range`
+//! header that can't be parsed, gets the whole response, as RFC 9110 allows. An
+//! `If-Range` validator must match the response's strong `ETag` or its
+//! `Last-Modified` date exactly, or the whole response is sent.
+
+use std::ops::Range;
+
+use hyper::header::{HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+
+/// How much of the response body to send.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RangeOutcome {
+    /// The whole body.
+    Full,
+    /// Only these bytes of it.
+    Partial(Range<u64>),
+    /// None of it: the range starts past the end of the body.
+    Unsatisfiable,
+}
+
+/// Decides how to answer the request's `Range` header from a response whose body
+/// is `length` bytes long, and sets the response's status and headers to match.
+/// The caller replaces the body.
+pub fn select(req: &Parts, res: &mut Response<Body>, length: u64) -> RangeOutcome {
+    if res.status() != StatusCode::OK {
+        return RangeOutcome::Full;
+    }
+    // A module can serve its own ranges, or refuse them with `Accept-Ranges: none`
+    match res.headers().get(ACCEPT_RANGES) {
+        Some(value) if !value.as_bytes().eq_ignore_ascii_case(b"bytes") => return RangeOutcome::Full,
+        Some(_) => (),
+        None => {
+            res.headers_mut().insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        }
+    }
+    if req.method != Method::GET {
+        return RangeOutcome::Full;
+    }
+    let range = match req.headers.get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) => range,
+        None => return RangeOutcome::Full,
+    };
+    if let Some(validator) = req.headers.get(IF_RANGE) {
+        if !if_range_matches(validator, res) {
+            return RangeOutcome::Full;
+        }
+    }
+
+    let outcome = parse_range(range, length);
+    match &outcome {
+        RangeOutcome::Full => (),
+        RangeOutcome::Partial(bytes) => {
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+            let content_range = format!("bytes {}-{}/{}", bytes.start, bytes.end - 1, length);
+            res.headers_mut().insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).unwrap());
+            // Any length the module gave is for the whole body
+            res.headers_mut().remove(CONTENT_LENGTH);
+        }
+        RangeOutcome::Unsatisfiable => {
+            *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            let content_range = format!("bytes */{}", length);
+            res.headers_mut().insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).unwrap());
+            res.headers_mut().remove(CONTENT_LENGTH);
+        }
+    }
+    outcome
+}
+
+// Weak entity tags can't be used with If-Range, so only a strong tag, compared
+// byte for byte, or the exact Last-Modified date will do.
+fn if_range_matches(validator: &HeaderValue, res: &Response<Body>) -> bool {
+    let validator = validator.as_bytes();
+    if validator.starts_with(b"\"") {
+        res.headers().get(ETAG).map(|etag| etag.as_bytes() == validator).unwrap_or(false)
+    } else if validator.starts_with(b"W/") {
+        false
+    } else {
+        res.headers().get(LAST_MODIFIED).map(|date| date.as_bytes() == validator).unwrap_or(false)
+    }
+}
+
+// Parses a `bytes=` range header for a body of `length` bytes.
+fn parse_range(header: &str, length: u64) -> RangeOutcome {
+    let spec = match header.trim().split_once('=') {
+        Some((unit, spec)) if unit.trim().eq_ignore_ascii_case("bytes") => spec.trim(),
+        _ => return RangeOutcome::Full,
+    };
+    if spec.contains(',') {
+        return RangeOutcome::Full;
+    }
+    let (first, last) = match spec.split_once('-') {
+        Some((first, last)) => (first.trim(), last.trim()),
+        None => return RangeOutcome::Full,
+    };
+    let parse = |text: &str| -> Option<u64> {
+        if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+            None
+        } else {
+            text.parse().ok()
+        }
+    };
+
+    if first.is_empty() {
+        // The last `suffix` bytes
+        return match parse(last) {
+            None => RangeOutcome::Full,
+            Some(0) => RangeOutcome::Unsatisfiable,
+            Some(_) if length == 0 => RangeOutcome::Unsatisfiable,
+            Some(suffix) => RangeOutcome::Partial(length.saturating_sub(suffix)..length),
+        };
+    }
+    let start = match parse(first) {
+        Some(start) => start,
+        None => return RangeOutcome::Full,
+    };
+    let end = if last.is_empty() {
+        length
+    } else {
+        match parse(last) {
+            Some(last) if last >= start => last.saturating_add(1).min(length),
+            _ => return RangeOutcome::Full,
+        }
+    };
+    if start >= length {
+        RangeOutcome::Unsatisfiable
+    } else {
+        RangeOutcome::Partial(start..end)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Parts {
+        let mut builder = hyper::Request::get("/video.mp4");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn response(headers: &[(&str, &str)]) -> Response<Body> {
+        let mut builder = Response::builder();
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn ranges_are_parsed_and_clamped() {
+        assert_eq!(RangeOutcome::Partial(0..100), parse_range("bytes=0-99", 1000));
+        assert_eq!(RangeOutcome::Partial(500..1000), parse_range("bytes=500-", 1000));
+        assert_eq!(RangeOutcome::Partial(900..1000), parse_range("bytes=-100", 1000));
+        assert_eq!(RangeOutcome::Partial(0..1000), parse_range("bytes=-5000", 1000));
+        assert_eq!(RangeOutcome::Partial(990..1000), parse_range("bytes=990-5000", 1000));
+        assert_eq!(RangeOutcome::Unsatisfiable, parse_range("bytes=1000-", 1000));
+        assert_eq!(RangeOutcome::Unsatisfiable, parse_range("bytes=-0", 1000));
+    }
+
+    #[test]
+    fn unusable_ranges_get_the_whole_body() {
+        for header in ["bytes=5-1", "bytes=0-1,5-9", "items=0-1", "bytes=a-b", "bytes=+1-2", "bytes"] {
+            assert_eq!(RangeOutcome::Full, parse_range(header, 1000), "{}", header);
+        }
+    }
+
+    #[test]
+    fn partial_responses_describe_their_range() {
+        let mut res = response(&[("Content-Length", "1000")]);
+        let outcome = select(&request(&[("Range", "bytes=100-199")]), &mut res, 1000);
+        assert_eq!(RangeOutcome::Partial(100..200), outcome);
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.status());
+        assert_eq!("bytes 100-199/1000", res.headers()[CONTENT_RANGE]);
+        assert_eq!("bytes", res.headers()[ACCEPT_RANGES]);
+        assert!(res.headers().get(CONTENT_LENGTH).is_none());
+
+        let mut res = response(&[]);
+        assert_eq!(RangeOutcome::Unsatisfiable, select(&request(&[("Range", "bytes=2000-")]), &mut res, 1000));
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, res.status());
+        assert_eq!("bytes */1000", res.headers()[CONTENT_RANGE]);
+    }
+
+    #[test]
+    fn if_range_must_match_a_strong_validator() {
+        let headers = [("ETag", "\"v2\""), ("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")];
+        let cases = [
+            ("\"v2\"", RangeOutcome::Partial(0..10)),
+            ("\"v1\"", RangeOutcome::Full),
+            ("W/\"v2\"", RangeOutcome::Full),
+            ("Wed, 21 Oct 2015 07:28:00 GMT", RangeOutcome::Partial(0..10)),
+            ("Thu, 22 Oct 2015 07:28:00 GMT", RangeOutcome::Full),
+        ];
+        for (validator, expected) in cases {
+            let req = request(&[("Range", "bytes=0-9"), ("If-Range", validator)]);
+            assert_eq!(expected, select(&req, &mut response(&headers), 1000), "{}", validator);
+        }
+    }
+
+    #[test]
+    fn modules_can_refuse_ranges() {
+        let mut res = response(&[("Accept-Ranges", "none")]);
+        assert_eq!(RangeOutcome::Full, select(&request(&[("Range", "bytes=0-9")]), &mut res, 1000));
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("none", res.headers()[ACCEPT_RANGES]);
+
+        let mut res = response(&[]);
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        assert_eq!(RangeOutcome::Full, select(&request(&[("Range", "bytes=0-9")]), &mut res, 1000));
+        assert!(res.headers().get(ACCEPT_RANGES).is_none());
+    }
+}
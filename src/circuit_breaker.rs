@@ -0,0 +1,164 @@
+//! Per-route circuit breaking for modules that keep failing.
+//!
+//! Each routing table entry gets its own breaker. After `failure_threshold` consecutive
+//! failures the breaker opens, and requests to the route are answered with a 503 until
+//! the cool-down expires. The first request after the cool-down is let through as a
+//! trial: if it succeeds the breaker closes, otherwise it opens again.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::MetricsRegistry;
+
+const BREAKER_OPEN_METRIC: &str = "wagi_circuit_breaker_open";
+const BREAKER_TRIPS_METRIC: &str = "wagi_circuit_breaker_trips_total";
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerSettings {
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    route: String,
+    settings: CircuitBreakerSettings,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    trial_in_progress: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BreakerDecision {
+    Allow,
+    /// The route is unavailable; the value is how long until a trial request is allowed.
+    Reject(Duration),
+}
+
+impl CircuitBreaker {
+    pub fn new(route: &str, settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            route: route.to_owned(),
+            settings: settings.clone(),
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn check(&self) -> BreakerDecision {
+        self.check_at(Instant::now())
+    }
+
+    pub fn record_success(&self, metrics: &MetricsRegistry) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            tracing::info!(route = %self.route, "Trial request succeeded; closing circuit breaker");
+            metrics.set_gauge(BREAKER_OPEN_METRIC, &[("route", &self.route)], 0.0);
+        }
+        *state = BreakerState::default();
+    }
+
+    pub fn record_failure(&self, metrics: &MetricsRegistry) {
+        self.record_failure_at(Instant::now(), metrics)
+    }
+
+    fn check_at(&self, now: Instant) -> BreakerDecision {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => BreakerDecision::Allow,
+            Some(until) if now < until => BreakerDecision::Reject(until - now),
+            Some(_) if state.trial_in_progress => BreakerDecision::Reject(self.settings.cooldown),
+            Some(_) => {
+                tracing::info!(route = %self.route, "Circuit breaker cool-down expired; allowing trial request");
+                state.trial_in_progress = true;
+                BreakerDecision::Allow
+            }
+        }
+    }
+
+    fn record_failure_at(&self, now: Instant, metrics: &MetricsRegistry) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        let trial_failed = state.trial_in_progress;
+        if trial_failed || state.consecutive_failures >= self.settings.failure_threshold {
+            tracing::warn!(
+                route = %self.route,
+                consecutive_failures = state.consecutive_failures,
+                cooldown = ?self.settings.cooldown,
+                "Opening circuit breaker"
+            );
+            state.open_until = Some(now + self.settings.cooldown);
+            state.trial_in_progress = false;
+            metrics.set_gauge(BREAKER_OPEN_METRIC, &[("route", &self.route)], 1.0);
+            metrics.increment_counter(BREAKER_TRIPS_METRIC, &[("route", &self.route)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn breaker(threshold: u32) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "/test",
+            &CircuitBreakerSettings {
+                failure_threshold: threshold,
+                cooldown: Duration::from_secs(10),
+            },
+        )
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_failures() {
+        let metrics = MetricsRegistry::default();
+        let cb = breaker(3);
+        let now = Instant::now();
+
+        cb.record_failure_at(now, &metrics);
+        cb.record_failure_at(now, &metrics);
+        assert_eq!(BreakerDecision::Allow, cb.check_at(now));
+
+        cb.record_failure_at(now, &metrics);
+        assert!(matches!(cb.check_at(now), BreakerDecision::Reject(_)));
+        assert!(metrics.render().contains("wagi_circuit_breaker_open{route=\"/test\"} 1"));
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let metrics = MetricsRegistry::default();
+        let cb = breaker(2);
+        let now = Instant::now();
+
+        cb.record_failure_at(now, &metrics);
+        cb.record_success(&metrics);
+        cb.record_failure_at(now, &metrics);
+        assert_eq!(BreakerDecision::Allow, cb.check_at(now));
+    }
+
+    #[test]
+    fn breaker_allows_single_trial_after_cooldown() {
+        let metrics = MetricsRegistry::default();
+        let cb = breaker(1);
+        let now = Instant::now();
+        cb.record_failure_at(now, &metrics);
+
+        let later = now + Duration::from_secs(11);
+        assert_eq!(BreakerDecision::Allow, cb.check_at(later));
+        assert!(matches!(cb.check_at(later), BreakerDecision::Reject(_)));
+
+        // A failed trial reopens the breaker for a full cool-down
+        cb.record_failure_at(later, &metrics);
+        assert!(matches!(cb.check_at(later + Duration::from_secs(5)), BreakerDecision::Reject(_)));
+
+        let much_later = later + Duration::from_secs(11);
+        assert_eq!(BreakerDecision::Allow, cb.check_at(much_later));
+        cb.record_success(&metrics);
+        assert_eq!(BreakerDecision::Allow, cb.check_at(much_later));
+        assert!(metrics.render().contains("wagi_circuit_breaker_open{route=\"/test\"} 0"));
+    }
+}
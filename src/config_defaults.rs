@@ -0,0 +1,103 @@
+//! The settings WAGI uses when no options are given.
+//!
+//! `wagi config print-defaults` prints each option that has a default as it would
+//! be given on the command line, with the default value and what the option does,
+//! as a starting point for a service definition:
+//!
+//! ```text
+//! # The IP address and port to listen on
+//! --listen 127.0.0.1:3000
+//! ```
+//!
+//! Options whose default is to leave something off are printed commented out,
+//! with an example value.
+
+use crate::handler_loader::FetchRetryPolicy;
+use crate::header_limits::DEFAULT_MAX_HEADER_VALUE_LEN;
+use crate::health_check::DEFAULT_HEALTH_CHECK_PATH;
+use crate::log_level::DEFAULT_VERBOSE_LOG_FILTER;
+use crate::url_limits::{DEFAULT_MAX_PATH_SEGMENTS, DEFAULT_MAX_URL_LENGTH};
+use crate::wagi_config::{DEFAULT_HOSTNAME, DEFAULT_LISTEN_ON, DEFAULT_TLS_SESSION_CACHE_SIZE, DEFAULT_WASM_CACHE_CONFIG_FILE};
+
+// An option, its value, whether that value is the default (rather than an example
+// for an option that is off by default), and what the option does
+struct DefaultOption {
+    option: &'static str,
+    value: String,
+    is_default: bool,
+    about: &'static str,
+}
+
+fn default_options() -> Vec<DefaultOption> {
+    let fetch_retry = FetchRetryPolicy::default();
+    let default = |option, value: &dyn ToString, about| DefaultOption { option, value: value.to_string(), is_default: true, about };
+    let example = |option, value: &dyn ToString, about| DefaultOption { option, value: value.to_string(), is_default: false, about };
+    vec![
+        default("--listen", &DEFAULT_LISTEN_ON, "The IP address and port to listen on"),
+        default("--hostname", &DEFAULT_HOSTNAME, "The host name (and port) used when a request doesn't give one"),
+        default("--cache", &DEFAULT_WASM_CACHE_CONFIG_FILE, "The Wasmtime cache configuration file, used if it exists"),
+        example("--module-cache", &"/var/cache/wagi", "Where fetched modules are cached. By default, a new temporary directory"),
+        example("--log-dir", &"/var/log/wagi", "Where module logs are written. By default, a new temporary directory"),
+        example("--overlay-dir", &"/var/lib/wagi/overlays", "Where requests' copies of overlaid volumes go. By default, the system temporary directory"),
+        default("--cranelift-opt-level", &"speed", "How hard Cranelift optimizes compiled modules"),
+        default("--verbose-log-filter", &DEFAULT_VERBOSE_LOG_FILTER, "The log filter that SIGUSR1 switches to"),
+        example("--max-connections", &"1024", "The most client connections to have open at once. By default, no limit"),
+        example("--keep-alive-timeout", &"75", "How many seconds a connection may be idle between requests. By default, no limit"),
+        example("--max-requests-per-connection", &"1000", "How many requests to answer on a connection before closing it. By default, no limit"),
+        default("--tls-session-cache-size", &DEFAULT_TLS_SESSION_CACHE_SIZE, "How many TLS sessions to remember for resumption"),
+        default("--trace-headers", &"x-request-id,traceparent", "The trace headers added to modules' outbound HTTP requests"),
+        example("--module-timeout", &"30", "How many seconds a module may run for. By default, no limit"),
+        example("--response-header-timeout", &"10", "How many seconds a module has to write its headers. By default, no limit"),
+        example("--circuit-breaker-threshold", &"5", "How many failures in a row take a route out of service. By default, never"),
+        default("--circuit-breaker-cooldown", &"30", "How many seconds a route stays out of service once its circuit breaker opens"),
+        default("--max-header-value-length", &DEFAULT_MAX_HEADER_VALUE_LEN, "The longest request header value passed to modules"),
+        default("--header-value-policy", &"drop", "What to do with header values over the limit: drop, truncate or reject"),
+        default("--max-url-length", &DEFAULT_MAX_URL_LENGTH, "The longest request path and query string accepted"),
+        default("--max-path-segments", &DEFAULT_MAX_PATH_SEGMENTS, "The most segments a request path may have"),
+        example("--spill-responses-over", &"8Mi", "How much output a module may write before its body goes to a file. By default, all of it"),
+        default("--drain-period", &"0", "How many seconds to keep answering 503 after a shutdown signal"),
+        example("--recycle-after-requests", &"1000000", "How many requests to handle before shutting down to be restarted. By default, no limit"),
+        example("--recycle-after", &"86400", "How many seconds to run for before shutting down to be restarted. By default, no limit"),
+        default("--fetch-attempts", &fetch_retry.attempts, "How many times to try fetching a remote module or parcel"),
+        default("--fetch-retry-delay", &fetch_retry.first_delay.as_secs_f64(), "How many seconds to wait before the first retry of a failed fetch"),
+        default("--missing-parcel", &"fail", "What to do when a bindle parcel can't be fetched: fail, skip or retry"),
+        default("--on-compile-error", &"fail", "What to do when a module can't be compiled: fail or skip"),
+        default("--health-check-path", &DEFAULT_HEALTH_CHECK_PATH, "The path of the inbuilt health check route"),
+        default("--health-check-body", &"OK", "The body of the health check response"),
+        default("--health-check-status", &"200", "The status of the health check response"),
+        example("--sample-every", &"1000", "Record one in this many requests to each route in full. By default, none"),
+        default("--sample-quota", &"100Mi", "The most space recorded samples may take up"),
+    ]
+}
+
+/// The options with defaults, annotated, one per line.
+pub fn annotated_defaults() -> String {
+    let mut text = String::from("# WAGI's default settings. Options that are commented out are off by default,\n# and are shown with an example value.\n");
+    for option in default_options() {
+        let comment = if option.is_default { "" } else { "# " };
+        text.push_str(&format!("\n# {}\n{}{} {}\n", option.about, comment, option.option, option.value));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_default_is_a_valid_option() {
+        for option in default_options() {
+            let args = vec!["wagi", "-c", "modules.toml", option.option, &option.value];
+            crate::wagi_app::wagi_app_definition()
+                .get_matches_from_safe(args)
+                .unwrap_or_else(|e| panic!("{} {} is not accepted: {}", option.option, option.value, e));
+        }
+    }
+
+    #[test]
+    fn options_that_are_off_are_commented_out() {
+        let text = annotated_defaults();
+        assert!(text.contains("\n--listen 127.0.0.1:3000\n"));
+        assert!(text.contains("\n# --module-timeout 30\n"));
+    }
+}
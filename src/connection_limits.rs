@@ -0,0 +1,359 @@
+//! Limits on the connections the server keeps open.
+//!
+//! By default WAGI accepts every connection it is offered and keeps idle
+//! keep-alive connections open for as long as clients like, which lets a flood of
+//! clients, or a few that never hang up, use up its file descriptors. With
+//! `--max-connections N`, WAGI stops accepting once `N` connections are open, and
+//! further clients wait in the listen backlog until one closes. With
+//! `--keep-alive-timeout SECONDS`, a connection with no request in progress is
+//! closed once it has been idle that long, and `0` turns keep-alive off. With
+//! `--max-requests-per-connection N`, the `N`th response on a connection has
+//! `Connection: close`, so that clients spread themselves over replicas again.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, CONNECTION};
+use hyper::server::accept::Accept;
+use hyper::{Body, Response};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+
+use crate::wagi_config::duration_from_secs;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionSettings {
+    /// The most connections to have open at once.
+    pub max_connections: Option<usize>,
+    /// How long a connection may sit idle between requests. Zero turns keep-alive off.
+    pub keep_alive_timeout: Option<Duration>,
+    /// Close connections after this many requests.
+    pub max_requests_per_connection: Option<u64>,
+    /// Whether to set TCP_NODELAY on accepted connections.
+    pub tcp_nodelay: bool,
+}
+
+impl ConnectionSettings {
+    /// Builds the settings from the command line values.
+    pub fn parse(max_connections: Option<&str>, keep_alive_timeout: Option<&str>, max_requests_per_connection: Option<&str>, tcp_nodelay: bool) -> anyhow::Result<Self> {
+        let max_connections = match max_connections {
+            Some(n) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => anyhow::bail!("Invalid maximum connections '{}': must be a whole number greater than zero", n),
+            },
+            None => None,
+        };
+        let keep_alive_timeout = match keep_alive_timeout {
+            Some(s) => match s.parse::<f64>().ok().and_then(duration_from_secs) {
+                Some(timeout) => Some(timeout),
+                None => anyhow::bail!("Invalid keep-alive timeout '{}': must be a number of seconds, from zero up to 100 years", s),
+            },
+            None => None,
+        };
+        let max_requests_per_connection = match max_requests_per_connection {
+            Some(n) => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => anyhow::bail!("Invalid maximum requests per connection '{}': must be a whole number greater than zero", n),
+            },
+            None => None,
+        };
+        Ok(Self { max_connections, keep_alive_timeout, max_requests_per_connection, tcp_nodelay })
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive_timeout != Some(Duration::ZERO)
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.keep_alive_timeout.filter(|t| !t.is_zero())
+    }
+}
+
+type AcquireSlot = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+/// Accepts connections from `I` while there are fewer than the maximum open.
+pub struct LimitedIncoming<I> {
+    inner: I,
+    settings: ConnectionSettings,
+    slots: Option<Arc<Semaphore>>,
+    acquiring: Option<AcquireSlot>,
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+impl<I> LimitedIncoming<I> {
+    pub fn new(inner: I, settings: &ConnectionSettings) -> Self {
+        Self {
+            inner,
+            settings: settings.clone(),
+            slots: settings.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            acquiring: None,
+            slot: None,
+        }
+    }
+}
+
+impl<I> Accept for LimitedIncoming<I>
+where
+    I: Accept + Unpin,
+    I::Conn: AsyncRead + AsyncWrite + Unpin,
+{
+    type Conn = Connection<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = &mut *self;
+        // Take a slot before accepting, so that clients over the limit wait in the
+        // listen backlog rather than holding a connection WAGI won't serve
+        if let (true, Some(slots)) = (this.slot.is_none(), &this.slots) {
+            let acquiring = this.acquiring.get_or_insert_with(|| Box::pin(slots.clone().acquire_owned()));
+            match acquiring.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(slot) => {
+                    this.acquiring = None;
+                    // The semaphore is never closed
+                    this.slot = slot.ok();
+                }
+            }
+        }
+        match Pin::new(&mut this.inner).poll_accept(cx) {
+            Poll::Ready(Some(Ok(conn))) => Poll::Ready(Some(Ok(Connection::new(conn, &this.settings, this.slot.take())))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// An accepted connection, which gives up its slot when it closes, and ends
+/// itself once it has been idle for the keep-alive timeout.
+pub struct Connection<C> {
+    inner: C,
+    requests: RequestCounter,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl<C> Connection<C> {
+    fn new(inner: C, settings: &ConnectionSettings, slot: Option<OwnedSemaphorePermit>) -> Self {
+        Self {
+            inner,
+            requests: RequestCounter {
+                counts: Arc::default(),
+                max_requests: settings.max_requests_per_connection,
+            },
+            idle: settings.idle_timeout().map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+            _slot: slot,
+        }
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Counts the requests on the connection, for its service to wrap them in.
+    pub fn requests(&self) -> RequestCounter {
+        self.requests.clone()
+    }
+
+    fn touch(&mut self) {
+        if let Some((timeout, sleep)) = &mut self.idle {
+            sleep.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+
+    // A connection isn't idle while a request is being handled, however long the
+    // module takes
+    fn idle_timed_out(&mut self, cx: &mut Context) -> bool {
+        match &mut self.idle {
+            Some((_, sleep)) if self.requests.counts.in_progress.load(Ordering::Relaxed) == 0 => sleep.as_mut().poll(cx).is_ready(),
+            _ => false,
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Connection<C> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    self.touch();
+                }
+                Poll::Ready(result)
+            }
+            // Ending the read, as if the client had closed the connection, makes
+            // hyper close it
+            Poll::Pending if self.idle_timed_out(cx) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Connection<C> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.touch();
+            }
+        }
+        result
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context, bufs: &[io::IoSlice]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.touch();
+            }
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Default)]
+struct RequestCounts {
+    handled: AtomicU64,
+    in_progress: AtomicUsize,
+}
+
+/// The requests on one connection.
+#[derive(Clone, Debug)]
+pub struct RequestCounter {
+    counts: Arc<RequestCounts>,
+    max_requests: Option<u64>,
+}
+
+impl RequestCounter {
+    /// Handles a request on the connection, asking the client to close the
+    /// connection after the response if it has had its share of requests.
+    pub async fn track<E>(self, handle: impl Future<Output = Result<Response<Body>, E>>) -> Result<Response<Body>, E> {
+        let number = self.counts.handled.fetch_add(1, Ordering::Relaxed) + 1;
+        let _in_progress = InProgress::start(&self.counts);
+        let mut res = handle.await?;
+        if matches!(self.max_requests, Some(max) if number >= max) {
+            res.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+        Ok(res)
+    }
+}
+
+// Counts a request as in progress until it is dropped, including if the client
+// goes away first
+struct InProgress<'a>(&'a RequestCounts);
+
+impl<'a> InProgress<'a> {
+    fn start(counts: &'a RequestCounts) -> Self {
+        counts.in_progress.fetch_add(1, Ordering::Relaxed);
+        Self(counts)
+    }
+}
+
+impl Drop for InProgress<'_> {
+    fn drop(&mut self) {
+        self.0.in_progress.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn settings(max_connections: Option<usize>, keep_alive_timeout: Option<Duration>, max_requests_per_connection: Option<u64>) -> ConnectionSettings {
+        ConnectionSettings { max_connections, keep_alive_timeout, max_requests_per_connection, tcp_nodelay: false }
+    }
+
+    #[test]
+    fn connection_settings_are_checked() {
+        assert_eq!(ConnectionSettings::default(), ConnectionSettings::parse(None, None, None, false).unwrap());
+        ConnectionSettings::parse(Some("0"), None, None, false).expect_err("zero connections");
+        ConnectionSettings::parse(None, Some("-1"), None, false).expect_err("negative timeout");
+        ConnectionSettings::parse(None, Some("1e20"), None, false).expect_err("timeout too long to hold");
+        ConnectionSettings::parse(None, None, Some("0"), false).expect_err("zero requests");
+
+        let settings = ConnectionSettings::parse(Some("1000"), Some("0"), Some("100"), true).unwrap();
+        assert_eq!(Some(1000), settings.max_connections);
+        assert!(!settings.keep_alive());
+        assert_eq!(None, settings.idle_timeout());
+    }
+
+    #[tokio::test]
+    async fn the_last_request_on_a_connection_closes_it() {
+        let (server, _client) = tokio::io::duplex(64);
+        let connection = Connection::new(server, &settings(None, None, Some(2)), None);
+        let respond = || async { Ok::<_, std::convert::Infallible>(Response::new(Body::empty())) };
+
+        let res = connection.requests().track(respond()).await.unwrap();
+        assert!(res.headers().get(CONNECTION).is_none());
+        let res = connection.requests().track(respond()).await.unwrap();
+        assert_eq!("close", res.headers()[CONNECTION]);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_ended() {
+        let (server, mut client) = tokio::io::duplex(64);
+        let mut connection = Connection::new(server, &settings(None, Some(Duration::from_millis(50)), None), None);
+
+        client.write_all(b"GET").await.unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(3, connection.read(&mut buf).await.unwrap());
+        // Reads nothing, as if the client had closed the connection
+        let read = tokio::time::timeout(Duration::from_secs(5), connection.read(&mut buf)).await.expect("the connection should time out");
+        assert_eq!(0, read.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connections_are_not_idle_while_requests_are_in_progress() {
+        let (server, _client) = tokio::io::duplex(64);
+        let mut connection = Connection::new(server, &settings(None, Some(Duration::from_millis(20)), None), None);
+        let counts = connection.requests().counts;
+        let _in_progress = InProgress::start(&counts);
+
+        let mut buf = [0; 8];
+        tokio::time::timeout(Duration::from_millis(200), connection.read(&mut buf)).await.expect_err("the connection should wait for the request");
+    }
+
+    async fn accept<I>(incoming: &mut LimitedIncoming<I>) -> Option<Result<Connection<I::Conn>, I::Error>>
+    where
+        I: Accept + Unpin,
+        I::Conn: AsyncRead + AsyncWrite + Unpin,
+    {
+        futures::future::poll_fn(|cx| Pin::new(&mut *incoming).poll_accept(cx)).await
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_for_a_slot() {
+        let listener = hyper::server::conn::AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr();
+        let mut incoming = LimitedIncoming::new(listener, &settings(Some(1), None, None));
+
+        let _first_client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let first = accept(&mut incoming).await.unwrap().unwrap();
+        let _second_client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let second = tokio::time::timeout(Duration::from_millis(100), accept(&mut incoming)).await;
+        assert!(second.is_err(), "the second connection should wait");
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), accept(&mut incoming)).await.expect("a slot should be free").unwrap().unwrap();
+    }
+}
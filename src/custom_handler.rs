@@ -0,0 +1,67 @@
+//! Native Rust handlers served alongside modules.
+//!
+//! A program that embeds WAGI can register its own handlers for some routes, and
+//! they go in the same routing table as the modules:
+//!
+//! ```ignore
+//! let configuration = WagiConfiguration::new(handlers)?
+//!     .with_custom_handler("/native/...", |parts: &Parts, _body: Vec<u8>, _client_addr: SocketAddr| {
+//!         let path = parts.uri.path().to_owned();
+//!         async move { Response::new(Body::from(format!("Hello from {}", path))) }
+//!     });
+//! ```
+//!
+//! Routes use the same syntax as module routes. Custom routes are matched after the
+//! inbuilt routes but before any module routes, and, like the inbuilt routes, are
+//! not subject to module settings such as `allow_from`.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use hyper::{http::request::Parts, Body, Response};
+
+/// Answers requests to a custom route.
+pub trait CustomHandler: Send + Sync {
+    /// Handles a request. The body has already been read.
+    fn handle(&self, parts: &Parts, body: Vec<u8>, client_addr: SocketAddr) -> BoxFuture<'static, Response<Body>>;
+}
+
+impl<F, Fut> CustomHandler for F
+where
+    F: Fn(&Parts, Vec<u8>, SocketAddr) -> Fut + Send + Sync,
+    Fut: Future<Output = Response<Body>> + Send + 'static,
+{
+    fn handle(&self, parts: &Parts, body: Vec<u8>, client_addr: SocketAddr) -> BoxFuture<'static, Response<Body>> {
+        Box::pin(self(parts, body, client_addr))
+    }
+}
+
+impl std::fmt::Debug for dyn CustomHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CustomHandler")
+    }
+}
+
+/// The custom handlers to serve, with their routes, in matching order.
+#[derive(Clone, Default)]
+pub struct CustomHandlers {
+    routes: Vec<(String, Arc<dyn CustomHandler>)>,
+}
+
+impl std::fmt::Debug for CustomHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.routes.iter().map(|(route, _)| route)).finish()
+    }
+}
+
+impl CustomHandlers {
+    pub fn add(&mut self, route: impl Into<String>, handler: impl CustomHandler + 'static) {
+        self.routes.push((route.into(), Arc::new(handler)));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<dyn CustomHandler>)> {
+        self.routes.iter().map(|(route, handler)| (route.as_str(), handler))
+    }
+}
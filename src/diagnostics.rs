@@ -0,0 +1,203 @@
+//! Diagnostics for a running server.
+//!
+//! On Unix, sending WAGI `SIGQUIT` (Ctrl+\ in a terminal) logs a report of the
+//! server's state: the routes it is serving, how many requests each route is
+//! handling, how big the module cache is, and how much memory the process uses.
+//! The server carries on running afterwards. This helps work out why a server is
+//! stuck without attaching a debugger.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::dispatcher::LiveRoutingTable;
+use crate::wagi_config::WagiConfiguration;
+
+/// Counts the requests each route is currently handling.
+#[derive(Clone, Debug, Default)]
+pub struct InFlightRequests {
+    counts: Arc<Mutex<BTreeMap<String, usize>>>,
+}
+
+/// Marks a request as finished when dropped.
+pub struct InFlightGuard {
+    counts: Arc<Mutex<BTreeMap<String, usize>>>,
+    route: String,
+}
+
+impl InFlightRequests {
+    pub fn start(&self, route: String) -> InFlightGuard {
+        *self.counts.lock().unwrap().entry(route.clone()).or_insert(0) += 1;
+        InFlightGuard {
+            counts: self.counts.clone(),
+            route,
+        }
+    }
+
+    pub fn count(&self, route: &str) -> usize {
+        self.counts.lock().unwrap().get(route).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap().values().sum()
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.route) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.route);
+            }
+        }
+    }
+}
+
+/// Logs a state report each time the process receives `SIGQUIT`. Does nothing on
+/// platforms without it.
+pub async fn dump_state_on_signal(configuration: WagiConfiguration, routing_table: LiveRoutingTable) {
+    imp::dump_state_on_signal(configuration, routing_table).await
+}
+
+#[cfg(unix)]
+mod imp {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use crate::dispatcher::LiveRoutingTable;
+    use crate::wagi_config::WagiConfiguration;
+
+    pub async fn dump_state_on_signal(configuration: WagiConfiguration, routing_table: LiveRoutingTable) {
+        let mut quit = match signal(SignalKind::quit()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!(error = %e, "Can't listen for SIGQUIT; state dumps are not available");
+                return;
+            }
+        };
+        while quit.recv().await.is_some() {
+            let report = super::state_report(&configuration, &routing_table);
+            tracing::info!("Received SIGQUIT: dumping server state\n{}", report);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use crate::dispatcher::LiveRoutingTable;
+    use crate::wagi_config::WagiConfiguration;
+
+    pub async fn dump_state_on_signal(_configuration: WagiConfiguration, _routing_table: LiveRoutingTable) {}
+}
+
+pub fn state_report(configuration: &WagiConfiguration, routing_table: &LiveRoutingTable) -> String {
+    let current = routing_table.current();
+    let in_flight = &current.global_context().in_flight;
+    let mut report = String::new();
+
+    // Writing to a String cannot fail.
+    let _ = writeln!(report, "Routes:");
+    for (route, handler) in current.describe_routes() {
+        let _ = writeln!(report, "  {} -> {}; {} in flight", route, handler, in_flight.count(&route));
+    }
+    let _ = writeln!(report, "In-flight requests: {}", in_flight.total());
+
+    let (files, bytes) = directory_usage(&configuration.asset_cache_dir);
+    let _ = writeln!(
+        report,
+        "Module cache: {} files, {:.1} MiB in {}",
+        files,
+        bytes as f64 / (1024.0 * 1024.0),
+        configuration.asset_cache_dir.display()
+    );
+
+    match resident_memory_kb() {
+        Some((current_kb, peak_kb)) => {
+            let _ = write!(report, "Memory (RSS): {} MiB, peak {} MiB", current_kb / 1024, peak_kb / 1024);
+        }
+        None => {
+            let _ = write!(report, "Memory (RSS): not available on this platform");
+        }
+    }
+    report
+}
+
+// The number of files under a directory and their total size. Unreadable entries
+// are skipped: this is a diagnostic, not an audit.
+pub(crate) fn directory_usage(dir: &Path) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return (0, 0),
+    };
+    for entry in entries.flatten() {
+        match entry.metadata() {
+            Ok(m) if m.is_dir() => {
+                let (f, b) = directory_usage(&entry.path());
+                files += f;
+                bytes += b;
+            }
+            Ok(m) => {
+                files += 1;
+                bytes += m.len();
+            }
+            Err(_) => (),
+        }
+    }
+    (files, bytes)
+}
+
+/// The current and peak resident set size of this process, in KiB.
+#[cfg(target_os = "linux")]
+pub fn resident_memory_kb() -> Option<(u64, u64)> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+    };
+    Some((field("VmRSS:")?, field("VmHWM:")?))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_memory_kb() -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_flight_requests_are_counted_until_the_guard_drops() {
+        let in_flight = InFlightRequests::default();
+        let first = in_flight.start("/a".to_owned());
+        let second = in_flight.start("/a".to_owned());
+        let other = in_flight.start("/b".to_owned());
+        assert_eq!(2, in_flight.count("/a"));
+        assert_eq!(3, in_flight.total());
+
+        drop(first);
+        drop(other);
+        assert_eq!(1, in_flight.count("/a"));
+        assert_eq!(0, in_flight.count("/b"));
+
+        drop(second);
+        assert_eq!(0, in_flight.total());
+    }
+
+    #[test]
+    fn directory_usage_counts_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), "12345").unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("b"), "123").unwrap();
+
+        assert_eq!((2, 8), directory_usage(dir.path()));
+        assert_eq!((0, 0), directory_usage(&dir.path().join("missing")));
+    }
+}
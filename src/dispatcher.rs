@@ -1,30 +1,70 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use anyhow::Context;
 use hyper::{
+    header::SET_COOKIE,
     http::request::Parts,
-    Body, Request, Response, StatusCode,
+    Body, HeaderMap, Method, Request, Response,
 };
 use sha2::{Digest, Sha256};
 use tracing::{instrument};
 
-use crate::dynamic_route::{DynamicRoutes, interpret_routes};
-use crate::handlers::{RouteHandler, WasmRouteHandler};
-use crate::http_util::{not_found};
+use crate::access_control::IpAccessList;
+use crate::audit::{AuditEntry, AuditedRequest};
+use crate::build_info::{render_version_json, ModuleInventoryEntry, VERSION_ROUTE};
+use crate::circuit_breaker::{BreakerDecision, CircuitBreaker};
+use crate::custom_handler::CustomHandler;
+use crate::error::{WagiError, WagiResult};
+use crate::error_report::ErrorReport;
+use crate::executor::ModuleExecutor;
+use crate::experiment::{check_variants, choose_variant, ExperimentVariant};
+use crate::fallback::{check_fallbacks, falls_through};
+use crate::dynamic_route::{DynamicRoutes, RouteRestrictions, interpret_routes};
+use crate::handlers::{ContentTypeDefaults, ModuleFailed, ModuleTimedOut, RouteHandler, WasmRouteHandler};
+use crate::http_util::{bad_framing, bad_request, check_body_framing, forbidden, gateway_timeout, internal_error, method_not_allowed, not_found, response_body_start, route_disabled, service_unavailable};
+use crate::instance_limit::InstanceLimit;
+use crate::instance_pool::{InstancePool, DEFAULT_WARM_INSTANCES};
+use crate::log_level::LOG_LEVEL_ROUTE;
+use crate::maintenance::{MaintenanceRequest, MAINTENANCE_ROUTE};
+use crate::metrics::{MetricsRegistry, METRICS_ROUTE};
 use crate::request::{RequestContext, RequestGlobalContext};
+use crate::route_explain::{explain_match, ExplainRequest, EXPLAIN_ROUTE};
+use crate::route_refresh::{RefreshRequest, ROUTES_REFRESH_ROUTE};
+use crate::route_toggle::{ToggleRequest, ROUTES_ROUTE};
+use crate::scheduler::TASKS_ROUTE;
 
 use crate::handler_loader::{WasmHandlerConfigurationEntry, WasmHandlerConfiguration};
 use crate::wasm_runner::{RunWasmResult, prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance_if_present, WasmLinkOptions};
 
 #[derive(Clone, Debug)]
 pub struct RoutingTable {
-    entries: Vec<RoutingTableEntry>,
+    // Shared, so that requests and refreshed tables don't copy the entries
+    entries: Vec<Arc<RoutingTableEntry>>,
     global_context: RequestGlobalContext,
 }
 
+/// A routing table that can be replaced while the server is running, for example
+/// when modules are rebuilt in watch mode. Requests that are already in progress
+/// complete using the table they started with.
+#[derive(Clone, Debug)]
+pub struct LiveRoutingTable {
+    current: Arc<RwLock<Arc<RoutingTable>>>,
+}
+
 #[derive(Clone, Debug)]
 struct RoutingTableEntry {
     pub route_pattern: RoutePattern,
     pub handler_info: RouteHandler,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Whether the configuration puts the route in service. Runtime toggles take
+    /// precedence over this.
+    pub enabled: bool,
+    /// For a route that a module added through `_routes`, the route of the module
+    /// entry that added it.
+    pub dynamic_parent: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -43,18 +83,106 @@ impl RoutingTable {
 
         let uri_path = req.uri().path().to_owned();
 
-        let (parts, body) = req.into_parts();
-        let data = hyper::body::to_bytes(body)
-            .await
-            .unwrap_or_default()
-            .to_vec();
+        // Checked before the body is read, as the body's end is what is in doubt
+        if let Err(reason) = check_body_framing(req.headers()) {
+            tracing::info!(%client_addr, path = %uri_path, reason, "Refusing request with ambiguous body framing");
+            return Ok(bad_framing(reason));
+        }
+
+        if let Err(rejection) = self.global_context.url_limits.check(req.uri()) {
+            tracing::info!(%client_addr, ?rejection, "Refusing request with an oversized or malformed URL");
+            return Ok(self.global_context.url_limits.response(&rejection));
+        }
+
+        let (mut parts, body) = req.into_parts();
+        let route = self.route_for(&uri_path);
+        let trusted = matches!(&route, Ok(rte) if rte.is_trusted());
+        if !trusted {
+            if let Err(name) = self.global_context.header_limits.apply(&mut parts.headers) {
+                tracing::info!(%client_addr, path = %uri_path, header = %name, "Refusing request with a header value that is too long or holds control characters");
+                return Ok(bad_request(format!("The {} header is too long or holds control characters", name)));
+            }
+        }
+        let data = match hyper::body::to_bytes(body).await {
+            Ok(data) => data.to_vec(),
+            Err(e) => {
+                // Usually the client has gone away; there is no one to run the module for
+                tracing::info!(error = %e, path = %uri_path, "Error reading request body; not running module");
+                return Err(e);
+            }
+        };
 
-        match self.route_for(&uri_path) {
+        match route {
             Ok(rte) => {
-                let request_context = RequestContext {
-                    client_addr,
+                // The routes handler needs the whole table, not just its own entry
+                match rte.handler_info {
+                    RouteHandler::Routes => return Ok(self.handle_routes_request(&parts, client_addr)),
+                    RouteHandler::Explain => return Ok(self.handle_explain_request(&parts, client_addr)),
+                    RouteHandler::Maintenance => return Ok(self.handle_maintenance_request(&parts, client_addr)),
+                    _ => (),
+                }
+                if is_maintainable(rte) {
+                    if let Some(res) = self.global_context.maintenance.response_for(&rte.route_pattern.original_text()) {
+                        tracing::debug!(route = %rte.route_pattern.original_text(), "Route is in maintenance; rejecting request");
+                        return Ok(res);
+                    }
+                }
+                let (rte, new_assignment) = self.experiment_variant_entry(rte, &parts.headers);
+                if !self.is_enabled(rte) {
+                    tracing::debug!(route = %rte.route_pattern.original_text(), "Route is disabled; rejecting request");
+                    return Ok(route_disabled());
+                }
+                let route = rte.route_pattern.original_text();
+                let audited = match &rte.handler_info {
+                    RouteHandler::Wasm(WasmRouteHandler { audit: Some(settings), wasm_module_name, .. }) => {
+                        let entry = AuditEntry::new(route.clone(), wasm_module_name.clone(), client_addr.ip().to_string(), &parts, &data, settings);
+                        match AuditedRequest::start(&self.global_context.audit_log, entry).await {
+                            Ok(audited) => Some(audited),
+                            Err(e) => {
+                                // The module must not run without a record of the request
+                                let report = ErrorReport {
+                                    summary: "Could not write to the audit log",
+                                    module: None,
+                                    public_message: "",
+                                    detail: &format!("{:#}", e),
+                                    stderr_tail: &[],
+                                };
+                                return Ok(report.respond(self.global_context.debug_errors));
+                            }
+                        }
+                    },
+                    _ => None,
                 };
-                let response = rte.handle_request(&parts, data, &request_context, &self.global_context);
+                let _in_flight = self.global_context.in_flight.start(route.clone());
+                // If the client disconnects, hyper drops this future. Running modules
+                // yield at every epoch tick, so dropping the future stops the module
+                // there rather than letting it run to completion for nobody.
+                let abandoned = AbandonedRequestGuard::new(route.clone(), &self.global_context.metrics);
+                let mut fallbacks = self.fallbacks_for(rte).peekable();
+                // The body is only copied if another module may need it
+                let mut data = data;
+                let body = if fallbacks.peek().is_some() { data.clone() } else { std::mem::take(&mut data) };
+                let mut response = self.run_entry(rte, &parts, body, client_addr).await;
+                while falls_through(response.status()) {
+                    let fallback = match fallbacks.next() {
+                        Some(fallback) => fallback,
+                        None => break,
+                    };
+                    tracing::debug!(%route, status = %response.status(), "Trying the route's next fallback module");
+                    let body = if fallbacks.peek().is_some() { data.clone() } else { std::mem::take(&mut data) };
+                    response = self.run_entry(fallback, &parts, body, client_addr).await;
+                }
+                abandoned.completed();
+                if let Some(variant) = new_assignment {
+                    response.headers_mut().append(SET_COOKIE, variant.set_cookie_header());
+                }
+                if let Some(audited) = audited {
+                    // The module has run, and the started entry records that, so its
+                    // response is sent even if the completion can't be recorded
+                    if let Err(e) = audited.completed(response.status()).await {
+                        tracing::error!(error = %format!("{:#}", e), %route, "Could not record request completion in the audit log");
+                    }
+                }
                 Ok(response)
             },
             Err(_) => Ok(not_found()),
@@ -62,34 +190,331 @@ impl RoutingTable {
 
     }
 
+    async fn run_entry(&self, rte: &Arc<RoutingTableEntry>, parts: &Parts, body: Vec<u8>, client_addr: SocketAddr) -> Response<Body> {
+        let header_timeout = match &rte.handler_info {
+            RouteHandler::Wasm(w) if !w.trusted => self.global_context.response_header_timeout,
+            _ => None,
+        };
+        if let Some(timeout) = header_timeout {
+            rte.clone().handle_request_with_header_timeout(copy_parts(parts), body, client_addr, self.global_context.clone(), timeout).await
+        } else {
+            let request_context = RequestContext {
+                client_addr,
+                stdout_watch: None,
+            };
+            rte.handle_request(parts, body, &request_context, &self.global_context).await
+        }
+    }
+
+    // The modules the route falls back to, in order. Routes that modules add
+    // through `_routes` have none.
+    fn fallbacks_for<'a>(&'a self, entry: &RoutingTableEntry) -> impl Iterator<Item = &'a Arc<RoutingTableEntry>> {
+        let has_fallbacks = matches!(entry.handler_info, RouteHandler::Wasm(_)) && entry.dynamic_parent.is_none();
+        let route = entry.route_pattern.original_text();
+        self.entries
+            .iter()
+            .filter(move |e| has_fallbacks && e.is_fallback() && e.dynamic_parent.is_none() && e.route_pattern.original_text() == route)
+    }
+
     #[instrument(level = "trace", skip(self))]
-    fn route_for(&self, uri_fragment: &str) -> Result<RoutingTableEntry, anyhow::Error> {
+    fn route_for(&self, uri_fragment: &str) -> Result<&Arc<RoutingTableEntry>, anyhow::Error> {
         for r in &self.entries {
             // TODO: I THINK THIS IS WRONG.  The spec says we need to match the *last* pattern
             // if there are multiple matching wildcards (this is mentioned under the docs for
             // the _routes feature).
             tracing::trace!(path = ?r.route_pattern, uri_fragment, "Trying route path");
             if r.is_match(uri_fragment) {
-                return Ok(r.clone());
+                return Ok(r);
             }
         }
 
         Err(anyhow::anyhow!("No handler for path {}", uri_fragment))
     }
+
+    // If the route is running an experiment, picks the entry for the client's
+    // variant. Also returns the variant to set a cookie for, if the client has
+    // just been assigned one.
+    fn experiment_variant_entry<'a>(&'a self, rte: &'a Arc<RoutingTableEntry>, headers: &HeaderMap) -> (&'a Arc<RoutingTableEntry>, Option<ExperimentVariant>) {
+        let experiment = match &rte.handler_info {
+            RouteHandler::Wasm(WasmRouteHandler { experiment: Some(v), .. }) => v.experiment.clone(),
+            _ => return (rte, None),
+        };
+        let route = rte.route_pattern.original_text();
+        let candidates: Vec<(&Arc<RoutingTableEntry>, &ExperimentVariant)> = self
+            .entries
+            .iter()
+            .filter(|e| e.route_pattern.original_text() == route)
+            .filter_map(|e| match &e.handler_info {
+                RouteHandler::Wasm(w) => w.experiment.as_ref().filter(|v| v.experiment == experiment).map(|v| (e, v)),
+                _ => None,
+            })
+            .collect();
+        let variants: Vec<&ExperimentVariant> = candidates.iter().map(|(_, v)| *v).collect();
+        let (index, new_cookie) = choose_variant(&variants, headers);
+        match candidates.get(index) {
+            Some((entry, variant)) => (*entry, if new_cookie { Some((*variant).clone()) } else { None }),
+            None => (rte, None),
+        }
+    }
+
+    // Only module routes can be taken out of service.
+    fn is_enabled(&self, entry: &RoutingTableEntry) -> bool {
+        match &entry.handler_info {
+            RouteHandler::Wasm(_) => self
+                .global_context
+                .route_toggles
+                .is_enabled(&entry.route_pattern.original_text(), entry.enabled),
+            _ => true,
+        }
+    }
+
+    fn handle_routes_request(&self, parts: &Parts, client_addr: SocketAddr) -> Response<Body> {
+        // The route list shows every module and what it is called, so it is for operators
+        if !self.global_context.admin_access.allows(parts, client_addr) {
+            tracing::info!(client_addr = %client_addr, "Refusing route state request from a client that is not an operator");
+            return forbidden();
+        }
+        match parts.method {
+            Method::GET | Method::HEAD => self.routes_response(),
+            Method::POST => {
+                let toggle = match ToggleRequest::parse(parts.uri.query().unwrap_or_default()) {
+                    Ok(t) => t,
+                    Err(e) => return bad_request(format!("{:#}", e)),
+                };
+                let is_module_route = self.entries.iter().any(|e| {
+                    matches!(e.handler_info, RouteHandler::Wasm(_)) && e.route_pattern.original_text() == toggle.route
+                });
+                if !is_module_route {
+                    return not_found();
+                }
+                self.global_context.route_toggles.set(&toggle.route, toggle.enabled);
+                tracing::info!(route = %toggle.route, enabled = toggle.enabled, "Route state changed");
+                self.routes_response()
+            }
+            _ => method_not_allowed("GET, HEAD, POST"),
+        }
+    }
+
+    fn handle_explain_request(&self, parts: &Parts, client_addr: SocketAddr) -> Response<Body> {
+        if parts.method != Method::GET && parts.method != Method::HEAD {
+            return method_not_allowed("GET, HEAD");
+        }
+        // Like the route list, this shows every route
+        if !self.global_context.admin_access.allows(parts, client_addr) {
+            tracing::info!(client_addr = %client_addr, "Refusing route explanation for a client that is not an operator");
+            return forbidden();
+        }
+        let request = match ExplainRequest::parse(parts.uri.query().unwrap_or_default()) {
+            Ok(r) => r,
+            Err(e) => return bad_request(format!("{:#}", e)),
+        };
+        // Route selection takes the first match, as route_for does
+        let mut winner = None;
+        let routes: Vec<_> = self
+            .entries
+            .iter()
+            .zip(self.describe_routes())
+            .map(|(e, (route, handler))| {
+                let (matched, reason) = explain_match(&e.route_pattern, &request.path);
+                let selected = matched && winner.is_none();
+                if selected {
+                    winner = Some(route.clone());
+                }
+                let reason = if matched && !selected {
+                    format!("{}, but an earlier route comes first", reason)
+                } else {
+                    reason
+                };
+                serde_json::json!({
+                    "route": route,
+                    "handler": handler,
+                    "added_by": e.dynamic_parent,
+                    "matched": matched,
+                    "selected": selected,
+                    "reason": reason,
+                })
+            })
+            .collect();
+        let body = serde_json::json!({
+            "path": request.path,
+            "selected": winner,
+            "routes": routes,
+        });
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn handle_maintenance_request(&self, parts: &Parts, client_addr: SocketAddr) -> Response<Body> {
+        let maintenance = &self.global_context.maintenance;
+        match parts.method {
+            Method::GET | Method::HEAD => (),
+            Method::POST | Method::DELETE => {
+                // Like route state, maintenance is for operators
+                if !self.global_context.admin_access.allows(parts, client_addr) {
+                    tracing::info!(client_addr = %client_addr, "Refusing to change maintenance for a client that is not an operator");
+                    return forbidden();
+                }
+                let request = match MaintenanceRequest::parse(parts.uri.query().unwrap_or_default()) {
+                    Ok(r) => r,
+                    Err(e) => return bad_request(format!("{:#}", e)),
+                };
+                if let Some(route) = &request.route {
+                    let is_maintainable_route = self.entries.iter().any(|e| is_maintainable(e) && e.route_pattern.original_text() == *route);
+                    if !is_maintainable_route {
+                        return not_found();
+                    }
+                }
+                if parts.method == Method::POST {
+                    maintenance.enter(request.route.as_deref(), request.duration);
+                } else {
+                    maintenance.exit(request.route.as_deref());
+                }
+            }
+            _ => return method_not_allowed("GET, HEAD, POST, DELETE"),
+        }
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(maintenance.render_json()))
+            .unwrap()
+    }
+
+    fn routes_response(&self) -> Response<Body> {
+        let routes: Vec<_> = self
+            .entries
+            .iter()
+            .zip(self.describe_routes())
+            .map(|(e, (route, handler))| serde_json::json!({
+                "route": route,
+                "handler": handler,
+                "enabled": self.is_enabled(e),
+            }))
+            .collect();
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({ "routes": routes }).to_string()))
+            .unwrap()
+    }
+}
+
+impl LiveRoutingTable {
+    pub fn new(routing_table: RoutingTable) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(routing_table))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<RoutingTable> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, routing_table: RoutingTable) {
+        *self.current.write().unwrap() = Arc::new(routing_table);
+    }
+
+    /// Asks the selected modules for their routes again, and puts the new routes
+    /// in service all at once. Returns the new table.
+    pub async fn refresh_dynamic_routes(&self, request: RefreshRequest) -> anyhow::Result<Arc<RoutingTable>> {
+        let base = self.current();
+        let source = base.clone();
+        // Running _routes blocks, so it is kept off the threads serving requests
+        let refreshed = tokio::task::spawn_blocking(move || source.with_refreshed_dynamic_routes(&request)).await??;
+        let refreshed = Arc::new(refreshed);
+        let mut current = self.current.write().unwrap();
+        // A watch mode reload has newer routes than the ones just built
+        if !Arc::ptr_eq(&current, &base) {
+            anyhow::bail!("The routing table was replaced while routes were being refreshed");
+        }
+        *current = refreshed.clone();
+        tracing::info!(routes = refreshed.entries.len(), "Refreshed dynamic routes");
+        Ok(refreshed)
+    }
+
+    pub async fn handle_request(
+        &self,
+        req: Request<Body>,
+        client_addr: SocketAddr,
+    ) -> Result<Response<Body>, hyper::Error> {
+        // Refreshing routes replaces the table, so it can't be left to the table itself
+        if req.uri().path() == ROUTES_REFRESH_ROUTE {
+            let (parts, _) = req.into_parts();
+            return Ok(self.handle_refresh_request(&parts, client_addr).await);
+        }
+        self.current().handle_request(req, client_addr).await
+    }
+
+    async fn handle_refresh_request(&self, parts: &Parts, client_addr: SocketAddr) -> Response<Body> {
+        if parts.method != Method::POST {
+            return method_not_allowed("POST");
+        }
+        // As with changing route state, this is for operators
+        if !self.current().global_context.admin_access.allows(parts, client_addr) {
+            tracing::info!(client_addr = %client_addr, "Refusing to refresh routes for a client that is not an operator");
+            return forbidden();
+        }
+        let request = RefreshRequest::parse(parts.uri.query().unwrap_or_default());
+        let all = request == RefreshRequest::all();
+        if !all && !self.current().entries.iter().any(|e| e.is_selected_for_refresh(&request)) {
+            return not_found();
+        }
+        match self.refresh_dynamic_routes(request).await {
+            Ok(table) => table.routes_response(),
+            Err(e) => internal_error(format!("Could not refresh routes: {:#}", e)),
+        }
+    }
 }
 
 const DEFAULT_ENTRYPOINT: &str = "_start";
+const ABANDONED_REQUESTS_METRIC: &str = "wagi_abandoned_requests_total";
+const EVICTIONS_METRIC: &str = "wagi_module_evictions_total";
+const HEADER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+// Fetches are retried in the background for as long as it takes, so this is a guess
+const PENDING_MODULE_RETRY_AFTER: Duration = Duration::from_secs(30);
 
 impl RoutingTableEntry {
     pub fn is_match(&self, uri_fragment: &str) -> bool {
         self.route_pattern.is_match(uri_fragment)
     }
 
+    fn is_trusted(&self) -> bool {
+        matches!(&self.handler_info, RouteHandler::Wasm(w) if w.trusted)
+    }
+
+    fn is_fallback(&self) -> bool {
+        matches!(&self.handler_info, RouteHandler::Wasm(w) if w.fallback)
+    }
+
+    // The route of the module entry the route comes from
+    fn configured_route(&self) -> String {
+        self.dynamic_parent.clone().unwrap_or_else(|| self.route_pattern.original_text())
+    }
+
+    fn is_selected_for_refresh(&self, request: &RefreshRequest) -> bool {
+        match &self.handler_info {
+            RouteHandler::Wasm(w) => request.selects(&w.wasm_module_name, &self.configured_route()),
+            _ => false,
+        }
+    }
+
     fn build_from_handler_config_entry(
         source: &WasmHandlerConfigurationEntry,
+        global_context: &RequestGlobalContext,
     ) -> Option<anyhow::Result<RoutingTableEntry>> {
         let route_pattern = RoutePattern::parse(&source.info.route);
-        let wasm_route_handler = WasmRouteHandler {
+        let access_control = match IpAccessList::parse(&source.info.allow_from, &source.info.deny_from) {
+            Ok(acl) => acl,
+            Err(e) => return Some(Err(e.context(format!("Invalid allow_from/deny_from for route {}", source.info.route)))),
+        };
+        let outbound_network = match global_context.outbound_network.for_module(source.info.outbound_tls.as_ref(), source.info.outbound_source_ip) {
+            Ok(network) => network,
+            Err(e) => return Some(Err(e.context(format!("Invalid ca_certificates/outbound_source_ip for route {}", source.info.route)))),
+        };
+        let executor = ModuleExecutor::new(source.info.executor.as_ref());
+        if global_context.deny_outbound_http && source.info.allowed_hosts.as_ref().map_or(false, |h| !h.is_empty()) {
+            tracing::warn!(route = %source.info.route, module = %source.info.name, "Ignoring allowed_hosts because outbound HTTP is denied");
+        }
+        let mut wasm_route_handler = WasmRouteHandler {
             wasm_module_source: source.module.clone(),
             wasm_module_name: source.info.name.clone(),
             entrypoint: source
@@ -98,15 +523,51 @@ impl RoutingTableEntry {
                 .clone()
                 .unwrap_or_else(|| DEFAULT_ENTRYPOINT.to_owned()),
             volumes: source.info.volume_mounts.clone(),
+            volume_overlay: source.info.volume_overlay,
             allowed_hosts: source.info.allowed_hosts.clone(),
             http_max_concurrency: source.info.http_max_concurrency,
+            args_mode: source.info.args_mode,
             argv: source.info.argv.clone(),
+            argv_from_query: source.info.argv_from_query.clone(),
+            instance_pool: None,
+            instance_limit: source.info.max_instances.map(|max| Arc::new(InstanceLimit::new(&source.info.name, max))),
+            executor,
+            access_control,
+            content_type_defaults: ContentTypeDefaults {
+                content_type: source.info.default_content_type.clone().or_else(|| global_context.default_content_type.clone()),
+                charset: source.info.default_charset.clone().or_else(|| global_context.default_charset.clone()),
+            },
+            // Trusted modules are only held to a timeout of their own
+            timeout: if source.info.trusted { source.info.timeout } else { source.info.timeout.or(global_context.module_timeout) },
+            trusted: source.info.trusted,
+            fallback: source.info.fallback,
+            stderr: source.info.stderr,
+            json: source.info.json.clone(),
+            accept_content_types: source.info.accept_content_types.clone(),
+            decode_path_info: source.info.decode_path_info,
+            query_env_vars: source.info.query_env_vars,
+            byte_ranges: source.info.byte_ranges,
+            experiment: source.info.experiment.clone(),
+            audit: source.info.audit.clone(),
+            outbound_network,
+            deny_outbound_http: global_context.deny_outbound_http,
+            global_env_vars: true,
+            bindle: source.info.bindle.clone(),
         };
+        if source.info.preinstantiate {
+            tracing::debug!(route = %source.info.route, "Pre-instantiating warm standby instances");
+            let pool = InstancePool::new(&source.module, wasm_route_handler.link_options(), DEFAULT_WARM_INSTANCES);
+            wasm_route_handler.instance_pool = Some(pool);
+        }
         let handler_info = RouteHandler::Wasm(wasm_route_handler);
+        let circuit_breaker = new_circuit_breaker(&route_pattern, global_context);
 
         Some(Ok(Self {
             route_pattern,
             handler_info,
+            circuit_breaker,
+            enabled: source.info.enabled,
+            dynamic_parent: None,
         }))
     }
 
@@ -114,6 +575,19 @@ impl RoutingTableEntry {
         Self {
             route_pattern: RoutePattern::Exact(path.to_owned()),
             handler_info: handler,
+            circuit_breaker: None,
+            enabled: true,
+            dynamic_parent: None,
+        }
+    }
+
+    fn custom(route: &str, handler: Arc<dyn CustomHandler>) -> Self {
+        Self {
+            route_pattern: RoutePattern::parse(route),
+            handler_info: RouteHandler::Custom(handler),
+            circuit_breaker: None,
+            enabled: true,
+            dynamic_parent: None,
         }
     }
 
@@ -126,10 +600,52 @@ impl RoutingTableEntry {
         format!("{:x}", hasher.finalize())
     }
 
+    /// Runs the module as a separate task, and answers with a 504 if it does not
+    /// finish writing its header block within the timeout. Once the headers are
+    /// written, the module may take as long as it needs to write the body.
+    async fn handle_request_with_header_timeout(
+        self: Arc<Self>,
+        parts: Parts,
+        body: Vec<u8>,
+        client_addr: SocketAddr,
+        global_context: RequestGlobalContext,
+        timeout: Duration,
+    ) -> Response<Body> {
+        let route = self.route_pattern.original_text();
+        let (stdout_watch, mut stdout_rx) = tokio::sync::mpsc::unbounded_channel();
+        let request_context = RequestContext {
+            client_addr,
+            stdout_watch: Some(stdout_watch),
+        };
+        let mut run = AbortOnDrop(tokio::spawn(async move {
+            self.handle_request(&parts, body, &request_context, &global_context).await
+        }));
+
+        let headers_written = async {
+            // If the sender goes away without sending, the module failed before it
+            // started, and `run` has the response for that.
+            if let Some(stdout) = stdout_rx.recv().await {
+                while response_body_start(&stdout.read().unwrap()).is_none() {
+                    tokio::time::sleep(HEADER_POLL_INTERVAL).await;
+                }
+            }
+        };
+
+        tokio::select! {
+            result = &mut run.0 => return result.unwrap_or_else(|e| internal_error(e)),
+            _ = headers_written => (),
+            _ = tokio::time::sleep(timeout) => {
+                tracing::warn!(%route, ?timeout, "Module did not write response headers in time");
+                return gateway_timeout();
+            }
+        }
+        (&mut run.0).await.unwrap_or_else(|e| internal_error(e))
+    }
+
     // TODO: I don't think this rightly belongs here. But
     // reasonable place to at least understand the decomposition and
     // dependencies.
-    pub fn handle_request(
+    pub async fn handle_request(
         &self,
         req: &Parts,
         body: Vec<u8>,
@@ -137,17 +653,90 @@ impl RoutingTableEntry {
         global_context: &RequestGlobalContext,
     ) -> Response<Body> {
         match &self.handler_info {
-            RouteHandler::HealthCheck => Response::new(Body::from("OK")),
+            RouteHandler::HealthCheck => match &global_context.health_check {
+                Some(settings) => settings.response(),
+                None => not_found(),
+            },
+            RouteHandler::Metrics => {
+                // The labels name every route and module, so this is for operators
+                if !global_context.admin_access.allows(req, request_context.client_addr) {
+                    tracing::info!(client_addr = %request_context.client_addr, "Refusing metrics request from a client that is not an operator");
+                    return forbidden();
+                }
+                Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(global_context.metrics.render()))
+                    .unwrap()
+            },
+            RouteHandler::Version(modules, bindle) => {
+                // What is being served is only shown to operators, as at /_wagi/routes
+                let json = if global_context.admin_access.allows(req, request_context.client_addr) {
+                    render_version_json(Some(modules.as_slice()), bindle.as_deref())
+                } else {
+                    render_version_json(None, None)
+                };
+                Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(json))
+                    .unwrap()
+            },
+            RouteHandler::Tasks => {
+                // Task errors carry paths and host names, so this is for operators
+                if !global_context.admin_access.allows(req, request_context.client_addr) {
+                    tracing::info!(client_addr = %request_context.client_addr, "Refusing task status request from a client that is not an operator");
+                    return forbidden();
+                }
+                Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(global_context.task_status.render_json()))
+                    .unwrap()
+            },
+            // Handled by the routing table, which knows about all the routes
+            RouteHandler::Routes | RouteHandler::Explain | RouteHandler::Maintenance => not_found(),
+            RouteHandler::LogLevel => match &global_context.log_level {
+                Some(log_level) => log_level.handle_request(req, request_context.client_addr, &global_context.admin_access),
+                None => not_found(),
+            },
+            RouteHandler::Custom(handler) => handler.handle(req, body, request_context.client_addr).await,
             RouteHandler::Wasm(w) => {
-                let response = w.handle_request(&self.route_pattern, req, body, request_context, global_context, self.unique_key());
+                if !w.access_control.permits(request_context.client_addr.ip()) {
+                    tracing::info!(client_addr = %request_context.client_addr, route = %self.route_pattern.original_text(), "Client address not permitted for route");
+                    return forbidden();
+                }
+                if !w.wasm_module_source.is_loaded() {
+                    tracing::debug!(route = %self.route_pattern.original_text(), "Module is still being fetched; rejecting request");
+                    return service_unavailable(PENDING_MODULE_RETRY_AFTER);
+                }
+                if let Some(cb) = &self.circuit_breaker {
+                    if let BreakerDecision::Reject(retry_after) = cb.check() {
+                        tracing::debug!(route = %self.route_pattern.original_text(), "Circuit breaker open; rejecting request");
+                        return service_unavailable(retry_after);
+                    }
+                }
+                let response = w.handle_request(&self.route_pattern, req, body, request_context, global_context, self.unique_key()).await;
+                if let Some(cb) = &self.circuit_breaker {
+                    match &response {
+                        Ok(_) => cb.record_success(&global_context.metrics),
+                        Err(_) => cb.record_failure(&global_context.metrics),
+                    }
+                }
                 match response {
                     Ok(res) => res,
+                    Err(e) if e.is::<ModuleTimedOut>() => {
+                        tracing::error!(error = %e, "WASM module timed out");
+                        gateway_timeout()
+                    }
                     Err(e) => {
-                        tracing::error!(error = %e, "error running WASM module");
+                        let stderr_tail = e.downcast_ref::<ModuleFailed>().map(|f| f.stderr_tail.clone()).unwrap_or_default();
                         // A 500 error makes sense here
-                        let mut srv_err = Response::default();
-                        *srv_err.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                        srv_err
+                        let report = ErrorReport {
+                            summary: "Error running WASM module",
+                            module: Some(&w.wasm_module_name),
+                            public_message: "",
+                            detail: &format!("{:#}", e),
+                            stderr_tail: &stderr_tail,
+                        };
+                        report.respond(global_context.debug_errors)
                     }
                 }
         
@@ -240,54 +829,307 @@ fn concat_no_duplicate_slash(prefix: &str, suffix: &str) -> String {
 }
 
 impl RoutingTable {
-    pub fn build(source: &WasmHandlerConfiguration, global_context: RequestGlobalContext) -> anyhow::Result<RoutingTable> {
-        let user_entries = Self::build_from_handler_config_entries(&source.entries)?;
-        let full_user_entries = augment_dynamic_routes(user_entries, &global_context)?;
+    pub fn build(source: &WasmHandlerConfiguration, global_context: RequestGlobalContext) -> WagiResult<RoutingTable> {
+        check_volume_mounts(source, global_context.allow_missing_volumes)
+            .map_err(WagiError::Config)?;
+        let user_entries = Self::build_from_handler_config_entries(&source.entries, &global_context)
+            .map_err(WagiError::Config)?;
+        // Discovering dynamic routes means running the modules' _routes entrypoints
+        let full_user_entries = augment_dynamic_routes(user_entries, &global_context)
+            .map_err(WagiError::Runtime)?;
+
+        check_experiments(&full_user_entries)
+            .map_err(WagiError::Config)?;
+        check_fallbacks(full_user_entries.iter().filter(|e| e.dynamic_parent.is_none()).map(|e| (e.route_pattern.original_text(), e.is_fallback())))
+            .map_err(WagiError::Config)?;
 
-        let built_in_entries = Self::inbuilt_patterns();
+        let built_in_entries = Self::inbuilt_patterns(source, &global_context);
+        let custom_entries = global_context
+            .custom_handlers
+            .iter()
+            .map(|(route, handler)| RoutingTableEntry::custom(route, handler.clone()));
 
-        let entries = built_in_entries.into_iter().chain(full_user_entries).collect();
+        let entries = built_in_entries.into_iter().chain(custom_entries).chain(full_user_entries).map(Arc::new).collect();
         Ok(Self {
             entries,
             global_context,
         })
     }
 
-    fn build_from_handler_config_entries(entries: &[WasmHandlerConfigurationEntry]) -> anyhow::Result<Vec<RoutingTableEntry>> {
+    /// Builds the table on a blocking thread, as `build` runs modules' `_routes`
+    /// functions and waits for them. Gives the handlers back with the table.
+    pub async fn build_on_blocking_thread(source: WasmHandlerConfiguration, global_context: RequestGlobalContext) -> WagiResult<(WasmHandlerConfiguration, RoutingTable)> {
+        tokio::task::spawn_blocking(move || {
+            let routing_table = Self::build(&source, global_context)?;
+            Ok((source, routing_table))
+        })
+        .await
+        .map_err(|e| WagiError::Runtime(anyhow::Error::new(e)))?
+    }
+
+    /// A table with no routes, for a server that is not ready to handle requests.
+    pub fn empty(global_context: RequestGlobalContext) -> RoutingTable {
+        Self {
+            entries: vec![],
+            global_context,
+        }
+    }
+
+    pub fn global_context(&self) -> &RequestGlobalContext {
+        &self.global_context
+    }
+
+    /// A copy of the table in which the selected modules have been asked for their
+    /// routes again. Fails if any of them cannot say.
+    fn with_refreshed_dynamic_routes(&self, request: &RefreshRequest) -> anyhow::Result<RoutingTable> {
+        let mut entries = vec![];
+        for entry in &self.entries {
+            if !entry.is_selected_for_refresh(request) {
+                entries.push(entry.clone());
+                continue;
+            }
+            // The module's routes are put back where the module entry is
+            if let (RouteHandler::Wasm(w), None) = (&entry.handler_info, &entry.dynamic_parent) {
+                let refreshed = augment_one_wasm_with_dynamic_routes(entry, w, &self.global_context)
+                    .with_context(|| format!("Error getting routes from module {} at {}", w.wasm_module_name, entry.route_pattern.original_text()))?;
+                entries.extend(refreshed.into_iter().map(Arc::new));
+            }
+        }
+        check_experiments(entries.iter().map(Arc::as_ref))?;
+        Ok(Self {
+            entries,
+            global_context: self.global_context.clone(),
+        })
+    }
+
+    /// Drops the compiled modules, and warm instances, of routes that have not
+    /// been used for `idle_ttl`. Returns how many modules were evicted.
+    pub fn evict_idle_modules(&self, idle_ttl: Duration) -> usize {
+        let mut evicted = 0;
+        for entry in &self.entries {
+            if let RouteHandler::Wasm(w) = &entry.handler_info {
+                if w.wasm_module_source.evict_if_idle(idle_ttl) {
+                    if let Some(pool) = &w.instance_pool {
+                        pool.clear();
+                    }
+                    self.global_context.metrics.increment_counter(EVICTIONS_METRIC, &[("module", &w.wasm_module_name)]);
+                    evicted += 1;
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Each route, in matching order, with a description of what handles it.
+    pub fn describe_routes(&self) -> Vec<(String, String)> {
+        self.entries
+            .iter()
+            .map(|e| {
+                let handler = match &e.handler_info {
+                    RouteHandler::HealthCheck => "health check".to_owned(),
+                    RouteHandler::Metrics => "metrics".to_owned(),
+                    RouteHandler::Version(..) => "version".to_owned(),
+                    RouteHandler::Tasks => "task status".to_owned(),
+                    RouteHandler::Routes => "route status".to_owned(),
+                    RouteHandler::Explain => "route explanation".to_owned(),
+                    RouteHandler::LogLevel => "log level".to_owned(),
+                    RouteHandler::Maintenance => "maintenance".to_owned(),
+                    RouteHandler::Custom(_) if self.global_context.maintenance.is_active(&e.route_pattern.original_text()) => "custom handler, in maintenance".to_owned(),
+                    RouteHandler::Custom(_) => "custom handler".to_owned(),
+                    RouteHandler::Wasm(w) => {
+                        let mut description = format!("module {}, entrypoint {}", w.wasm_module_name, w.entrypoint);
+                        if let Some(pool) = &w.instance_pool {
+                            description.push_str(&format!(", {}/{} warm instances", pool.available(), pool.capacity()));
+                        }
+                        if let Some(limit) = &w.instance_limit {
+                            description.push_str(&format!(", {}/{} instances in use", limit.in_use(), limit.max_instances()));
+                        }
+                        if let Some(v) = &w.experiment {
+                            description.push_str(&format!(", experiment {} variant {}", v.experiment, v.variant));
+                        }
+                        if w.wasm_module_source.is_evicted() {
+                            description.push_str(", evicted while idle");
+                        }
+                        if !self.is_enabled(e) {
+                            description.push_str(", disabled");
+                        }
+                        if self.global_context.maintenance.is_active(&e.route_pattern.original_text()) {
+                            description.push_str(", in maintenance");
+                        }
+                        description
+                    }
+                };
+                (e.route_pattern.original_text(), handler)
+            })
+            .collect()
+    }
+
+    fn build_from_handler_config_entries(entries: &[WasmHandlerConfigurationEntry], global_context: &RequestGlobalContext) -> anyhow::Result<Vec<RoutingTableEntry>> {
         entries
             .iter()
-            .filter_map(|e| RoutingTableEntry::build_from_handler_config_entry(e))
+            .filter_map(|e| RoutingTableEntry::build_from_handler_config_entry(e, global_context))
             .collect()
     }
 
-    fn inbuilt_patterns() -> Vec<RoutingTableEntry> {
-        vec![
-            RoutingTableEntry::inbuilt("/healthz", RouteHandler::HealthCheck),
-        ]
+    fn inbuilt_patterns(source: &WasmHandlerConfiguration, global_context: &RequestGlobalContext) -> Vec<RoutingTableEntry> {
+        let inventory = source
+            .entries
+            .iter()
+            .map(|e| ModuleInventoryEntry {
+                name: e.info.name.clone(),
+                route: e.info.route.clone(),
+                digest: e.info.module_digest.clone(),
+            })
+            .collect();
+        // All of a bindle's handlers come from the same invoice
+        let bindle = source.entries.iter().find_map(|e| e.info.bindle.clone());
+        let health_check = global_context
+            .health_check
+            .as_ref()
+            .map(|settings| RoutingTableEntry::inbuilt(&settings.path, RouteHandler::HealthCheck));
+        health_check.into_iter().chain(vec![
+            RoutingTableEntry::inbuilt(METRICS_ROUTE, RouteHandler::Metrics),
+            RoutingTableEntry::inbuilt(VERSION_ROUTE, RouteHandler::Version(Arc::new(inventory), bindle)),
+            RoutingTableEntry::inbuilt(TASKS_ROUTE, RouteHandler::Tasks),
+            RoutingTableEntry::inbuilt(ROUTES_ROUTE, RouteHandler::Routes),
+            RoutingTableEntry::inbuilt(EXPLAIN_ROUTE, RouteHandler::Explain),
+            RoutingTableEntry::inbuilt(LOG_LEVEL_ROUTE, RouteHandler::LogLevel),
+            RoutingTableEntry::inbuilt(MAINTENANCE_ROUTE, RouteHandler::Maintenance),
+        ]).collect()
     }
 }
 
+fn new_circuit_breaker(route_pattern: &RoutePattern, global_context: &RequestGlobalContext) -> Option<Arc<CircuitBreaker>> {
+    global_context
+        .circuit_breaker
+        .as_ref()
+        .map(|settings| Arc::new(CircuitBreaker::new(&route_pattern.original_text(), settings)))
+}
+
+// Inbuilt routes stay up in maintenance, so that it can be ended.
+fn is_maintainable(entry: &RoutingTableEntry) -> bool {
+    matches!(entry.handler_info, RouteHandler::Wasm(_) | RouteHandler::Custom(_))
+}
+
+fn check_experiments<'a>(entries: impl IntoIterator<Item = &'a RoutingTableEntry>) -> anyhow::Result<()> {
+    check_variants(entries.into_iter().filter_map(|e| match &e.handler_info {
+        RouteHandler::Wasm(w) => w.experiment.as_ref().map(|v| (e.route_pattern.original_text(), v)),
+        _ => None,
+    }))
+}
+
 fn augment_dynamic_routes(base_entries: Vec<RoutingTableEntry>, global_context: &RequestGlobalContext) -> anyhow::Result<Vec<RoutingTableEntry>> {
     let results: anyhow::Result<Vec<_>> = base_entries.into_iter().map(|e| augment_one_with_dynamic_routes(e, global_context)).collect();
     let augmented = results?.into_iter().flatten().collect();
     Ok(augmented)
 }
 
+// Requests carry no extensions by the time they reach a module, so the method,
+// URI, version and headers are all there is to copy
+fn copy_parts(parts: &Parts) -> Parts {
+    let (mut copy, _) = Request::new(()).into_parts();
+    copy.method = parts.method.clone();
+    copy.uri = parts.uri.clone();
+    copy.version = parts.version;
+    copy.headers = parts.headers.clone();
+    copy
+}
+
+/// Aborts a spawned task when dropped, so that a module running in it stops if
+/// whoever was waiting for it goes away.
+pub(crate) struct AbortOnDrop<T>(pub(crate) tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Counts and logs requests whose handling was dropped before it completed,
+/// which happens when the client disconnects.
+struct AbandonedRequestGuard {
+    route: String,
+    metrics: MetricsRegistry,
+    completed: bool,
+}
+
+impl AbandonedRequestGuard {
+    fn new(route: String, metrics: &MetricsRegistry) -> Self {
+        Self {
+            route,
+            metrics: metrics.clone(),
+            completed: false,
+        }
+    }
+
+    fn completed(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for AbandonedRequestGuard {
+    fn drop(&mut self) {
+        if !self.completed {
+            tracing::info!(route = %self.route, "Client disconnected before the response was ready; stopped handling the request");
+            self.metrics.increment_counter(ABANDONED_REQUESTS_METRIC, &[("route", &self.route)]);
+        }
+    }
+}
+
+// A volume whose host directory cannot be opened is left out of the guest's
+// filesystem, so catch missing directories before serving rather than at request time.
+fn check_volume_mounts(source: &WasmHandlerConfiguration, allow_missing: bool) -> anyhow::Result<()> {
+    let problems = volume_mount_problems(
+        source.entries.iter().map(|e| (format!("route {}", e.info.route), &e.info.volume_mounts))
+            .chain(source.tasks.iter().map(|t| (format!("task {}", t.info.name), &t.info.volume_mounts)))
+    );
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if allow_missing {
+        for problem in &problems {
+            tracing::warn!("{}; the module will run without this volume", problem);
+        }
+        return Ok(());
+    }
+    Err(anyhow::anyhow!("Invalid volume mounts:\n  {}", problems.join("\n  ")))
+}
+
+// `mounts` pairs a description of each route or task with its volumes.
+fn volume_mount_problems<'a>(mounts: impl Iterator<Item = (String, &'a HashMap<String, String>)>) -> Vec<String> {
+    let mut problems = vec![];
+    for (owner, volumes) in mounts {
+        let mut volumes: Vec<_> = volumes.iter().collect();
+        volumes.sort();
+        for (guest, host) in volumes {
+            let problem = match std::fs::metadata(host) {
+                Ok(m) if m.is_dir() => continue,
+                Ok(_) => "is not a directory".to_owned(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => "does not exist".to_owned(),
+                Err(e) => format!("cannot be accessed: {}", e),
+            };
+            problems.push(format!("{}: host path {} for volume {} {}", owner, host, guest, problem));
+        }
+    }
+    problems
+}
+
 fn augment_one_with_dynamic_routes(routing_table_entry: RoutingTableEntry, global_context: &RequestGlobalContext) -> anyhow::Result<Vec<RoutingTableEntry>> {
     match &routing_table_entry.handler_info {
         RouteHandler::Wasm(w) => augment_one_wasm_with_dynamic_routes(&routing_table_entry, w, global_context),
-        RouteHandler::HealthCheck => Ok(vec![routing_table_entry]),
+        RouteHandler::HealthCheck | RouteHandler::Metrics | RouteHandler::Version(..) | RouteHandler::Tasks | RouteHandler::Routes | RouteHandler::Explain | RouteHandler::LogLevel | RouteHandler::Maintenance | RouteHandler::Custom(_) => Ok(vec![routing_table_entry]),
     }
 }
 
 fn augment_one_wasm_with_dynamic_routes(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, global_context: &RequestGlobalContext) -> anyhow::Result<Vec<RoutingTableEntry>> {
-    let redirects = prepare_stdio_streams(vec![] /* TODO: eww */, global_context, routing_table_entry.unique_key())?;
+    let redirects = prepare_stdio_streams(vec![] /* TODO: eww */, global_context, routing_table_entry.unique_key(), wasm_route_handler.stderr, &wasm_route_handler.wasm_module_name, None, None)?;
 
     let ctx = build_wasi_context_for_dynamic_route_query(redirects.streams);
-    let link_options = WasmLinkOptions::none();
-    let (store, instance) = prepare_wasm_instance(ctx, &wasm_route_handler.wasm_module_source, link_options)?;
+    let link_options = WasmLinkOptions::none().with_http_denied(global_context.deny_outbound_http);
+    // Routing tables are built before serving, outside any request, so it's fine to block here
+    let (store, instance) = futures::executor::block_on(prepare_wasm_instance(ctx, &wasm_route_handler.wasm_module_source, link_options))?;
 
-    match run_prepared_wasm_instance_if_present(instance, store, "_routes") {
+    match futures::executor::block_on(run_prepared_wasm_instance_if_present(instance, store, "_routes")) {
         RunWasmResult::WasmError(e) => Err(e),
         RunWasmResult::EntrypointNotFound => Ok(vec![routing_table_entry.clone()]),
         RunWasmResult::Ok(_) => {
@@ -295,7 +1137,7 @@ fn augment_one_wasm_with_dynamic_routes(routing_table_entry: &RoutingTableEntry,
             let dynamic_routes_text = std::str::from_utf8(&*out)?;
             let dynamic_routes = interpret_routes(dynamic_routes_text)?;
         
-            let mut dynamic_route_entries = append_all_dynamic_routes(routing_table_entry, wasm_route_handler, dynamic_routes);
+            let mut dynamic_route_entries = append_all_dynamic_routes(routing_table_entry, wasm_route_handler, dynamic_routes, global_context);
             dynamic_route_entries.reverse();
             dynamic_route_entries.push(routing_table_entry.clone());
             Ok(dynamic_route_entries)
@@ -303,26 +1145,34 @@ fn augment_one_wasm_with_dynamic_routes(routing_table_entry: &RoutingTableEntry,
     }
 }
 
-fn append_all_dynamic_routes(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_routes: DynamicRoutes) -> Vec<RoutingTableEntry> {
+fn append_all_dynamic_routes(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_routes: DynamicRoutes, global_context: &RequestGlobalContext) -> Vec<RoutingTableEntry> {
     dynamic_routes
         .subpath_entrypoints.iter()
-        .map(|dr| append_one_dynamic_route(routing_table_entry, wasm_route_handler, &dr.0, &dr.1))
+        .map(|dr| append_one_dynamic_route(routing_table_entry, wasm_route_handler, &dr.0, &dr.1, &dr.2, global_context))
         .collect()
 }
 
-fn append_one_dynamic_route(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_route_pattern: &RoutePattern, entrypoint: &str) -> RoutingTableEntry {
+fn append_one_dynamic_route(routing_table_entry: &RoutingTableEntry, wasm_route_handler: &WasmRouteHandler, dynamic_route_pattern: &RoutePattern, entrypoint: &str, restrictions: &RouteRestrictions, global_context: &RequestGlobalContext) -> RoutingTableEntry {
     let mut subpath_handler = wasm_route_handler.clone();
     subpath_handler.entrypoint = entrypoint.to_owned();
+    restrictions.apply(&mut subpath_handler);
+    // A fallback's own routes have nothing to fall back from
+    subpath_handler.fallback = false;
+    let route_pattern = routing_table_entry.route_pattern.append(dynamic_route_pattern);
+    let circuit_breaker = new_circuit_breaker(&route_pattern, global_context);
     RoutingTableEntry {
-        route_pattern: routing_table_entry.route_pattern.append(dynamic_route_pattern),
+        route_pattern,
         handler_info: RouteHandler::Wasm(subpath_handler),
+        circuit_breaker,
+        enabled: routing_table_entry.enabled,
+        dynamic_parent: Some(routing_table_entry.route_pattern.original_text()),
     }
 }
 
 fn build_wasi_context_for_dynamic_route_query(redirects: crate::wasm_module::IOStreamRedirects) -> wasi_common::WasiCtx {
     let builder = wasi_cap_std_sync::WasiCtxBuilder::new()
-        .stderr(Box::new(redirects.stderr))
-        .stdout(Box::new(redirects.stdout));
+        .stderr(redirects.stderr)
+        .stdout(redirects.stdout);
 
     builder.build()
 }
@@ -331,6 +1181,61 @@ fn build_wasi_context_for_dynamic_route_query(redirects: crate::wasm_module::IOS
 mod test {
     use super::*;
 
+    #[test]
+    fn only_requests_dropped_before_completion_are_counted_as_abandoned() {
+        let metrics = MetricsRegistry::default();
+        AbandonedRequestGuard::new("/done".to_owned(), &metrics).completed();
+        drop(AbandonedRequestGuard::new("/gone".to_owned(), &metrics));
+
+        let text = metrics.render();
+        assert!(text.contains("wagi_abandoned_requests_total{route=\"/gone\"} 1"), "{}", text);
+        assert!(!text.contains("/done"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn dropping_abort_on_drop_cancels_the_task() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = AbortOnDrop(tokio::spawn(async move {
+            let _tx = tx;
+            futures::future::pending::<()>().await;
+        }));
+        drop(task);
+        // The sender is dropped when the aborted task is torn down
+        assert!(rx.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_and_refreshes_share_the_table_entries() {
+        use crate::wagi_config::{HandlerConfigurationSource, WagiConfiguration};
+
+        let configuration = WagiConfiguration::new(HandlerConfigurationSource::InMemory(vec![])).unwrap();
+        let mut table = RoutingTable::empty(configuration.request_global_context());
+        table.entries.push(Arc::new(RoutingTableEntry::inbuilt(METRICS_ROUTE, RouteHandler::Metrics)));
+
+        let entry = table.route_for(METRICS_ROUTE).unwrap();
+        assert!(Arc::ptr_eq(entry, &table.entries[0]));
+        let refreshed = table.with_refreshed_dynamic_routes(&RefreshRequest::all()).unwrap();
+        assert!(Arc::ptr_eq(&refreshed.entries[0], &table.entries[0]));
+    }
+
+    #[test]
+    fn missing_and_non_directory_volumes_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.txt");
+        std::fs::write(&file, "not a dir").unwrap();
+        let missing = dir.path().join("missing");
+        let volumes: HashMap<String, String> = vec![
+            ("/data".to_owned(), dir.path().display().to_string()),
+            ("/file".to_owned(), file.display().to_string()),
+            ("/missing".to_owned(), missing.display().to_string()),
+        ].into_iter().collect();
+
+        let problems = volume_mount_problems(vec![("route /app".to_owned(), &volumes)].into_iter());
+        assert_eq!(2, problems.len());
+        assert_eq!(format!("route /app: host path {} for volume /file is not a directory", file.display()), problems[0]);
+        assert_eq!(format!("route /app: host path {} for volume /missing does not exist", missing.display()), problems[1]);
+    }
+
     #[test]
     fn should_produce_relative_path() {
         let uri_path = "/static/images/icon.png";
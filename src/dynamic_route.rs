@@ -1,9 +1,55 @@
 use crate::dispatcher::RoutePattern;
+use crate::handlers::WasmRouteHandler;
 
 pub struct DynamicRoutes {
     // Using a Vec rather than a HashMap because order matters
     // (and direct lookup doesn't because some routes may be prefixes)
-    pub subpath_entrypoints: Vec<(RoutePattern, String)>,  // TODO: private
+    pub subpath_entrypoints: Vec<(RoutePattern, String, RouteRestrictions)>,  // TODO: private
+}
+
+/// What a dynamic route gives up of the permissions of the module it belongs to.
+/// A line of `_routes` output can follow the entrypoint with any of `no-volumes`,
+/// `no-http` and `no-env`, e.g. `/render render no-volumes no-env`, so that an
+/// entrypoint that handles untrusted input can't reach what the rest of the
+/// module can.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RouteRestrictions {
+    /// The route gets none of the module's volumes.
+    pub no_volumes: bool,
+    /// The route can't make outbound HTTP requests.
+    pub no_http: bool,
+    /// The route doesn't get the global environment variables.
+    pub no_env: bool,
+}
+
+impl RouteRestrictions {
+    fn parse(words: &[&str]) -> anyhow::Result<Self> {
+        let mut restrictions = Self::default();
+        for word in words {
+            match *word {
+                "no-volumes" => restrictions.no_volumes = true,
+                "no-http" => restrictions.no_http = true,
+                "no-env" => restrictions.no_env = true,
+                _ => anyhow::bail!("Unknown dynamic route restriction '{}': expected no-volumes, no-http or no-env", word),
+            }
+        }
+        Ok(restrictions)
+    }
+
+    pub fn apply(&self, handler: &mut WasmRouteHandler) {
+        if self.no_volumes {
+            handler.volumes.clear();
+            handler.volume_overlay = None;
+        }
+        if self.no_http {
+            handler.deny_outbound_http = true;
+            // Warm instances were linked with the module's HTTP functions
+            handler.instance_pool = None;
+        }
+        if self.no_env {
+            handler.global_env_vars = false;
+        }
+    }
 }
 
 pub fn interpret_routes(route_text: impl Into<String>) -> anyhow::Result<DynamicRoutes> {
@@ -20,21 +66,23 @@ pub fn interpret_routes(route_text: impl Into<String>) -> anyhow::Result<Dynamic
     Ok(DynamicRoutes { subpath_entrypoints: routes })
 }
 
-fn parse_dynamic_route(line: &str) -> anyhow::Result<(RoutePattern, String)> {
+fn parse_dynamic_route(line: &str) -> anyhow::Result<(RoutePattern, String, RouteRestrictions)> {
     let parts: Vec<&str> = line.trim().split_whitespace().collect();
 
     if parts.is_empty() {
         return Err(anyhow::anyhow!("Dynamic routes contained empty line"));
     }
-    if parts.len() != 2 {
+    if parts.len() < 2 {
         return Err(anyhow::anyhow!("Dynamic routes contained invalid line {}", line));
     }
 
     let path_text = parts.get(0).unwrap_or(&"/");
     let entrypoint = parts.get(1).unwrap_or(&"_start").to_string();
+    let restrictions = RouteRestrictions::parse(&parts[2..])
+        .map_err(|e| anyhow::anyhow!("Dynamic routes contained invalid line {}: {}", line, e))?;
 
     let route_pattern = RoutePattern::parse(path_text);
-    Ok((route_pattern, entrypoint))
+    Ok((route_pattern, entrypoint, restrictions))
 }
 
 #[cfg(test)]
@@ -90,4 +138,14 @@ mod test {
         assert_eq!(RoutePattern::Prefix("/goodbye".to_owned()), entrypoints[1].0);
         assert_eq!("au_revoir", entrypoints[1].1);
     }
+
+    #[test]
+    pub fn can_parse_route_restrictions() {
+        let routes = interpret_routes("/hello hello\n/render render no-volumes  no-env").unwrap();
+        let entrypoints = routes.subpath_entrypoints;
+
+        assert_eq!(RouteRestrictions::default(), entrypoints[0].2);
+        assert_eq!(RouteRestrictions { no_volumes: true, no_http: false, no_env: true }, entrypoints[1].2);
+        assert!(interpret_routes("/render render no-volumes no-disk").is_err());
+    }
 }
\ No newline at end of file
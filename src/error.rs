@@ -0,0 +1,38 @@
+//! The kinds of error that stop WAGI from starting or serving.
+//!
+//! Internally WAGI uses `anyhow` for error context. At the public entry points
+//! (parsing the command line, loading handlers, building the routing table and
+//! serving) errors are classified, so that callers can react to the kind of
+//! failure and the CLI can exit with a distinct status code for each.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WagiError {
+    /// The command line, modules.toml or bindle invoice is invalid.
+    #[error("Configuration error: {0:#}\nCheck the command line options and the module configuration.")]
+    Config(anyhow::Error),
+    /// A module, invoice or asset could not be fetched from its source.
+    #[error("Error fetching modules or assets: {0:#}\nCheck that the module references are correct and that their sources are reachable with the configured credentials.")]
+    Fetch(anyhow::Error),
+    /// A module could not be compiled.
+    #[error("Error compiling module: {0:#}\nCheck that the module is a valid WebAssembly module for the WASI target.")]
+    Compile(anyhow::Error),
+    /// A failure while running modules or serving requests.
+    #[error("Runtime error: {0:#}")]
+    Runtime(anyhow::Error),
+}
+
+pub type WagiResult<T> = Result<T, WagiError>;
+
+impl WagiError {
+    /// The process exit code for this kind of error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Fetch(_) => 3,
+            Self::Compile(_) => 4,
+            Self::Runtime(_) => 5,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Reporting errors that happen while handling a request.
+//!
+//! When WAGI answers `500 Internal Server Error`, it makes up a short error ID,
+//! logs it with the error and the end of the module's stderr, and sends it to the
+//! client in the body and in the `X-Wagi-Error-Id` header. A user can then report
+//! the ID, and an operator can search the logs for it to find exactly what failed.
+
+use hyper::{header::HeaderValue, Body, Response, StatusCode};
+
+pub const ERROR_ID_HEADER: &str = "x-wagi-error-id";
+
+/// A short random ID for one error.
+pub fn new_error_id() -> String {
+    format!("{:012x}", rand::random::<u64>() & 0xffff_ffff_ffff)
+}
+
+/// An error to log and answer with a 500.
+pub struct ErrorReport<'a> {
+    /// What the log entry says happened.
+    pub summary: &'a str,
+    pub module: Option<&'a str>,
+    /// What clients are told. May be empty.
+    pub public_message: &'a str,
+    /// The full error, which clients only see with `--debug-errors`.
+    pub detail: &'a str,
+    pub stderr_tail: &'a [String],
+}
+
+impl<'a> ErrorReport<'a> {
+    /// Logs the error under a new error ID, and returns the response for it. With
+    /// `debug_errors`, the body has the full error and the end of the module's stderr.
+    pub fn respond(&self, debug_errors: bool) -> Response<Body> {
+        let error_id = new_error_id();
+        tracing::error!(
+            %error_id,
+            module = self.module.unwrap_or_default(),
+            error = %self.detail,
+            stderr_tail = %self.stderr_tail.join("\n"),
+            "{}", self.summary
+        );
+        self.response(&error_id, debug_errors)
+    }
+
+    fn response(&self, error_id: &str, debug_errors: bool) -> Response<Body> {
+        let mut body = String::new();
+        let message = if debug_errors { self.detail } else { self.public_message };
+        if !message.is_empty() {
+            body.push_str(message);
+            body.push('\n');
+        }
+        body.push_str(&format!("Error ID: {}\n", error_id));
+        if debug_errors && !self.stderr_tail.is_empty() {
+            body.push_str("\nLast lines of module stderr:\n");
+            for line in self.stderr_tail {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        if let Ok(value) = HeaderValue::from_str(error_id) {
+            res.headers_mut().insert(ERROR_ID_HEADER, value);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body_of(res: Response<Body>) -> String {
+        let body = futures::executor::block_on(hyper::body::to_bytes(res.into_body())).unwrap();
+        String::from_utf8_lossy(&body).into_owned()
+    }
+
+    fn report(tail: &[String]) -> ErrorReport<'_> {
+        ErrorReport {
+            summary: "Error running WASM module",
+            module: Some("app.wasm"),
+            public_message: "",
+            detail: "wasm trap: unreachable",
+            stderr_tail: tail,
+        }
+    }
+
+    #[test]
+    fn debug_error_responses_include_the_stderr_tail() {
+        let tail = vec!["reading config".to_owned(), "panicked at 'oops'".to_owned()];
+
+        let res = report(&tail).response("0123456789ab", true);
+        assert_eq!(
+            "wasm trap: unreachable\nError ID: 0123456789ab\n\nLast lines of module stderr:\nreading config\npanicked at 'oops'\n",
+            body_of(res)
+        );
+
+        let res = report(&tail).response("0123456789ab", false);
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!("0123456789ab", res.headers()[ERROR_ID_HEADER]);
+        assert_eq!("Error ID: 0123456789ab\n", body_of(res));
+    }
+
+    #[test]
+    fn each_error_gets_its_own_id() {
+        let res = report(&[]).respond(false);
+        let id = res.headers()[ERROR_ID_HEADER].to_str().unwrap().to_owned();
+        assert_eq!(12, id.len());
+        assert_eq!(format!("Error ID: {}\n", id), body_of(res));
+        assert_ne!(id, new_error_id());
+    }
+}
@@ -0,0 +1,204 @@
+//! Running heavy modules on threads of their own.
+//!
+//! Modules normally run on the async runtime's worker threads, which every request
+//! shares. Running modules yield at every epoch tick, but a module that does a lot
+//! of computation under load still keeps those threads busy, and requests to other
+//! routes wait behind it. A module with `dedicated_threads = N` runs on a pool of
+//! `N` threads of its own instead, so that it can only hold up its own requests.
+//! With `cpus = [2, 3]`, the pool's threads are also pinned to those CPUs, so the
+//! module can be kept off the cores that serve latency-sensitive routes. A module
+//! with `cpus` and no `dedicated_threads` gets one thread per CPU. Pinning is only
+//! supported on Linux.
+//!
+//! A pool's threads are started when the module first runs, not when the routing
+//! table is built. With `--harden`, the table is built before the sandbox is
+//! applied, and the sandbox only covers threads started after it.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+
+use crate::dispatcher::AbortOnDrop;
+
+// The number of CPUs a Linux cpu_set_t can hold
+const MAX_CPUS: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecutorSettings {
+    pub threads: usize,
+    pub cpus: Option<Vec<usize>>,
+}
+
+impl ExecutorSettings {
+    /// Builds the settings from a module's `dedicated_threads` and `cpus`. There
+    /// are none unless one of them is set.
+    pub fn parse(threads: Option<usize>, cpus: Option<&[usize]>) -> anyhow::Result<Option<Self>> {
+        let cpus = match cpus {
+            None => None,
+            Some([]) => anyhow::bail!("cpus must name at least one CPU"),
+            Some(_) if !cfg!(target_os = "linux") => anyhow::bail!("cpus is only supported on Linux"),
+            Some(cpus) => {
+                if let Some(cpu) = cpus.iter().find(|c| **c >= MAX_CPUS) {
+                    anyhow::bail!("CPU {} is out of range: CPUs are numbered from 0 to {}", cpu, MAX_CPUS - 1);
+                }
+                Some(cpus.to_vec())
+            }
+        };
+        let threads = match (threads, &cpus) {
+            (Some(0), _) => anyhow::bail!("dedicated_threads must be at least 1"),
+            (Some(threads), _) => threads,
+            (None, Some(cpus)) => cpus.len(),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self { threads, cpus }))
+    }
+}
+
+/// Where a module's requests run.
+#[derive(Clone, Debug)]
+pub enum ModuleExecutor {
+    /// On the async runtime's worker threads, with everything else.
+    Shared,
+    /// On the module's own threads.
+    Dedicated(Arc<DedicatedPool>),
+}
+
+impl ModuleExecutor {
+    pub fn new(settings: Option<&ExecutorSettings>) -> Self {
+        match settings {
+            None => Self::Shared,
+            Some(settings) => Self::Dedicated(Arc::new(DedicatedPool::new(settings))),
+        }
+    }
+
+    /// Runs the future to completion on the executor's threads. Dropping the
+    /// returned future drops the one given, wherever it is running, so timeouts
+    /// and client disconnects still stop the module.
+    pub async fn run<T, F>(&self, future: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = anyhow::Result<T>> + Send + 'static,
+    {
+        match self {
+            Self::Shared => future.await,
+            Self::Dedicated(pool) => {
+                let mut task = AbortOnDrop(pool.spawn(future)?);
+                (&mut task.0).await.context("Module thread failed")?
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DedicatedPool {
+    settings: ExecutorSettings,
+    // Started on first use
+    runtime: Mutex<Option<tokio::runtime::Runtime>>,
+}
+
+impl DedicatedPool {
+    fn new(settings: &ExecutorSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            runtime: Mutex::new(None),
+        }
+    }
+
+    fn spawn<T, F>(&self, future: F) -> anyhow::Result<tokio::task::JoinHandle<T>>
+    where
+        T: Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let mut runtime = self.runtime.lock().unwrap();
+        if runtime.is_none() {
+            *runtime = Some(self.start()?);
+        }
+        Ok(runtime.as_ref().unwrap().spawn(future))
+    }
+
+    fn start(&self) -> anyhow::Result<tokio::runtime::Runtime> {
+        let cpus = self.settings.cpus.clone();
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(self.settings.threads)
+            .thread_name("wagi-dedicated")
+            .enable_all()
+            .on_thread_start(move || {
+                if let Some(cpus) = &cpus {
+                    pin_to_cpus(cpus);
+                }
+            })
+            .build()
+            .context("Could not start the module's threads")
+    }
+
+    #[cfg(test)]
+    fn is_started(&self) -> bool {
+        self.runtime.lock().unwrap().is_some()
+    }
+}
+
+impl Drop for DedicatedPool {
+    // Routing tables are dropped on the async runtime, where a runtime can't
+    // wait for its own threads to finish
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.get_mut().unwrap().take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpus(cpus: &[usize]) {
+    // SAFETY: cpu_set_t is a plain bitmask, and the CPUs were checked to fit in it
+    // when the settings were parsed
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        tracing::warn!(?cpus, error = %std::io::Error::last_os_error(), "Could not pin module thread to its CPUs");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpus(_cpus: &[usize]) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_are_only_made_for_dedicated_modules() {
+        assert_eq!(None, ExecutorSettings::parse(None, None).unwrap());
+        assert_eq!(Some(ExecutorSettings { threads: 2, cpus: None }), ExecutorSettings::parse(Some(2), None).unwrap());
+        ExecutorSettings::parse(Some(0), None).expect_err("a pool needs threads");
+        ExecutorSettings::parse(None, Some(&[])).expect_err("cpus must not be empty");
+        ExecutorSettings::parse(None, Some(&[MAX_CPUS])).expect_err("the CPU is out of range");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn modules_pinned_to_cpus_get_a_thread_per_cpu() {
+        let settings = ExecutorSettings::parse(None, Some(&[0, 1])).unwrap().unwrap();
+        assert_eq!(2, settings.threads);
+    }
+
+    #[tokio::test]
+    async fn dedicated_modules_run_on_their_own_threads() {
+        let settings = ExecutorSettings::parse(Some(1), None).unwrap();
+        let executor = ModuleExecutor::new(settings.as_ref());
+        let pool = match &executor {
+            ModuleExecutor::Dedicated(pool) => pool.clone(),
+            ModuleExecutor::Shared => panic!("the module should have a pool"),
+        };
+        assert!(!pool.is_started(), "threads are started on first use");
+        let thread = executor.run(async { Ok(std::thread::current().name().map(|n| n.to_owned())) }).await.unwrap();
+        assert_eq!(Some("wagi-dedicated".to_owned()), thread);
+        assert!(pool.is_started());
+        drop(executor);
+    }
+}
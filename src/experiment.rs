@@ -0,0 +1,157 @@
+//! A/B experiments between modules serving the same route.
+//!
+//! Module entries with the same `route` and `experiment` are the variants of that
+//! experiment. Each client is assigned a variant at random, in proportion to the
+//! variants' weights, and a cookie keeps it on that variant for later requests.
+//! The module sees the assignment in `X_EXPERIMENT` and `X_EXPERIMENT_VARIANT`.
+
+use hyper::header::{HeaderMap, HeaderValue, COOKIE};
+use rand::Rng;
+
+const COOKIE_PREFIX: &str = "wagi_exp_";
+const COOKIE_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// The experiment variant a route's module serves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExperimentVariant {
+    pub experiment: String,
+    pub variant: String,
+    /// How often the variant is assigned, relative to the other variants' weights.
+    pub weight: u32,
+}
+
+impl ExperimentVariant {
+    pub fn parse(experiment: &str, variant: &str, weight: Option<u32>) -> anyhow::Result<Self> {
+        validate_name("experiment", experiment)?;
+        validate_name("variant", variant)?;
+        if weight == Some(0) {
+            anyhow::bail!("Invalid variant_weight for variant {}: must be at least 1", variant);
+        }
+        Ok(Self {
+            experiment: experiment.to_owned(),
+            variant: variant.to_owned(),
+            weight: weight.unwrap_or(1),
+        })
+    }
+
+    /// The environment variables that tell the module which variant it is serving.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        vec![
+            ("X_EXPERIMENT".to_owned(), self.experiment.clone()),
+            ("X_EXPERIMENT_VARIANT".to_owned(), self.variant.clone()),
+        ]
+    }
+
+    /// The cookie that keeps the client on this variant.
+    pub fn set_cookie_header(&self) -> HeaderValue {
+        let cookie = format!(
+            "{}{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            COOKIE_PREFIX, self.experiment, self.variant, COOKIE_MAX_AGE_SECS
+        );
+        // Names are validated to be cookie-safe
+        HeaderValue::from_str(&cookie).expect("experiment cookie should be a valid header value")
+    }
+}
+
+// Names go into cookies, so they are kept to characters that need no quoting
+fn validate_name(what: &str, name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        anyhow::bail!("Invalid {} name '{}': must be letters, digits, '-' and '_'", what, name);
+    }
+    Ok(())
+}
+
+/// Picks the variant for a request: the one named in the client's cookie, if it is
+/// still one of the variants, or otherwise one at random by weight. Returns the
+/// index of the variant, and whether the client needs a new cookie.
+pub fn choose_variant(variants: &[&ExperimentVariant], headers: &HeaderMap) -> (usize, bool) {
+    let assigned = variants.first().and_then(|v| assigned_variant(headers, &v.experiment));
+    if let Some(index) = assigned.and_then(|a| variants.iter().position(|v| v.variant == a)) {
+        return (index, false);
+    }
+    let total: u64 = variants.iter().map(|v| v.weight as u64).sum();
+    let mut pick = rand::thread_rng().gen_range(0..total.max(1));
+    for (index, variant) in variants.iter().enumerate() {
+        if pick < variant.weight as u64 {
+            return (index, true);
+        }
+        pick -= variant.weight as u64;
+    }
+    (0, true)
+}
+
+fn assigned_variant(headers: &HeaderMap, experiment: &str) -> Option<String> {
+    let cookie_name = format!("{}{}", COOKIE_PREFIX, experiment);
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|cookie| cookie.split_once('='))
+        .find(|(name, _)| name.trim() == cookie_name)
+        .map(|(_, value)| value.trim().to_owned())
+}
+
+/// Checks that no experiment on a route has two variants with the same name.
+/// Takes each route with its variant.
+pub fn check_variants<'a>(variants: impl Iterator<Item = (String, &'a ExperimentVariant)>) -> anyhow::Result<()> {
+    let mut seen = std::collections::HashSet::new();
+    for (route, variant) in variants {
+        if !seen.insert((route.clone(), &variant.experiment, &variant.variant)) {
+            anyhow::bail!("Experiment {} has more than one variant {} for route {}", variant.experiment, variant.variant, route);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn variant(name: &str, weight: u32) -> ExperimentVariant {
+        ExperimentVariant::parse("checkout", name, Some(weight)).unwrap()
+    }
+
+    fn cookie_headers(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn assigned_variant_is_kept() {
+        let (a, b) = (variant("a", 1), variant("b", 1));
+        let variants = [&a, &b];
+        assert_eq!((1, false), choose_variant(&variants, &cookie_headers("x=1; wagi_exp_checkout=b")));
+        // A variant that no longer exists is replaced
+        let (_, new_cookie) = choose_variant(&variants, &cookie_headers("wagi_exp_checkout=gone"));
+        assert!(new_cookie);
+    }
+
+    #[test]
+    fn new_clients_are_assigned_by_weight() {
+        let (a, b) = (variant("a", 1), variant("b", 3));
+        let variants = [&a, &b];
+        let b_count = (0..4000).filter(|_| choose_variant(&variants, &HeaderMap::new()).0 == 1).count();
+        assert!((2700..3300).contains(&b_count), "b was chosen {} times out of 4000", b_count);
+    }
+
+    #[test]
+    fn names_must_be_cookie_safe() {
+        ExperimentVariant::parse("checkout-2", "new_flow", None).expect("names should be valid");
+        ExperimentVariant::parse("check out", "a", None).expect_err("experiment names cannot have spaces");
+        ExperimentVariant::parse("checkout", "a;b", None).expect_err("variant names cannot have semicolons");
+        ExperimentVariant::parse("checkout", "a", Some(0)).expect_err("weight must be positive");
+        assert_eq!(
+            "wagi_exp_checkout=a; Path=/; Max-Age=2592000; HttpOnly; SameSite=Lax",
+            variant("a", 1).set_cookie_header().to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn duplicate_variants_are_rejected() {
+        let (a, b) = (variant("a", 1), variant("a", 1));
+        check_variants(vec![("/x".to_owned(), &a), ("/y".to_owned(), &b)].into_iter()).expect("different routes");
+        check_variants(vec![("/x".to_owned(), &a), ("/x".to_owned(), &b)].into_iter()).expect_err("same route");
+    }
+}
@@ -0,0 +1,74 @@
+//! Fallback modules.
+//!
+//! A module marked `fallback = true` shares its route with a module listed before
+//! it. When the earlier module answers a request with `404 Not Found` or
+//! `500 Internal Server Error`, the request is run again by the fallback, and so on
+//! down the route's fallbacks in the order they are listed, like `try_files` in
+//! other web servers:
+//!
+//! ```toml
+//! [[module]]
+//! route = "/..."
+//! module = "app.wasm"
+//!
+//! [[module]]
+//! route = "/..."
+//! module = "fileserver.gr.wasm"
+//! fallback = true
+//!
+//! [[module]]
+//! route = "/..."
+//! module = "not-found.wasm"
+//! fallback = true
+//! ```
+//!
+//! The response of the last module tried is the one the client gets.
+
+use std::collections::HashSet;
+
+use hyper::StatusCode;
+
+/// Whether a response with this status goes on to the route's next fallback.
+pub fn falls_through(status: StatusCode) -> bool {
+    status == StatusCode::NOT_FOUND || status == StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Checks that each fallback comes after a module on the same route that is not a
+/// fallback, given the routes of the modules in order and whether each is a
+/// fallback.
+pub fn check_fallbacks(routes: impl Iterator<Item = (String, bool)>) -> anyhow::Result<()> {
+    let mut served = HashSet::new();
+    for (route, fallback) in routes {
+        if !fallback {
+            served.insert(route);
+        } else if !served.contains(&route) {
+            anyhow::bail!("The fallback module for route {} must be listed after a module for the same route that is not a fallback", route);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn routes(routes: &[(&str, bool)]) -> impl Iterator<Item = (String, bool)> {
+        routes.iter().map(|(r, f)| (r.to_string(), *f)).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn fallbacks_must_follow_a_module_for_their_route() {
+        check_fallbacks(routes(&[("/...", false), ("/...", true), ("/...", true), ("/a", false)])).unwrap();
+        check_fallbacks(routes(&[("/...", true), ("/...", false)])).expect_err("the fallback comes first");
+        check_fallbacks(routes(&[("/a", false), ("/b", true)])).expect_err("the fallback's route has no other module");
+    }
+
+    #[test]
+    fn not_found_and_server_errors_fall_through() {
+        assert!(falls_through(StatusCode::NOT_FOUND));
+        assert!(falls_through(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!falls_through(StatusCode::OK));
+        assert!(!falls_through(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!falls_through(StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,446 @@
+//! Storage for modules, invoices and assets fetched from remote sources.
+//!
+//! All module sources (OCI, bindle) and the emplacer go through a `Cache`, so
+//! there is one place that decides where fetched data lives. Entries are
+//! identified by a relative, `/`-separated key such as `<sha256>` or
+//! `_ASSETS/<invoice-key>/images/toast.png`.
+//!
+//! Entries are written to a temporary file and renamed into place, so a reader
+//! never sees a partly written entry, even from another process. When several
+//! WAGI replicas share a cache directory, a shared cache also takes a lock file
+//! while fetching an entry, so only one replica downloads it and the others wait
+//! for it to appear.
+//!
+//! A compressed cache stores entries zstd-compressed, with the SHA256 digest of
+//! the uncompressed content so that corruption is noticed when the entry is read.
+//! Assets are always stored as plain files, because their directories are mounted
+//! into modules. Compressed entries are read back transparently either way.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+#[async_trait::async_trait]
+pub trait CacheBackend: Send + Sync {
+    /// Returns the content of the entry, or `None` if there is no such entry.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    async fn put(&self, key: &str, content: &[u8]) -> anyhow::Result<()>;
+    async fn contains(&self, key: &str) -> bool;
+    /// The local filesystem path of the entry, or of the directory containing
+    /// entries under the key prefix. Returns `None` if the backend does not
+    /// keep entries on local disk.
+    fn local_path(&self, key: &str) -> Option<PathBuf>;
+    /// Waits until no other process is fetching the entry, and stops others from
+    /// fetching it until the returned lock is dropped. Backends that are not
+    /// shared between processes need not do anything.
+    async fn lock(&self, _key: &str) -> anyhow::Result<CacheLock> {
+        Ok(CacheLock::none())
+    }
+}
+
+/// Held while fetching a cache entry. Dropping it releases the lock.
+pub struct CacheLock {
+    lock_file: Option<PathBuf>,
+}
+
+impl CacheLock {
+    pub fn none() -> Self {
+        Self { lock_file: None }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        if let Some(path) = &self.lock_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!(path = %path.display(), error = %e, "Failed to remove cache lock file");
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    backend: Arc<dyn CacheBackend>,
+}
+
+impl Cache {
+    pub fn new(backend: impl CacheBackend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
+        }
+    }
+
+    pub fn local_dir(root: impl AsRef<Path>) -> Self {
+        Self::new(LocalDirCache::new(root))
+    }
+
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        self.backend.get(key).await
+    }
+
+    pub async fn put(&self, key: &str, content: &[u8]) -> anyhow::Result<()> {
+        self.backend.put(key, content).await
+    }
+
+    pub async fn contains(&self, key: &str) -> bool {
+        self.backend.contains(key).await
+    }
+
+    pub fn local_path(&self, key: &str) -> Option<PathBuf> {
+        self.backend.local_path(key)
+    }
+
+    /// Calls `fetch` and stores the result, unless the entry already exists. If
+    /// another process sharing the cache is already fetching the entry, waits
+    /// for it rather than fetching it again.
+    pub async fn put_if_missing<F, Fut>(&self, key: &str, fetch: F) -> anyhow::Result<()>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+    {
+        if self.contains(key).await {
+            return Ok(());
+        }
+        let _lock = self.backend.lock(key).await?;
+        // Whoever held the lock before us has probably fetched it
+        if self.contains(key).await {
+            return Ok(());
+        }
+        let content = fetch().await?;
+        self.put(key, &content).await
+    }
+
+    /// Like `put_if_missing`, but also fetches the entry again if its content
+    /// fails `validate`, for example because it was truncated or tampered with.
+    /// Fetched content must pass `validate` too.
+    pub async fn put_unless_valid<V, F, Fut>(&self, key: &str, validate: V, fetch: F) -> anyhow::Result<()>
+    where
+        V: Fn(&[u8]) -> anyhow::Result<()>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+    {
+        if self.is_valid(key, &validate).await {
+            return Ok(());
+        }
+        let _lock = self.backend.lock(key).await?;
+        if self.is_valid(key, &validate).await {
+            return Ok(());
+        }
+        let content = fetch().await?;
+        validate(&content).with_context(|| "Fetched content is not valid")?;
+        self.put(key, &content).await
+    }
+
+    async fn is_valid(&self, key: &str, validate: impl Fn(&[u8]) -> anyhow::Result<()>) -> bool {
+        match self.get(key).await {
+            Ok(Some(content)) => match validate(&content) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(key, error = %e, "Cache entry is not valid; fetching it again");
+                    false
+                }
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Derives a cache key from arbitrary text such as a module URL or invoice ID.
+pub fn hashed_key(text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(text);
+    let result = hasher.finalize();
+    format!("{:x}", result)
+}
+
+const LOCK_DIR: &str = "_LOCKS";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// A process that crashes while fetching leaves its lock file behind. No fetch
+// should take this long, so an older lock is assumed to be abandoned.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(600);
+
+// Entries under these prefixes are mounted into modules as directories.
+const MOUNTED_KEY_PREFIXES: &[&str] = &["_ASSETS/", "_GROUP_ASSETS/"];
+const COMPRESSED_ENTRY_MAGIC: &[u8] = b"WAGI-ZSTD\n";
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Stores each entry as a file under a root directory, with the key as its relative path.
+pub struct LocalDirCache {
+    root: PathBuf,
+    shared: bool,
+    compressed: bool,
+    quota: Option<u64>,
+}
+
+impl LocalDirCache {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+            shared: false,
+            compressed: false,
+            quota: None,
+        }
+    }
+
+    /// The directory, possibly on a network filesystem, is read and written by
+    /// other processes at the same time.
+    pub fn shared(self) -> Self {
+        Self { shared: true, ..self }
+    }
+
+    /// Store entries that are not mounted into modules zstd-compressed.
+    pub fn compressed(self) -> Self {
+        Self { compressed: true, ..self }
+    }
+
+    /// Refuse to store entries once the directory holds this many bytes.
+    pub fn with_quota(self, bytes: u64) -> Self {
+        Self { quota: Some(bytes), ..self }
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    // Lock files are kept apart from the entries, because asset directories are
+    // mounted into modules.
+    fn lock_path_for(&self, key: &str) -> PathBuf {
+        self.root.join(LOCK_DIR).join(hashed_key(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheBackend for LocalDirCache {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.path_for(key);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = tokio::fs::read(&path).await
+            .with_context(|| format!("Error reading cache file {}", path.display()))?;
+        match content.strip_prefix(COMPRESSED_ENTRY_MAGIC) {
+            Some(compressed) => decompress_entry(compressed)
+                .map(Some)
+                .with_context(|| format!("Error reading compressed cache file {}", path.display())),
+            None => Ok(Some(content)),
+        }
+    }
+
+    async fn put(&self, key: &str, content: &[u8]) -> anyhow::Result<()> {
+        let path = self.path_for(key);
+        if let Some(quota) = self.quota {
+            let (_, used) = crate::diagnostics::directory_usage(&self.root);
+            if used.saturating_add(content.len() as u64) > quota {
+                anyhow::bail!("Cannot cache {}: the cache directory {} would exceed its quota of {} bytes", key, self.root.display(), quota);
+            }
+        }
+        let is_mounted = MOUNTED_KEY_PREFIXES.iter().any(|prefix| key.starts_with(prefix));
+        let result = if self.compressed && !is_mounted {
+            let entry = compress_entry(content)
+                .with_context(|| format!("Error compressing cache entry {}", key))?;
+            safely_write(&path, &entry).await
+        } else {
+            safely_write(&path, content).await
+        };
+        result.with_context(|| format!("Error writing cache file {}", path.display()))
+    }
+
+    async fn contains(&self, key: &str) -> bool {
+        self.path_for(key).is_file()
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path_for(key))
+    }
+
+    async fn lock(&self, key: &str) -> anyhow::Result<CacheLock> {
+        if !self.shared {
+            return Ok(CacheLock::none());
+        }
+        let lock_file = self.lock_path_for(key);
+        let lock_dir = self.root.join(LOCK_DIR);
+        tokio::fs::create_dir_all(&lock_dir).await
+            .with_context(|| format!("Error creating cache lock directory {}", lock_dir.display()))?;
+        let mut waiting = false;
+        loop {
+            // Creating a file that must not already exist is atomic, including on NFS
+            match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&lock_file).await {
+                Ok(_) => return Ok(CacheLock { lock_file: Some(lock_file) }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if is_stale(&lock_file) {
+                        tracing::warn!(key, lock_file = %lock_file.display(), "Breaking abandoned cache lock");
+                        let _ = tokio::fs::remove_file(&lock_file).await;
+                        continue;
+                    }
+                    if !waiting {
+                        tracing::debug!(key, "Another process is fetching this cache entry; waiting for it");
+                        waiting = true;
+                    }
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Error creating cache lock file {}", lock_file.display()));
+                }
+            }
+        }
+    }
+}
+
+// A compressed entry is the magic bytes, the SHA256 digest of the uncompressed
+// content, and the zstd-compressed content.
+fn compress_entry(content: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut entry = COMPRESSED_ENTRY_MAGIC.to_vec();
+    entry.extend_from_slice(&Sha256::digest(content));
+    entry.extend(zstd::encode_all(content, COMPRESSION_LEVEL)?);
+    Ok(entry)
+}
+
+// Takes the entry without the magic bytes.
+fn decompress_entry(entry: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let digest_len = Sha256::output_size();
+    if entry.len() < digest_len {
+        anyhow::bail!("compressed entry is truncated");
+    }
+    let (expected_digest, compressed) = entry.split_at(digest_len);
+    let content = zstd::decode_all(compressed)?;
+    if Sha256::digest(&content).as_slice() != expected_digest {
+        anyhow::bail!("content does not match its digest");
+    }
+    Ok(content)
+}
+
+fn is_stale(lock_file: &Path) -> bool {
+    std::fs::metadata(lock_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map(|age| age > STALE_LOCK_AGE)
+        .unwrap_or(false)
+}
+
+// Writes to a temporary file in the same directory and renames it into place, so
+// that the entry appears all at once or not at all.
+async fn safely_write(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let dir = path.parent().ok_or_else(||
+        std::io::Error::new(std::io::ErrorKind::Other, format!("cache location {} has no parent directory", path.display()))
+    )?;
+    let file_name = path.file_name().ok_or_else(||
+        std::io::Error::new(std::io::ErrorKind::Other, format!("cache location {} has no file name", path.display()))
+    )?;
+    tokio::fs::create_dir_all(dir).await?;
+    let temp_path = dir.join(format!(".{}.{:016x}.tmp", file_name.to_string_lossy(), rand::random::<u64>()));
+    if let Err(e) = tokio::fs::write(&temp_path, content).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+    if let Err(e) = tokio::fs::rename(&temp_path, path).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pick_test_dir() -> PathBuf {
+        let project_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let timestamp = chrono::Local::now()
+            .format("%Y.%m.%d.%H.%M.%S.%3f")
+            .to_string();
+        project_path.join("tests_working_dir").join(format!("cache-{}", timestamp))
+    }
+
+    #[tokio::test]
+    async fn local_dir_cache_round_trips_nested_keys() {
+        let dir = pick_test_dir();
+        let cache = Cache::local_dir(&dir);
+
+        assert!(!cache.contains("_ASSETS/abc/images/toast.png").await);
+        assert!(cache.get("_ASSETS/abc/images/toast.png").await.unwrap().is_none());
+
+        cache.put("_ASSETS/abc/images/toast.png", b"toast").await
+            .expect("Should have written cache entry");
+
+        assert!(cache.contains("_ASSETS/abc/images/toast.png").await);
+        assert_eq!(b"toast".to_vec(), cache.get("_ASSETS/abc/images/toast.png").await.unwrap().unwrap());
+        assert!(cache.local_path("_ASSETS/abc").unwrap().is_dir());
+
+        tokio::fs::remove_dir_all(&dir).await
+            .expect("(note: test body passed, but cleanup failed");
+    }
+
+    #[tokio::test]
+    async fn shared_cache_fetches_each_entry_once() {
+        let dir = pick_test_dir();
+        let first = Cache::new(LocalDirCache::new(&dir).shared());
+        let second = Cache::new(LocalDirCache::new(&dir).shared());
+        let fetches = std::sync::atomic::AtomicUsize::new(0);
+        let counter = &fetches;
+        let fetch = move || async move {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_, anyhow::Error>(b"parcel".to_vec())
+        };
+
+        let (a, b) = tokio::join!(first.put_if_missing("abc", fetch), second.put_if_missing("abc", fetch));
+        a.expect("first fetch should succeed");
+        b.expect("second fetch should succeed");
+
+        assert_eq!(1, fetches.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(b"parcel".to_vec(), first.get("abc").await.unwrap().unwrap());
+        // Neither temporary files nor the lock should be left behind
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().filter(|e| e.as_ref().unwrap().path().is_file()).count());
+        assert_eq!(0, std::fs::read_dir(dir.join(LOCK_DIR)).unwrap().count());
+
+        tokio::fs::remove_dir_all(&dir).await
+            .expect("(note: test body passed, but cleanup failed");
+    }
+
+    #[tokio::test]
+    async fn compressed_cache_compresses_modules_but_not_assets() {
+        let dir = pick_test_dir();
+        let cache = Cache::new(LocalDirCache::new(&dir).compressed());
+        let module = b"(module) ".repeat(1000);
+
+        cache.put("abc", &module).await.unwrap();
+        cache.put("_ASSETS/abc/page.html", b"<html/>").await.unwrap();
+
+        let stored = std::fs::read(dir.join("abc")).unwrap();
+        assert!(stored.starts_with(COMPRESSED_ENTRY_MAGIC));
+        assert!(stored.len() < module.len());
+        assert_eq!(module, cache.get("abc").await.unwrap().unwrap());
+        assert_eq!(b"<html/>".to_vec(), std::fs::read(dir.join("_ASSETS/abc/page.html")).unwrap());
+
+        // A plain cache still reads compressed entries
+        assert_eq!(module, Cache::local_dir(&dir).get("abc").await.unwrap().unwrap());
+
+        let mut corrupt = stored.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        std::fs::write(dir.join("abc"), corrupt).unwrap();
+        cache.get("abc").await.expect_err("corrupt entry should not be returned");
+
+        tokio::fs::remove_dir_all(&dir).await
+            .expect("(note: test body passed, but cleanup failed");
+    }
+
+    #[tokio::test]
+    async fn cache_with_quota_refuses_entries_that_do_not_fit() {
+        let dir = pick_test_dir();
+        let cache = Cache::new(LocalDirCache::new(&dir).with_quota(10));
+
+        cache.put("abc", b"123456").await.expect("entry should fit");
+        cache.put("def", b"123456").await.expect_err("entry should exceed the quota");
+        assert!(!cache.contains("def").await);
+
+        tokio::fs::remove_dir_all(&dir).await
+            .expect("(note: test body passed, but cleanup failed");
+    }
+}
@@ -1,52 +1,202 @@
 use std::path::PathBuf;
 
 use anyhow::Context;
+use wasmtime::{Engine, Module};
 
-use crate::wasm_module::WasmModuleSource;
+use crate::wasm_module::{has_start_function, EngineSettings, PendingModule, WasmModuleSource};
 
 use super::{
-    loader::{LoadedHandlerConfiguration, LoadedHandlerConfigurationEntry},
-    WasmHandlerConfiguration, WasmHandlerConfigurationEntry,
+    loader::{LoadedHandlerConfiguration, LoadedHandlerConfigurationEntry, LoadedModule, LoadedTaskConfigurationEntry},
+    precompiled::PrecompiledModules,
+    HandlerInfo, WasmHandlerConfiguration, WasmHandlerConfigurationEntry, WasmTaskConfigurationEntry,
 };
 
 pub struct WasmCompilationSettings {
     pub cache_config_path: PathBuf,
+    pub engine: EngineSettings,
+    /// Whether routes' compiled modules may be dropped while they are idle.
+    pub evict_idle_modules: bool,
+    /// Where compiled modules are kept across restarts, if anywhere.
+    pub compiled_module_cache: Option<PathBuf>,
+    pub on_compile_error: CompileErrorPolicy,
+}
+
+/// What to do when a module can't be compiled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompileErrorPolicy {
+    /// Don't start.
+    Fail,
+    /// Start without the module. Its route answers 503, and a task that uses it
+    /// doesn't run.
+    Skip,
+}
+
+impl Default for CompileErrorPolicy {
+    fn default() -> Self {
+        Self::Fail
+    }
+}
+
+impl std::str::FromStr for CompileErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            _ => Err(anyhow::anyhow!("Unknown compile error policy '{}': expected fail or skip", s)),
+        }
+    }
+}
+
+/// A module that failed to compile, and where it came from.
+#[derive(Debug)]
+pub struct CompileFailure {
+    /// The route or task that uses the module.
+    pub used_by: String,
+    /// The module reference, or the parcel name for a bindle handler.
+    pub module: String,
+    pub digest: Option<String>,
+    pub error: anyhow::Error,
+}
+
+impl CompileFailure {
+    fn for_handler(info: &HandlerInfo, error: anyhow::Error) -> Self {
+        Self {
+            used_by: format!("route {}", info.route),
+            module: info.name.clone(),
+            digest: Some(info.module_digest.clone()),
+            error,
+        }
+    }
+
+    fn log(&self) {
+        tracing::error!(
+            used_by = %self.used_by,
+            module = %self.module,
+            digest = %self.digest.as_deref().unwrap_or("unknown"),
+            error = %format!("{:#}", self.error),
+            "Module failed to compile"
+        );
+    }
+}
+
+impl std::fmt::Display for CompileFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: module {}", self.used_by, self.module)?;
+        if let Some(digest) = &self.digest {
+            write!(f, " (digest {})", digest)?;
+        }
+        write!(f, ": {:#}", self.error)
+    }
+}
+
+// All modules share one engine, and so one epoch ticker
+pub fn new_engine(compilation_settings: &WasmCompilationSettings) -> anyhow::Result<Engine> {
+    WasmModuleSource::new_engine(&compilation_settings.cache_config_path, &compilation_settings.engine)
+        .with_context(|| "Error creating Wasm engine")
 }
 
 pub fn compile(
     uncompiled_handlers: LoadedHandlerConfiguration,
-    compilation_settings: WasmCompilationSettings,
+    engine: &Engine,
+    compilation_settings: &WasmCompilationSettings,
 ) -> anyhow::Result<WasmHandlerConfiguration> {
-    uncompiled_handlers.compile_modules(|module_bytes| {
-        crate::wasm_module::WasmModuleSource::from_module_bytes(
-            module_bytes,
-            &compilation_settings.cache_config_path,
-        )
-    })
+    let precompiled = compilation_settings.compiled_module_cache.as_ref().map(PrecompiledModules::new);
+    let compile_module = |module_bytes: &[u8]| match &precompiled {
+        Some(precompiled) => precompiled.load_or_compile(engine, module_bytes),
+        None => Module::new(engine, module_bytes),
+    };
+    let compile = |module_bytes: std::sync::Arc<Vec<u8>>| Ok(WasmModuleSource::Compiled(compile_module(&module_bytes)?, engine.clone()));
+    // Tasks are not evicted: they are not in the routing table, which is where
+    // idle modules are looked for
+    let compile_handler = |module_bytes: std::sync::Arc<Vec<u8>>| if compilation_settings.evict_idle_modules {
+        let module = compile_module(&module_bytes)?;
+        Ok(WasmModuleSource::evictable(module_bytes, module, engine))
+    } else {
+        compile(module_bytes)
+    };
+    uncompiled_handlers.compile_modules(compile_handler, compile, compilation_settings.on_compile_error)
 }
 
 impl LoadedHandlerConfiguration {
+    /// Compiles every module, even after one fails, so that all the failures are
+    /// reported together. With `CompileErrorPolicy::Skip`, routes whose modules
+    /// failed answer 503 and tasks whose modules failed are left out.
     pub fn compile_modules(
         self,
-        compile: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
+        compile_handler: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
+        compile_task: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
+        on_error: CompileErrorPolicy,
     ) -> anyhow::Result<WasmHandlerConfiguration> {
-        let result: anyhow::Result<Vec<WasmHandlerConfigurationEntry>> = self
-            .entries
-            .into_iter()
-            .map(|e| e.compile_module(|m| compile(m)))
-            .collect();
-        Ok(WasmHandlerConfiguration { entries: result? })
+        let mut failures = vec![];
+        let mut entries = vec![];
+        for entry in self.entries {
+            let (info, compiled) = entry.compile_module(|m| compile_handler(m));
+            let module = match compiled {
+                Ok(module) => module,
+                Err(error) => {
+                    failures.push(CompileFailure::for_handler(&info, error));
+                    // Never filled, so the route answers 503
+                    WasmModuleSource::Pending(PendingModule::new(&info.name))
+                }
+            };
+            entries.push(WasmHandlerConfigurationEntry { info, module });
+        }
+        let mut tasks = vec![];
+        for task in self.tasks {
+            let (used_by, module) = (format!("task {}", task.info.name), task.info.module.clone());
+            match task.compile_module(|m| compile_task(m)) {
+                Ok(compiled) => tasks.push(compiled),
+                Err(error) => failures.push(CompileFailure { used_by, module, digest: None, error }),
+            }
+        }
+
+        for failure in &failures {
+            failure.log();
+        }
+        if !failures.is_empty() && on_error == CompileErrorPolicy::Fail {
+            let report: Vec<String> = failures.iter().map(|f| format!("  {}", f)).collect();
+            anyhow::bail!("{} module(s) failed to compile:\n{}", failures.len(), report.join("\n"));
+        }
+        Ok(WasmHandlerConfiguration { entries, tasks })
     }
 }
 
 impl LoadedHandlerConfigurationEntry {
+    /// Compiles the module. The handler's info is given back whether or not it
+    /// compiles, so that failures can be reported with it.
+    pub fn compile_module(
+        self,
+        compile: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
+    ) -> (HandlerInfo, anyhow::Result<WasmModuleSource>) {
+        let mut info = self.info;
+        // A start function runs when the module is instantiated, which for pooled
+        // instances is before the request's WASI context is swapped in
+        let may_have_start_function = match &self.module {
+            LoadedModule::Fetched(module) => has_start_function(module),
+            LoadedModule::Retrying(_) => true,
+        };
+        if info.preinstantiate && may_have_start_function {
+            tracing::warn!(route = %info.route, module = %info.name, "Not pre-instantiating module, because it has a start function or has not been fetched yet");
+            info.preinstantiate = false;
+        }
+        let compiled_module = match self.module {
+            LoadedModule::Fetched(module) => compile(module),
+            // Compiled by the background fetch
+            LoadedModule::Retrying(pending) => Ok(WasmModuleSource::Pending(pending)),
+        };
+        (info, compiled_module)
+    }
+}
+
+impl LoadedTaskConfigurationEntry {
     pub fn compile_module(
         self,
         compile: impl Fn(std::sync::Arc<Vec<u8>>) -> anyhow::Result<WasmModuleSource>,
-    ) -> anyhow::Result<WasmHandlerConfigurationEntry> {
-        let compiled_module = compile(self.module)
-            .with_context(|| format!("Error compiling Wasm module {}", &self.info.name))?;
-        Ok(WasmHandlerConfigurationEntry {
+    ) -> anyhow::Result<WasmTaskConfigurationEntry> {
+        let compiled_module = compile(self.module)?;
+        Ok(WasmTaskConfigurationEntry {
             info: self.info,
             module: compiled_module,
         })
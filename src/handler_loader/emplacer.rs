@@ -1,17 +1,24 @@
-use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::Arc};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
 
 use anyhow::Context;
 use bindle::Invoice;
+use futures::StreamExt;
 use sha2::{Digest, Sha256};
 
+use super::cache::{hashed_key, Cache};
+use super::fetch_retry::{FetchRetryPolicy, MissingParcelPolicy};
 use crate::{
     bindle_util::{InvoiceUnderstander, WagiHandlerInfo},
-    wagi_config::{HandlerConfigurationSource, WagiConfiguration},
+    wagi_config::{HandlerConfigurationSource, InMemoryModule, WagiConfiguration},
 };
 
 pub enum EmplacedHandlerConfiguration {
     ModuleMapFile(PathBuf),
+    ModuleDir(PathBuf),
     Bindle(Emplacer, Invoice),
+    InMemory(Vec<InMemoryModule>),
 }
 
 pub async fn emplace(
@@ -27,9 +34,17 @@ pub async fn emplace(
     Ok(emplaced_config)
 }
 
+// How many parcels to fetch from the bindle server at once
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+#[derive(Clone)]
 pub struct Emplacer {
-    cache_path: PathBuf,
+    cache: Cache,
     source: HandlerConfigurationSource,
+    fetch_retry: FetchRetryPolicy,
+    missing_parcel: MissingParcelPolicy,
+    /// The cache keys of parcels that could not be fetched.
+    missing: HashSet<String>,
 }
 
 pub struct Bits {
@@ -40,18 +55,20 @@ pub struct Bits {
 impl Emplacer {
     async fn new(configuration: &WagiConfiguration) -> anyhow::Result<Self> {
         Self::new_from_settings(
-            &configuration.asset_cache_dir,
-            &configuration.handlers
+            configuration.module_cache(),
+            &configuration.handlers,
+            &configuration.fetch_retry,
+            configuration.missing_parcel,
         ).await
     }
 
-    async fn new_from_settings(asset_cache_dir: &Path, handlers: &HandlerConfigurationSource) -> anyhow::Result<Self> {
-        let cache_path = asset_cache_dir.to_owned();
-        tokio::fs::create_dir_all(&cache_path).await
-            .with_context(|| format!("Can't create asset cache directory {}", cache_path.display()))?;
+    async fn new_from_settings(cache: Cache, handlers: &HandlerConfigurationSource, fetch_retry: &FetchRetryPolicy, missing_parcel: MissingParcelPolicy) -> anyhow::Result<Self> {
         Ok(Self {
-            cache_path,
+            cache,
             source: handlers.clone(),
+            fetch_retry: fetch_retry.clone(),
+            missing_parcel,
+            missing: HashSet::new(),
         })
     }
 
@@ -59,30 +76,89 @@ impl Emplacer {
         match self.source.clone() {
             HandlerConfigurationSource::ModuleConfigFile(path) =>
                 Ok(EmplacedHandlerConfiguration::ModuleMapFile(path.clone())),
+            HandlerConfigurationSource::ModuleDir(path) =>
+                Ok(EmplacedHandlerConfiguration::ModuleDir(path)),
             HandlerConfigurationSource::StandaloneBindle(bindle_base_dir, id) =>
                 self.emplace_standalone_bindle(&bindle_base_dir, &id).await,
             HandlerConfigurationSource::RemoteBindle(bindle_connection_info, id) =>
                 self.emplace_remote_bindle(bindle_connection_info, &id).await,
+            HandlerConfigurationSource::InMemory(modules) =>
+                Ok(EmplacedHandlerConfiguration::InMemory(modules)),
         }.with_context(|| "Error caching assets from bindle")
     }
 
     // TODO: NO! NO! NO!
     pub async fn get_bits_for(&self, handler: &WagiHandlerInfo) -> anyhow::Result<Bits> {
-        let module_parcel_path = self.module_parcel_path(&handler.parcel);
-        let wasm_module = tokio::fs::read(&module_parcel_path).await
-            .with_context(|| format!("Error reading module {} from cache path {}", handler.parcel.label.name, module_parcel_path.display()))?;
-
-        let volume_mounts = if handler.asset_parcels().is_empty() {
-            HashMap::new()
-        } else {
-            self.asset_dir_volume_mount(&handler.invoice_id)
-        };
         Ok(Bits {
-            wasm_module: Arc::new(wasm_module),
-            volume_mounts,
+            wasm_module: Arc::new(self.module_bytes_for(handler).await?),
+            volume_mounts: self.volume_mounts_for(handler)?,
         })
     }
 
+    async fn module_bytes_for(&self, handler: &WagiHandlerInfo) -> anyhow::Result<Vec<u8>> {
+        let wasm_module = self.cache.get(&module_parcel_key(&handler.parcel)).await
+            .with_context(|| format!("Error reading module {} from cache", handler.parcel.label.name))?
+            .ok_or_else(|| anyhow::anyhow!("Module {} was not found in cache", handler.parcel.label.name))?;
+        // The cache could have been changed since the module was emplaced
+        verify_parcel(&handler.parcel, &wasm_module)
+            .with_context(|| format!("Module {} in cache is not the one in the invoice", handler.parcel.label.name))?;
+        Ok(wasm_module)
+    }
+
+    /// Where the handler's assets are mounted from. The directories need not
+    /// exist yet.
+    pub fn volume_mounts_for(&self, handler: &WagiHandlerInfo) -> anyhow::Result<HashMap<String, String>> {
+        let mut volume_mounts = HashMap::new();
+        if !handler.asset_parcels().is_empty() {
+            volume_mounts.insert("/".to_owned(), self.local_asset_dir(&handler.invoice_id, &asset_dir_key(&handler.invoice_id))?);
+        }
+        for mount in &handler.group_mounts {
+            let host_dir = self.local_asset_dir(&handler.invoice_id, &group_asset_dir_key(&handler.invoice_id, &mount.group))?;
+            volume_mounts.insert(mount.guest_path.clone(), host_dir);
+        }
+        Ok(volume_mounts)
+    }
+
+    pub fn missing_parcel_policy(&self) -> MissingParcelPolicy {
+        self.missing_parcel
+    }
+
+    pub fn fetch_retry(&self) -> &FetchRetryPolicy {
+        &self.fetch_retry
+    }
+
+    /// Whether any parcel the handler needs could not be fetched.
+    pub fn has_missing_parcels(&self, handler: &WagiHandlerInfo) -> bool {
+        parcel_placements(&handler.invoice_id, std::slice::from_ref(handler))
+            .iter()
+            .any(|(key, _)| self.missing.contains(key))
+    }
+
+    /// Fetches the handler's parcels from the bindle again, and returns its module.
+    pub async fn fetch_again(&self, handler: &WagiHandlerInfo) -> anyhow::Result<Vec<u8>> {
+        match &self.source {
+            HandlerConfigurationSource::StandaloneBindle(bindle_base_dir, id) => {
+                let reader = bindle::standalone::StandaloneRead::new(bindle_base_dir, id).await
+                    .with_context(|| format!("Error constructing bindle reader for {} in {}", id, bindle_base_dir.display()))?;
+                self.emplace_handler_parcels(&reader, handler).await?;
+            }
+            HandlerConfigurationSource::RemoteBindle(bindle_connection_info, _) =>
+                self.emplace_handler_parcels(&bindle_connection_info.client()?, handler).await?,
+            HandlerConfigurationSource::ModuleConfigFile(_) | HandlerConfigurationSource::ModuleDir(_) | HandlerConfigurationSource::InMemory(_) =>
+                anyhow::bail!("Handler {} does not come from a bindle", handler.parcel.label.name),
+        }
+        self.module_bytes_for(handler).await
+    }
+
+    async fn emplace_handler_parcels(&self, reader: &impl BindleReader, handler: &WagiHandlerInfo) -> anyhow::Result<()> {
+        let placements = parcel_placements(&handler.invoice_id, std::slice::from_ref(handler));
+        let progress = EmplaceProgress::new(placements.len());
+        for (key, parcel) in &placements {
+            self.emplace_parcel(reader, &handler.invoice_id, key, parcel, &progress).await?;
+        }
+        Ok(())
+    }
+
     async fn emplace_standalone_bindle(self, bindle_base_dir: &Path, id: &bindle::Id) -> anyhow::Result<EmplacedHandlerConfiguration> {
         let reader = bindle::standalone::StandaloneRead::new(bindle_base_dir, id).await
             .with_context(|| format!("Error constructing bindle reader for {} in {}", id, bindle_base_dir.display()))?;
@@ -94,120 +170,196 @@ impl Emplacer {
         self.emplace_bindle(&bindle_connection_info.client()?, id).await
     }
 
-    async fn emplace_bindle(self, reader: &impl BindleReader, id: &bindle::Id) -> anyhow::Result<EmplacedHandlerConfiguration> {
-        let invoice_path = self.invoice_path(id);
-        if !invoice_path.is_file() {
-            let invoice_text = reader.get_invoice_bytes(id).await?;
-            safely_write(&invoice_path, invoice_text).await
-                .with_context(|| format!("Error writing invoice {} to cache", &id))?;
-        }
+    async fn emplace_bindle(mut self, reader: &impl BindleReader, id: &bindle::Id) -> anyhow::Result<EmplacedHandlerConfiguration> {
+        let invoice_key = invoice_key(id);
+        let invoice_name = format!("invoice {}", id);
+        let fetch_invoice = || self.fetch_retry.fetch(&invoice_name, || reader.get_invoice_bytes(id));
+        self.cache.put_if_missing(&invoice_key, fetch_invoice).await
+            .with_context(|| format!("Error writing invoice {} to cache", &id))?;
 
-        let invoice_text = tokio::fs::read(&invoice_path).await
-            .with_context(|| format!("Error reading cached invoice file {}", invoice_path.display()))?;
+        let invoice_text = self.cache.get(&invoice_key).await
+            .with_context(|| format!("Error reading cached invoice {}", &id))?
+            .ok_or_else(|| anyhow::anyhow!("Invoice {} was not found in cache", &id))?;
         let invoice_raw = toml::from_slice(&invoice_text)
-            .with_context(|| format!("Error parsing cached invoice file {}", invoice_path.display()))?;
+            .with_context(|| format!("Error parsing cached invoice {}", &id))?;
 
         let invoice = InvoiceUnderstander::new(&invoice_raw);
 
-        let module_parcels = invoice.parse_wagi_handlers();
-
-        let module_placements = module_parcels.iter().map(|h| self.emplace_module_and_assets(reader, id, h));
-        let all_module_placements = futures::future::join_all(module_placements).await;
-
-        match all_module_placements.into_iter().find_map(|e| e.err()) {
-            Some(e) => Err(e),
-            None => Ok(EmplacedHandlerConfiguration::Bindle(self, invoice_raw))
+        let placements = parcel_placements(id, &invoice.parse_wagi_handlers());
+        let progress = EmplaceProgress::new(placements.len());
+        // Failures are told apart by key, so that handlers that need other parcels can still be served
+        let emplacer = &self;
+        let results: Vec<(&String, anyhow::Result<()>)> = futures::stream::iter(&placements)
+            .map(|(key, parcel)| {
+                let progress = &progress;
+                async move { (key, emplacer.emplace_parcel(reader, id, key, parcel, progress).await) }
+            })
+            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .collect()
+            .await;
+
+        let mut missing = HashSet::new();
+        for (key, result) in results {
+            if let Err(e) = result {
+                if self.missing_parcel == MissingParcelPolicy::Fail {
+                    return Err(e);
+                }
+                let error = format!("{:#}", e);
+                tracing::error!(invoice_id = %id, %error, "Could not fetch parcel; the handlers that need it will answer 503");
+                missing.insert(key.clone());
+            }
         }
+        progress.log_summary(id);
+        self.missing = missing;
+        Ok(EmplacedHandlerConfiguration::Bindle(self, invoice_raw))
     }
 
-    async fn emplace_module_and_assets(&self, reader: &impl BindleReader, invoice_id: &bindle::Id, handler: &WagiHandlerInfo) -> anyhow::Result<()> {
-        self.emplace_module(reader, invoice_id, &handler.parcel).await?;
-        self.emplace_as_assets(reader, invoice_id, &handler.asset_parcels()).await?;
+    async fn emplace_parcel(&self, reader: &impl BindleReader, invoice_id: &bindle::Id, key: &str, parcel: &bindle::Parcel, progress: &EmplaceProgress) -> anyhow::Result<()> {
+        let fetch = move || async move {
+            let content = fetch_parcel(reader, invoice_id, parcel, &self.fetch_retry).await?;
+            progress.fetched(parcel, content.len());
+            Ok(content)
+        };
+        self.cache.put_unless_valid(key, |content| verify_parcel(parcel, content), fetch).await
+            .with_context(|| format!("Error caching parcel {}", parcel.label.name))?;
+        progress.done();
         Ok(())
     }
 
-    async fn emplace_module(&self, reader: &impl BindleReader, invoice_id: &bindle::Id, parcel: &bindle::Parcel) -> anyhow::Result<()> {
-        let parcel_path = self.cache_path.join(&parcel.label.sha256);
-        if parcel_path.is_file() {
-            return Ok(());
-        }
+    pub fn asset_path_for(&self, invoice_id: &bindle::Id) -> Option<PathBuf> {
+        self.cache.local_path(&asset_dir_key(invoice_id))
+    }
 
-        let parcel_data = reader.get_parcel(invoice_id, parcel).await?;
-        safely_write(&parcel_path, parcel_data).await
-            .with_context(|| format!("Error caching parcel {} at {}", parcel.label.name, parcel_path.display()))
+    fn local_asset_dir(&self, invoice_id: &bindle::Id, dir_key: &str) -> anyhow::Result<String> {
+        // Assets are mounted into the guest as a directory, so they must be on local disk
+        let asset_path = self.cache.local_path(dir_key)
+            .ok_or_else(|| anyhow::anyhow!("Assets for {} cannot be mounted because the cache is not on local disk", invoice_id))?;
+        Ok(asset_path.display().to_string())  // TODO: maybe volumes should map PathBufs // or struct of host and guest
     }
+}
 
-    async fn emplace_as_asset(&self, reader: &impl BindleReader, invoice_id: &bindle::Id, parcel: &bindle::Parcel) -> anyhow::Result<()> {
-        let parcel_path = self.asset_parcel_path(invoice_id, parcel);
-        if parcel_path.is_file() {
-            return Ok(());
-        }
+// The cache key of each parcel the handlers need, modules and assets alike. A
+// parcel used by several handlers only needs fetching once.
+fn parcel_placements(invoice_id: &bindle::Id, handlers: &[WagiHandlerInfo]) -> Vec<(String, bindle::Parcel)> {
+    let mut seen = HashSet::new();
+    handlers
+        .iter()
+        .flat_map(|h| {
+            let module = (module_parcel_key(&h.parcel), h.parcel.clone());
+            let assets = h.asset_parcels().into_iter().map(|p| (asset_parcel_key(invoice_id, &p), p));
+            let group_assets = h.group_mounts.iter().flat_map(|m| {
+                m.parcels.iter().map(|p| (group_asset_parcel_key(invoice_id, &m.group, p), p.clone()))
+            });
+            std::iter::once(module).chain(assets).chain(group_assets).collect::<Vec<_>>()
+        })
+        .filter(|(key, _)| seen.insert(key.clone()))
+        .collect()
+}
 
-        let parcel_data = reader.get_parcel(invoice_id, parcel).await?;
-        safely_write(&parcel_path, parcel_data).await
-            .with_context(|| format!("Error caching parcel {} at {}", parcel.label.name, parcel_path.display()))?;
-        Ok(())
-    }
+async fn fetch_parcel(reader: &impl BindleReader, invoice_id: &bindle::Id, parcel: &bindle::Parcel, retry: &FetchRetryPolicy) -> anyhow::Result<Vec<u8>> {
+    let parcel_name = format!("parcel {}", parcel.label.name);
+    retry.fetch(&parcel_name, || reader.get_parcel(invoice_id, parcel)).await
+}
 
-    async fn emplace_as_assets(&self, reader: &impl BindleReader, invoice_id: &bindle::Id, parcels: &[bindle::Parcel]) -> anyhow::Result<()> {
-        let placement_futures = parcels.iter().map(|parcel| self.emplace_as_asset(reader, invoice_id, parcel));
-        let all_placements = futures::future::join_all(placement_futures).await;
-        let first_error = all_placements.into_iter().find(|p| p.is_err());
-        first_error.unwrap_or(Ok(()))
-    }
+/// Tracks how far through emplacing a bindle's parcels WAGI is, for the logs.
+struct EmplaceProgress {
+    total: usize,
+    done: AtomicUsize,
+    fetched: AtomicUsize,
+    fetched_bytes: AtomicU64,
+    started: Instant,
+}
 
-    // TODO: there is a potential risk here if two bindle servers have different content
-    // for the same invoice id - if we cached data from the 'old' server we would use that
-    // in place of the new one
-    fn invoice_path(&self, invoice_id: &bindle::Id) -> PathBuf {
-        let filename = invoice_cache_key(invoice_id);
-        self.invoices_path().join(filename)
+impl EmplaceProgress {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: AtomicUsize::new(0),
+            fetched: AtomicUsize::new(0),
+            fetched_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+        }
     }
 
-    fn module_parcel_path(&self, parcel: &bindle::Parcel) -> PathBuf {
-        self.cache_path.join(&parcel.label.sha256)
+    fn fetched(&self, parcel: &bindle::Parcel, bytes: usize) {
+        self.fetched.fetch_add(1, Ordering::Relaxed);
+        let total_bytes = self.fetched_bytes.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        tracing::info!(
+            parcel = %parcel.label.name,
+            bytes,
+            "Fetched parcel ({}/{} parcels ready, {} bytes downloaded)",
+            self.done.load(Ordering::Relaxed) + 1,
+            self.total,
+            total_bytes,
+        );
     }
 
-    fn asset_parcel_path(&self, invoice_id: &bindle::Id, parcel: &bindle::Parcel) -> PathBuf {
-        self.asset_path_for(invoice_id).join(&parcel.label.name)
+    fn done(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
     }
 
-    fn invoices_path(&self) -> PathBuf {
-        self.cache_path.join("_INVOICES")
+    fn log_summary(&self, invoice_id: &bindle::Id) {
+        tracing::info!(
+            %invoice_id,
+            parcels = self.total,
+            fetched = self.fetched.load(Ordering::Relaxed),
+            bytes = self.fetched_bytes.load(Ordering::Relaxed),
+            elapsed = ?self.started.elapsed(),
+            "Emplaced bindle: {} parcels, {} fetched from the bindle server and the rest already cached",
+            self.total,
+            self.fetched.load(Ordering::Relaxed),
+        );
     }
+}
 
-    fn asset_path(&self) -> PathBuf {
-        self.cache_path.join("_ASSETS")
-    }
+// TODO: there is a potential risk here if two bindle servers have different content
+// for the same invoice id - if we cached data from the 'old' server we would use that
+// in place of the new one
+fn invoice_key(id: &bindle::Id) -> String {
+    format!("_INVOICES/{}", invoice_hash(id))
+}
 
-    pub fn asset_path_for(&self, invoice_id: &bindle::Id) -> PathBuf {
-        let key = invoice_cache_key(invoice_id);
-        self.asset_path().join(key)
-    }
+fn module_parcel_key(parcel: &bindle::Parcel) -> String {
+    parcel.label.sha256.clone()
+}
 
-    fn asset_dir_volume_mount(&self, invoice_id: &bindle::Id) -> HashMap<String, String> {
-        let mut volumes = HashMap::new();
-        volumes.insert("/".to_owned(), self.asset_path_for(invoice_id).display().to_string());  // TODO: maybe volumes should map PathBufs // or struct of host and guest
-        volumes
-    }
-    
+fn asset_dir_key(id: &bindle::Id) -> String {
+    format!("_ASSETS/{}", invoice_hash(id))
 }
 
-fn invoice_cache_key(id: &bindle::Id) -> String {
-    let invoice_id_string = format!("{}/{}", id.name(), id.version_string());
+fn asset_parcel_key(id: &bindle::Id, parcel: &bindle::Parcel) -> String {
+    format!("{}/{}", asset_dir_key(id), parcel.label.name)
+}
+
+/// Checks that the content is the parcel the invoice describes.
+fn verify_parcel(parcel: &bindle::Parcel, content: &[u8]) -> anyhow::Result<()> {
     let mut hasher = Sha256::new();
-    hasher.update(invoice_id_string);
-    let result = hasher.finalize();
-    format!("{:x}", result)
+    hasher.update(content);
+    let actual = format!("{:x}", hasher.finalize());
+    if actual.eq_ignore_ascii_case(&parcel.label.sha256) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Parcel {} has SHA256 {} but the invoice expects {}",
+            parcel.label.name,
+            actual,
+            parcel.label.sha256
+        ))
+    }
+}
+
+// Groups mounted at their own guest paths are kept out of the asset directory,
+// because that is mounted at the root.
+fn group_asset_dir_key(id: &bindle::Id, group: &str) -> String {
+    format!("_GROUP_ASSETS/{}/{}", invoice_hash(id), hashed_key(group))
 }
 
-async fn safely_write(path: impl AsRef<Path>, content: Vec<u8>) -> std::io::Result<()> {
-    let path = path.as_ref();
-    let dir = path.parent().ok_or_else(||
-        std::io::Error::new(std::io::ErrorKind::Other, format!("cache location {} has no parent directory", path.display()))
-    )?;
-    tokio::fs::create_dir_all(dir).await?;
-    tokio::fs::write(path, content).await
+fn group_asset_parcel_key(id: &bindle::Id, group: &str, parcel: &bindle::Parcel) -> String {
+    format!("{}/{}", group_asset_dir_key(id, group), parcel.label.name)
+}
+
+fn invoice_hash(id: &bindle::Id) -> String {
+    hashed_key(&format!("{}/{}", id.name(), id.version_string()))
 }
 
 #[async_trait::async_trait]
@@ -273,7 +425,7 @@ mod test {
             .expect("Test bindle ID should have been valid");
         let asset_cache_dir = pick_test_dir();
         let handlers = HandlerConfigurationSource::StandaloneBindle(test_data_dir(), test_id);
-        let emplacer = Emplacer::new_from_settings(&asset_cache_dir, &handlers).await
+        let emplacer = Emplacer::new_from_settings(Cache::local_dir(&asset_cache_dir), &handlers, &FetchRetryPolicy::default(), MissingParcelPolicy::Fail).await
             .expect("Should have created emplacer");
         emplacer.emplace_all().await
             .expect("Should have emplaced files");
@@ -297,4 +449,126 @@ mod test {
         tokio::fs::remove_dir_all(&asset_cache_dir).await
             .expect("(note: test body passed, but cleanup failed");
     }
+
+    #[tokio::test]
+    async fn corrupt_cached_parcels_are_fetched_again() {
+        let test_id = bindle::Id::from_str("itowlson/toast-on-demand/0.1.0-ivan-20210924170616069")
+            .expect("Test bindle ID should have been valid");
+        let asset_cache_dir = pick_test_dir();
+        let handlers = HandlerConfigurationSource::StandaloneBindle(test_data_dir(), test_id);
+        let module_path = asset_cache_dir.join("d7cc2648c55b8b1896472b1f87da9d80c26c8e9bd71602ba981123639140bf77");
+        let asset_path = asset_cache_dir.join("_ASSETS/28e62d239a12d50b11db734eb4a37bf9e746fd487f2a375d17db3a82d6869d54/images/derrida.png");
+
+        let emplacer = Emplacer::new_from_settings(Cache::local_dir(&asset_cache_dir), &handlers, &FetchRetryPolicy::default(), MissingParcelPolicy::Fail).await
+            .expect("Should have created emplacer");
+        emplacer.emplace_all().await
+            .expect("Should have emplaced files");
+        let module = std::fs::read(&module_path).expect("Module should have been emplaced");
+        let asset = std::fs::read(&asset_path).expect("Asset should have been emplaced");
+
+        // As if a download had been cut short
+        std::fs::write(&module_path, &module[..module.len() / 2]).unwrap();
+        std::fs::write(&asset_path, b"not derrida").unwrap();
+
+        let emplacer = Emplacer::new_from_settings(Cache::local_dir(&asset_cache_dir), &handlers, &FetchRetryPolicy::default(), MissingParcelPolicy::Fail).await
+            .expect("Should have created emplacer");
+        emplacer.emplace_all().await
+            .expect("Should have emplaced files again");
+        assert_eq!(module, std::fs::read(&module_path).unwrap());
+        assert_eq!(asset, std::fs::read(&asset_path).unwrap());
+
+        tokio::fs::remove_dir_all(&asset_cache_dir).await
+            .expect("(note: test body passed, but cleanup failed");
+    }
+
+    #[tokio::test]
+    async fn missing_parcels_can_be_skipped() {
+        let test_id = bindle::Id::from_str("itowlson/toast-on-demand/0.1.0-ivan-20210924170616069")
+            .expect("Test bindle ID should have been valid");
+        let bindle_dir = tempfile::tempdir().unwrap();
+        let invoice_dir = "28e62d239a12d50b11db734eb4a37bf9e746fd487f2a375d17db3a82d6869d54";
+        std::fs::create_dir_all(bindle_dir.path().join(invoice_dir).join("parcels")).unwrap();
+        for file in std::fs::read_dir(test_data_dir().join(invoice_dir).join("parcels")).unwrap() {
+            let file = file.unwrap();
+            std::fs::copy(file.path(), bindle_dir.path().join(invoice_dir).join("parcels").join(file.file_name())).unwrap();
+        }
+        std::fs::copy(test_data_dir().join(invoice_dir).join("invoice.toml"), bindle_dir.path().join(invoice_dir).join("invoice.toml")).unwrap();
+        std::fs::remove_file(bindle_dir.path().join(invoice_dir).join("parcels/d7cc2648c55b8b1896472b1f87da9d80c26c8e9bd71602ba981123639140bf77.dat")).unwrap();
+
+        let asset_cache_dir = tempfile::tempdir().unwrap();
+        let handlers = HandlerConfigurationSource::StandaloneBindle(bindle_dir.path().to_owned(), test_id);
+        let retry = FetchRetryPolicy { attempts: 1, first_delay: std::time::Duration::from_millis(1) };
+
+        let emplacer = Emplacer::new_from_settings(Cache::local_dir(asset_cache_dir.path()), &handlers, &retry, MissingParcelPolicy::Fail).await
+            .expect("Should have created emplacer");
+        emplacer.emplace_all().await
+            .expect_err("Should have failed on the missing parcel");
+
+        let emplacer = Emplacer::new_from_settings(Cache::local_dir(asset_cache_dir.path()), &handlers, &retry, MissingParcelPolicy::Skip).await
+            .expect("Should have created emplacer");
+        let (emplacer, invoice) = match emplacer.emplace_all().await.expect("Should have skipped the missing parcel") {
+            EmplacedHandlerConfiguration::Bindle(emplacer, invoice) => (emplacer, invoice),
+            _ => panic!("Expected a bindle"),
+        };
+        // Only the fileserver handler has all its parcels
+        let mut missing: Vec<_> = InvoiceUnderstander::new(&invoice)
+            .parse_wagi_handlers()
+            .into_iter()
+            .filter(|h| emplacer.has_missing_parcels(h))
+            .map(|h| h.route)
+            .collect();
+        missing.sort();
+        assert_eq!(vec!["/", "/blah/...", "/thing"], missing);
+    }
+
+    struct FlakyReader {
+        failures_left: std::sync::Mutex<u32>,
+    }
+
+    #[async_trait::async_trait]
+    impl BindleReader for FlakyReader {
+        async fn get_invoice_bytes(&self, id: &bindle::Id) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("no invoice {}", id)
+        }
+        async fn get_parcel(&self, _id: &bindle::Id, _parcel: &bindle::Parcel) -> anyhow::Result<Vec<u8>> {
+            let mut failures_left = self.failures_left.lock().unwrap();
+            if *failures_left > 0 {
+                *failures_left -= 1;
+                anyhow::bail!("connection reset");
+            }
+            Ok(b"parcel".to_vec())
+        }
+    }
+
+    fn test_parcel() -> bindle::Parcel {
+        bindle::Parcel {
+            label: bindle::Label {
+                sha256: "550a49ead28c8f626c7026d5fad7b537003546386755f06a410a55a76055be87".to_owned(),
+                name: "flaky.wasm".to_owned(),
+                media_type: "application/wasm".to_owned(),
+                size: 6,
+                annotations: None,
+                feature: None,
+                origin: None,
+            },
+            conditions: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn parcel_fetches_are_retried() {
+        let id = bindle::Id::from_str("flaky/1.0.0").unwrap();
+        let parcel = test_parcel();
+
+        let retry = FetchRetryPolicy { attempts: 3, first_delay: std::time::Duration::from_millis(1) };
+
+        let reader = FlakyReader { failures_left: std::sync::Mutex::new(2) };
+        let content = fetch_parcel(&reader, &id, &parcel, &retry).await
+            .expect("Should have succeeded on the last attempt");
+        assert_eq!(b"parcel".to_vec(), content);
+
+        let reader = FlakyReader { failures_left: std::sync::Mutex::new(3) };
+        fetch_parcel(&reader, &id, &parcel, &retry).await
+            .expect_err("Should have given up after the last attempt");
+    }
 }
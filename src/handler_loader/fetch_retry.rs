@@ -0,0 +1,201 @@
+//! Retrying remote fetches that fail.
+//!
+//! A single dropped connection to a registry or bindle server shouldn't stop
+//! WAGI from starting, so remote modules and parcels are fetched several times,
+//! waiting twice as long after each failure. With `--retry-fetch-in-background`,
+//! a module map entry whose module still can't be fetched doesn't stop WAGI either:
+//! its route answers 503 while the fetch is retried in the background, and starts
+//! serving as soon as the module arrives. `--missing-parcel` does the same for
+//! bindle handlers whose parcels can't be fetched, or leaves them answering 503.
+
+use std::future::Future;
+use std::time::Duration;
+
+use wasmtime::Engine;
+
+use crate::wagi_config::WagiConfiguration;
+use crate::wasm_module::PendingModule;
+
+use super::loader::ModuleMapConfigurationEntry;
+use super::module_loader;
+
+const DEFAULT_FETCH_ATTEMPTS: u32 = 3;
+const DEFAULT_FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+// Background retries carry on indefinitely, so they back off further than that
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchRetryPolicy {
+    /// How many times to try a fetch before giving up, including the first try.
+    pub attempts: u32,
+    /// How long to wait after the first failure. Each later wait is twice as long.
+    pub first_delay: Duration,
+}
+
+impl Default for FetchRetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_FETCH_ATTEMPTS,
+            first_delay: DEFAULT_FIRST_RETRY_DELAY,
+        }
+    }
+}
+
+impl FetchRetryPolicy {
+    /// Runs the fetch until it succeeds or the attempts are used up. `what` names
+    /// the thing being fetched, for logs and errors.
+    pub async fn fetch<T, Fut>(&self, what: &str, fetch: impl Fn() -> Fut) -> anyhow::Result<T>
+    where
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match fetch().await {
+                Ok(fetched) => return Ok(fetched),
+                Err(e) if attempt < self.attempts => {
+                    let delay = self.delay_after(attempt);
+                    let error = format!("{:#}", e);
+                    tracing::warn!(%what, attempt, %error, "Error fetching; retrying in {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.context(format!("Gave up fetching {} after {} attempts", what, attempt))),
+            }
+        }
+    }
+
+    /// How long to wait after the given (1-based) failed attempt.
+    fn delay_after(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.first_delay.saturating_mul(factor).min(MAX_RETRY_DELAY)
+    }
+}
+
+/// What to do when a parcel that a bindle handler needs can't be fetched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingParcelPolicy {
+    /// Don't start.
+    Fail,
+    /// Start without the handler. Its route answers 503.
+    Skip,
+    /// Start, and keep fetching the handler's parcels in the background. Its route
+    /// answers 503 until they arrive.
+    Retry,
+}
+
+impl Default for MissingParcelPolicy {
+    fn default() -> Self {
+        Self::Fail
+    }
+}
+
+impl std::str::FromStr for MissingParcelPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "retry" => Ok(Self::Retry),
+            _ => Err(anyhow::anyhow!("Unknown missing parcel policy '{}': expected fail, skip or retry", s)),
+        }
+    }
+}
+
+/// Keeps fetching the module for the entry until it can be fetched and compiled,
+/// then puts it into service. Gives up if the route stops being served, for
+/// example because watch mode reloaded the configuration.
+pub(super) fn retry_in_background(
+    entry: ModuleMapConfigurationEntry,
+    configuration: WagiConfiguration,
+    engine: Engine,
+    pending: PendingModule,
+) {
+    let fetch_retry = configuration.fetch_retry.clone();
+    let (module, route) = (entry.module.clone(), entry.route.clone());
+    let load = move || {
+        let entry = entry.clone();
+        let configuration = configuration.clone();
+        async move { module_loader::load_from_module_map_entry(&entry, &configuration).await }
+    };
+    retry_load_in_background(module, route, fetch_retry, engine, pending, load);
+}
+
+/// Keeps calling `load` until the module it returns can be compiled, then puts
+/// the module into service.
+pub(super) fn retry_load_in_background<Fut>(
+    module: String,
+    route: String,
+    policy: FetchRetryPolicy,
+    engine: Engine,
+    pending: PendingModule,
+    load: impl Fn() -> Fut + Send + 'static,
+) where
+    Fut: Future<Output = anyhow::Result<Vec<u8>>> + Send,
+{
+    tokio::spawn(async move {
+        let mut round: u32 = 1;
+        loop {
+            // Each round already retries a few times, so wait as long as its last wait
+            tokio::time::sleep(policy.delay_after(round.saturating_mul(policy.attempts.max(1)))).await;
+            if pending.is_abandoned() {
+                tracing::debug!(%module, "Route no longer served; stopping background fetch");
+                return;
+            }
+            let loaded = load().await
+                .and_then(|bytes| wasmtime::Module::new(&engine, &bytes));
+            match loaded {
+                Ok(compiled) => {
+                    pending.fill(compiled, engine);
+                    tracing::info!(%module, %route, "Module fetched in the background; route is now served");
+                    return;
+                }
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    tracing::warn!(%module, %route, %error, "Module still can't be loaded; will retry");
+                }
+            }
+            round += 1;
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn quick_policy() -> FetchRetryPolicy {
+        FetchRetryPolicy { attempts: 3, first_delay: Duration::from_millis(1) }
+    }
+
+    async fn flaky_fetch(failures: u32, calls: &AtomicU32) -> anyhow::Result<&'static str> {
+        if calls.fetch_add(1, Ordering::SeqCst) < failures {
+            anyhow::bail!("connection reset");
+        }
+        Ok("module")
+    }
+
+    #[tokio::test]
+    async fn fetches_are_retried_until_the_attempts_run_out() {
+        let calls = AtomicU32::new(0);
+        let fetched = quick_policy().fetch("oci:flaky", || flaky_fetch(2, &calls)).await
+            .expect("Should have succeeded on the last attempt");
+        assert_eq!("module", fetched);
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        let calls = AtomicU32::new(0);
+        quick_policy().fetch("oci:flaky", || flaky_fetch(3, &calls)).await
+            .expect_err("Should have given up after the last attempt");
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn delays_double_up_to_a_limit() {
+        let policy = FetchRetryPolicy { attempts: 3, first_delay: Duration::from_secs(1) };
+        assert_eq!(Duration::from_secs(1), policy.delay_after(1));
+        assert_eq!(Duration::from_secs(2), policy.delay_after(2));
+        assert_eq!(Duration::from_secs(8), policy.delay_after(4));
+        assert_eq!(MAX_RETRY_DELAY, policy.delay_after(40));
+    }
+}
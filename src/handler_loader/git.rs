@@ -0,0 +1,198 @@
+//! Fetching modules from Git repositories.
+//!
+//! References have the form `git+https://example.com/repo.git#ref:path/to/module.wasm`,
+//! where `ref` is a branch, tag or commit SHA (defaulting to `HEAD` if empty). The ref is
+//! resolved to a commit SHA at startup and the module is cached by that SHA, so a branch
+//! reference picks up new commits on restart while unchanged commits are never fetched
+//! twice. This uses the `git` command line, so it must be installed and able to
+//! authenticate to the repository.
+
+use std::path::Path;
+
+use anyhow::Context;
+use url::Url;
+
+use super::cache::{hashed_key, Cache};
+
+const GIT_SCHEME_PREFIX: &str = "git+";
+
+#[derive(Debug, PartialEq)]
+struct GitModuleReference {
+    repository: String,
+    git_ref: String,
+    path: String,
+}
+
+impl GitModuleReference {
+    fn parse(uri: &Url) -> anyhow::Result<Self> {
+        let fragment = uri.fragment()
+            .ok_or_else(|| anyhow::anyhow!("Git module reference '{}' must end with #ref:path/to/module.wasm", uri))?;
+        let (git_ref, path) = fragment.split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Git module reference '{}' must end with #ref:path/to/module.wasm", uri))?;
+        let path = path.trim_start_matches('/');
+        if path.is_empty() {
+            anyhow::bail!("Git module reference '{}' does not specify a module path", uri);
+        }
+
+        let mut repository_uri = uri.clone();
+        repository_uri.set_fragment(None);
+        let repository = repository_uri.as_str().trim_start_matches(GIT_SCHEME_PREFIX).to_owned();
+
+        Ok(Self {
+            repository,
+            git_ref: if git_ref.is_empty() { "HEAD".to_owned() } else { git_ref.to_owned() },
+            path: path.to_owned(),
+        })
+    }
+}
+
+pub fn is_git_scheme(scheme: &str) -> bool {
+    scheme.starts_with(GIT_SCHEME_PREFIX)
+}
+
+#[tracing::instrument(level = "info", skip(cache))]
+pub async fn load_from_git(uri: &Url, cache: &Cache) -> anyhow::Result<Vec<u8>> {
+    let reference = GitModuleReference::parse(uri)?;
+    let commit = resolve_commit(&reference).await?;
+    tracing::debug!(%commit, git_ref = %reference.git_ref, "Resolved Git reference");
+
+    let cache_key = hashed_key(&format!("{}@{}:{}", reference.repository, commit, reference.path));
+    if let Ok(Some(bytes)) = cache.get(&cache_key).await {
+        return Ok(bytes);
+    }
+
+    let work_dir = tempfile::tempdir()
+        .with_context(|| "Error creating temporary directory for Git fetch")?;
+    git(work_dir.path(), &["init", "--quiet"]).await?;
+    // The repository comes from the module reference, so `--` stops one that starts
+    // with a dash from being taken as an option
+    git(work_dir.path(), &["fetch", "--quiet", "--depth", "1", "--", &reference.repository, &commit]).await
+        .with_context(|| format!("Error fetching commit {} from {}", commit, reference.repository))?;
+    let bytes = git(work_dir.path(), &["cat-file", "blob", &format!("FETCH_HEAD:{}", reference.path)]).await
+        .with_context(|| format!("Module {} not found at commit {} of {}", reference.path, commit, reference.repository))?;
+
+    // If a cache write fails, log it but continue on.
+    tracing::trace!("writing Git module to module cache");
+    if let Err(e) = cache.put(&cache_key, &bytes).await {
+        tracing::warn!(error = %e, "failed to write module to cache");
+    }
+
+    Ok(bytes)
+}
+
+async fn resolve_commit(reference: &GitModuleReference) -> anyhow::Result<String> {
+    if is_commit_sha(&reference.git_ref) {
+        return Ok(reference.git_ref.to_lowercase());
+    }
+    let refnames = candidate_refnames(&reference.git_ref);
+    let mut args: Vec<&str> = vec!["ls-remote", "--", &reference.repository];
+    args.extend(refnames.iter().map(|r| r.as_str()));
+    let output = git(Path::new("."), &args).await
+        .with_context(|| format!("Error resolving ref {} in {}", reference.git_ref, reference.repository))?;
+    let output = String::from_utf8_lossy(&output);
+    listed_commit(&output, &refnames)
+        .ok_or_else(|| anyhow::anyhow!("Ref {} not found in {}", reference.git_ref, reference.repository))
+}
+
+// The refnames that the ref may stand for, most preferred first. As with `git
+// rev-parse`, a tag wins over a branch of the same name, and an annotated tag is
+// peeled (`^{}`) to the commit it tags.
+fn candidate_refnames(git_ref: &str) -> Vec<String> {
+    if git_ref == "HEAD" {
+        vec![git_ref.to_owned()]
+    } else if git_ref.starts_with("refs/") {
+        vec![format!("{}^{{}}", git_ref), git_ref.to_owned()]
+    } else {
+        vec![
+            format!("refs/tags/{}^{{}}", git_ref),
+            format!("refs/tags/{}", git_ref),
+            format!("refs/heads/{}", git_ref),
+        ]
+    }
+}
+
+// Output lines are `<sha>\t<refname>`. `ls-remote` matches its patterns against the
+// ends of refnames, so it can list refs other than those asked for, sorted ahead of
+// them; only a refname that is exactly one of those asked for is taken.
+fn listed_commit(ls_remote_output: &str, refnames: &[String]) -> Option<String> {
+    let listed: Vec<(&str, &str)> = ls_remote_output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(sha, _)| is_commit_sha(sha))
+        .collect();
+    refnames
+        .iter()
+        .find_map(|wanted| listed.iter().find(|(_, name)| name.trim() == wanted.as_str()))
+        .map(|(sha, _)| (*sha).to_owned())
+}
+
+fn is_commit_sha(text: &str) -> bool {
+    text.len() == 40 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn git(dir: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let output = tokio::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        // Never block startup waiting for a password
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .await
+        .with_context(|| "Error running git: is it installed and on the PATH?")?;
+    if !output.status.success() {
+        anyhow::bail!("git {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<GitModuleReference> {
+        GitModuleReference::parse(&Url::parse(text).unwrap())
+    }
+
+    #[test]
+    fn git_references_are_parsed() {
+        let reference = parse("git+https://github.com/example/site.git#v1.2.0:target/wasm32-wasi/release/site.wasm").unwrap();
+        assert_eq!(GitModuleReference {
+            repository: "https://github.com/example/site.git".to_owned(),
+            git_ref: "v1.2.0".to_owned(),
+            path: "target/wasm32-wasi/release/site.wasm".to_owned(),
+        }, reference);
+
+        let reference = parse("git+https://github.com/example/site.git#:site.wasm").unwrap();
+        assert_eq!("HEAD", reference.git_ref);
+    }
+
+    #[test]
+    fn git_references_must_have_a_path() {
+        assert!(parse("git+https://github.com/example/site.git").is_err());
+        assert!(parse("git+https://github.com/example/site.git#main").is_err());
+        assert!(parse("git+https://github.com/example/site.git#main:").is_err());
+    }
+
+    #[test]
+    fn ls_remote_output_gives_commit() {
+        let output = "4b825dc642cb6eb9a060e54bf8d69288fbee4904\trefs/heads/main\n";
+        assert_eq!(Some("4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_owned()), listed_commit(output, &candidate_refnames("main")));
+        assert_eq!(None, listed_commit("", &candidate_refnames("main")));
+    }
+
+    #[test]
+    fn ls_remote_refs_must_match_exactly() {
+        let output = "\
+            1111111111111111111111111111111111111111\trefs/heads/feature/main\n\
+            2222222222222222222222222222222222222222\trefs/heads/main\n\
+            3333333333333333333333333333333333333333\trefs/tags/v1.0\n\
+            4444444444444444444444444444444444444444\trefs/tags/v1.0^{}\n";
+        let commit = |git_ref: &str| listed_commit(output, &candidate_refnames(git_ref));
+        assert_eq!(Some("2222222222222222222222222222222222222222".to_owned()), commit("main"));
+        assert_eq!(Some("2222222222222222222222222222222222222222".to_owned()), commit("refs/heads/main"));
+        assert_eq!(Some("1111111111111111111111111111111111111111".to_owned()), commit("feature/main"));
+        // The commit an annotated tag points at, not the tag object
+        assert_eq!(Some("4444444444444444444444444444444444444444".to_owned()), commit("v1.0"));
+        assert_eq!(None, commit("ain"));
+    }
+}
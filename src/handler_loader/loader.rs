@@ -1,32 +1,73 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, net::IpAddr, path::{Path, PathBuf}, time::Duration};
 
 use anyhow::Context;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 use crate::{
+    accept_content_types::AcceptedContentTypes,
+    audit::AuditSettings,
+    build_info::BindleInfo,
     bindle_util::{InvoiceUnderstander, WagiHandlerInfo},
-    wagi_config::WagiConfiguration,
+    error::{WagiError, WagiResult},
+    executor::ExecutorSettings,
+    experiment::ExperimentVariant,
+    handlers::ArgsMode,
+    json_request::JsonRequestSettings,
+    outbound_network::{check_source_ip, OutboundTls},
+    scheduler::Schedule,
+    stderr::StderrDestination,
+    volume_overlay::VolumeOverlay,
+    wagi_config::timeout_from_secs,
+    wagi_config::{InMemoryModule, WagiConfiguration},
+    wasm_module::PendingModule,
 };
 
 use super::{
     emplacer::{EmplacedHandlerConfiguration, Emplacer},
+    fetch_retry::{self, MissingParcelPolicy},
     module_loader::{self, Loaded},
-    HandlerInfo,
+    validation,
+    BuildSettings, HandlerInfo, TaskInfo,
 };
 
 pub struct LoadedHandlerConfiguration {
     pub entries: Vec<LoadedHandlerConfigurationEntry>,
+    pub tasks: Vec<LoadedTaskConfigurationEntry>,
 }
 
 pub struct LoadedHandlerConfigurationEntry {
     pub info: HandlerInfo,
+    pub module: LoadedModule,
+}
+
+pub enum LoadedModule {
+    Fetched(std::sync::Arc<Vec<u8>>),
+    /// The module could not be fetched, and is being fetched again in the background.
+    Retrying(PendingModule),
+}
+
+pub struct LoadedTaskConfigurationEntry {
+    pub info: TaskInfo,
     pub module: std::sync::Arc<Vec<u8>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct ModuleMapConfiguration {
-    #[serde(rename = "module")]
+    #[serde(rename = "module", default)]
     pub entries: Vec<ModuleMapConfigurationEntry>,
+    #[serde(rename = "task", default)]
+    pub tasks: Vec<TaskConfigurationEntry>,
+    #[serde(rename = "include", default)]
+    pub includes: Vec<IncludeConfigurationEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct IncludeConfigurationEntry {
+    // Another module config file, relative to the directory of the one including it
+    pub file: String,
+    // Put in front of the routes of the included file's modules
+    pub route: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -34,38 +75,132 @@ pub struct ModuleMapConfigurationEntry {
     // The route to wire up
     pub route: String,
     // The Wasm to wire it up to
-    pub module: String,  // file path, file://foo URL, bindle:foo/bar/1.2.3 or oci:foo/bar:1.2.3 (bindle: is deprecated which is good because it's not clear which parcel you'd use)
+    pub module: String,  // file path, file://foo URL, bindle:foo/bar/1.2.3, oci:foo/bar:1.2.3, s3://bucket/key or git+https://host/repo#ref:path (bindle: is deprecated which is good because it's not clear which parcel you'd use)
     pub entrypoint: Option<String>,
     pub bindle_server: Option<String>,
     // The environment in which to run it
     pub volumes: Option<HashMap<String, String>>,
     pub allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
+    pub args_mode: Option<ArgsMode>,
     pub argv: Option<String>,
+    // The query parameters to pass as --name=value arguments
+    pub argv_from_query: Option<Vec<String>>,
+    pub preinstantiate: Option<bool>,
+    pub max_instances: Option<usize>,
+    // Whether the module runs on threads of its own, and the CPUs they are pinned to
+    pub dedicated_threads: Option<usize>,
+    pub cpus: Option<Vec<usize>>,
+    pub enabled: Option<bool>,
+    pub allow_from: Option<Vec<String>>,
+    pub deny_from: Option<Vec<String>>,
+    pub default_content_type: Option<String>,
+    pub default_charset: Option<String>,
+    // Whether PATH_INFO and PATH_TRANSLATED are percent-decoded
+    pub decode_path_info: Option<bool>,
+    // Whether to parse the query into QUERY_<NAME> and X_QUERY_PARAMS
+    pub query_env_vars: Option<bool>,
+    // Whether to answer Range requests from the module's response
+    pub byte_ranges: Option<bool>,
+    // How to rebuild the module in watch mode
+    pub build_command: Option<String>,
+    pub build_dir: Option<String>,
+    pub watch: Option<Vec<String>>,
+    // Seconds the module may run for
+    pub timeout: Option<f64>,
+    pub stderr: Option<StderrDestination>,
+    // Whether request bodies must be JSON, and which of their fields to pass on
+    pub json: Option<bool>,
+    pub json_fields: Option<Vec<String>>,
+    // The media types request bodies may be sent as
+    pub accept_content_types: Option<Vec<String>>,
+    // Whether each request gets its own copy of the volumes, and what becomes of it
+    pub volume_overlay: Option<VolumeOverlay>,
+    // Modules on the same route in the same experiment are its variants
+    pub experiment: Option<String>,
+    pub variant: Option<String>,
+    pub variant_weight: Option<u32>,
+    // Whether to record requests in the audit log, and how much of their bodies
+    pub audit: Option<bool>,
+    pub audit_body_bytes: Option<usize>,
+    // PEM files of CAs that outbound HTTPS requests trust, and whether to trust only those
+    pub ca_certificates: Option<Vec<String>>,
+    pub ca_certificates_only: Option<bool>,
+    // The local address outbound HTTP requests are sent from
+    pub outbound_source_ip: Option<String>,
+    // Whether the module is exempt from the global timeouts and request header limits
+    pub trusted: Option<bool>,
+    // Whether the module only gets requests that earlier modules on its route answer with 404 or 500
+    pub fallback: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TaskConfigurationEntry {
+    // Defaults to the module reference
+    pub name: Option<String>,
+    // Cron syntax, in UTC
+    pub schedule: String,
+    // The Wasm to run, and the same environment settings as a module
+    pub module: String,
+    pub entrypoint: Option<String>,
+    pub bindle_server: Option<String>,
+    pub volumes: Option<HashMap<String, String>>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub http_max_concurrency: Option<u32>,
+    pub timeout: Option<f64>,
+    pub stderr: Option<StderrDestination>,
 }
 
 pub async fn load(
     emplaced_handlers: EmplacedHandlerConfiguration,
     configuration: &WagiConfiguration,
-) -> anyhow::Result<LoadedHandlerConfiguration> {
-    load_handler_configuration(emplaced_handlers, configuration).await
+    engine: &wasmtime::Engine,
+) -> WagiResult<LoadedHandlerConfiguration> {
+    load_handler_configuration(emplaced_handlers, configuration, engine).await
 }
 
-pub async fn load_handler_configuration(pre_handler_config: EmplacedHandlerConfiguration, configuration: &WagiConfiguration) -> anyhow::Result<LoadedHandlerConfiguration> {
+pub async fn load_handler_configuration(pre_handler_config: EmplacedHandlerConfiguration, configuration: &WagiConfiguration, engine: &wasmtime::Engine) -> WagiResult<LoadedHandlerConfiguration> {
     match pre_handler_config {
         EmplacedHandlerConfiguration::ModuleMapFile(path) => {
-            let module_map_configuration = read_module_map_configuration(&path).await?;
-            handlers_for_module_map(&module_map_configuration, configuration).await
+            let module_map_configuration = read_module_map_configuration(&path).await
+                .and_then(|m| check_module_map(&m))
+                .map_err(WagiError::Config)?;
+            handlers_for_module_map(&module_map_configuration, configuration, engine).await
+                .with_context(|| "Failed to load one or more Wasm modules from source")
+                .map_err(WagiError::Fetch)
+        },
+        EmplacedHandlerConfiguration::ModuleDir(path) => {
+            let module_map_configuration = read_module_dir(&path)
+                .and_then(|m| check_module_map(&m))
+                .map_err(WagiError::Config)?;
+            handlers_for_module_map(&module_map_configuration, configuration, engine).await
+                .with_context(|| "Failed to load one or more Wasm modules from source")
+                .map_err(WagiError::Fetch)
+        },
+        EmplacedHandlerConfiguration::Bindle(emplacer, invoice) => {
+            let wagi_handlers = bindle_handlers(&invoice)
+                .map_err(WagiError::Config)?;
+            handlers_for_bindle(wagi_handlers, &invoice, &emplacer, &configuration.bindle_annotations, engine).await
+                .with_context(|| "Failed to load one or more Wasm modules from source")
+                .map_err(WagiError::Fetch)
         },
-        EmplacedHandlerConfiguration::Bindle(emplacer, invoice) =>
-            handlers_for_bindle(&invoice, &emplacer).await,
+        EmplacedHandlerConfiguration::InMemory(modules) => Ok(LoadedHandlerConfiguration {
+            entries: modules.into_iter().map(LoadedHandlerConfigurationEntry::from_in_memory_module).collect(),
+            tasks: vec![],
+        }),
     }
 }
 
 async fn read_module_map_configuration(path: &Path) -> anyhow::Result<ModuleMapConfiguration> {
+    read_module_map_file(path, "", &mut vec![])
+}
+
+// Reads a module config file, along with the files it includes, recursively.
+// `route_prefix` goes in front of the file's routes, and `including` holds the
+// files that led to this one, to catch files that include themselves.
+fn read_module_map_file(path: &Path, route_prefix: &str, including: &mut Vec<PathBuf>) -> anyhow::Result<ModuleMapConfiguration> {
     tracing::info!(?path, "Loading modules config file");
-    if !tokio::fs::metadata(&path)
-        .await
+    if !std::fs::metadata(&path)
         .map(|m| m.is_file())
         .unwrap_or(false)
     {
@@ -77,84 +212,794 @@ async fn read_module_map_configuration(path: &Path) -> anyhow::Result<ModuleMapC
 
     let data = std::fs::read(path)
         .with_context(|| format!("Couldn't read module config file at {}", path.display()))?;
-    let modules: ModuleMapConfiguration = toml::from_slice(&data)
+    validation::check_for_unknown_keys(&String::from_utf8_lossy(&data))
+        .with_context(|| format!("File {} is not a valid WAGI module config", path.display()))?;
+    // Deserialised straight from the text first, so that errors in the modules' own
+    // settings are reported with their positions, then again with group settings filled in
+    let mut modules: ModuleMapConfiguration = toml::from_slice(&data)
         .with_context(|| format!("File {} contained invalid TOML or was not a WAGI module config", path.display()))?;
+    let mut document: toml::Value = toml::from_slice(&data)?;
+    if apply_groups(&mut document).with_context(|| format!("Invalid groups in {}", path.display()))? {
+        modules = document.try_into()
+            .with_context(|| format!("File {} has invalid settings in a [[group]]", path.display()))?;
+    }
+    for entry in modules.entries.iter_mut() {
+        entry.route = prefixed_route(route_prefix, &entry.route);
+    }
+
+    let canonical_path = path.canonicalize()?;
+    if including.contains(&canonical_path) {
+        anyhow::bail!("Module config file {} includes itself", path.display());
+    }
+    including.push(canonical_path);
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    for include in std::mem::take(&mut modules.includes) {
+        let included_path = base_dir.join(&include.file);
+        let included_prefix = prefixed_route(route_prefix, include.route.as_deref().unwrap_or("/"));
+        let included = read_module_map_file(&included_path, &included_prefix, including)
+            .with_context(|| format!("Failed to include {} in {}", include.file, path.display()))?;
+        if let Some(route) = conflicting_route(&modules.entries, &included.entries) {
+            anyhow::bail!("Route {} in {} is already used in {} or a file it includes", route, included_path.display(), path.display());
+        }
+        modules.entries.extend(included.entries);
+        modules.tasks.extend(included.tasks);
+    }
+    including.pop();
     Ok(modules)
 }
 
-async fn handlers_for_module_map(module_map: &ModuleMapConfiguration, configuration: &WagiConfiguration) -> anyhow::Result<LoadedHandlerConfiguration> {
+// Fills in each module's settings from the `[[group]]` it names, returning whether the
+// file has any groups. A setting the module gives itself wins; for tables such as
+// `volumes`, the module's entries are added to the group's. Groups only apply within
+// the file that defines them.
+fn apply_groups(document: &mut toml::Value) -> anyhow::Result<bool> {
+    let table = match document.as_table_mut() {
+        Some(t) => t,
+        None => return Ok(false),
+    };
+    let group_list = match table.remove("group") {
+        None => return Ok(false),
+        Some(toml::Value::Array(a)) => a,
+        Some(_) => anyhow::bail!("group must be an array of tables ([[group]])"),
+    };
+
+    let mut groups = HashMap::new();
+    for group in group_list {
+        let mut settings = match group {
+            toml::Value::Table(t) => t,
+            _ => anyhow::bail!("group must be an array of tables ([[group]])"),
+        };
+        let name = match settings.remove("name") {
+            Some(toml::Value::String(name)) => name,
+            _ => anyhow::bail!("Every [[group]] must have a name"),
+        };
+        if groups.insert(name.clone(), settings).is_some() {
+            anyhow::bail!("Group {} is defined more than once", name);
+        }
+    }
+
+    let modules = table.get_mut("module").and_then(|m| m.as_array_mut());
+    for module in modules.into_iter().flatten().filter_map(|m| m.as_table_mut()) {
+        let name = match module.remove("group") {
+            None => continue,
+            Some(toml::Value::String(name)) => name,
+            Some(_) => anyhow::bail!("A module's group must be the name of a [[group]]"),
+        };
+        let settings = groups.get(&name).ok_or_else(|| {
+            let module_name = module.get("module").and_then(|m| m.as_str()).unwrap_or_default();
+            anyhow::anyhow!("Module {} is in group {}, but there is no [[group]] with that name", module_name, name)
+        })?;
+        for (key, inherited) in settings {
+            match (module.get_mut(key), inherited) {
+                (None, _) => {
+                    module.insert(key.clone(), inherited.clone());
+                }
+                (Some(toml::Value::Table(own)), toml::Value::Table(inherited)) => {
+                    for (k, v) in inherited {
+                        own.entry(k.clone()).or_insert_with(|| v.clone());
+                    }
+                }
+                (Some(_), _) => (),
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Builds the module map for a directory of modules: each `name.wasm` is served at
+// `/name`, and `index.wasm` at `/`. A `name.toml` beside a module holds settings for
+// its entry, in the same keys as a modules.toml entry, and can also give its route.
+fn read_module_dir(dir: &Path) -> anyhow::Result<ModuleMapConfiguration> {
+    tracing::info!(?dir, "Loading modules directory");
+    let mut wasm_paths = vec![];
+    for dir_entry in std::fs::read_dir(dir).with_context(|| format!("Couldn't read module directory {}", dir.display()))? {
+        let path = dir_entry?.path();
+        if path.extension() == Some(std::ffi::OsStr::new("wasm")) && path.is_file() {
+            wasm_paths.push(path);
+        }
+    }
+    if wasm_paths.is_empty() {
+        anyhow::bail!("No .wasm files found in module directory {}", dir.display());
+    }
+    // So that the routing table doesn't depend on the order the OS lists files in
+    wasm_paths.sort();
+
+    let mut entries: Vec<ModuleMapConfigurationEntry> = vec![];
+    for wasm_path in wasm_paths {
+        let entry = module_dir_entry(&wasm_path)?;
+        if let Some(route) = conflicting_route(&entries, std::slice::from_ref(&entry)) {
+            anyhow::bail!("Route {} of {} is already used by another module in {}", route, wasm_path.display(), dir.display());
+        }
+        entries.push(entry);
+    }
+    Ok(ModuleMapConfiguration { entries, tasks: vec![], includes: vec![] })
+}
+
+fn module_dir_entry(wasm_path: &Path) -> anyhow::Result<ModuleMapConfigurationEntry> {
+    let name = wasm_path.file_stem().and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Module file name {} is not valid UTF-8", wasm_path.display()))?;
+    let route = match name {
+        "index" => "/".to_owned(),
+        _ => format!("/{}", name),
+    };
+
+    let settings_path = wasm_path.with_extension("toml");
+    let mut settings = if settings_path.is_file() {
+        let text = std::fs::read_to_string(&settings_path)
+            .with_context(|| format!("Couldn't read module settings file at {}", settings_path.display()))?;
+        validation::check_for_unknown_module_settings(&text)
+            .with_context(|| format!("File {} is not a valid WAGI module settings file", settings_path.display()))?;
+        toml::from_str::<toml::value::Table>(&text)
+            .with_context(|| format!("File {} contained invalid TOML", settings_path.display()))?
+    } else {
+        toml::value::Table::new()
+    };
+    settings.entry("route").or_insert(toml::Value::String(route));
+    settings.insert("module".to_owned(), toml::Value::String(wasm_path.display().to_string()));
+    toml::Value::Table(settings).try_into()
+        .with_context(|| format!("File {} contained invalid module settings", settings_path.display()))
+}
+
+fn prefixed_route(prefix: &str, route: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    match route {
+        _ if prefix.is_empty() => route.to_owned(),
+        "/" | "" => prefix.to_owned(),
+        _ if route.starts_with('/') => format!("{}{}", prefix, route),
+        _ => format!("{}/{}", prefix, route),
+    }
+}
+
+// Variants of the same experiment share their route on purpose
+fn conflicting_route<'a>(existing: &[ModuleMapConfigurationEntry], added: &'a [ModuleMapConfigurationEntry]) -> Option<&'a str> {
+    added
+        .iter()
+        .find(|a| existing.iter().any(|e| e.route == a.route && (e.experiment.is_none() || a.experiment.is_none())))
+        .map(|a| a.route.as_str())
+}
+
+async fn handlers_for_module_map(module_map: &ModuleMapConfiguration, configuration: &WagiConfiguration, engine: &wasmtime::Engine) -> anyhow::Result<LoadedHandlerConfiguration> {
     let loaders = module_map
         .entries
         .iter()
-        .map(|e| handler_for_module_map_entry(e, configuration));
+        .map(|e| handler_for_module_map_entry(e, configuration, engine));
+
+    let entries: anyhow::Result<Vec<_>> = futures::future::join_all(loaders).await.into_iter().collect();
+
+    let task_loaders = module_map
+        .tasks
+        .iter()
+        .map(|t| task_for_module_map_entry(t, configuration));
+    let tasks: anyhow::Result<Vec<_>> = futures::future::join_all(task_loaders).await.into_iter().collect();
+
+    Ok(LoadedHandlerConfiguration { entries: entries?, tasks: tasks? })
+}
+
+// The invoice's handlers, with the environment variables in their allowed hosts
+// filled in.
+fn bindle_handlers(invoice: &bindle::Invoice) -> anyhow::Result<Vec<WagiHandlerInfo>> {
+    let mut wagi_handlers = InvoiceUnderstander::new(invoice).parse_wagi_handlers();
+    for handler in wagi_handlers.iter_mut() {
+        if let Some(hosts) = &handler.allowed_hosts {
+            handler.allowed_hosts = Some(expand_allowed_hosts(hosts)
+                .with_context(|| format!("Invalid allowed_hosts for parcel {}", handler.parcel.label.name))?);
+        }
+    }
+    Ok(wagi_handlers)
+}
+
+async fn handlers_for_bindle(wagi_handlers: Vec<WagiHandlerInfo>, invoice: &bindle::Invoice, emplacer: &Emplacer, annotation_keys: &[String], engine: &wasmtime::Engine) -> anyhow::Result<LoadedHandlerConfiguration> {
+    let bindle = std::sync::Arc::new(BindleInfo::from_invoice(invoice, annotation_keys));
+    tracing::info!(
+        bindle = %bindle.id,
+        description = bindle.description.as_deref().unwrap_or_default(),
+        annotations = ?bindle.annotations,
+        "Loading bindle"
+    );
+    let loaders = wagi_handlers.into_iter().map(|h| handler_for_bindle_handler(h, emplacer, engine));
+    let mut entries = futures::future::join_all(loaders).await.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+    for entry in entries.iter_mut() {
+        entry.info.bindle = Some(bindle.clone());
+    }
+
+    // Bindles have no way to declare tasks
+    Ok(LoadedHandlerConfiguration { entries, tasks: vec![] })
+}
+
+async fn handler_for_bindle_handler(handler: WagiHandlerInfo, emplacer: &Emplacer, engine: &wasmtime::Engine) -> anyhow::Result<LoadedHandlerConfigurationEntry> {
+    if !emplacer.has_missing_parcels(&handler) {
+        let bits = emplacer.get_bits_for(&handler).await?;
+        return Ok(LoadedHandlerConfigurationEntry::from_loaded_bindle_handler((handler, bits)));
+    }
+
+    let retry = emplacer.missing_parcel_policy() == MissingParcelPolicy::Retry;
+    let (module, route) = (handler.parcel.label.name.clone(), handler.route.clone());
+    if retry {
+        tracing::error!(%module, %route, "Parcels for handler could not be fetched; its route will answer 503 while they are fetched again in the background");
+    } else {
+        tracing::error!(%module, %route, "Parcels for handler could not be fetched; its route will answer 503");
+    }
+    let volume_mounts = emplacer.volume_mounts_for(&handler)?;
+    // So that the volumes can be mounted once the parcels arrive
+    for host_dir in volume_mounts.values() {
+        std::fs::create_dir_all(host_dir)
+            .with_context(|| format!("Error creating asset directory {}", host_dir))?;
+    }
+    let pending = PendingModule::new(&module);
+    if retry {
+        let emplacer = std::sync::Arc::new(emplacer.clone());
+        let retried = handler.clone();
+        let load = move || {
+            let (emplacer, handler) = (emplacer.clone(), retried.clone());
+            async move { emplacer.fetch_again(&handler).await }
+        };
+        fetch_retry::retry_load_in_background(module, route, emplacer.fetch_retry().clone(), engine.clone(), pending.clone(), load);
+    }
+    Ok(LoadedHandlerConfigurationEntry::from_pending_bindle_handler(handler, volume_mounts, pending))
+}
+
+async fn handler_for_module_map_entry(module_map_entry: &ModuleMapConfigurationEntry, configuration: &WagiConfiguration, engine: &wasmtime::Engine) -> anyhow::Result<LoadedHandlerConfigurationEntry> {
+    let mut module_map_entry = module_map_entry.clone();
+    if let Some(volumes) = &module_map_entry.volumes {
+        module_map_entry.volumes = Some(module_loader::prefetch_volumes(volumes, configuration).await?);
+    }
+    match module_loader::load_from_module_map_entry(&module_map_entry, configuration).await {
+        Ok(content) => Ok(LoadedHandlerConfigurationEntry::from_loaded_module_map_entry(Loaded::new(&module_map_entry, content))),
+        Err(e) if configuration.retry_fetch_in_background => {
+            let error = format!("{:#}", e);
+            tracing::error!(module = %module_map_entry.module, route = %module_map_entry.route, %error, "Error fetching module; its route will answer 503 while the fetch is retried in the background");
+            let pending = PendingModule::new(&module_map_entry.module);
+            fetch_retry::retry_in_background(module_map_entry.clone(), configuration.clone(), engine.clone(), pending.clone());
+            Ok(LoadedHandlerConfigurationEntry::from_pending_module_map_entry(module_map_entry, pending))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Checks the settings of the modules and tasks before anything is fetched, so that
+// a mistake in them is reported as a configuration error rather than as a failure
+// to fetch. Returns the map with the environment variables in allowed hosts filled in.
+fn check_module_map(module_map: &ModuleMapConfiguration) -> anyhow::Result<ModuleMapConfiguration> {
+    Ok(ModuleMapConfiguration {
+        entries: module_map.entries.iter().map(check_module_map_entry).collect::<anyhow::Result<_>>()?,
+        tasks: module_map.tasks.iter().map(check_task).collect::<anyhow::Result<_>>()?,
+        includes: vec![],
+    })
+}
+
+fn check_module_map_entry(module_map_entry: &ModuleMapConfigurationEntry) -> anyhow::Result<ModuleMapConfigurationEntry> {
+    if let Some(secs) = module_map_entry.timeout {
+        timeout_from_secs(secs)
+            .with_context(|| format!("Invalid timeout for module {}", module_map_entry.module))?;
+    }
+    ArgsMode::resolve(module_map_entry.args_mode, &module_map_entry.argv, &module_map_entry.argv_from_query)
+        .with_context(|| format!("Invalid args_mode for module {}", module_map_entry.module))?;
+    if module_map_entry.max_instances == Some(0) {
+        anyhow::bail!("Invalid max_instances for module {}: must be at least 1", module_map_entry.module);
+    }
+    executor_settings(module_map_entry)?;
+    experiment_variant(module_map_entry)
+        .with_context(|| format!("Invalid experiment for module {}", module_map_entry.module))?;
+    if module_map_entry.json_fields.is_some() && module_map_entry.json != Some(true) {
+        anyhow::bail!("Invalid json_fields for module {}: only used with json = true", module_map_entry.module);
+    }
+    if module_map_entry.fallback == Some(true) && module_map_entry.experiment.is_some() {
+        anyhow::bail!("Invalid fallback for module {}: a fallback can't be an experiment variant", module_map_entry.module);
+    }
+    if let Some(types) = &module_map_entry.accept_content_types {
+        AcceptedContentTypes::parse(types)
+            .with_context(|| format!("Invalid accept_content_types for module {}", module_map_entry.module))?;
+    }
+    if module_map_entry.volume_overlay.is_some() && module_map_entry.volumes.as_ref().map_or(true, |v| v.is_empty()) {
+        anyhow::bail!("Invalid volume_overlay for module {}: only used with volumes", module_map_entry.module);
+    }
+    if module_map_entry.audit_body_bytes.is_some() && module_map_entry.audit != Some(true) {
+        anyhow::bail!("Invalid audit_body_bytes for module {}: only used with audit = true", module_map_entry.module);
+    }
+    if let Some(tls) = outbound_tls(module_map_entry)? {
+        tls.certificates()
+            .with_context(|| format!("Invalid ca_certificates for module {}", module_map_entry.module))?;
+    }
+    if let Some(ip) = outbound_source_ip(module_map_entry)? {
+        check_source_ip(ip)
+            .with_context(|| format!("Invalid outbound_source_ip for module {}", module_map_entry.module))?;
+    }
+    let mut module_map_entry = module_map_entry.clone();
+    if let Some(hosts) = &module_map_entry.allowed_hosts {
+        module_map_entry.allowed_hosts = Some(expand_allowed_hosts(hosts)
+            .with_context(|| format!("Invalid allowed_hosts for module {}", module_map_entry.module))?);
+    }
+    Ok(module_map_entry)
+}
+
+fn check_task(task: &TaskConfigurationEntry) -> anyhow::Result<TaskConfigurationEntry> {
+    let name = task_name(task);
+    Schedule::parse(&task.schedule)
+        .with_context(|| format!("Invalid schedule for task {}", name))?;
+    if let Some(secs) = task.timeout {
+        timeout_from_secs(secs)
+            .with_context(|| format!("Invalid timeout for task {}", name))?;
+    }
+    let mut task = task.clone();
+    if let Some(hosts) = &task.allowed_hosts {
+        task.allowed_hosts = Some(expand_allowed_hosts(hosts)
+            .with_context(|| format!("Invalid allowed_hosts for task {}", name))?);
+    }
+    Ok(task)
+}
 
-    let loadeds: anyhow::Result<Vec<_>> = futures::future::join_all(loaders).await.into_iter().collect();
-    
-    let entries =
-        loadeds?
-        .into_iter()
-        .map(LoadedHandlerConfigurationEntry::from_loaded_module_map_entry)
-        .collect();
+fn task_name(task: &TaskConfigurationEntry) -> String {
+    task.name.clone().unwrap_or_else(|| task.module.clone())
+}
 
-    Ok(LoadedHandlerConfiguration { entries })
+// The task's settings have been through `check_task`.
+async fn task_for_module_map_entry(task: &TaskConfigurationEntry, configuration: &WagiConfiguration) -> anyhow::Result<LoadedTaskConfigurationEntry> {
+    let name = task_name(task);
+    let schedule = Schedule::parse(&task.schedule)
+        .with_context(|| format!("Invalid schedule for task {}", name))?;
+    let timeout = task.timeout.map(timeout_from_secs).transpose()
+        .with_context(|| format!("Invalid timeout for task {}", name))?;
+    let volume_mounts = match &task.volumes {
+        Some(volumes) => module_loader::prefetch_volumes(volumes, configuration).await?,
+        None => HashMap::new(),
+    };
+    let content = module_loader::load_module(&task.module, task.bindle_server.as_deref(), configuration).await
+        .with_context(|| format!("Error loading module for task {}", name))?;
+    let info = TaskInfo {
+        name,
+        module: task.module.clone(),
+        schedule,
+        entrypoint: task.entrypoint.clone(),
+        allowed_hosts: task.allowed_hosts.clone(),
+        http_max_concurrency: task.http_max_concurrency,
+        volume_mounts,
+        timeout,
+        stderr: task.stderr.unwrap_or_default(),
+    };
+    Ok(LoadedTaskConfigurationEntry {
+        info,
+        module: std::sync::Arc::new(content),
+    })
 }
 
-async fn handlers_for_bindle(invoice: &bindle::Invoice, emplacer: &Emplacer) -> anyhow::Result<LoadedHandlerConfiguration> {
-    let invoice = InvoiceUnderstander::new(invoice);
+// Replaces `${NAME}` in each host with the value of the NAME environment variable
+// of the WAGI process, so that one module map can be promoted between environments.
+fn expand_allowed_hosts(hosts: &[String]) -> anyhow::Result<Vec<String>> {
+    hosts.iter().map(|h| expand_env_vars(h, |name| std::env::var(name).ok())).collect()
+}
 
-    let wagi_handlers = invoice.parse_wagi_handlers();
+pub(super) fn expand_env_vars(text: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}')
+            .ok_or_else(|| anyhow::anyhow!("'{}' has an unterminated '${{'", text))?;
+        let name = &after[..end];
+        if name.is_empty() {
+            anyhow::bail!("'{}' has an empty variable reference", text);
+        }
+        let value = lookup(name)
+            .ok_or_else(|| anyhow::anyhow!("'{}' refers to environment variable {}, which is not set", text, name))?;
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
 
-    let loaders = wagi_handlers.iter().map(|h| emplacer.get_bits_for(h));
-    let loadeds: anyhow::Result<Vec<_>> = futures::future::join_all(loaders).await.into_iter().collect();
+fn module_digest(content: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content);
+    format!("{:x}", hasher.finalize())
+}
 
-    let entries =
-        wagi_handlers
-        .into_iter()
-        .zip(loadeds?.into_iter())
-        .map(LoadedHandlerConfigurationEntry::from_loaded_bindle_handler)
-        .collect();
+fn build_settings(command: Option<String>, build_dir: Option<String>, watch: Option<Vec<String>>) -> Option<BuildSettings> {
+    let command = command?;
+    let working_dir = PathBuf::from(build_dir.unwrap_or_else(|| ".".to_owned()));
+    let watch = match watch {
+        Some(paths) => paths.iter().map(|p| working_dir.join(p)).collect(),
+        None => vec![working_dir.clone()],
+    };
+    Some(BuildSettings {
+        command,
+        working_dir,
+        watch,
+    })
+}
 
-    Ok(LoadedHandlerConfiguration { entries })
+fn experiment_variant(entry: &ModuleMapConfigurationEntry) -> anyhow::Result<Option<ExperimentVariant>> {
+    match (&entry.experiment, &entry.variant) {
+        (None, None) if entry.variant_weight.is_some() => anyhow::bail!("variant_weight is only used with experiment and variant"),
+        (None, None) => Ok(None),
+        (Some(experiment), Some(variant)) => ExperimentVariant::parse(experiment, variant, entry.variant_weight).map(Some),
+        _ => anyhow::bail!("experiment and variant must be set together"),
+    }
 }
 
-async fn handler_for_module_map_entry(module_map_entry: &ModuleMapConfigurationEntry, configuration: &WagiConfiguration) -> anyhow::Result<Loaded<ModuleMapConfigurationEntry>> {
-    module_loader::load_from_module_map_entry(module_map_entry, configuration)
-        .await
-        .map(|v| Loaded::new(module_map_entry, v))
+fn outbound_tls(entry: &ModuleMapConfigurationEntry) -> anyhow::Result<Option<OutboundTls>> {
+    match (&entry.ca_certificates, entry.ca_certificates_only) {
+        (None, Some(true)) => anyhow::bail!("Invalid ca_certificates_only for module {}: only used with ca_certificates", entry.module),
+        (None, _) => Ok(None),
+        (Some(paths), only) => Ok(Some(OutboundTls {
+            ca_certificates: paths.iter().map(PathBuf::from).collect(),
+            ca_certificates_only: only.unwrap_or(false),
+        })),
+    }
+}
+
+fn outbound_source_ip(entry: &ModuleMapConfigurationEntry) -> anyhow::Result<Option<IpAddr>> {
+    entry.outbound_source_ip
+        .as_ref()
+        .map(|ip| ip.parse::<IpAddr>())
+        .transpose()
+        .with_context(|| format!("Invalid outbound_source_ip for module {}: must be an IP address", entry.module))
+}
+
+fn executor_settings(entry: &ModuleMapConfigurationEntry) -> anyhow::Result<Option<ExecutorSettings>> {
+    ExecutorSettings::parse(entry.dedicated_threads, entry.cpus.as_deref())
+        .with_context(|| format!("Invalid dedicated_threads or cpus for module {}", entry.module))
+}
+
+fn handler_info_for_module_map_entry(entry: ModuleMapConfigurationEntry, module_digest: String) -> HandlerInfo {
+    // Validated when the module was loaded
+    let experiment = experiment_variant(&entry).unwrap_or_default();
+    let executor = executor_settings(&entry).unwrap_or_default();
+    let outbound_tls = outbound_tls(&entry).unwrap_or_default();
+    let outbound_source_ip = outbound_source_ip(&entry).unwrap_or_default();
+    HandlerInfo {
+        module_digest,
+        name: entry.module,
+        route: entry.route,
+        entrypoint: entry.entrypoint,
+        allowed_hosts: entry.allowed_hosts,
+        http_max_concurrency: entry.http_max_concurrency,
+        volume_mounts: entry.volumes.unwrap_or_default(),
+        volume_overlay: entry.volume_overlay,
+        // Validated when the module was loaded
+        args_mode: ArgsMode::resolve(entry.args_mode, &entry.argv, &entry.argv_from_query).unwrap_or_default(),
+        argv: entry.argv,
+        argv_from_query: entry.argv_from_query,
+        preinstantiate: entry.preinstantiate.unwrap_or(false),
+        trusted: entry.trusted.unwrap_or(false),
+        fallback: entry.fallback.unwrap_or(false),
+        max_instances: entry.max_instances,
+        executor,
+        enabled: entry.enabled.unwrap_or(true),
+        allow_from: entry.allow_from,
+        deny_from: entry.deny_from,
+        default_content_type: entry.default_content_type,
+        default_charset: entry.default_charset,
+        decode_path_info: entry.decode_path_info.unwrap_or(true),
+        query_env_vars: entry.query_env_vars.unwrap_or(false),
+        byte_ranges: entry.byte_ranges.unwrap_or(false),
+        build: build_settings(entry.build_command, entry.build_dir, entry.watch),
+        // Validated when the module was loaded
+        timeout: entry.timeout.and_then(|secs| timeout_from_secs(secs).ok()),
+        stderr: entry.stderr.unwrap_or_default(),
+        json: match entry.json {
+            Some(true) => Some(JsonRequestSettings { fields: entry.json_fields.unwrap_or_default() }),
+            _ => None,
+        },
+        // Validated when the module was loaded
+        accept_content_types: entry.accept_content_types.and_then(|t| AcceptedContentTypes::parse(&t).ok()),
+        experiment,
+        audit: match entry.audit {
+            Some(true) => Some(AuditSettings { body_limit: entry.audit_body_bytes.unwrap_or(0) }),
+            _ => None,
+        },
+        outbound_tls,
+        outbound_source_ip,
+        bindle: None,
+    }
 }
 
 // TODO: consider replacing these functions with Into implementations
 impl LoadedHandlerConfigurationEntry {
     fn from_loaded_module_map_entry(lmmce: Loaded<ModuleMapConfigurationEntry>) -> Self {
+        Self {
+            info: handler_info_for_module_map_entry(lmmce.metadata, module_digest(&lmmce.content)),
+            module: LoadedModule::Fetched(lmmce.content),
+        }
+    }
+
+    fn from_pending_module_map_entry(module_map_entry: ModuleMapConfigurationEntry, pending: PendingModule) -> Self {
+        Self {
+            // Not known until the module arrives
+            info: handler_info_for_module_map_entry(module_map_entry, String::new()),
+            module: LoadedModule::Retrying(pending),
+        }
+    }
+
+    fn from_in_memory_module(module: InMemoryModule) -> Self {
         let info = HandlerInfo {
-            name: lmmce.metadata.module,
-            route: lmmce.metadata.route,
-            entrypoint: lmmce.metadata.entrypoint,
-            allowed_hosts: lmmce.metadata.allowed_hosts,
-            http_max_concurrency: lmmce.metadata.http_max_concurrency,
-            volume_mounts: lmmce.metadata.volumes.unwrap_or_default(),
-            argv: lmmce.metadata.argv,
+            module_digest: module_digest(&module.content),
+            name: module.name,
+            route: module.route,
+            entrypoint: module.entrypoint,
+            allowed_hosts: None,
+            http_max_concurrency: None,
+            volume_mounts: HashMap::new(),
+            volume_overlay: None,
+            args_mode: ArgsMode::default(),
+            argv: None,
+            argv_from_query: None,
+            preinstantiate: false,
+            trusted: module.trusted,
+            fallback: false,
+            max_instances: None,
+            executor: None,
+            enabled: true,
+            allow_from: None,
+            deny_from: None,
+            default_content_type: None,
+            default_charset: None,
+            decode_path_info: true,
+            query_env_vars: false,
+            byte_ranges: false,
+            build: None,
+            timeout: None,
+            stderr: StderrDestination::default(),
+            json: None,
+            accept_content_types: None,
+            experiment: None,
+            audit: None,
+            outbound_tls: None,
+            outbound_source_ip: None,
+            bindle: None,
         };
         Self {
             info,
-            module: lmmce.content,
+            module: LoadedModule::Fetched(module.content),
         }
     }
 
     fn from_loaded_bindle_handler(whib: (WagiHandlerInfo, super::emplacer::Bits)) -> Self {
         let (whi, bits) = whib;
-        let info = HandlerInfo {
-            name: whi.parcel.label.name,
-            route: whi.route,
-            entrypoint: whi.entrypoint,
-            allowed_hosts: whi.allowed_hosts,
-            http_max_concurrency: None,
-            volume_mounts: bits.volume_mounts,
-            argv: whi.argv,
-        };
         Self {
-            info,
-            module: bits.wasm_module,
+            info: handler_info_for_bindle_handler(whi, module_digest(&bits.wasm_module), bits.volume_mounts),
+            module: LoadedModule::Fetched(bits.wasm_module),
         }
     }
+
+    fn from_pending_bindle_handler(whi: WagiHandlerInfo, volume_mounts: HashMap<String, String>, pending: PendingModule) -> Self {
+        Self {
+            // Not known until the module arrives
+            info: handler_info_for_bindle_handler(whi, String::new(), volume_mounts),
+            module: LoadedModule::Retrying(pending),
+        }
+    }
+}
+
+fn handler_info_for_bindle_handler(whi: WagiHandlerInfo, module_digest: String, volume_mounts: HashMap<String, String>) -> HandlerInfo {
+    HandlerInfo {
+        module_digest,
+        name: whi.parcel.label.name,
+        route: whi.route,
+        entrypoint: whi.entrypoint,
+        allowed_hosts: whi.allowed_hosts,
+        http_max_concurrency: None,
+        volume_mounts,
+        volume_overlay: whi.volume_overlay,
+        args_mode: whi.args_mode,
+        argv: whi.argv,
+        argv_from_query: whi.argv_from_query,
+        preinstantiate: whi.preinstantiate,
+        // The invoice is written by whoever publishes the bindle, so it can't vouch for itself
+        trusted: false,
+        fallback: whi.fallback,
+        max_instances: whi.max_instances,
+        // Threads and CPUs are the WAGI host's to hand out, not the bindle's
+        executor: None,
+        enabled: whi.enabled,
+        allow_from: whi.allow_from,
+        deny_from: whi.deny_from,
+        default_content_type: whi.default_content_type,
+        default_charset: whi.default_charset,
+        decode_path_info: whi.decode_path_info,
+        query_env_vars: whi.query_env_vars,
+        byte_ranges: whi.byte_ranges,
+        build: None,
+        timeout: whi.timeout,
+        stderr: whi.stderr,
+        json: whi.json,
+        accept_content_types: whi.accept_content_types,
+        experiment: whi.experiment,
+        audit: whi.audit,
+        // Bindles would have to name files on the WAGI host, so can't set these
+        outbound_tls: None,
+        outbound_source_ip: None,
+        // Filled in once all the handlers are loaded
+        bindle: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "API_HOST" => Some("api.staging.example.com".to_owned()),
+            "API_PORT" => Some("8443".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn env_vars_in_hosts_are_expanded() {
+        assert_eq!("https://api.staging.example.com", expand_env_vars("https://${API_HOST}", lookup).unwrap());
+        assert_eq!("https://api.staging.example.com:8443", expand_env_vars("https://${API_HOST}:${API_PORT}", lookup).unwrap());
+        assert_eq!("https://example.com", expand_env_vars("https://example.com", lookup).unwrap());
+        assert_eq!("insecure:allow-all", expand_env_vars("insecure:allow-all", lookup).unwrap());
+    }
+
+    #[tokio::test]
+    async fn invalid_settings_are_configuration_errors_not_fetch_errors() {
+        let dir = write_module_maps(&[
+            ("bad_settings.toml", "[[module]]\nroute = \"/\"\nmodule = \"missing.wasm\"\nmax_instances = 0\n"),
+            ("missing_module.toml", "[[module]]\nroute = \"/\"\nmodule = \"missing.wasm\"\n"),
+        ]);
+        let load = |file: &str| {
+            let path = dir.path().join(file);
+            let file = file.to_owned();
+            async move {
+                let configuration = WagiConfiguration::new(crate::wagi_config::HandlerConfigurationSource::ModuleConfigFile(path.clone())).unwrap();
+                match load_handler_configuration(EmplacedHandlerConfiguration::ModuleMapFile(path), &configuration, &wasmtime::Engine::default()).await {
+                    Ok(_) => panic!("{} should not have loaded", file),
+                    Err(e) => e,
+                }
+            }
+        };
+
+        // max_instances = 0 is invalid, and the module does not exist
+        let error = load("bad_settings.toml").await;
+        assert!(matches!(error, WagiError::Config(_)), "{}", error);
+        assert_eq!(2, error.exit_code());
+        let error = load("missing_module.toml").await;
+        assert_eq!(3, error.exit_code());
+    }
+
+    #[test]
+    fn bad_env_var_references_are_errors() {
+        assert!(expand_env_vars("https://${NOT_SET}", lookup).is_err());
+        assert!(expand_env_vars("https://${API_HOST", lookup).is_err());
+        assert!(expand_env_vars("https://${}", lookup).is_err());
+    }
+
+    fn write_module_maps(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, text) in files {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn included_routes_are_mounted_under_the_prefix() {
+        let dir = write_module_maps(&[
+            ("modules.toml", "[[module]]\nroute = \"/\"\nmodule = \"home.wasm\"\n\n[[include]]\nfile = \"team-a/routes.toml\"\nroute = \"/team-a\"\n"),
+            ("team-a/routes.toml", "[[module]]\nroute = \"/\"\nmodule = \"a.wasm\"\n\n[[include]]\nfile = \"api.toml\"\nroute = \"/api/\"\n"),
+            ("team-a/api.toml", "[[module]]\nroute = \"/items/...\"\nmodule = \"items.wasm\"\n"),
+        ]);
+        let modules = read_module_map_file(&dir.path().join("modules.toml"), "", &mut vec![]).unwrap();
+        let routes: Vec<_> = modules.entries.iter().map(|e| e.route.as_str()).collect();
+        assert_eq!(vec!["/", "/team-a", "/team-a/api/items/..."], routes);
+    }
+
+    #[test]
+    fn included_routes_cannot_conflict() {
+        let dir = write_module_maps(&[
+            ("modules.toml", "[[module]]\nroute = \"/team-a/x\"\nmodule = \"x.wasm\"\n\n[[include]]\nfile = \"a.toml\"\nroute = \"/team-a\"\n"),
+            ("a.toml", "[[module]]\nroute = \"/x\"\nmodule = \"other.wasm\"\n"),
+        ]);
+        let error = read_module_map_file(&dir.path().join("modules.toml"), "", &mut vec![]).expect_err("routes conflict");
+        assert!(error.to_string().contains("/team-a/x"), "unexpected error: {}", error);
+    }
+
+    #[test]
+    fn module_dirs_are_routed_by_file_name() {
+        let dir = write_module_maps(&[
+            ("index.wasm", ""),
+            ("hello.wasm", ""),
+            ("hello.toml", "entrypoint = \"greet\"\n"),
+            ("api.wasm", ""),
+            ("api.toml", "route = \"/api/...\"\nallowed_hosts = [\"https://example.com\"]\n"),
+            ("notes.txt", ""),
+        ]);
+        let modules = read_module_dir(dir.path()).unwrap();
+        let routes: Vec<_> = modules.entries.iter().map(|e| e.route.as_str()).collect();
+        assert_eq!(vec!["/api/...", "/hello", "/"], routes);
+        assert_eq!(Some("greet"), modules.entries[1].entrypoint.as_deref());
+        assert!(modules.entries[1].module.ends_with("hello.wasm"));
+    }
+
+    #[test]
+    fn module_dir_settings_are_checked() {
+        let dir = write_module_maps(&[("hello.wasm", ""), ("hello.toml", "module = \"other.wasm\"\n")]);
+        let error = read_module_dir(dir.path()).expect_err("the module is the .wasm file");
+        assert!(format!("{:#}", error).contains("unknown key `module`"), "unexpected error: {:#}", error);
+
+        let dir = write_module_maps(&[("a.wasm", ""), ("b.wasm", ""), ("b.toml", "route = \"/a\"\n")]);
+        read_module_dir(dir.path()).expect_err("routes conflict");
+    }
+
+    #[test]
+    fn modules_inherit_group_settings() {
+        let text = r#"
+            [[group]]
+            name = "api"
+            allowed_hosts = ["https://example.com"]
+            max_instances = 4
+            volumes = { "/data" = "data", "/config" = "config" }
+
+            [[module]]
+            route = "/items"
+            module = "items.wasm"
+            group = "api"
+
+            [[module]]
+            route = "/orders"
+            module = "orders.wasm"
+            group = "api"
+            max_instances = 1
+            volumes = { "/config" = "orders-config" }
+
+            [[module]]
+            route = "/"
+            module = "home.wasm"
+        "#;
+        let dir = write_module_maps(&[("modules.toml", text)]);
+        let modules = read_module_map_file(&dir.path().join("modules.toml"), "", &mut vec![]).unwrap();
+        let (items, orders, home) = (&modules.entries[0], &modules.entries[1], &modules.entries[2]);
+
+        assert_eq!(Some(vec!["https://example.com".to_owned()]), items.allowed_hosts);
+        assert_eq!(Some(4), items.max_instances);
+        assert_eq!(Some(1), orders.max_instances);
+        assert_eq!(Some(vec!["https://example.com".to_owned()]), orders.allowed_hosts);
+        let orders_volumes = orders.volumes.as_ref().unwrap();
+        assert_eq!("data", orders_volumes["/data"]);
+        assert_eq!("orders-config", orders_volumes["/config"]);
+        assert_eq!(None, home.allowed_hosts);
+    }
+
+    #[test]
+    fn modules_must_name_a_defined_group() {
+        let dir = write_module_maps(&[("modules.toml", "[[group]]\nname = \"api\"\n\n[[module]]\nroute = \"/\"\nmodule = \"a.wasm\"\ngroup = \"apj\"\n")]);
+        let error = read_module_map_file(&dir.path().join("modules.toml"), "", &mut vec![]).expect_err("there is no group apj");
+        assert!(format!("{:#}", error).contains("no [[group]] with that name"), "unexpected error: {:#}", error);
+    }
+
+    #[test]
+    fn include_loops_are_errors() {
+        let dir = write_module_maps(&[
+            ("modules.toml", "[[include]]\nfile = \"a.toml\"\n"),
+            ("a.toml", "[[include]]\nfile = \"modules.toml\"\nroute = \"/a\"\n"),
+        ]);
+        let error = read_module_map_file(&dir.path().join("modules.toml"), "", &mut vec![]).expect_err("files include each other");
+        assert!(format!("{:#}", error).contains("includes itself"), "unexpected error: {:#}", error);
+    }
 }
@@ -1,41 +1,131 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Context;
 
-use crate::{wagi_config::WagiConfiguration, wasm_module::WasmModuleSource};
+use crate::{accept_content_types::AcceptedContentTypes, audit::AuditSettings, build_info::BindleInfo, error::{WagiError, WagiResult}, executor::ExecutorSettings, experiment::ExperimentVariant, handlers::ArgsMode, json_request::JsonRequestSettings, outbound_network::OutboundTls, scheduler::Schedule, stderr::StderrDestination, volume_overlay::VolumeOverlay, wagi_config::WagiConfiguration, wasm_module::WasmModuleSource};
 
+mod cache;
 mod compiler;
 mod emplacer;
+mod fetch_retry;
+mod git;
 mod loader;
 mod module_loader;
+mod precompiled;
+mod registry_auth;
+mod s3;
+mod scheme_resolver;
+mod validation;
 
-pub use compiler::WasmCompilationSettings;
+pub use cache::{Cache, CacheBackend, LocalDirCache};
+pub use compiler::{CompileErrorPolicy, CompileFailure, WasmCompilationSettings};
+pub use fetch_retry::{FetchRetryPolicy, MissingParcelPolicy};
+pub use registry_auth::RegistryCredentials;
+pub use scheme_resolver::{ResolveContext, SchemeResolver, SchemeResolvers};
 
-pub async fn load_handlers(configuration: &WagiConfiguration) -> anyhow::Result<WasmHandlerConfiguration> {
+pub async fn load_handlers(configuration: &WagiConfiguration) -> WagiResult<WasmHandlerConfiguration> {
     let emplaced_handlers = emplacer::emplace(&configuration /* configuration.handlers, configuration.placement_settings() */).await
-        .with_context(|| "Failed to copy modules and assets to local cache")?;
-    let loaded_handlers = loader::load(emplaced_handlers, &configuration /* .loader_settings() */).await
-        .with_context(|| "Failed to load one or more Wasm modules from source")?;
-    let handlers = compiler::compile(loaded_handlers, configuration.wasm_compilation_settings())
-        .with_context(|| "Failed to compile one or more Wasm modules")?;
+        .with_context(|| "Failed to copy modules and assets to local cache")
+        .map_err(WagiError::Fetch)?;
+    let compilation_settings = configuration.wasm_compilation_settings();
+    let engine = compiler::new_engine(&compilation_settings)
+        .map_err(WagiError::Compile)?;
+    // The loader distinguishes invalid configuration from fetch failures itself
+    let loaded_handlers = loader::load(emplaced_handlers, &configuration /* .loader_settings() */, &engine).await?;
+    let handlers = compiler::compile(loaded_handlers, &engine, &compilation_settings)
+        .with_context(|| "Failed to compile one or more Wasm modules")
+        .map_err(WagiError::Compile)?;
     Ok(handlers)
 }
 
 pub struct HandlerInfo {
     pub name: String,
+    pub module_digest: String,
     pub route: String,
     pub entrypoint: Option<String>,
     pub allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
     pub volume_mounts: HashMap<String, String>,
-    pub argv: Option<String>
+    /// If set, each request gets its own copy of the volumes.
+    pub volume_overlay: Option<VolumeOverlay>,
+    pub args_mode: ArgsMode,
+    pub argv: Option<String>,
+    /// The query parameters passed as `--name=value` arguments in the `query` mode.
+    pub argv_from_query: Option<Vec<String>>,
+    pub preinstantiate: bool,
+    /// Whether the module is exempt from the global timeouts and request header limits.
+    pub trusted: bool,
+    /// Whether the module only gets requests that earlier modules on its route
+    /// answer with 404 or 500.
+    pub fallback: bool,
+    pub max_instances: Option<usize>,
+    /// If set, the module runs on threads of its own.
+    pub executor: Option<ExecutorSettings>,
+    /// Whether the route starts in service. It can be changed at runtime.
+    pub enabled: bool,
+    pub allow_from: Option<Vec<String>>,
+    pub deny_from: Option<Vec<String>>,
+    pub default_content_type: Option<String>,
+    pub default_charset: Option<String>,
+    /// Whether `PATH_INFO` and `PATH_TRANSLATED` are percent-decoded.
+    pub decode_path_info: bool,
+    /// Whether the query is parsed into `QUERY_<NAME>` and `X_QUERY_PARAMS`.
+    pub query_env_vars: bool,
+    /// Whether `Range` requests are answered from the module's response.
+    pub byte_ranges: bool,
+    pub build: Option<BuildSettings>,
+    pub timeout: Option<Duration>,
+    pub stderr: StderrDestination,
+    /// If set, request bodies must be JSON.
+    pub json: Option<JsonRequestSettings>,
+    /// If set, the media types request bodies may be sent as.
+    pub accept_content_types: Option<AcceptedContentTypes>,
+    /// The experiment variant this module serves on its route, if any.
+    pub experiment: Option<ExperimentVariant>,
+    /// If set, requests are recorded in the audit log.
+    pub audit: Option<AuditSettings>,
+    /// If set, the CAs the module's outbound HTTPS requests trust.
+    pub outbound_tls: Option<OutboundTls>,
+    /// If set, the local address the module's outbound HTTP requests are sent from.
+    pub outbound_source_ip: Option<IpAddr>,
+    /// The bindle the module comes from, if any.
+    pub bindle: Option<Arc<BindleInfo>>,
+}
+
+/// How to rebuild a module from source in watch mode.
+#[derive(Clone, Debug)]
+pub struct BuildSettings {
+    pub command: String,
+    pub working_dir: PathBuf,
+    pub watch: Vec<PathBuf>,
+}
+
+/// A module that runs on a schedule rather than in response to requests.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub name: String,
+    pub module: String,
+    pub schedule: Schedule,
+    pub entrypoint: Option<String>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub http_max_concurrency: Option<u32>,
+    pub volume_mounts: HashMap<String, String>,
+    pub timeout: Option<Duration>,
+    pub stderr: StderrDestination,
 }
 
 pub struct WasmHandlerConfiguration {
     pub entries: Vec<WasmHandlerConfigurationEntry>,
+    pub tasks: Vec<WasmTaskConfigurationEntry>,
 }
 
 pub struct WasmHandlerConfigurationEntry {
     pub info: HandlerInfo,
     pub module: WasmModuleSource,
 }
+
+#[derive(Clone)]
+pub struct WasmTaskConfigurationEntry {
+    pub info: TaskInfo,
+    pub module: WasmModuleSource,
+}
@@ -1,62 +1,82 @@
-use std::{path::Path, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Context;
 // TODO: move OCI-specific stuff out to a helper file
 use oci_distribution::client::{Client, ClientConfig};
-use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
-use docker_credential::DockerCredential;
-use sha2::{Digest, Sha256};
 use url::Url;
 
 use crate::wagi_config::WagiConfiguration;
 
+use super::cache::{hashed_key, Cache};
 use super::loader::ModuleMapConfigurationEntry;
+use super::registry_auth::RegistryCredentials;
+use super::s3;
+use super::scheme_resolver::ResolveContext;
 
 pub async fn load_from_module_map_entry(module_map_entry: &ModuleMapConfigurationEntry, configuration: &WagiConfiguration) -> anyhow::Result<Vec<u8>> {
-    let module_ref = module_map_entry.module.clone();
-    match url::Url::parse(&module_ref) {
+    load_module(&module_map_entry.module, module_map_entry.bindle_server.as_deref(), configuration).await
+}
+
+pub async fn load_module(module_ref: &str, bindle_server: Option<&str>, configuration: &WagiConfiguration) -> anyhow::Result<Vec<u8>> {
+    match url::Url::parse(module_ref) {
         Err(e) => {
             tracing::debug!(
                 error = %e,
                 "Error parsing module URI. Assuming this is a local file"
             );
-            let bytes = tokio::fs::read(&module_ref).await
+            let bytes = tokio::fs::read(module_ref).await
                 .with_context(|| format!("Error reading file '{}' referenced by module config", module_ref))?;
             Ok(bytes)
         },
-        Ok(uri) => match uri.scheme() {
-            "file" => match uri.to_file_path() {
-                Ok(p) => Ok(tokio::fs::read(&p).await
-                    .with_context(|| format!("Error reading file '{}' referenced by module file: URI", p.display()))?),
-                Err(e) => Err(anyhow::anyhow!("Cannot get path to file {}: {:#?}", module_ref, e)),
-            }
-            "bindle" => {
-                // TODO: should we allow --bindle-server so modules.toml can resolve?  This is deprecated so not keen
-                let bindle_server = module_map_entry.bindle_server.as_ref().ok_or_else(|| anyhow::anyhow!("No Bindle server specified for module {}", module_ref))?;
-                load_bindle(bindle_server, &uri, &configuration.asset_cache_dir).await
-            },
+        Ok(uri) => {
             // "parcel" => self.load_parcel(&uri, store.engine(), cache).await,  // TODO: this is not mentioned in the spec...?
-            "oci" => load_from_oci(&uri, &configuration.asset_cache_dir).await,
-            s => Err(anyhow::anyhow!("Unknown scheme {} in module reference {}", s, module_ref)),
+            let resolver = configuration.scheme_resolvers.find(uri.scheme()).ok_or_else(|| anyhow::anyhow!(
+                "Unknown scheme {} in module reference {}: expected file, bindle, oci, s3, git+https or a scheme registered by the program embedding WAGI",
+                uri.scheme(),
+                module_ref
+            ))?;
+            let cache = configuration.module_cache();
+            let context = ResolveContext { bindle_server, cache: &cache, configuration };
+            if resolver.retries() {
+                configuration.fetch_retry.fetch(module_ref, || resolver.resolve(&uri, &context)).await
+            } else {
+                resolver.resolve(&uri, &context).await
+            }
         }
     }
 }
 
+/// Replaces any `s3://` volume sources with local directories containing the
+/// prefetched objects. Other volume sources are returned unchanged.
+pub async fn prefetch_volumes(volumes: &HashMap<String, String>, configuration: &WagiConfiguration) -> anyhow::Result<HashMap<String, String>> {
+    let mut prefetched = HashMap::new();
+    for (guest, host) in volumes {
+        let host = if s3::is_s3_reference(host) {
+            let uri = Url::parse(host)?;
+            let local_dir = s3::prefetch_assets(&uri, &configuration.module_cache()).await
+                .with_context(|| format!("Error prefetching assets for volume {} from {}", guest, host))?;
+            local_dir.display().to_string()
+        } else {
+            host.clone()
+        };
+        prefetched.insert(guest.clone(), host);
+    }
+    Ok(prefetched)
+}
+
 const WASM_LAYER_CONTENT_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm";
 
-#[tracing::instrument(level = "info", skip(cache))]
-async fn load_from_oci(
+#[tracing::instrument(level = "info", skip(cache, credentials))]
+pub(super) async fn load_from_oci(
     uri: &url::Url,
-    cache: impl AsRef<Path>,
+    cache: &Cache,
+    credentials: &RegistryCredentials,
 ) -> anyhow::Result<Vec<u8>> {
-    let cache_file_name = hash_name(uri);
-    let cache_file_path = cache.as_ref().join(cache_file_name);
+    let cache_key = hashed_key(uri.as_str());
 
-    if cache_file_path.is_file() {
-        if let Ok(bytes) = tokio::fs::read(&cache_file_path).await {
-            return Ok(bytes);
-        }
+    if let Ok(Some(bytes)) = cache.get(&cache_key).await {
+        return Ok(bytes);
     }
 
     let config = ClientConfig {
@@ -67,12 +87,6 @@ async fn load_from_oci(
     };
     let mut oc = Client::new(config);
 
-    let mut auth = RegistryAuth::Anonymous;
-
-    if let Ok(DockerCredential::UsernamePassword(user_name, password)) = docker_credential::get_credential(uri.as_str()) {
-        auth = RegistryAuth::Basic(user_name, password);
-    };
-
     let img = url_to_oci(uri).map_err(|e| {
         tracing::error!(
             error = %e,
@@ -81,6 +95,7 @@ async fn load_from_oci(
         e
     })
         .with_context(|| format!("Could not convert URI '{}' to OCI reference", uri))?;
+    let auth = credentials.auth_for(img.registry());
     let data = oc
         .pull(&img, &auth, vec![WASM_LAYER_CONTENT_TYPE])
         .await
@@ -98,7 +113,7 @@ async fn load_from_oci(
 
     // If a cache write fails, log it but continue on.
     tracing::trace!("writing layer to module cache");
-    if let Err(e) = safely_write(&cache_file_path, &bytes).await
+    if let Err(e) = cache.put(&cache_key, &bytes).await
     {
         tracing::warn!(error = %e, "failed to write module to cache");
     }
@@ -124,18 +139,15 @@ fn url_to_oci(uri: &Url) -> anyhow::Result<Reference> {
 /// TODO: this currently fetches the first application/wasm condition-less parcel from the bindle and tries
 /// to load it.
 #[tracing::instrument(level = "info", skip(cache))]
-async fn load_bindle(
+pub(super) async fn load_bindle(
     server: &str,
     uri: &url::Url,
-    cache: impl AsRef<Path>,
+    cache: &Cache,
 ) -> anyhow::Result<Vec<u8>> {
-    let cache_file_name = hash_name(uri);
-    let cache_file_path = cache.as_ref().join(cache_file_name);
+    let cache_key = hashed_key(uri.as_str());
 
-    if cache_file_path.is_file() {
-        if let Ok(bytes) = tokio::fs::read(&cache_file_path).await {
-            return Ok(bytes);
-        }
+    if let Ok(Some(bytes)) = cache.get(&cache_key).await {
+        return Ok(bytes);
     }
 
     let bindle_name = uri.path();
@@ -211,36 +223,13 @@ async fn load_bindle(
         })?;
 
     tracing::trace!("Writing module parcel to cache");
-    if let Err(e) = safely_write(&cache_file_path, &bytes).await {
+    if let Err(e) = cache.put(&cache_key, &bytes).await {
         tracing::warn!(error = %e, "Failed to cache bindle")
     }
 
     Ok(bytes)
 }
 
-fn hash_name(url: &Url) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(&url.as_str());
-    let result = hasher.finalize();
-    format!("{:x}", result)
-}
-
-// TODO: this is copied from `emplacer`*.  As emplacer is effectively a cache manager,
-// we should look at combining this module with that (in whatever suitable way).
-// Leaving this for now, though, until we figure out what we are deprecating (and
-// so this refactor doesn't go on forever).
-//
-// *Except I changed it to take an &Vec instead of a Vec but I am sure our mighty
-// brains can reconcile that if and when the moment comes.
-async fn safely_write(path: impl AsRef<Path>, content: &[u8]) -> std::io::Result<()> {
-    let path = path.as_ref();
-    let dir = path.parent().ok_or_else(||
-        std::io::Error::new(std::io::ErrorKind::Other, format!("cache location {} has no parent directory", path.display()))
-    )?;
-    tokio::fs::create_dir_all(dir).await?;
-    tokio::fs::write(path, content).await
-}
-
 pub struct Loaded<T> {
     pub metadata: T,
     pub content: Arc<Vec<u8>>,
@@ -0,0 +1,96 @@
+//! Compiled modules kept across restarts.
+//!
+//! With `--compiled-module-cache`, each module WAGI compiles at startup is also
+//! written to `<dir>/<sha256 of the module>.cwasm`. When WAGI next starts with a
+//! module whose digest has a file there, it loads the compiled code from the file
+//! instead of compiling the module again, which makes restarts of servers with many
+//! or large modules much quicker.
+//!
+//! A file compiled by a different version of WAGI or Wasmtime, or with different
+//! engine settings, can't be loaded. The module is then compiled as usual and the
+//! file replaced.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use wasmtime::{Engine, Module};
+
+pub struct PrecompiledModules {
+    dir: PathBuf,
+}
+
+impl PrecompiledModules {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Loads the module's compiled code from the directory if it is there and
+    /// usable, or compiles the module and saves the compiled code for next time.
+    pub fn load_or_compile(&self, engine: &Engine, module_bytes: &[u8]) -> anyhow::Result<Module> {
+        let path = self.dir.join(format!("{:x}.cwasm", Sha256::digest(module_bytes)));
+        match std::fs::read(&path) {
+            // SAFETY: deserializing runs the file's machine code, so the directory must
+            // only be writable by WAGI, as the documentation of the option says.
+            Ok(compiled) => match unsafe { Module::deserialize(engine, &compiled) } {
+                Ok(module) => {
+                    tracing::debug!(path = %path.display(), "Loaded precompiled module");
+                    return Ok(module);
+                }
+                Err(e) => tracing::info!(path = %path.display(), error = %e, "Precompiled module can't be used; compiling the module again"),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "Error reading precompiled module; compiling the module again"),
+        }
+
+        let module = Module::new(engine, module_bytes)?;
+        // The module is still usable if it can't be saved
+        if let Err(e) = self.save(&path, &module) {
+            tracing::warn!(path = %path.display(), error = %format!("{:#}", e), "Could not save precompiled module");
+        }
+        Ok(module)
+    }
+
+    // Written to a temporary file and renamed into place, so that another WAGI
+    // starting at the same time never reads a partly written file
+    fn save(&self, path: &Path, module: &Module) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&module.serialize()?)?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WAT: &[u8] = b"(module (func (export \"_start\")))";
+
+    #[test]
+    fn compiled_modules_are_saved_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::default();
+        let precompiled = PrecompiledModules::new(dir.path());
+
+        precompiled.load_or_compile(&engine, WAT).unwrap();
+        let saved = dir.path().join(format!("{:x}.cwasm", Sha256::digest(WAT)));
+        let modified = std::fs::metadata(&saved).unwrap().modified().unwrap();
+
+        let module = precompiled.load_or_compile(&engine, WAT).unwrap();
+        assert!(module.get_export("_start").is_some());
+        assert_eq!(modified, std::fs::metadata(&saved).unwrap().modified().unwrap(), "the saved module should have been loaded, not replaced");
+    }
+
+    #[test]
+    fn unusable_files_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::default();
+        let saved = dir.path().join(format!("{:x}.cwasm", Sha256::digest(WAT)));
+        std::fs::write(&saved, b"not a compiled module").unwrap();
+
+        PrecompiledModules::new(dir.path()).load_or_compile(&engine, WAT).unwrap();
+        assert_ne!(b"not a compiled module".to_vec(), std::fs::read(&saved).unwrap());
+    }
+}
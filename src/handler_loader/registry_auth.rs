@@ -0,0 +1,178 @@
+//! Credentials for pulling modules from OCI registries.
+//!
+//! Credentials can be listed per registry in a TOML file passed with
+//! `--registry-credentials`:
+//!
+//! ```toml
+//! [[registry]]
+//! host = "ghcr.io"
+//! username = "octocat"
+//! password = "${GHCR_PASSWORD}"
+//!
+//! [[registry]]
+//! host = "myregistry.azurecr.io"
+//! token = "${ACR_TOKEN}"
+//! ```
+//!
+//! `${NAME}` is replaced with the value of the NAME environment variable, so that
+//! secrets need not be written into the file. A registry that is not listed falls
+//! back to the ambient Docker credentials (the Docker config file and its credential
+//! helpers) unless `--no-ambient-registry-credentials` is given, in which case it
+//! is pulled from anonymously.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Context;
+use docker_credential::DockerCredential;
+use oci_distribution::secrets::RegistryAuth;
+
+use super::loader::expand_env_vars;
+
+// The user name Docker sends with identity tokens
+const TOKEN_USER_NAME: &str = "<token>";
+
+// Not Debug, so that secrets don't end up in logs
+#[derive(Clone)]
+pub struct RegistryCredentials {
+    configured: HashMap<String, Credential>,
+    allow_ambient: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Credential {
+    Basic(String, String),
+    Token(Option<String>, String),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    #[serde(default)]
+    registry: Vec<CredentialsFileEntry>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFileEntry {
+    host: String,
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+}
+
+impl RegistryCredentials {
+    /// Loads the credentials file, if one is given.
+    pub fn load(file: Option<&Path>, allow_ambient: bool) -> anyhow::Result<Self> {
+        let configured = match file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Error reading registry credentials file {}", path.display()))?;
+                parse_credentials(&text, |name| std::env::var(name).ok())
+                    .with_context(|| format!("Error parsing registry credentials file {}", path.display()))?
+            }
+            None => HashMap::new(),
+        };
+        Ok(Self { configured, allow_ambient })
+    }
+
+    /// The authentication to pull from the given registry host (and port, if any).
+    pub fn auth_for(&self, registry: &str) -> RegistryAuth {
+        if let Some(credential) = self.configured.get(registry) {
+            tracing::debug!(%registry, "Using configured registry credentials");
+            return credential.to_auth();
+        }
+        if self.allow_ambient {
+            match docker_credential::get_credential(registry) {
+                Ok(DockerCredential::UsernamePassword(user_name, password)) => {
+                    tracing::debug!(%registry, "Using Docker registry credentials");
+                    return RegistryAuth::Basic(user_name, password);
+                }
+                Ok(DockerCredential::IdentityToken(token)) => {
+                    tracing::debug!(%registry, "Using Docker registry identity token");
+                    return RegistryAuth::Basic(TOKEN_USER_NAME.to_owned(), token);
+                }
+                Err(e) => tracing::trace!(%registry, error = %e, "No Docker registry credentials"),
+            }
+        }
+        RegistryAuth::Anonymous
+    }
+}
+
+impl Credential {
+    fn to_auth(&self) -> RegistryAuth {
+        // The registry client only speaks basic authentication, which registries
+        // also accept for tokens
+        match self {
+            Self::Basic(user_name, password) => RegistryAuth::Basic(user_name.clone(), password.clone()),
+            Self::Token(user_name, token) => RegistryAuth::Basic(
+                user_name.clone().unwrap_or_else(|| TOKEN_USER_NAME.to_owned()),
+                token.clone(),
+            ),
+        }
+    }
+}
+
+fn parse_credentials(text: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<HashMap<String, Credential>> {
+    let file: CredentialsFile = toml::from_str(text)?;
+    let mut credentials = HashMap::new();
+    for entry in file.registry {
+        let expand = |value: Option<String>| value.map(|v| expand_env_vars(&v, &lookup)).transpose();
+        let username = expand(entry.username)?;
+        let credential = match (expand(entry.password)?, expand(entry.token)?) {
+            (Some(password), None) => Credential::Basic(
+                username.ok_or_else(|| anyhow::anyhow!("Registry {} has a password but no username", entry.host))?,
+                password,
+            ),
+            (None, Some(token)) => Credential::Token(username, token),
+            (Some(_), Some(_)) => anyhow::bail!("Registry {} has both a password and a token", entry.host),
+            (None, None) => anyhow::bail!("Registry {} needs a password or a token", entry.host),
+        };
+        if credentials.insert(entry.host.clone(), credential).is_some() {
+            anyhow::bail!("Registry {} is listed more than once", entry.host);
+        }
+    }
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "GHCR_PASSWORD" => Some("hunter2".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn credentials_are_read_per_registry() {
+        let text = r#"
+            [[registry]]
+            host = "ghcr.io"
+            username = "octocat"
+            password = "${GHCR_PASSWORD}"
+
+            [[registry]]
+            host = "localhost:5000"
+            token = "abc123"
+        "#;
+        let credentials = parse_credentials(text, lookup).unwrap();
+        assert_eq!(Some(&Credential::Basic("octocat".to_owned(), "hunter2".to_owned())), credentials.get("ghcr.io"));
+        assert_eq!(Some(&Credential::Token(None, "abc123".to_owned())), credentials.get("localhost:5000"));
+
+        let credentials = RegistryCredentials { configured: credentials, allow_ambient: false };
+        assert!(matches!(credentials.auth_for("localhost:5000"), RegistryAuth::Basic(u, t) if u == "<token>" && t == "abc123"));
+        assert!(matches!(credentials.auth_for("docker.io"), RegistryAuth::Anonymous));
+    }
+
+    #[test]
+    fn incomplete_or_ambiguous_entries_are_rejected() {
+        parse_credentials("[[registry]]\nhost = \"ghcr.io\"\npassword = \"p\"", lookup).expect_err("password needs a username");
+        parse_credentials("[[registry]]\nhost = \"ghcr.io\"\nusername = \"u\"", lookup).expect_err("needs a password or token");
+        parse_credentials("[[registry]]\nhost = \"ghcr.io\"\npassword = \"p\"\ntoken = \"t\"\nusername = \"u\"", lookup).expect_err("cannot have both");
+        parse_credentials("[[registry]]\nhost = \"ghcr.io\"\ntoken = \"${NOT_SET}\"", lookup).expect_err("variable must be set");
+        parse_credentials("[[registry]]\nhost = \"ghcr.io\"\ntoken = \"a\"\n[[registry]]\nhost = \"ghcr.io\"\ntoken = \"b\"", lookup).expect_err("duplicate host");
+    }
+}
@@ -0,0 +1,165 @@
+//! Fetching modules and assets from S3 (or S3-compatible) object storage.
+//!
+//! References have the form `s3://bucket/key`. Credentials and region are resolved
+//! the standard AWS way (environment, shared config and credentials files, instance
+//! metadata, etc.). Fetched objects are kept in the module cache, so, as with OCI
+//! layers, each object is only downloaded once.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use futures::StreamExt;
+use url::Url;
+
+use super::cache::{hashed_key, Cache};
+
+pub const S3_SCHEME: &str = "s3";
+
+struct S3Location {
+    bucket: String,
+    key: String,
+}
+
+impl S3Location {
+    fn parse(uri: &Url) -> anyhow::Result<Self> {
+        let bucket = uri.host_str()
+            .filter(|b| !b.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("S3 reference '{}' has no bucket name", uri))?;
+        let key = uri.path().trim_start_matches('/');
+        Ok(Self {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+async fn client() -> aws_sdk_s3::Client {
+    let config = aws_config::load_from_env().await;
+    aws_sdk_s3::Client::new(&config)
+}
+
+/// Loads the module object identified by an `s3://bucket/key` reference.
+#[tracing::instrument(level = "info", skip(cache))]
+pub async fn load_from_s3(uri: &Url, cache: &Cache) -> anyhow::Result<Vec<u8>> {
+    let cache_key = hashed_key(uri.as_str());
+
+    if let Ok(Some(bytes)) = cache.get(&cache_key).await {
+        return Ok(bytes);
+    }
+
+    let location = S3Location::parse(uri)?;
+    if location.key.is_empty() {
+        anyhow::bail!("S3 module reference '{}' has no object key", uri);
+    }
+
+    let bytes = get_object(&client().await, &location.bucket, &location.key).await?;
+
+    // If a cache write fails, log it but continue on.
+    tracing::trace!("writing S3 object to module cache");
+    if let Err(e) = cache.put(&cache_key, &bytes).await {
+        tracing::warn!(error = %e, "failed to write module to cache");
+    }
+
+    Ok(bytes)
+}
+
+/// Downloads every object under an `s3://bucket/prefix` reference into the cache,
+/// and returns the local directory containing them, for use as a volume mount.
+/// Objects already in the cache are not downloaded again.
+#[tracing::instrument(level = "info", skip(cache))]
+pub async fn prefetch_assets(uri: &Url, cache: &Cache) -> anyhow::Result<PathBuf> {
+    let location = S3Location::parse(uri)?;
+    let dir_key = format!("_ASSETS/{}", hashed_key(uri.as_str()));
+    let local_dir = cache.local_path(&dir_key)
+        .ok_or_else(|| anyhow::anyhow!("Assets from {} cannot be mounted because the cache is not on local disk", uri))?;
+
+    let prefix = match location.key.as_str() {
+        "" => String::new(),
+        k => format!("{}/", k.trim_end_matches('/')),
+    };
+
+    let client = client().await;
+    let mut pages = client.list_objects_v2()
+        .bucket(&location.bucket)
+        .prefix(&prefix)
+        .into_paginator()
+        .send();
+
+    while let Some(page) = pages.next().await {
+        let page = page.with_context(|| format!("Error listing objects under {}", uri))?;
+        for object_key in page.contents().unwrap_or_default().iter().filter_map(|o| o.key()) {
+            let relative_path = match asset_relative_path(object_key, &prefix) {
+                Some(p) => p,
+                None => {
+                    tracing::warn!(key = object_key, "Skipping S3 object whose key is not a valid relative path");
+                    continue;
+                }
+            };
+            let asset_key = format!("{}/{}", dir_key, relative_path);
+            if cache.contains(&asset_key).await {
+                continue;
+            }
+            let bytes = get_object(&client, &location.bucket, object_key).await?;
+            cache.put(&asset_key, &bytes).await
+                .with_context(|| format!("Error caching S3 object {}", object_key))?;
+        }
+    }
+
+    tokio::fs::create_dir_all(&local_dir).await
+        .with_context(|| format!("Can't create asset directory {}", local_dir.display()))?;
+    Ok(local_dir)
+}
+
+async fn get_object(client: &aws_sdk_s3::Client, bucket: &str, key: &str) -> anyhow::Result<Vec<u8>> {
+    let response = client.get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .with_context(|| format!("Failed to get S3 object {} from bucket {}", key, bucket))?;
+    let data = response.body.collect().await
+        .with_context(|| format!("Failed to read S3 object {} from bucket {}", key, bucket))?;
+    Ok(data.into_bytes().to_vec())
+}
+
+// Object keys become paths on disk, so anything that could escape the asset
+// directory is rejected. "Directory" placeholder objects are skipped too.
+fn asset_relative_path<'a>(object_key: &'a str, prefix: &str) -> Option<&'a str> {
+    let relative = object_key.strip_prefix(prefix)?;
+    let is_safe = !relative.is_empty()
+        && !relative.starts_with('/')
+        && !relative.ends_with('/')
+        && relative.split('/').all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    if is_safe {
+        Some(relative)
+    } else {
+        None
+    }
+}
+
+pub fn is_s3_reference(text: &str) -> bool {
+    Url::parse(text).map(|u| u.scheme() == S3_SCHEME).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn s3_locations_are_parsed() {
+        let location = S3Location::parse(&Url::parse("s3://my-bucket/builds/app.wasm").unwrap()).unwrap();
+        assert_eq!("my-bucket", location.bucket);
+        assert_eq!("builds/app.wasm", location.key);
+
+        assert!(S3Location::parse(&Url::parse("s3:///app.wasm").unwrap()).is_err());
+    }
+
+    #[test]
+    fn asset_paths_cannot_escape_the_asset_directory() {
+        assert_eq!(Some("images/toast.png"), asset_relative_path("site/images/toast.png", "site/"));
+        assert_eq!(None, asset_relative_path("site/../etc/passwd", "site/"));
+        assert_eq!(None, asset_relative_path("site/images/", "site/"));
+        assert_eq!(None, asset_relative_path("other/toast.png", "site/"));
+        assert_eq!(None, asset_relative_path("site//toast.png", "site/"));
+    }
+}
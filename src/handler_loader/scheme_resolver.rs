@@ -0,0 +1,221 @@
+//! Fetching modules by the scheme of their reference.
+//!
+//! Each scheme a module reference can have (`file:`, `bindle:`, `oci:`, `s3:` and
+//! `git+...:`) is handled by a `SchemeResolver`, which turns the reference into the
+//! module's bytes. A program that embeds WAGI can register resolvers of its own, for
+//! example to load modules from a secret store or from IPFS:
+//!
+//! ```ignore
+//! struct VaultResolver { /* ... */ }
+//!
+//! #[async_trait::async_trait]
+//! impl SchemeResolver for VaultResolver {
+//!     fn handles(&self, scheme: &str) -> bool {
+//!         scheme == "vault"
+//!     }
+//!
+//!     async fn resolve(&self, uri: &Url, context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+//!         self.read_secret(uri.path()).await
+//!     }
+//! }
+//!
+//! let configuration = WagiConfiguration::new(handlers)?
+//!     .with_scheme_resolver(VaultResolver::new());
+//! ```
+//!
+//! Registered resolvers are asked before the built-in ones, so a program can also
+//! replace how a built-in scheme is fetched. References that aren't URLs are read
+//! as local files, whatever resolvers are registered.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use url::Url;
+
+use crate::wagi_config::WagiConfiguration;
+
+use super::cache::Cache;
+use super::{git, module_loader, s3};
+
+/// Fetches modules whose references have the schemes it handles.
+#[async_trait::async_trait]
+pub trait SchemeResolver: Send + Sync {
+    /// Whether references with this scheme are fetched by this resolver.
+    fn handles(&self, scheme: &str) -> bool;
+    /// Fetches the module the reference names.
+    async fn resolve(&self, uri: &Url, context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>>;
+    /// Whether failed fetches are tried again under the `--fetch-attempts` policy.
+    /// Resolvers for local sources, where trying again won't help, can turn it off.
+    fn retries(&self) -> bool {
+        true
+    }
+}
+
+/// What a resolver is given along with the reference.
+pub struct ResolveContext<'a> {
+    /// The Bindle server given for the module in `modules.toml`, if any.
+    pub bindle_server: Option<&'a str>,
+    /// Where fetched modules are kept.
+    pub cache: &'a Cache,
+    pub configuration: &'a WagiConfiguration,
+}
+
+/// The resolvers for module references, in the order they are asked.
+#[derive(Clone)]
+pub struct SchemeResolvers {
+    resolvers: Vec<Arc<dyn SchemeResolver>>,
+}
+
+impl Default for SchemeResolvers {
+    fn default() -> Self {
+        Self {
+            resolvers: vec![
+                Arc::new(FileResolver),
+                Arc::new(BindleResolver),
+                Arc::new(OciResolver),
+                Arc::new(S3Resolver),
+                Arc::new(GitResolver),
+            ],
+        }
+    }
+}
+
+impl std::fmt::Debug for SchemeResolvers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SchemeResolvers({})", self.resolvers.len())
+    }
+}
+
+impl SchemeResolvers {
+    /// Adds a resolver, which is asked before the ones already registered.
+    pub fn add(&mut self, resolver: impl SchemeResolver + 'static) {
+        self.resolvers.insert(0, Arc::new(resolver));
+    }
+
+    pub fn find(&self, scheme: &str) -> Option<&Arc<dyn SchemeResolver>> {
+        self.resolvers.iter().find(|r| r.handles(scheme))
+    }
+}
+
+struct FileResolver;
+
+#[async_trait::async_trait]
+impl SchemeResolver for FileResolver {
+    fn handles(&self, scheme: &str) -> bool {
+        scheme == "file"
+    }
+
+    async fn resolve(&self, uri: &Url, _context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+        match uri.to_file_path() {
+            Ok(p) => Ok(tokio::fs::read(&p).await
+                .with_context(|| format!("Error reading file '{}' referenced by module file: URI", p.display()))?),
+            Err(e) => Err(anyhow::anyhow!("Cannot get path to file {}: {:#?}", uri, e)),
+        }
+    }
+
+    fn retries(&self) -> bool {
+        false
+    }
+}
+
+struct BindleResolver;
+
+#[async_trait::async_trait]
+impl SchemeResolver for BindleResolver {
+    fn handles(&self, scheme: &str) -> bool {
+        scheme == "bindle"
+    }
+
+    async fn resolve(&self, uri: &Url, context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+        // TODO: should we allow --bindle-server so modules.toml can resolve?  This is deprecated so not keen
+        let bindle_server = context.bindle_server.ok_or_else(|| anyhow::anyhow!("No Bindle server specified for module {}", uri))?;
+        module_loader::load_bindle(bindle_server, uri, context.cache).await
+    }
+}
+
+struct OciResolver;
+
+#[async_trait::async_trait]
+impl SchemeResolver for OciResolver {
+    fn handles(&self, scheme: &str) -> bool {
+        scheme == "oci"
+    }
+
+    async fn resolve(&self, uri: &Url, context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+        module_loader::load_from_oci(uri, context.cache, &context.configuration.registry_credentials).await
+    }
+}
+
+struct S3Resolver;
+
+#[async_trait::async_trait]
+impl SchemeResolver for S3Resolver {
+    fn handles(&self, scheme: &str) -> bool {
+        scheme == s3::S3_SCHEME
+    }
+
+    async fn resolve(&self, uri: &Url, context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+        s3::load_from_s3(uri, context.cache).await
+    }
+}
+
+struct GitResolver;
+
+#[async_trait::async_trait]
+impl SchemeResolver for GitResolver {
+    fn handles(&self, scheme: &str) -> bool {
+        git::is_git_scheme(scheme)
+    }
+
+    async fn resolve(&self, uri: &Url, context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+        git::load_from_git(uri, context.cache).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Constant(&'static str, &'static [u8]);
+
+    #[async_trait::async_trait]
+    impl SchemeResolver for Constant {
+        fn handles(&self, scheme: &str) -> bool {
+            scheme == self.0
+        }
+
+        async fn resolve(&self, _uri: &Url, _context: &ResolveContext<'_>) -> anyhow::Result<Vec<u8>> {
+            Ok(self.1.to_vec())
+        }
+    }
+
+    #[test]
+    fn built_in_schemes_have_resolvers() {
+        let resolvers = SchemeResolvers::default();
+        for scheme in ["file", "bindle", "oci", "s3", "git+https"] {
+            assert!(resolvers.find(scheme).is_some(), "{}", scheme);
+        }
+        assert!(resolvers.find("vault").is_none());
+        assert!(!resolvers.find("file").unwrap().retries());
+    }
+
+    #[tokio::test]
+    async fn registered_resolvers_come_before_built_in_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let configuration = WagiConfiguration::builder()
+            .in_memory_modules(vec![])
+            .log_dir(dir.path().join("logs"))
+            .asset_cache_dir(dir.path().join("cache"))
+            .build()
+            .unwrap();
+        let mut resolvers = SchemeResolvers::default();
+        resolvers.add(Constant("vault", b"from vault"));
+        resolvers.add(Constant("oci", b"from elsewhere"));
+
+        let context = ResolveContext { bindle_server: None, cache: &configuration.module_cache(), configuration: &configuration };
+        let uri = Url::parse("oci:example/foo:1.2.3").unwrap();
+        let bytes = resolvers.find("oci").unwrap().resolve(&uri, &context).await.unwrap();
+        assert_eq!(b"from elsewhere".to_vec(), bytes);
+        assert!(resolvers.find("vault").is_some());
+    }
+}
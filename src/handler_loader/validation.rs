@@ -0,0 +1,294 @@
+//! Checks a `modules.toml` for keys that WAGI does not understand.
+//!
+//! Serde ignores unknown keys, so a misspelled setting (say, `allowed_host` for
+//! `allowed_hosts`) would otherwise be silently dropped. Type errors are already
+//! reported, with their position, when the file is deserialised.
+
+// These must list every field of the corresponding structs in loader.rs.
+const TOP_LEVEL_KEYS: &[&str] = &["module", "task", "include", "group"];
+const MODULE_KEYS: &[&str] = &[
+    "route",
+    "module",
+    "repository",
+    "entrypoint",
+    "bindle_server",
+    "volumes",
+    "allowed_hosts",
+    "http_max_concurrency",
+    "args_mode",
+    "argv",
+    "argv_from_query",
+    "preinstantiate",
+    "max_instances",
+    "dedicated_threads",
+    "cpus",
+    "enabled",
+    "allow_from",
+    "deny_from",
+    "default_content_type",
+    "default_charset",
+    "decode_path_info",
+    "query_env_vars",
+    "byte_ranges",
+    "json",
+    "json_fields",
+    "accept_content_types",
+    "volume_overlay",
+    "experiment",
+    "variant",
+    "variant_weight",
+    "audit",
+    "audit_body_bytes",
+    "ca_certificates",
+    "ca_certificates_only",
+    "outbound_source_ip",
+    "trusted",
+    "fallback",
+    "build_command",
+    "build_dir",
+    "watch",
+    "timeout",
+    "stderr",
+    // Not a field: the settings of the named group are filled in before deserialising
+    "group",
+];
+const TASK_KEYS: &[&str] = &[
+    "name",
+    "schedule",
+    "module",
+    "entrypoint",
+    "bindle_server",
+    "volumes",
+    "allowed_hosts",
+    "http_max_concurrency",
+    "timeout",
+    "stderr",
+];
+const INCLUDE_KEYS: &[&str] = &["file", "route"];
+
+// Module keys that only make sense on the module itself, not in a group
+const UNGROUPED_MODULE_KEYS: &[&str] = &["route", "module", "group"];
+
+// A group has a name, and any module setting its members can share
+fn group_keys() -> Vec<&'static str> {
+    std::iter::once("name")
+        .chain(MODULE_KEYS.iter().copied().filter(|k| !UNGROUPED_MODULE_KEYS.contains(k)))
+        .collect()
+}
+
+/// Returns an error listing every unknown key, with its position and a suggestion
+/// if it looks like a misspelling. TOML syntax errors are left for the deserialiser
+/// to report.
+pub fn check_for_unknown_keys(text: &str) -> anyhow::Result<()> {
+    let document: toml::Value = match toml::from_str(text) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+    let table = match document.as_table() {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let mut problems = vec![];
+    for key in table.keys().filter(|k| !TOP_LEVEL_KEYS.contains(&k.as_str())) {
+        let position = position_of_top_level_key(text, key);
+        problems.push(describe_unknown_key(key, "at the top level", TOP_LEVEL_KEYS, position));
+    }
+
+    // Each array-of-tables section, and the keys its entries may have
+    let group_keys = group_keys();
+    let sections: [(&str, &[&str]); 4] = [("module", MODULE_KEYS), ("task", TASK_KEYS), ("include", INCLUDE_KEYS), ("group", &group_keys)];
+    for (section, known_keys) in sections {
+        let entries = table.get(section).and_then(|m| m.as_array()).map(|a| a.as_slice()).unwrap_or_default();
+        for (index, entry) in entries.iter().enumerate() {
+            let keys = match entry.as_table() {
+                Some(t) => t.keys(),
+                None => continue,
+            };
+            for key in keys.filter(|k| !known_keys.contains(&k.as_str())) {
+                let position = position_of_entry_key(text, section, index, key);
+                let location = format!("in {} {}", section, index + 1);
+                problems.push(describe_unknown_key(key, &location, known_keys, position));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Unknown keys in module configuration:\n  {}", problems.join("\n  ")))
+    }
+}
+
+/// Checks the settings file beside a module in a module directory, which has the
+/// keys of a `[[module]]` entry at its top level. Its module is always the `.wasm`
+/// file beside it.
+pub fn check_for_unknown_module_settings(text: &str) -> anyhow::Result<()> {
+    let document: toml::Value = match toml::from_str(text) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+    let table = match document.as_table() {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let known_keys: Vec<&str> = MODULE_KEYS.iter().copied().filter(|k| *k != "module" && *k != "group").collect();
+    let problems: Vec<_> = table
+        .keys()
+        .filter(|k| !known_keys.contains(&k.as_str()))
+        .map(|key| describe_unknown_key(key, "in module settings", &known_keys, position_of_top_level_key(text, key)))
+        .collect();
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Unknown keys in module settings:\n  {}", problems.join("\n  ")))
+    }
+}
+
+fn describe_unknown_key(key: &str, location: &str, known: &[&str], position: Option<(usize, usize)>) -> String {
+    let mut description = format!("unknown key `{}` {}", key, location);
+    if let Some((line, column)) = position {
+        description.push_str(&format!(" at line {} column {}", line, column));
+    }
+    if let Some(suggestion) = closest_match(key, known) {
+        description.push_str(&format!(" (did you mean `{}`?)", suggestion));
+    }
+    description
+}
+
+// Suggests a known key if the unknown one is a small edit away from it.
+fn closest_match<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|k| (*k, edit_distance(key, k)))
+        .filter(|(_, distance)| *distance <= 2)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(k, _)| k)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// The parsed document does not carry positions, so keys are found by scanning the
+// text. This handles the usual `key = value` layout; if a key is written some other
+// way (e.g. in an inline table) it is reported without a position.
+
+fn position_of_top_level_key(text: &str, key: &str) -> Option<(usize, usize)> {
+    let headers = [format!("[[{}]]", key), format!("[{}]", key)];
+    let mut before_first_table = true;
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if headers.iter().any(|h| h == trimmed) {
+            return Some((i + 1, line.len() - line.trim_start().len() + 1));
+        }
+        if trimmed.starts_with('[') {
+            before_first_table = false;
+        } else if before_first_table {
+            if let Some(column) = key_column(line, key) {
+                return Some((i + 1, column));
+            }
+        }
+    }
+    None
+}
+
+fn position_of_entry_key(text: &str, section: &str, entry_index: usize, key: &str) -> Option<(usize, usize)> {
+    let header = format!("[[{}]]", section);
+    let mut entries_seen = 0;
+    let mut in_entry = false;
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_entry = trimmed == header && entries_seen == entry_index;
+            if trimmed == header {
+                entries_seen += 1;
+            }
+            continue;
+        }
+        if in_entry {
+            if let Some(column) = key_column(line, key) {
+                return Some((i + 1, column));
+            }
+        }
+    }
+    None
+}
+
+// The 1-based column of `key` if the line assigns to it.
+fn key_column(line: &str, key: &str) -> Option<usize> {
+    let indent = line.len() - line.trim_start().len();
+    let rest = line.trim_start();
+    let rest = rest
+        .strip_prefix(key)
+        .or_else(|| rest.strip_prefix(&format!("\"{}\"", key)))?;
+    if rest.trim_start().starts_with('=') {
+        Some(indent + 1)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn known_keys_are_accepted() {
+        let text = r#"
+            [[module]]
+            route = "/"
+            module = "examples/hello.wat"
+            allowed_hosts = ["https://example.com"]
+        "#;
+        check_for_unknown_keys(text).expect("All keys should be known");
+    }
+
+    #[test]
+    fn misspelled_keys_are_reported_with_position_and_suggestion() {
+        let text = "[[module]]\nroute = \"/\"\nmodule = \"a.wasm\"\n\n[[module]]\nroute = \"/b\"\nmodule = \"b.wasm\"\n  allowed_host = [\"https://example.com\"]\n";
+        let message = check_for_unknown_keys(text).unwrap_err().to_string();
+        assert!(message.contains("unknown key `allowed_host` in module 2 at line 8 column 3 (did you mean `allowed_hosts`?)"), "{}", message);
+    }
+
+    #[test]
+    fn task_keys_are_checked_separately_from_module_keys() {
+        let text = "[[module]]\nroute = \"/\"\nmodule = \"a.wasm\"\n\n[[task]]\nschedule = \"@daily\"\nmodule = \"cleanup.wasm\"\nroute = \"/cleanup\"\n";
+        let message = check_for_unknown_keys(text).unwrap_err().to_string();
+        assert!(message.contains("unknown key `route` in task 1 at line 8 column 1"), "{}", message);
+    }
+
+    #[test]
+    fn unknown_top_level_keys_are_reported() {
+        let text = "[[modules]]\nroute = \"/\"\nmodule = \"a.wasm\"\n";
+        let message = check_for_unknown_keys(text).unwrap_err().to_string();
+        assert!(message.contains("unknown key `modules` at the top level at line 1 column 1 (did you mean `module`?)"), "{}", message);
+    }
+
+    #[test]
+    fn groups_take_module_settings_but_not_routes() {
+        let text = "[[group]]\nname = \"api\"\nallowed_hosts = [\"https://example.com\"]\nroute = \"/api\"\n\n[[module]]\nroute = \"/\"\nmodule = \"a.wasm\"\ngroup = \"api\"\n";
+        let message = check_for_unknown_keys(text).unwrap_err().to_string();
+        assert!(message.contains("unknown key `route` in group 1 at line 4 column 1"), "{}", message);
+        assert!(!message.contains("allowed_hosts"), "{}", message);
+        assert!(!message.contains("module 1"), "{}", message);
+    }
+
+    #[test]
+    fn unrelated_keys_get_no_suggestion() {
+        assert_eq!(None, closest_match("colour_scheme", MODULE_KEYS));
+        assert_eq!(Some("timeout"), closest_match("timout", MODULE_KEYS));
+    }
+}
@@ -1,27 +1,57 @@
 use std::{collections::HashMap};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use wasi_cap_std_sync::Dir;
+use anyhow::Context;
 use hyper::{
     http::header::{HeaderName, HeaderValue},
     http::request::Parts,
     Body, Response, StatusCode,
 };
-use tracing::{debug};
+use serde::Deserialize;
+use tracing::{debug, Instrument};
 use wasi_cap_std_sync::WasiCtxBuilder;
 use wasmtime::*;
 use wasmtime_wasi::*;
 
+use crate::accept_content_types::AcceptedContentTypes;
+use crate::access_control::IpAccessList;
+use crate::audit::AuditSettings;
+use crate::build_info::{BindleInfo, ModuleInventoryEntry};
+use crate::byte_ranges::RangeOutcome;
+use crate::custom_handler::CustomHandler;
 use crate::dispatcher::RoutePattern;
-use crate::http_util::{internal_error, parse_cgi_headers};
+use crate::error_report::ErrorReport;
+use crate::executor::ModuleExecutor;
+use crate::experiment::ExperimentVariant;
+use crate::http_util::{informational_responses_len, internal_error, parse_cgi_headers, parse_status, HostSettings};
+use crate::instance_limit::InstanceLimit;
+use crate::instance_pool::InstancePool;
+use crate::json_request::JsonRequestSettings;
+use crate::outbound_http::{with_request_context, OutboundRequestContext};
+use crate::outbound_network::OutboundNetwork;
 use crate::request::{RequestContext, RequestGlobalContext};
+use crate::spill::SpilledBody;
+use crate::stderr::StderrDestination;
+use crate::volume_overlay::{OverlayVolumes, VolumeOverlay};
 
 use crate::wasm_module::WasmModuleSource;
-use crate::wasm_runner::{prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance, WasmLinkOptions};
+use crate::wasm_runner::{preopen_volumes, prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance, WasmLinkOptions};
+
+pub(crate) const INSTANTIATION_TIME_METRIC: &str = "wagi_module_instantiation_seconds_total";
+pub(crate) const EXECUTION_TIME_METRIC: &str = "wagi_module_execution_seconds_total";
 
 #[derive(Clone, Debug)]
 pub enum RouteHandler {
     HealthCheck,
+    Metrics,
+    Version(Arc<Vec<ModuleInventoryEntry>>, Option<Arc<BindleInfo>>),
+    Tasks,
+    Routes,
+    Explain,
+    LogLevel,
+    Maintenance,
+    Custom(Arc<dyn CustomHandler>),
     Wasm(WasmRouteHandler),
 }
 
@@ -31,13 +61,171 @@ pub struct WasmRouteHandler {
     pub wasm_module_name: String,
     pub entrypoint: String,
     pub volumes: HashMap<String, String>,
+    /// If set, each request gets its own copy of the volumes.
+    pub volume_overlay: Option<VolumeOverlay>,
     pub allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
+    pub args_mode: ArgsMode,
     pub argv: Option<String>,
+    /// The query parameters passed as `--name=value` arguments in the `query` mode.
+    pub argv_from_query: Option<Vec<String>>,
+    pub instance_pool: Option<Arc<InstancePool>>,
+    /// Caps how many instances of the module may exist at once.
+    pub instance_limit: Option<Arc<InstanceLimit>>,
+    /// Where the module runs.
+    pub executor: ModuleExecutor,
+    pub access_control: IpAccessList,
+    pub content_type_defaults: ContentTypeDefaults,
+    /// How long the module may run before it is abandoned.
+    pub timeout: Option<Duration>,
+    /// If set, the module is exempt from the global timeouts and request header limits.
+    pub trusted: bool,
+    /// Whether the module only gets requests that earlier modules on its route
+    /// answer with 404 or 500.
+    pub fallback: bool,
+    pub stderr: StderrDestination,
+    /// If set, request bodies must be JSON.
+    pub json: Option<JsonRequestSettings>,
+    /// If set, the media types request bodies may be sent as.
+    pub accept_content_types: Option<AcceptedContentTypes>,
+    /// Whether `PATH_INFO` and `PATH_TRANSLATED` are percent-decoded.
+    pub decode_path_info: bool,
+    /// Whether the query is parsed into `QUERY_<NAME>` and `X_QUERY_PARAMS`.
+    pub query_env_vars: bool,
+    /// Whether `Range` requests are answered from the module's response.
+    pub byte_ranges: bool,
+    /// The experiment variant this module serves, if the route has an experiment.
+    pub experiment: Option<ExperimentVariant>,
+    /// If set, requests are recorded in the audit log.
+    pub audit: Option<AuditSettings>,
+    pub outbound_network: OutboundNetwork,
+    /// If set, the module does not get the outbound HTTP functions.
+    pub deny_outbound_http: bool,
+    /// Whether the module gets the environment variables from `--env` and
+    /// `--env-file`. Dynamic routes can give them up with `no-env`.
+    pub global_env_vars: bool,
+    /// The bindle the module comes from, if any, for the `X_BINDLE_*` variables.
+    pub bindle: Option<Arc<BindleInfo>>,
+}
+
+/// The error when a module runs past its timeout.
+#[derive(Debug)]
+pub struct ModuleTimedOut {
+    pub module: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for ModuleTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Module {} did not complete within {:?}", self.module, self.timeout)
+    }
+}
+
+impl std::error::Error for ModuleTimedOut {}
+
+/// The error when a module fails while running, for example by trapping. It
+/// carries the last lines the module wrote to stderr.
+#[derive(Debug)]
+pub struct ModuleFailed {
+    pub error: anyhow::Error,
+    pub stderr_tail: Vec<String>,
+}
+
+impl std::fmt::Display for ModuleFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for ModuleFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// The error when a module's output is not a valid CGI response.
+#[derive(Debug)]
+struct InvalidResponse(&'static str);
+
+impl std::fmt::Display for InvalidResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for InvalidResponse {}
+
+/// How the module's argv is built from the request.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgsMode {
+    /// The script name followed by each `&`-separated query parameter, as CGI 1.1
+    /// describes.
+    #[default]
+    Cgi,
+    /// Only the script name. The module reads the query from `QUERY_STRING`.
+    None,
+    /// Built from the route's `argv` template.
+    Template,
+    /// The script name followed by `--name=value` for each of the route's
+    /// `argv_from_query` parameters in the request, in the order they are listed.
+    Query,
+}
+
+impl std::str::FromStr for ArgsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cgi" => Ok(Self::Cgi),
+            "none" => Ok(Self::None),
+            "template" => Ok(Self::Template),
+            "query" => Ok(Self::Query),
+            _ => Err(anyhow::anyhow!("Unknown args_mode '{}': expected cgi, none, template or query", s)),
+        }
+    }
+}
+
+impl ArgsMode {
+    /// Works out the mode for a route from its settings. Setting an `argv` template
+    /// without a mode implies `template`, as it did before modes existed, and
+    /// setting `argv_from_query` without a mode implies `query`.
+    pub fn resolve(mode: Option<ArgsMode>, argv: &Option<String>, argv_from_query: &Option<Vec<String>>) -> anyhow::Result<ArgsMode> {
+        if argv.is_some() && argv_from_query.is_some() {
+            anyhow::bail!("argv and argv_from_query can't both be set");
+        }
+        if let Some(params) = argv_from_query {
+            if let Some(param) = params.iter().find(|p| p.is_empty() || p.starts_with('-') || p.contains('=')) {
+                anyhow::bail!("Invalid argv_from_query parameter '{}': must not be empty, start with '-' or contain '='", param);
+            }
+        }
+        match (mode, argv, argv_from_query) {
+            (None, Some(_), _) => Ok(Self::Template),
+            (None, None, Some(_)) => Ok(Self::Query),
+            (None, None, None) => Ok(Self::Cgi),
+            (Some(Self::Template), None, _) => Err(anyhow::anyhow!("args_mode 'template' needs an argv template")),
+            (Some(Self::Template), Some(_), _) => Ok(Self::Template),
+            (Some(Self::Query), _, None) => Err(anyhow::anyhow!("args_mode 'query' needs argv_from_query")),
+            (Some(Self::Query), _, Some(_)) => Ok(Self::Query),
+            (Some(mode), Some(_), _) => Err(anyhow::anyhow!("argv is only used with args_mode 'template', not {:?}", mode)),
+            (Some(mode), _, Some(_)) => Err(anyhow::anyhow!("argv_from_query is only used with args_mode 'query', not {:?}", mode)),
+            (Some(mode), None, None) => Ok(mode),
+        }
+    }
+}
+
+/// What to do about the Content-Type of responses that don't fully specify one.
+#[derive(Clone, Debug, Default)]
+pub struct ContentTypeDefaults {
+    /// Used when a module writes a body but no Content-Type. If this is not set,
+    /// such a response is an error, as the CGI spec requires.
+    pub content_type: Option<String>,
+    /// Added to `text/*` content types that don't specify a charset.
+    pub charset: Option<String>,
 }
 
 impl WasmRouteHandler {
-    pub fn handle_request(
+    pub async fn handle_request(
         &self,
         matched_route: &RoutePattern,
         req: &Parts,
@@ -46,101 +234,257 @@ impl WasmRouteHandler {
         global_context: &RequestGlobalContext,
         logging_key: String,
     ) -> Result<Response<Body>, anyhow::Error> {
-        let startup_span = tracing::info_span!("module instantiation").entered();
-        let headers = crate::http_util::build_headers(
+        if let Some(accepted) = &self.accept_content_types {
+            if let Err(rejection) = accepted.check(req, &body) {
+                return Ok(rejection);
+            }
+        }
+        let json_vars = match &self.json {
+            Some(settings) => match settings.env_vars(req, &body) {
+                Ok(vars) => vars,
+                Err(rejection) => return Ok(rejection),
+            },
+            None => HashMap::new(),
+        };
+        let startup_span = tracing::info_span!("module instantiation");
+        let no_env_vars = HashMap::new();
+        let mut headers = crate::http_util::build_headers(
             matched_route,
             req,
             body.len(),
             request_context.client_addr,
-            global_context.default_host.as_str(),
-            global_context.use_tls,
-            &global_context.global_env_vars,
+            &HostSettings {
+                default_host: global_context.default_host.as_str(),
+                use_tls: global_context.use_tls,
+                trusted_proxies: &global_context.trusted_proxies,
+            },
+            self.decode_path_info,
+            if self.global_env_vars { &global_context.global_env_vars } else { &no_env_vars },
         );
+        headers.extend(json_vars);
+        if self.query_env_vars {
+            // A parameter can't replace a built-in variable such as QUERY_STRING
+            for (key, val) in crate::http_util::query_env_vars(req.uri.query().unwrap_or_default()) {
+                headers.entry(key).or_insert(val);
+            }
+        }
+        if let Some(experiment) = &self.experiment {
+            headers.extend(experiment.env_vars());
+        }
+        if let Some(bindle) = &self.bindle {
+            headers.extend(bindle.env_vars());
+        }
 
-        let redirects = prepare_stdio_streams(body, global_context, logging_key)?;
+        let mut sample = global_context.sampler.as_ref()
+            .and_then(|sampler| sampler.start(&matched_route.original_text(), &self.wasm_module_name, req));
+        if let Some(sample) = &mut sample {
+            sample.record_env(&headers, &build_argv(self.args_mode, &self.argv, &self.argv_from_query, req));
+        }
 
-        let ctx = self.build_wasi_context_for_request(req, headers, redirects.streams)?;
+        let redirects = prepare_stdio_streams(body, global_context, logging_key, self.stderr, &self.wasm_module_name, sample.as_ref().map(|s| s.output()), global_context.spill.as_ref())?;
+        if let Some(watch) = &request_context.stdout_watch {
+            // The receiver may have given up already; that's fine.
+            let _ = watch.send(redirects.stdout_mutex.clone());
+        }
 
-        let (store, instance) = self.prepare_wasm_instance(ctx)?;
+        let overlay = match self.volume_overlay {
+            Some(_) => {
+                let volumes = self.volumes.clone();
+                let dir = global_context.overlay_dir.clone();
+                Some(tokio::task::spawn_blocking(move || OverlayVolumes::snapshot(&volumes, &dir)).await?
+                    .context("Could not copy volumes for the request")?)
+            }
+            None => None,
+        };
+        let volumes = overlay.as_ref().map(|o| o.volumes()).unwrap_or(&self.volumes);
+        let ctx = self.build_wasi_context_for_request(req, headers, redirects.streams, volumes)?;
 
-        // Drop manually to get instantiation time
-        drop(startup_span);
+        // Held until the module has finished running and its Store is gone
+        let _instance_permit = match &self.instance_limit {
+            Some(limit) => Some(limit.acquire(&global_context.metrics).await),
+            None => None,
+        };
+        let instantiation_started = Instant::now();
+        let (store, instance) = self.prepare_wasm_instance(ctx)
+            .instrument(startup_span)
+            .await?;
+        let module_label = [("module", self.wasm_module_name.as_str())];
+        global_context.metrics.add_to_counter(INSTANTIATION_TIME_METRIC, &module_label, instantiation_started.elapsed().as_secs_f64());
 
-        run_prepared_wasm_instance(instance, store, &self.entrypoint, &self.wasm_module_name)?;
+        let outbound_context = OutboundRequestContext::for_request(
+            &self.wasm_module_name,
+            &req.headers,
+            global_context.trace_headers,
+            global_context.metrics.clone(),
+        );
+        let (entrypoint, module_name) = (self.entrypoint.clone(), self.wasm_module_name.clone());
+        let run = self.executor.run(with_request_context(
+            outbound_context,
+            async move { run_prepared_wasm_instance(instance, store, &entrypoint, &module_name).await },
+        ));
+        let execution_started = Instant::now();
+        let outcome = match self.timeout {
+            None => run.await,
+            // Running modules yield at every epoch tick, so the timeout can fire
+            // even if the module never makes a host call. Dropping the future
+            // abandons the module.
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or_else(|_| Err(ModuleTimedOut { module: self.wasm_module_name.clone(), timeout }.into())),
+        };
+        global_context.metrics.add_to_counter(EXECUTION_TIME_METRIC, &module_label, execution_started.elapsed().as_secs_f64());
+        if let (Some(sample), Err(e)) = (&mut sample, &outcome) {
+            sample.record_error(e);
+        }
+        match outcome {
+            Ok(memory_bytes) => global_context.memory_stats.record(&matched_route.original_text(), &self.wasm_module_name, memory_bytes, &global_context.metrics),
+            Err(e) if e.is::<ModuleTimedOut>() => return Err(e),
+            Err(e) => return Err(ModuleFailed { error: e, stderr_tail: redirects.stderr_tail.lines() }.into()),
+        }
 
-        compose_response(redirects.stdout_mutex)
+        let range_request = if self.byte_ranges { Some(req) } else { None };
+        let response = compose_checked_response(redirects.stdout_mutex, &redirects.spilled, &self.content_type_defaults, range_request);
+        if let (Some(overlay), Some(VolumeOverlay::Commit), Ok(res)) = (overlay, self.volume_overlay, &response) {
+            // A failed request leaves no partial changes behind
+            if !res.status().is_server_error() {
+                tokio::task::spawn_blocking(move || overlay.commit()).await?
+                    .context("Could not commit the request's changes to its volumes")?;
+            }
+        }
+        let response = match response {
+            Err(e) if e.is::<InvalidResponse>() => {
+                let message = e.to_string();
+                if let Some(sample) = &mut sample {
+                    sample.record_error(&e);
+                }
+                let report = ErrorReport {
+                    summary: "Module wrote an invalid response",
+                    module: Some(&self.wasm_module_name),
+                    public_message: &message,
+                    detail: &message,
+                    stderr_tail: &redirects.stderr_tail.lines(),
+                };
+                Ok(report.respond(global_context.debug_errors))
+            }
+            other => other,
+        };
+        if let (Some(sample), Ok(res)) = (&mut sample, &response) {
+            sample.record_status(res.status());
+        }
+        response
     }
 
-    fn build_wasi_context_for_request(&self, req: &Parts, headers: HashMap<String, String>, redirects: crate::wasm_module::IOStreamRedirects) -> Result<WasiCtx, Error> {
-        let args = self.build_argv(req);
+    fn build_wasi_context_for_request(&self, req: &Parts, headers: HashMap<String, String>, redirects: crate::wasm_module::IOStreamRedirects, volumes: &HashMap<String, String>) -> Result<WasiCtx, Error> {
+        let args = build_argv(self.args_mode, &self.argv, &self.argv_from_query, req);
         let headers: Vec<(String, String)> = headers
             .iter()
             .map(|(k, v)| (k.clone(), v.clone()))
             .collect();
-        let mut builder = WasiCtxBuilder::new()
+        let builder = WasiCtxBuilder::new()
             .args(&args)?
             .envs(&headers)?
-            .stderr(Box::new(redirects.stderr)) // STDERR goes to the console of the server
-            .stdout(Box::new(redirects.stdout)) // STDOUT is sent to a Vec<u8>, which becomes the Body later
+            .stderr(redirects.stderr)
+            .stdout(redirects.stdout) // STDOUT is sent to a Vec<u8>, which becomes the Body later
             .stdin(Box::new(redirects.stdin));
 
-        for (guest, host) in &self.volumes {
-            debug!(%host, %guest, "Mapping volume from host to guest");
-            // Try to open the dir or log an error.
-            match Dir::open_ambient_dir(host, ambient_authority()) {
-                Ok(dir) => {
-                    builder = builder.preopened_dir(dir, guest)?;
-                }
-                Err(e) => tracing::error!(%host, %guest, error = %e, "Error opening directory"),
-            };
+        let ctx = preopen_volumes(builder, volumes)?.build();
+        Ok(ctx)
+    }
+
+    async fn prepare_wasm_instance(&self,  ctx: WasiCtx) -> Result<(Store<WasiCtx>, Instance), Error> {
+        if let Some(pool) = &self.instance_pool {
+            if let Some((mut store, instance)) = pool.take() {
+                debug!("Using pre-instantiated Wasm instance.");
+                // Modules with start functions aren't pooled, so nothing has run in
+                // the instance yet, and the placeholder context can be swapped for
+                // the real one.
+                *store.data_mut() = ctx;
+                return Ok((store, instance));
+            }
         }
+        debug!("Preparing Wasm instance.");
+        prepare_wasm_instance(ctx, &self.wasm_module_source, self.link_options()).await
+    }
 
-        let ctx = builder.build();
-        Ok(ctx)
+    pub fn link_options(&self) -> WasmLinkOptions {
+        WasmLinkOptions::default()
+            .with_http(self.allowed_hosts.clone(), self.http_max_concurrency)
+            .with_outbound_network(self.outbound_network.clone())
+            .with_http_denied(self.deny_outbound_http)
     }
+}
 
-    /// Build the argv array that will be passed to the module.
-    /// 
-    /// If an `argv` override is set in the handler, then this will override the CGI defaults.
-    /// 
-    /// In the arg override: ${SCRIPT_NAME} will be replaced with the script name, and ${ARGS}
-    /// will be replaced by the arg-formatted query parameters. E.g. 'foo=bar&baz=lurman' will
-    /// become 'foo=bar baz=lurman'
-    fn build_argv(&self, req: &Parts) -> Vec<String> {
-        match &self.argv {
-            None => {
-                let uri_path = req.uri.path();
-                let mut args = vec![uri_path.to_string()];
-                req.uri
-                    .query()
-                    .map(|q| q.split('&').for_each(|item| args.push(item.to_string())))
-                    .take();
-                args
-            },
-            Some(template) => {
-                let script_name = req.uri.path();
-                let uri = req.uri.query().unwrap_or("");
-                let params = uri.replace('&', " ");
-                let arg_string =  template
-                    .replace("${SCRIPT_NAME}", script_name)
-                    .replace("${ARGS}", params.as_str());
-                arg_string
-                    .split_whitespace()
-                    .map(|s| s.to_string())
-                    .collect()
+/// Build the argv array that will be passed to the module, according to the
+/// route's `args_mode`.
+/// 
+/// In the `template` mode: ${SCRIPT_NAME} will be replaced with the script name, and ${ARGS}
+/// will be replaced by the arg-formatted query parameters. E.g. 'foo=bar&baz=lurman' will
+/// become 'foo=bar baz=lurman'
+///
+/// In the `query` mode, each listed parameter in the query becomes `--name=value`,
+/// or `--name` if it has no value, in the order of the list. Values are
+/// percent-decoded, and parameters may be separated by `&` or `;`, so the client's
+/// encoding doesn't change what the module sees. A parameter given more than once
+/// gives an argument for each time, and one not in the list is left out.
+fn build_argv(args_mode: ArgsMode, argv: &Option<String>, argv_from_query: &Option<Vec<String>>, req: &Parts) -> Vec<String> {
+    let template = match (args_mode, argv) {
+        (ArgsMode::None, _) => return vec![req.uri.path().to_string()],
+        (ArgsMode::Query, _) => return query_argv(argv_from_query.as_deref().unwrap_or_default(), req),
+        (ArgsMode::Template, Some(template)) => Some(template),
+        _ => None,
+    };
+    match template {
+        None => {
+            let uri_path = req.uri.path();
+            let mut args = vec![uri_path.to_string()];
+            req.uri
+                .query()
+                .map(|q| q.split('&').for_each(|item| args.push(item.to_string())))
+                .take();
+            args
+        },
+        Some(template) => {
+            let script_name = req.uri.path();
+            let uri = req.uri.query().unwrap_or("");
+            let params = uri.replace('&', " ");
+            let arg_string =  template
+                .replace("${SCRIPT_NAME}", script_name)
+                .replace("${ARGS}", params.as_str());
+            arg_string
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect()
+        }
+    }
+}
+
+fn query_argv(names: &[String], req: &Parts) -> Vec<String> {
+    let query = req.uri.query().unwrap_or("").replace(';', "&");
+    let params: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+    let mut args = vec![req.uri.path().to_string()];
+    for name in names {
+        for (_, value) in params.iter().filter(|(n, _)| n == name) {
+            if value.is_empty() {
+                args.push(format!("--{}", name));
+            } else {
+                args.push(format!("--{}={}", name, value));
             }
         }
     }
+    args
+}
 
-    fn prepare_wasm_instance(&self,  ctx: WasiCtx) -> Result<(Store<WasiCtx>, Instance), Error> {
-        debug!("Preparing Wasm instance.");
-        let link_options = WasmLinkOptions::default()
-            .with_http(self.allowed_hosts.clone(), self.http_max_concurrency);
-        prepare_wasm_instance(ctx, &self.wasm_module_source, link_options)
+pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>, content_type_defaults: &ContentTypeDefaults) -> Result<Response<Body>, Error> {
+    match compose_checked_response(stdout_mutex, &SpilledBody::default(), content_type_defaults, None) {
+        Err(e) if e.is::<InvalidResponse>() => Ok(internal_error(e)),
+        other => other,
     }
 }
 
-pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<Body>, Error> {
+// Like `compose_response`, but an invalid response is an `InvalidResponse` error
+// rather than a 500 response. If `range_request` is given, the response answers
+// its `Range` header.
+fn compose_checked_response(stdout_mutex: Arc<RwLock<Vec<u8>>>, spilled: &SpilledBody, content_type_defaults: &ContentTypeDefaults, range_request: Option<&Parts>) -> Result<Response<Body>, Error> {
     // Okay, once we get here, all the information we need to send back in the response
     // should be written to the STDOUT buffer. We fetch that, format it, and send
     // it back. In the process, we might need to alter the status code of the result.
@@ -151,6 +495,7 @@ pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<B
     // to the client.
     debug!("composing response");
     let out = stdout_mutex.read().unwrap();
+    let out = skip_informational_responses(&out)?;
     let mut last = 0;
     let mut scan_headers = true;
     let mut buffer: Vec<u8> = Vec::new();
@@ -168,10 +513,14 @@ pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<B
         last = *i;
         buffer.push(*i)
     });
-    let mut res = Response::new(Body::from(buffer));
+    let spilled = spilled.take()?;
+    let has_body = !buffer.is_empty() || spilled.is_some();
+    // The body is added once the headers are known, as they decide how much of it to send
+    let mut res = Response::new(Body::empty());
     let mut sufficient_response = false;
     parse_cgi_headers(String::from_utf8(out_headers)?)
         .iter()
+        .map(|(name, value)| (name, value.as_str()))
         .for_each(|h| {
             use hyper::header::{CONTENT_TYPE, LOCATION};
             match h.0.to_lowercase().as_str() {
@@ -186,13 +535,16 @@ pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<B
                     // do not set content type correctly if a status is an error.
                     // See https://datatracker.ietf.org/doc/html/rfc3875#section-6.2
                     sufficient_response = true;
-                    // Status can be `Status CODE [STRING]`, and we just want the CODE.
-                    let status_code = h.1.split_once(' ').map(|(code, _)| code).unwrap_or(h.1);
-                    tracing::debug!(status_code, "Raw status code");
-                    match status_code.parse::<StatusCode>() {
-                        Ok(code) => *res.status_mut() = code,
-                        Err(e) => {
-                            tracing::log::warn!("Failed to parse code: {}", e);
+                    tracing::debug!(status = h.1, "Raw status");
+                    match parse_status(h.1) {
+                        Some((code, reason)) => {
+                            *res.status_mut() = code;
+                            if let Some(reason) = reason {
+                                res.extensions_mut().insert(ReasonPhrase(reason));
+                            }
+                        }
+                        None => {
+                            tracing::warn!(status = h.1, "Module sent an invalid Status header");
                             *res.status_mut() = StatusCode::BAD_GATEWAY;
                         }
                     }
@@ -206,11 +558,19 @@ pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<B
                 _ => {
                     // If the header can be parsed into a valid HTTP header, it is
                     // added to the headers. Otherwise it is ignored.
+                    //
+                    // Headers are appended rather than inserted, because modules may
+                    // legitimately send the same header more than once (e.g. Set-Cookie
+                    // or Link), and each occurrence must reach the client in order.
                     match HeaderName::from_lowercase(h.0.as_str().to_lowercase().as_bytes()) {
-                        Ok(hdr) => {
-                            res.headers_mut()
-                                .insert(hdr, HeaderValue::from_str(h.1).unwrap());
-                        }
+                        Ok(hdr) => match HeaderValue::from_str(h.1) {
+                            Ok(val) => {
+                                res.headers_mut().append(hdr, val);
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, header_name = %h.0, "Invalid header value")
+                            }
+                        },
                         Err(e) => {
                             tracing::error!(error = %e, header_name = %h.0, "Invalid header name")
                         }
@@ -218,14 +578,243 @@ pub fn compose_response(stdout_mutex: Arc<RwLock<Vec<u8>>>) -> Result<Response<B
                 }
             }
         });
+    if !sufficient_response && has_body {
+        if let Some(content_type) = &content_type_defaults.content_type {
+            match HeaderValue::from_str(content_type) {
+                Ok(v) => {
+                    debug!(%content_type, "Module did not set Content-Type; using default");
+                    res.headers_mut().insert(hyper::header::CONTENT_TYPE, v);
+                    sufficient_response = true;
+                }
+                Err(e) => tracing::error!(error = %e, %content_type, "Invalid default Content-Type"),
+            }
+        }
+    }
+    if let Some(charset) = &content_type_defaults.charset {
+        apply_default_charset(&mut res, charset);
+    }
     if !sufficient_response {
-        tracing::debug!("{:?}", res.body());
-        return Ok(internal_error(
+        tracing::debug!(body = %String::from_utf8_lossy(&buffer), "Insufficient response");
+        return Err(InvalidResponse(
             // Technically, we let `status` be sufficient, but this is more lenient
             // than the specification.
             "Exactly one of 'location' or 'content-type' must be specified",
-        ));
+        ).into());
     }
+
+    let outcome = match range_request {
+        Some(req) => {
+            let spilled_len = match &spilled {
+                Some(file) => file.metadata()?.len(),
+                None => 0,
+            };
+            crate::byte_ranges::select(req, &mut res, buffer.len() as u64 + spilled_len)
+        }
+        None => RangeOutcome::Full,
+    };
+    *res.body_mut() = match (outcome, spilled) {
+        (RangeOutcome::Full, Some(file)) => crate::spill::body(buffer, file),
+        (RangeOutcome::Full, None) => Body::from(buffer),
+        (RangeOutcome::Partial(range), Some(file)) => crate::spill::range_body(buffer, file, range)?,
+        (RangeOutcome::Partial(range), None) => Body::from(buffer[range.start as usize..range.end as usize].to_vec()),
+        (RangeOutcome::Unsatisfiable, _) => Body::empty(),
+    };
     debug!("Response successfully sent");
     Ok(res)
-}
\ No newline at end of file
+}
+
+/// A reason phrase a module gave in its `Status` header that differs from the
+/// standard one for the code. It is kept with the response, but HTTP/1.1 responses
+/// are still sent with the standard phrase: hyper does not support custom ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReasonPhrase(pub String);
+
+// Hyper can't send interim (1xx) responses, so a module that writes one (say, a
+// `103 Early Hints` block ahead of its real response) has it dropped, and the
+// response that follows is used. Output that is only an interim response is invalid.
+fn skip_informational_responses(out: &[u8]) -> Result<&[u8], Error> {
+    let start = informational_responses_len(out);
+    if start > 0 {
+        debug!(bytes = start, "Dropping interim responses from module");
+        if start == out.len() {
+            return Err(InvalidResponse("Module sent only an informational (1xx) response").into());
+        }
+    }
+    Ok(&out[start..])
+}
+
+fn apply_default_charset(res: &mut Response<Body>, charset: &str) {
+    let content_type = match res.headers().get(hyper::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(ct) => ct.to_owned(),
+        None => return,
+    };
+    let lower = content_type.to_lowercase();
+    if !lower.starts_with("text/") || lower.contains("charset=") {
+        return;
+    }
+    match HeaderValue::from_str(&format!("{}; charset={}", content_type, charset)) {
+        Ok(v) => {
+            res.headers_mut().insert(hyper::header::CONTENT_TYPE, v);
+        }
+        Err(e) => tracing::error!(error = %e, %charset, "Invalid default charset"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn compose(output: &str) -> Response<Body> {
+        compose_with(output, &ContentTypeDefaults::default())
+    }
+
+    fn compose_with(output: &str, defaults: &ContentTypeDefaults) -> Response<Body> {
+        let stdout_mutex = Arc::new(RwLock::new(output.as_bytes().to_vec()));
+        compose_response(stdout_mutex, defaults).expect("Response should have been composed")
+    }
+
+    #[test]
+    fn multiple_set_cookie_headers_are_all_sent() {
+        let res = compose("Content-Type: text/plain\nSet-Cookie: a=1\nSet-Cookie: b=2\n\nhello");
+
+        let cookies: Vec<_> = res
+            .headers()
+            .get_all(hyper::header::SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(vec!["a=1", "b=2"], cookies);
+    }
+
+    #[test]
+    fn repeated_headers_are_preserved_in_order() {
+        let res = compose("Content-Type: text/plain\nLink: </a>; rel=preload\nX-Custom: one\nLink: </b>; rel=preload\n\nhello");
+
+        let links: Vec<_> = res
+            .headers()
+            .get_all(hyper::header::LINK)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(vec!["</a>; rel=preload", "</b>; rel=preload"], links);
+        assert_eq!("one", res.headers()["x-custom"]);
+    }
+
+    #[test]
+    fn invalid_header_values_are_ignored() {
+        let res = compose("Content-Type: text/plain\nX-Bad: \u{7f}\n\nhello");
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(res.headers().get("x-bad").is_none());
+    }
+
+    #[test]
+    fn missing_content_type_is_an_error_without_a_default() {
+        let res = compose("X-Custom: one\n\nhello");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+    }
+
+    fn parts(uri: &str) -> Parts {
+        hyper::Request::get(uri).body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn argv_follows_the_args_mode() {
+        let req = parts("http://example.com/env?greet=matt&foo=bar");
+        let template = Some("ruby index.rb ${SCRIPT_NAME} ${ARGS}".to_owned());
+
+        assert_eq!(vec!["/env", "greet=matt", "foo=bar"], build_argv(ArgsMode::Cgi, &None, &None, &req));
+        assert_eq!(vec!["/env"], build_argv(ArgsMode::None, &None, &None, &req));
+        assert_eq!(vec!["ruby", "index.rb", "/env", "greet=matt", "foo=bar"], build_argv(ArgsMode::Template, &template, &None, &req));
+    }
+
+    #[test]
+    fn query_argv_follows_the_listed_order_whatever_the_encoding() {
+        let names = Some(vec!["tamanho".to_owned(), "imprime".to_owned()]);
+        let expected = vec!["/fig", "--tamanho=10 px", "--imprime"];
+        for uri in [
+            "http://example.com/fig?imprime&tamanho=10+px&other=x",
+            "http://example.com/fig?imprime=;tamanho=10%20px",
+            "http://example.com/fig?%74amanho=10%20px&imprime",
+        ] {
+            assert_eq!(expected, build_argv(ArgsMode::Query, &None, &names, &parts(uri)), "{}", uri);
+        }
+        let req = parts("http://example.com/fig?tamanho=a%26b%3Dc&tamanho=2");
+        assert_eq!(vec!["/fig", "--tamanho=a&b=c", "--tamanho=2"], build_argv(ArgsMode::Query, &None, &names, &req));
+    }
+
+    #[test]
+    fn args_mode_defaults_depend_on_argv() {
+        let template = Some("${SCRIPT_NAME}".to_owned());
+        let from_query = Some(vec!["size".to_owned()]);
+        assert_eq!(ArgsMode::Cgi, ArgsMode::resolve(None, &None, &None).unwrap());
+        assert_eq!(ArgsMode::Template, ArgsMode::resolve(None, &template, &None).unwrap());
+        assert_eq!(ArgsMode::Query, ArgsMode::resolve(None, &None, &from_query).unwrap());
+        assert_eq!(ArgsMode::None, ArgsMode::resolve(Some(ArgsMode::None), &None, &None).unwrap());
+        ArgsMode::resolve(Some(ArgsMode::Template), &None, &None).expect_err("template without argv");
+        ArgsMode::resolve(Some(ArgsMode::Cgi), &template, &None).expect_err("argv without template mode");
+        ArgsMode::resolve(Some(ArgsMode::Query), &None, &None).expect_err("query without argv_from_query");
+        ArgsMode::resolve(Some(ArgsMode::Cgi), &None, &from_query).expect_err("argv_from_query without query mode");
+        ArgsMode::resolve(None, &template, &from_query).expect_err("argv and argv_from_query");
+        ArgsMode::resolve(None, &None, &Some(vec!["a=b".to_owned()])).expect_err("not a parameter name");
+    }
+
+    #[test]
+    fn status_codes_and_reasons_are_passed_through() {
+        let res = compose("Content-Type: text/plain\nStatus: 207 Multi-Status\n\n");
+        assert_eq!(StatusCode::MULTI_STATUS, res.status());
+        assert!(res.extensions().get::<ReasonPhrase>().is_none());
+
+        let res = compose("Content-Type: text/plain\nStatus: 418\tI'm a Teapot, Really\n\n");
+        assert_eq!(418, res.status().as_u16());
+        assert_eq!(Some(&ReasonPhrase("I'm a Teapot, Really".to_owned())), res.extensions().get::<ReasonPhrase>());
+
+        let res = compose("Content-Type: text/plain\nStatus: HTTP/1.1 299 Custom\n\n");
+        assert_eq!(299, res.status().as_u16());
+
+        let res = compose("Content-Type: text/plain\nStatus: OK\n\n");
+        assert_eq!(StatusCode::BAD_GATEWAY, res.status());
+    }
+
+    #[test]
+    fn interim_responses_are_skipped() {
+        let res = compose("Status: 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\nContent-Type: text/plain\r\nStatus: 201\r\n\r\ncreated");
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert!(res.headers().get(hyper::header::LINK).is_none());
+        let body = futures::executor::block_on(hyper::body::to_bytes(res.into_body())).unwrap();
+        assert_eq!("created", String::from_utf8_lossy(&body));
+
+        let res = compose("Status: 100\n\n");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+    }
+
+    #[test]
+    fn default_content_type_is_used_when_module_sends_a_body() {
+        let defaults = ContentTypeDefaults {
+            content_type: Some("text/html".to_owned()),
+            charset: None,
+        };
+        let res = compose_with("X-Custom: one\n\nhello", &defaults);
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("text/html", res.headers()["content-type"]);
+
+        let res = compose_with("Content-Type: application/json\n\n{}", &defaults);
+        assert_eq!("application/json", res.headers()["content-type"]);
+    }
+
+    #[test]
+    fn default_charset_is_only_applied_to_text_without_charset() {
+        let defaults = ContentTypeDefaults {
+            content_type: None,
+            charset: Some("utf-8".to_owned()),
+        };
+        let res = compose_with("Content-Type: text/plain\n\nhello", &defaults);
+        assert_eq!("text/plain; charset=utf-8", res.headers()["content-type"]);
+
+        let res = compose_with("Content-Type: text/plain; charset=latin1\n\nhello", &defaults);
+        assert_eq!("text/plain; charset=latin1", res.headers()["content-type"]);
+
+        let res = compose_with("Content-Type: image/png\n\nhello", &defaults);
+        assert_eq!("image/png", res.headers()["content-type"]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Hardening of the host process (`--harden`).
+//!
+//! Once startup is complete (modules fetched and compiled, caches written), the process
+//! no longer needs most of the filesystem or many of the syscalls it could use. On
+//! Linux, hardening uses Landlock to confine filesystem access to the paths WAGI still
+//! needs while serving, and a seccomp filter to refuse syscalls that no WAGI server
+//! has any business making (running programs, loading kernel modules, tracing other
+//! processes, and so on). This is defence in depth on top of the WASI sandbox: it
+//! limits what an attacker could do if they escaped a module.
+//!
+//! Landlock only applies to the thread that enables it and to threads that thread
+//! creates afterwards, so hardening must happen before the serving threads start.
+
+use crate::handler_loader::WasmHandlerConfiguration;
+use crate::wagi_config::WagiConfiguration;
+
+/// Restricts the calling thread, and every thread it starts afterwards.
+pub fn apply(configuration: &WagiConfiguration, handlers: &WasmHandlerConfiguration) -> anyhow::Result<()> {
+    imp::apply(configuration, handlers)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::path::{Path, PathBuf};
+
+    use anyhow::Context;
+    use landlock::{
+        Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI,
+    };
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, SeccompRule};
+
+    use crate::handler_loader::WasmHandlerConfiguration;
+    use crate::wagi_config::WagiConfiguration;
+
+    // System paths that outbound HTTP (DNS resolution, TLS roots) and the Rust
+    // standard library may read while serving.
+    const SYSTEM_READ_ONLY_PATHS: &[&str] = &[
+        "/etc",
+        "/usr/share/ca-certificates",
+        "/usr/lib/ssl",
+        "/proc",
+        "/dev/urandom",
+    ];
+
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_personality,
+    ];
+
+    pub fn apply(configuration: &WagiConfiguration, handlers: &WasmHandlerConfiguration) -> anyhow::Result<()> {
+        restrict_filesystem(configuration, handlers)
+            .with_context(|| "Error restricting filesystem access with Landlock")?;
+        restrict_syscalls()
+            .with_context(|| "Error installing seccomp filter")?;
+        Ok(())
+    }
+
+    fn restrict_filesystem(configuration: &WagiConfiguration, handlers: &WasmHandlerConfiguration) -> anyhow::Result<()> {
+        let abi = ABI::V1;
+        let (read_write, read_only) = allowed_paths(configuration, handlers);
+
+        let mut ruleset = Ruleset::new()
+            .handle_access(AccessFs::from_all(abi))?
+            .create()?;
+        for path in existing(&read_write) {
+            ruleset = ruleset.add_rule(PathBeneath::new(PathFd::new(path)?, access_for(path, AccessFs::from_all(abi))))?;
+        }
+        for path in existing(&read_only) {
+            ruleset = ruleset.add_rule(PathBeneath::new(PathFd::new(path)?, access_for(path, AccessFs::from_read(abi))))?;
+        }
+
+        let status = ruleset.restrict_self()?;
+        match status.ruleset {
+            RulesetStatus::FullyEnforced => Ok(()),
+            RulesetStatus::PartiallyEnforced => {
+                tracing::warn!("Landlock is only partially supported by this kernel; filesystem restrictions are incomplete");
+                Ok(())
+            }
+            RulesetStatus::NotEnforced => Err(anyhow::anyhow!(
+                "Landlock is not supported or not enabled in this kernel (it needs Linux 5.13 or later with Landlock in the LSM list)"
+            )),
+        }
+    }
+
+    // Modules write their logs to the log directory, and WAGI writes the audit log
+    // (which is there by default). Modules may read and write their volume mounts
+    // (which include any bindle assets in the module cache), as may scheduled
+    // tasks, and requests to modules with volume overlays copy the volumes into the
+    // overlay directory. The TLS certificate and key are read when the listener starts.
+    fn allowed_paths(configuration: &WagiConfiguration, handlers: &WasmHandlerConfiguration) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut read_write = vec![configuration.log_dir.clone()];
+        // The audit log may not exist until the first audited request
+        read_write.extend(configuration.audit_log.parent().map(PathBuf::from));
+        read_write.extend(configuration.spill.as_ref().map(|s| s.dir.clone()));
+        read_write.extend(
+            handlers.entries.iter()
+                .flat_map(|e| e.info.volume_mounts.values())
+                .chain(handlers.tasks.iter().flat_map(|t| t.info.volume_mounts.values()))
+                .map(PathBuf::from)
+        );
+        if handlers.entries.iter().any(|e| e.info.volume_overlay.is_some()) {
+            read_write.push(configuration.overlay_dir.clone());
+        }
+
+        let mut read_only: Vec<PathBuf> = SYSTEM_READ_ONLY_PATHS.iter().map(PathBuf::from).collect();
+        if let Some(tls) = &configuration.http_configuration.tls {
+            read_only.push(tls.cert_path.clone());
+            read_only.push(tls.key_path.clone());
+            read_only.extend(tls.ocsp_response_path.clone());
+        }
+
+        (read_write, read_only)
+    }
+
+    // Landlock refuses rules on paths that do not exist, and system paths vary
+    // between distributions, so missing paths are skipped.
+    fn existing(paths: &[PathBuf]) -> impl Iterator<Item = &PathBuf> {
+        paths.iter().filter(|p| {
+            let exists = p.exists();
+            if !exists {
+                tracing::debug!(path = %p.display(), "Not adding Landlock rule for path that does not exist");
+            }
+            exists
+        })
+    }
+
+    // Directory-only rights (such as listing or creating entries) cannot be granted on a file.
+    fn access_for(path: &Path, access: landlock::BitFlags<AccessFs>) -> landlock::BitFlags<AccessFs> {
+        if path.is_dir() {
+            access
+        } else {
+            access & (AccessFs::ReadFile | AccessFs::WriteFile)
+        }
+    }
+
+    fn restrict_syscalls() -> anyhow::Result<()> {
+        // An empty rule list matches the syscall whatever its arguments
+        let rules: BTreeMap<i64, Vec<SeccompRule>> = DENIED_SYSCALLS
+            .iter()
+            .map(|s| (*s as i64, vec![]))
+            .collect();
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            std::env::consts::ARCH.try_into()?,
+        )?;
+        let program: BpfProgram = filter.try_into()?;
+        seccompiler::apply_filter_all_threads(&program)?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::volume_overlay::VolumeOverlay;
+        use crate::wagi_config::{HandlerConfigurationSource, InMemoryModule};
+
+        #[tokio::test]
+        async fn overlay_directory_is_writable_when_a_module_uses_overlays() {
+            let modules = vec![InMemoryModule::new("/", "empty", "(module)")];
+            let mut configuration = WagiConfiguration::new(HandlerConfigurationSource::InMemory(modules)).unwrap();
+            configuration.overlay_dir = tempfile::tempdir().unwrap().into_path();
+            let mut handlers = crate::handler_loader::load_handlers(&configuration).await.unwrap();
+
+            let (read_write, _) = allowed_paths(&configuration, &handlers);
+            assert!(!read_write.contains(&configuration.overlay_dir));
+
+            let volume = tempfile::tempdir().unwrap();
+            let info = &mut handlers.entries[0].info;
+            info.volume_mounts.insert("/data".to_owned(), volume.path().display().to_string());
+            info.volume_overlay = Some(VolumeOverlay::Commit);
+            let (read_write, _) = allowed_paths(&configuration, &handlers);
+            assert!(read_write.contains(&configuration.overlay_dir));
+            assert!(read_write.contains(&volume.path().to_path_buf()));
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use crate::handler_loader::WasmHandlerConfiguration;
+    use crate::wagi_config::WagiConfiguration;
+
+    pub fn apply(_configuration: &WagiConfiguration, _handlers: &WasmHandlerConfiguration) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("--harden is only supported on Linux"))
+    }
+}
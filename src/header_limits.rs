@@ -0,0 +1,168 @@
+//! Limits on the request header values that reach modules.
+//!
+//! Modules see request headers as `HTTP_*` environment variables, so a client can
+//! put whatever it likes into a module's environment. Values that hold control
+//! characters, or are longer than the limit, are dropped, cut down or refused
+//! before the module runs. Modules marked `trusted` get the headers as they are.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+pub const DEFAULT_MAX_HEADER_VALUE_LEN: usize = 8192;
+
+/// What to do with a header value that breaks the limits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderValuePolicy {
+    /// Leave the header out.
+    Drop,
+    /// Remove the control characters and cut the value to the maximum length.
+    Truncate,
+    /// Answer the request with `400 Bad Request`.
+    Reject,
+}
+
+impl HeaderValuePolicy {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "drop" => Ok(Self::Drop),
+            "truncate" => Ok(Self::Truncate),
+            "reject" => Ok(Self::Reject),
+            _ => anyhow::bail!("Invalid header value policy '{}': must be drop, truncate or reject", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderLimits {
+    pub max_value_len: usize,
+    pub policy: HeaderValuePolicy,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_value_len: DEFAULT_MAX_HEADER_VALUE_LEN,
+            policy: HeaderValuePolicy::Drop,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Applies the limits to the headers. Returns the name of the first header that
+    /// breaks them if the policy is to reject the request.
+    pub fn apply(&self, headers: &mut HeaderMap) -> Result<(), HeaderName> {
+        if headers.values().all(|v| self.allows(v)) {
+            return Ok(());
+        }
+        let original = std::mem::take(headers);
+        let mut name = None;
+        for (n, value) in original {
+            // Values after the first of a name come with no name
+            if let Some(n) = n {
+                name = Some(n);
+            }
+            let name = name.clone().expect("the first header should have a name");
+            if self.allows(&value) {
+                headers.append(name, value);
+                continue;
+            }
+            match self.policy {
+                HeaderValuePolicy::Drop => {
+                    tracing::debug!(header = %name, "Dropping header value with control characters or over the length limit");
+                }
+                HeaderValuePolicy::Truncate => {
+                    headers.append(name, self.truncate(&value));
+                }
+                HeaderValuePolicy::Reject => return Err(name),
+            }
+        }
+        Ok(())
+    }
+
+    fn allows(&self, value: &HeaderValue) -> bool {
+        value.len() <= self.max_value_len && !has_control_chars(value.as_bytes())
+    }
+
+    fn truncate(&self, value: &HeaderValue) -> HeaderValue {
+        let mut kept: Vec<u8> = match std::str::from_utf8(value.as_bytes()) {
+            Ok(text) => text.chars().filter(|c| !c.is_control()).collect::<String>().into_bytes(),
+            Err(_) => value.as_bytes().iter().copied().filter(|b| !is_control_byte(*b)).collect(),
+        };
+        if kept.len() > self.max_value_len {
+            let mut end = self.max_value_len;
+            if let Ok(text) = std::str::from_utf8(&kept) {
+                // Don't leave half a character at the end
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+            }
+            kept.truncate(end);
+        }
+        HeaderValue::from_bytes(&kept).expect("value without control characters should be a valid header value")
+    }
+}
+
+fn is_control_byte(b: u8) -> bool {
+    b < 0x20 || b == 0x7f
+}
+
+// Includes the C1 controls in UTF-8 text, as well as tabs and DEL
+fn has_control_chars(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.chars().any(|c| c.is_control()),
+        Err(_) => bytes.iter().any(|b| is_control_byte(*b)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ok", HeaderValue::from_static("fine"));
+        headers.append("x-multi", HeaderValue::from_static("one"));
+        headers.append("x-multi", HeaderValue::from_static("tw\to"));
+        headers.insert("x-long", HeaderValue::from_str(&"é".repeat(6)).unwrap());
+        headers.insert("x-c1", HeaderValue::from_bytes("a\u{85}b".as_bytes()).unwrap());
+        headers
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers.get_all(name).iter().map(|v| v.to_str().unwrap()).collect()
+    }
+
+    fn limits(policy: HeaderValuePolicy) -> HeaderLimits {
+        HeaderLimits { max_value_len: 5, policy }
+    }
+
+    #[test]
+    fn values_breaking_the_limits_can_be_dropped() {
+        let mut h = headers();
+        limits(HeaderValuePolicy::Drop).apply(&mut h).unwrap();
+        assert_eq!("fine", h["x-ok"]);
+        assert_eq!(vec!["one"], values(&h, "x-multi"));
+        assert!(!h.contains_key("x-long"));
+        assert!(!h.contains_key("x-c1"));
+    }
+
+    #[test]
+    fn values_breaking_the_limits_can_be_truncated() {
+        let mut h = headers();
+        limits(HeaderValuePolicy::Truncate).apply(&mut h).unwrap();
+        assert_eq!(vec!["one", "two"], values(&h, "x-multi"));
+        // Two bytes per character, so the limit would split the third
+        assert_eq!("éé".as_bytes(), h["x-long"].as_bytes());
+        assert_eq!("ab", h["x-c1"]);
+    }
+
+    #[test]
+    fn values_breaking_the_limits_can_be_rejected() {
+        let mut h = headers();
+        let name = limits(HeaderValuePolicy::Reject).apply(&mut h).expect_err("should be rejected");
+        assert!(["x-multi", "x-long", "x-c1"].contains(&name.as_str()));
+
+        let mut h = HeaderMap::new();
+        h.insert("x-ok", HeaderValue::from_static("fine"));
+        limits(HeaderValuePolicy::Reject).apply(&mut h).expect("nothing to reject");
+    }
+}
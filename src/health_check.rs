@@ -0,0 +1,86 @@
+//! The inbuilt health check route.
+//!
+//! By default WAGI answers `GET /healthz` with `200 OK` and the body `OK`. Some load
+//! balancers expect a particular path, body or status, so all three can be changed,
+//! and the route can be turned off so that a module can serve the health check itself.
+
+use hyper::{Body, Response, StatusCode};
+
+pub const DEFAULT_HEALTH_CHECK_PATH: &str = "/healthz";
+const DEFAULT_HEALTH_CHECK_BODY: &str = "OK";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheckSettings {
+    pub path: String,
+    pub body: String,
+    pub status: StatusCode,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_HEALTH_CHECK_PATH.to_owned(),
+            body: DEFAULT_HEALTH_CHECK_BODY.to_owned(),
+            status: StatusCode::OK,
+        }
+    }
+}
+
+impl HealthCheckSettings {
+    /// Builds the settings from the command line values, using the defaults for
+    /// any that are not given.
+    pub fn parse(path: Option<&str>, body: Option<&str>, status: Option<&str>) -> anyhow::Result<Self> {
+        let defaults = Self::default();
+        let path = match path {
+            Some(p) if p.starts_with('/') => p.to_owned(),
+            Some(p) => anyhow::bail!("Invalid health check path '{}': must start with '/'", p),
+            None => defaults.path,
+        };
+        let status = match status {
+            Some(s) => parse_status(s)?,
+            None => defaults.status,
+        };
+        Ok(Self {
+            path,
+            body: body.map(|b| b.to_owned()).unwrap_or(defaults.body),
+            status,
+        })
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.status_mut() = self.status;
+        res
+    }
+}
+
+fn parse_status(text: &str) -> anyhow::Result<StatusCode> {
+    // An informational status is not a complete response
+    match text.parse::<u16>() {
+        Ok(n @ 200..=599) => Ok(StatusCode::from_u16(n)?),
+        _ => Err(anyhow::anyhow!("Invalid health check status '{}': must be an HTTP status code from 200 to 599", text)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unset_values_use_the_defaults() {
+        assert_eq!(HealthCheckSettings::default(), HealthCheckSettings::parse(None, None, None).unwrap());
+
+        let settings = HealthCheckSettings::parse(Some("/status"), None, Some("204")).unwrap();
+        assert_eq!("/status", settings.path);
+        assert_eq!("OK", settings.body);
+        assert_eq!(StatusCode::NO_CONTENT, settings.status);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        HealthCheckSettings::parse(Some("status"), None, None).expect_err("path must be absolute");
+        HealthCheckSettings::parse(None, None, Some("ok")).expect_err("status must be a number");
+        HealthCheckSettings::parse(None, None, Some("100")).expect_err("status must not be informational");
+        HealthCheckSettings::parse(None, None, Some("600")).expect_err("status must be in range");
+    }
+}
@@ -0,0 +1,107 @@
+//! Checking modules' `allowed_hosts` at startup.
+//!
+//! A mistyped or unreachable entry in `allowed_hosts` otherwise only shows up as
+//! failed outbound requests inside the module, at request time. With
+//! `--check-allowed-hosts`, WAGI sends a `HEAD` request to each allowed host once
+//! the modules are loaded, and logs a warning for each one that is not a valid URL,
+//! can't be resolved, or can't be connected to. Any HTTP response, whatever its
+//! status, counts as reachable. The checks run in the background and never stop
+//! WAGI from serving.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use url::Url;
+
+use crate::handler_loader::WasmHandlerConfiguration;
+use crate::outbound_http::ALLOW_ALL_HOSTS;
+use crate::outbound_network::{OutboundNetwork, OutboundTls};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// One allowed host, with the modules that allow it. Modules with their own CAs
+// or source address are checked with those.
+struct HostCheck {
+    host: String,
+    tls: Option<OutboundTls>,
+    source_ip: Option<IpAddr>,
+    modules: Vec<String>,
+}
+
+/// Starts checking the allowed hosts of every module and task.
+pub fn start(handlers: &WasmHandlerConfiguration, network: &OutboundNetwork) {
+    let module_hosts = handlers.entries.iter().map(|e| (&e.info.name, &e.info.allowed_hosts, &e.info.outbound_tls, e.info.outbound_source_ip));
+    let task_hosts = handlers.tasks.iter().map(|t| (&t.info.name, &t.info.allowed_hosts, &None, None));
+    let checks = collect_checks(module_hosts.chain(task_hosts));
+    for check in checks {
+        let network = match network.for_module(check.tls.as_ref(), check.source_ip) {
+            Ok(network) => network,
+            // Already reported when the routing table was built
+            Err(_) => continue,
+        };
+        tokio::spawn(async move { check_host(check, network).await });
+    }
+}
+
+fn collect_checks<'a>(sources: impl Iterator<Item = (&'a String, &'a Option<Vec<String>>, &'a Option<OutboundTls>, Option<IpAddr>)>) -> Vec<HostCheck> {
+    let mut checks: Vec<HostCheck> = vec![];
+    for (module, hosts, tls, source_ip) in sources {
+        for host in hosts.iter().flatten().filter(|h| *h != ALLOW_ALL_HOSTS) {
+            match checks.iter_mut().find(|c| c.host == *host && c.tls == *tls && c.source_ip == source_ip) {
+                Some(check) => check.modules.push(module.clone()),
+                None => checks.push(HostCheck {
+                    host: host.clone(),
+                    tls: tls.clone(),
+                    source_ip,
+                    modules: vec![module.clone()],
+                }),
+            }
+        }
+    }
+    checks
+}
+
+async fn check_host(check: HostCheck, network: OutboundNetwork) {
+    let modules = check.modules.join(", ");
+    let url = match Url::parse(&check.host) {
+        Ok(url) if url.host_str().is_some() => url,
+        _ => {
+            tracing::warn!(host = %check.host, %modules, "Allowed host is not a URL with a host name, so no request can match it");
+            return;
+        }
+    };
+    let result = network.client().head(url).timeout(CHECK_TIMEOUT).send().await;
+    match result {
+        Ok(response) => tracing::debug!(host = %check.host, status = %response.status(), "Allowed host is reachable"),
+        Err(e) => tracing::warn!(
+            host = %check.host,
+            %modules,
+            error = %format!("{:#}", anyhow::Error::new(e)),
+            "Allowed host could not be reached; the modules' requests to it will fail"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn each_host_is_checked_once() {
+        let (a, b, c) = ("a.wasm".to_owned(), "b.wasm".to_owned(), "c.wasm".to_owned());
+        let shared = Some(vec!["https://api.example.com".to_owned(), ALLOW_ALL_HOSTS.to_owned()]);
+        let other = Some(vec!["https://api.example.com".to_owned(), "https://other.example.com".to_owned()]);
+        let own_ca = Some(OutboundTls { ca_certificates: vec!["ca.pem".into()], ca_certificates_only: true });
+        let checks = collect_checks(vec![(&a, &shared, &None, None), (&b, &other, &None, None), (&c, &shared, &own_ca, None)].into_iter());
+
+        let summary: Vec<_> = checks.iter().map(|c| (c.host.as_str(), c.tls.is_some(), c.modules.join(","))).collect();
+        assert_eq!(
+            vec![
+                ("https://api.example.com", false, "a.wasm,b.wasm".to_owned()),
+                ("https://other.example.com", false, "b.wasm".to_owned()),
+                ("https://api.example.com", true, "c.wasm".to_owned()),
+            ],
+            summary
+        );
+    }
+}
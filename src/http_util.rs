@@ -5,12 +5,14 @@ use std::net::SocketAddr;
 
 use hyper::HeaderMap;
 use hyper::{
-    header::HOST,
+    header::{CONNECTION, CONTENT_LENGTH, COOKIE, HOST, TRANSFER_ENCODING},
     http::request::Parts,
     Body, Response, StatusCode,
 };
 
+use crate::access_control::IpNetwork;
 use crate::dispatcher::RoutePattern;
+use crate::error_report::ErrorReport;
 use crate::version::*;
 
 /// Create an HTTP 404 response
@@ -20,26 +22,238 @@ pub(crate) fn not_found() -> Response<Body> {
     not_found
 }
 
-/// Create an HTTP 500 response
+/// Create an HTTP 403 response
+pub(crate) fn forbidden() -> Response<Body> {
+    let mut forbidden = Response::default();
+    *forbidden.status_mut() = StatusCode::FORBIDDEN;
+    forbidden
+}
+
+/// Create an HTTP 400 response
+pub(crate) fn bad_request(msg: impl std::string::ToString) -> Response<Body> {
+    let mut res = Response::new(Body::from(msg.to_string()));
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    res
+}
+
+/// Create an HTTP 400 response for a request whose body framing is ambiguous. The
+/// connection is closed, since the client and any proxies in front of WAGI may not
+/// agree on where the next request starts.
+pub(crate) fn bad_framing(msg: impl std::string::ToString) -> Response<Body> {
+    let mut res = bad_request(msg);
+    res.headers_mut().insert(CONNECTION, hyper::header::HeaderValue::from_static("close"));
+    res
+}
+
+/// Create an HTTP 415 response, naming the media type the route does accept
+pub(crate) fn unsupported_media_type(accepted: &str) -> Response<Body> {
+    let mut res = Response::new(Body::from(format!("This route only accepts {} request bodies", accepted)));
+    *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    res
+}
+
+/// Create an HTTP 405 response, listing the methods the route does allow
+pub(crate) fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    res.headers_mut().insert(hyper::header::ALLOW, hyper::header::HeaderValue::from_static(allow));
+    res
+}
+
+/// Create an HTTP 500 response, logging the message under a new error ID
 pub(crate) fn internal_error(msg: impl std::string::ToString) -> Response<Body> {
     let message = msg.to_string();
-    tracing::error!(error = %message, "HTTP 500 error");
-    let mut res = Response::new(Body::from(message));
-    *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    let report = ErrorReport {
+        summary: "HTTP 500 error",
+        module: None,
+        public_message: &message,
+        detail: &message,
+        stderr_tail: &[],
+    };
+    report.respond(false)
+}
+
+/// Create an HTTP 503 response, telling the client when it is worth trying again
+pub(crate) fn service_unavailable(retry_after: std::time::Duration) -> Response<Body> {
+    // Retry-After is in whole seconds; round up so clients don't retry too early.
+    let retry_secs = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+    let mut res = Response::new(Body::from("Service temporarily unavailable"));
+    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    res.headers_mut().insert(
+        hyper::header::RETRY_AFTER,
+        hyper::header::HeaderValue::from(retry_secs),
+    );
     res
 }
 
-pub(crate) fn parse_cgi_headers(headers: String) -> HashMap<String, String> {
-    let mut map = HashMap::new();
+/// Create an HTTP 503 response for a route that has been taken out of service.
+/// There is no Retry-After, because there is no telling when it will be back.
+pub(crate) fn route_disabled() -> Response<Body> {
+    let mut res = Response::new(Body::from("Route is disabled"));
+    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    res
+}
+
+/// Create an HTTP 504 response
+pub(crate) fn gateway_timeout() -> Response<Body> {
+    let mut res = Response::new(Body::from("Timed out waiting for the response"));
+    *res.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    res
+}
+
+/// Create an HTTP 301 response sending a plain HTTP request to the same path and
+/// query on the HTTPS port. The host comes from the request's `Host` header, or
+/// `default_host` if that is missing or not a plain host name or address.
+pub(crate) fn https_redirect(req: &Parts, default_host: &str, https_port: u16) -> Response<Body> {
+    let requested_host = req.headers.get(HOST).and_then(|v| v.to_str().ok());
+    let host = match requested_host.map(|h| split_forwarded_host(h, 80).0) {
+        Some(host) if is_plain_host(&host) => host,
+        _ => split_forwarded_host(default_host, 80).0,
+    };
+    let authority = if https_port == 443 {
+        host
+    } else {
+        format!("{}:{}", host, https_port)
+    };
+    let path_and_query = req.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = format!("https://{}{}", authority, path_and_query);
+    let mut res = Response::default();
+    *res.status_mut() = StatusCode::MOVED_PERMANENTLY;
+    match hyper::header::HeaderValue::from_str(&location) {
+        Ok(value) => {
+            res.headers_mut().insert(hyper::header::LOCATION, value);
+        }
+        Err(_) => *res.status_mut() = StatusCode::BAD_REQUEST,
+    }
+    res
+}
+
+// A name or an address, which can go in a URL as is
+fn is_plain_host(host: &str) -> bool {
+    let bracketed_ipv6 = host.starts_with('[') && host.ends_with(']') && host[1..host.len() - 1].parse::<std::net::Ipv6Addr>().is_ok();
+    bracketed_ipv6 || (!host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'))
+}
+
+/// Where the body of the final response starts in a module's output, once its
+/// header block is complete: just past the blank line that separates its headers
+/// from the body. Informational (1xx) responses before it are skipped, so a module
+/// that has only written, say, a `103 Early Hints` block has not written its headers.
+pub(crate) fn response_body_start(output: &[u8]) -> Option<usize> {
+    let start = informational_responses_len(output);
+    header_block_end(&output[start..]).map(|end| start + end)
+}
+
+/// How much of a module's output is complete informational (1xx) responses, which
+/// come ahead of the final response.
+pub(crate) fn informational_responses_len(output: &[u8]) -> usize {
+    let mut start = 0;
+    while let Some(end) = header_block_end(&output[start..]) {
+        let headers = String::from_utf8_lossy(&output[start..start + end]).into_owned();
+        let status = parse_cgi_headers(headers)
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("status"))
+            .and_then(|(_, value)| parse_status(&value));
+        match status {
+            Some((code, _)) if code.is_informational() => start += end,
+            _ => break,
+        }
+    }
+    start
+}
+
+// The offset just past the blank line that ends the header block, if there is one.
+// As when composing the response, carriage returns are ignored.
+fn header_block_end(output: &[u8]) -> Option<usize> {
+    let mut last = 0;
+    for (i, b) in output.iter().enumerate() {
+        match *b {
+            b'\r' => continue,
+            b'\n' if last == b'\n' => return Some(i + 1),
+            b => last = b,
+        }
+    }
+    None
+}
+
+/// Parses a CGI `Status` value: a three-digit code, optionally followed by a reason
+/// phrase. Some frameworks write a full status line (`HTTP/1.1 207 Multi-Status`),
+/// so a leading protocol version is ignored.
+pub(crate) fn parse_status(value: &str) -> Option<(StatusCode, Option<String>)> {
+    let mut value = value.trim();
+    if value.starts_with("HTTP/") {
+        value = value.split_once(char::is_whitespace)?.1.trim_start();
+    }
+    let (code, reason) = match value.split_once(char::is_whitespace) {
+        Some((code, reason)) => (code, reason.trim()),
+        None => (value, ""),
+    };
+    if code.len() != 3 {
+        return None;
+    }
+    let code = StatusCode::from_bytes(code.as_bytes()).ok()?;
+    let reason = if reason.is_empty() || code.canonical_reason() == Some(reason) {
+        None
+    } else {
+        Some(reason.to_owned())
+    };
+    Some((code, reason))
+}
+
+/// Parse the header block written by a module.
+///
+/// Headers are returned in the order the module wrote them, including repeats,
+/// because some headers (such as Set-Cookie) are legitimately sent more than once.
+pub(crate) fn parse_cgi_headers(headers: String) -> Vec<(String, String)> {
+    let mut parsed = vec![];
     headers.trim().split('\n').for_each(|h| {
         let parts: Vec<&str> = h.splitn(2, ':').collect();
         if parts.len() != 2 {
             tracing::warn!(header = h, "corrupt header");
             return;
         }
-        map.insert(parts[0].trim().to_owned(), parts[1].trim().to_owned());
+        parsed.push((parts[0].trim().to_owned(), parts[1].trim().to_owned()));
     });
-    map
+    parsed
+}
+
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// How the server's name, port and scheme are worked out for a request.
+pub struct HostSettings<'a> {
+    /// Used if the request has no `Host` header.
+    pub default_host: &'a str,
+    pub use_tls: bool,
+    /// Proxies whose `X-Forwarded-Host` and `X-Forwarded-Proto` headers are believed.
+    pub trusted_proxies: &'a [IpNetwork],
+}
+
+/// Checks that there is only one way to read where the request body ends. A front
+/// proxy that reads the body one way while WAGI reads it another can be made to
+/// pass a hidden second request through (request smuggling), so the combinations
+/// that proxies are known to disagree on are refused:
+///
+/// - both `Content-Length` and `Transfer-Encoding`
+/// - more than one `Content-Length`, or one that is not a plain number
+/// - any `Transfer-Encoding` other than a single `chunked`
+pub fn check_body_framing(headers: &HeaderMap) -> Result<(), &'static str> {
+    let content_lengths: Vec<_> = headers.get_all(CONTENT_LENGTH).iter().collect();
+    let transfer_encodings: Vec<_> = headers.get_all(TRANSFER_ENCODING).iter().collect();
+    if !content_lengths.is_empty() && !transfer_encodings.is_empty() {
+        return Err("Content-Length and Transfer-Encoding cannot both be given");
+    }
+    match content_lengths.as_slice() {
+        [] => {}
+        [length] if !length.is_empty() && length.as_bytes().iter().all(u8::is_ascii_digit) => {}
+        [_] => return Err("Content-Length must be a number"),
+        _ => return Err("Content-Length can only be given once"),
+    }
+    match transfer_encodings.as_slice() {
+        [] => {}
+        [encoding] if encoding.to_str().map_or(false, |e| e.trim().eq_ignore_ascii_case("chunked")) => {}
+        _ => return Err("the only Transfer-Encoding supported is chunked"),
+    }
+    Ok(())
 }
 
 // TODO: doesn't properly belong here - more about parsing headers into
@@ -49,11 +263,24 @@ pub fn build_headers(
     req: &Parts,
     content_length: usize,
     client_addr: SocketAddr,
-    default_host: &str,
-    use_tls: bool,
+    host_settings: &HostSettings,
+    decode_path_info: bool,
     environment: &HashMap<String, String>,
 ) -> HashMap<String, String> {
-    let (host, port) = parse_host_header_uri(&req.headers, &req.uri, default_host);
+    // Behind a proxy, the Host header and scheme are the proxy's, not the public ones
+    let from_trusted_proxy = host_settings.trusted_proxies.iter().any(|n| n.contains(client_addr.ip()));
+    let forwarded = |name: &str| match from_trusted_proxy {
+        true => first_forwarded_value(&req.headers, name),
+        false => None,
+    };
+    let use_tls = match forwarded(X_FORWARDED_PROTO) {
+        Some(proto) => proto.eq_ignore_ascii_case("https"),
+        None => host_settings.use_tls,
+    };
+    let (host, port) = match forwarded(X_FORWARDED_HOST) {
+        Some(forwarded_host) => split_forwarded_host(&forwarded_host, if use_tls { 443 } else { 80 }),
+        None => parse_host_header_uri(&req.headers, &req.uri, host_settings.default_host),
+    };
     let path_info = route.relative_path(req.uri.path());
 
     // Note that we put these first so that there is no chance that they overwrite
@@ -134,14 +361,19 @@ pub fn build_headers(
     // I am intentionally ignoring the PATH_INFO rule that says that a PATH_INFO
     // cannot have a path seperator in it. If it becomes important to distinguish
     // between what was decoded out of the path and what is encoded in the path,
-    // the X_RAW_PATH_INFO can be used.
+    // the X_RAW_PATH_INFO can be used, or decoding can be turned off for the
+    // route so that PATH_INFO is as raw as X_RAW_PATH_INFO.
     //
     // https://datatracker.ietf.org/doc/html/rfc3875#section-4.1.5
     let pathsegment = path_info;
-    let pathinfo = url_escape::decode(&pathsegment);
+    let pathinfo = match decode_path_info {
+        true => url_escape::decode(&pathsegment),
+        false => std::borrow::Cow::Borrowed(pathsegment.as_str()),
+    };
     headers.insert("X_RAW_PATH_INFO".to_owned(), pathsegment.clone());
     headers.insert("PATH_INFO".to_owned(), pathinfo.to_string());
-    // PATH_TRANSLATED is the url-decoded version of PATH_INFO
+    // PATH_TRANSLATED is the url-decoded version of PATH_INFO (or the raw one, if
+    // decoding is turned off)
     // https://datatracker.ietf.org/doc/html/rfc3875#section-4.1.6
     headers.insert("PATH_TRANSLATED".to_owned(), pathinfo.to_string());
 
@@ -165,17 +397,141 @@ pub fn build_headers(
             "HTTP_{}",
             header.0.as_str().to_uppercase().replace("-", "_")
         );
-        // Per spec 4.1.18, skip some headers
-        if key == "HTTP_AUTHORIZATION" || key == "HTTP_CONNECTION" {
+        // Per spec 4.1.18, skip some headers. The body the module reads has had
+        // any chunking removed, so its length is only in CONTENT_LENGTH.
+        if key == "HTTP_AUTHORIZATION" || key == "HTTP_CONNECTION" || key == "HTTP_CONTENT_LENGTH" || key == "HTTP_TRANSFER_ENCODING" {
             return;
         }
         let val = header.1.to_str().unwrap_or("CORRUPT VALUE").to_owned();
         headers.insert(key, val);
     });
 
+    // Not part of the spec, but saves every module from parsing HTTP_COOKIE itself.
+    for (key, val) in cookie_env_vars(&req.headers) {
+        headers.entry(key).or_insert(val);
+    }
+
     headers
 }
 
+const MAX_COOKIE_ENV_VARS: usize = 64;
+const MAX_COOKIE_VALUE_LEN: usize = 4096;
+
+/// Parse Cookie headers into `COOKIE_<NAME>` environment variables.
+///
+/// Names are upper-cased and any character that is not alphanumeric becomes `_`.
+/// If two cookies map to the same variable name, the first one wins. Oversized
+/// values are skipped rather than truncated, since a truncated cookie is worse than
+/// a missing one; the raw header is still available in HTTP_COOKIE.
+fn cookie_env_vars(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = vec![];
+    let cookies = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'));
+    for cookie in cookies {
+        let (name, value) = match cookie.split_once('=') {
+            Some((n, v)) => (n.trim(), v.trim()),
+            None => continue,
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(unquoted) => unquoted,
+            None => value,
+        };
+        if value.len() > MAX_COOKIE_VALUE_LEN {
+            tracing::debug!(cookie = name, "Cookie value too long to expose as env var");
+            continue;
+        }
+        let key = env_var_name("COOKIE_", name);
+        if vars.iter().any(|(k, _)| k == &key) {
+            continue;
+        }
+        if vars.len() >= MAX_COOKIE_ENV_VARS {
+            tracing::debug!("Too many cookies to expose as env vars; ignoring the rest");
+            break;
+        }
+        vars.push((key, value.to_owned()));
+    }
+    vars
+}
+
+// Each proxy on the way appends to the list, so the first value is the one the
+// client used
+fn first_forwarded_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+fn split_forwarded_host(value: &str, default_port: u16) -> (String, String) {
+    match value.rsplit_once(':') {
+        // The colons of an IPv6 address are inside brackets
+        Some((host, port)) if port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']')) => {
+            (host.to_owned(), port.to_owned())
+        }
+        _ => (value.to_owned(), default_port.to_string()),
+    }
+}
+
+// Upper-cases the name and replaces anything that is not a letter or digit with `_`
+pub(crate) fn env_var_name(prefix: &str, name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}{}", prefix, name)
+}
+
+const MAX_QUERY_ENV_VARS: usize = 64;
+const MAX_QUERY_VALUE_LEN: usize = 4096;
+const MAX_QUERY_PARAMS_JSON_LEN: usize = 64 * 1024;
+
+/// Parse a query string into `QUERY_<NAME>` environment variables, holding the
+/// first value of each parameter, and `X_QUERY_PARAMS`, a JSON object mapping
+/// each parameter to the list of its values.
+///
+/// Names are mangled as for cookies, and the same limits apply to the `QUERY_`
+/// variables. If the JSON would be longer than 64KiB it is left out. The raw query
+/// is still available in QUERY_STRING.
+pub fn query_env_vars(query: &str) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = vec![];
+    let mut params = serde_json::Map::new();
+    for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        if name.is_empty() {
+            continue;
+        }
+        if let serde_json::Value::Array(values) = params
+            .entry(name.as_ref())
+            .or_insert_with(|| serde_json::Value::Array(vec![]))
+        {
+            values.push(serde_json::Value::String(value.clone().into_owned()));
+        }
+        if value.len() > MAX_QUERY_VALUE_LEN {
+            tracing::debug!(param = %name, "Query parameter too long to expose as env var");
+            continue;
+        }
+        let key = env_var_name("QUERY_", &name);
+        if vars.iter().any(|(k, _)| k == &key) || vars.len() >= MAX_QUERY_ENV_VARS {
+            continue;
+        }
+        vars.push((key, value.into_owned()));
+    }
+    let json = serde_json::Value::Object(params).to_string();
+    if json.len() <= MAX_QUERY_PARAMS_JSON_LEN {
+        vars.push(("X_QUERY_PARAMS".to_owned(), json));
+    } else {
+        tracing::debug!("Query too long to expose as X_QUERY_PARAMS");
+    }
+    vars
+}
+
 /// Internal utility function for parsing a host header.
 ///
 /// This attempts to use three sources to construct a definitive host/port pair, ordering
@@ -240,6 +596,24 @@ mod test {
     use hyper::http::request::Request;
     use std::str::FromStr;
 
+    #[test]
+    fn http_requests_are_redirected_to_https() {
+        let redirect = |uri: &str, host: Option<&str>, port: u16| {
+            let mut req = Request::get(uri);
+            if let Some(host) = host {
+                req = req.header(HOST, host);
+            }
+            let res = https_redirect(&req.body(()).unwrap().into_parts().0, "example.com:3000", port);
+            assert_eq!(StatusCode::MOVED_PERMANENTLY, res.status());
+            res.headers()[hyper::header::LOCATION].to_str().unwrap().to_owned()
+        };
+        assert_eq!("https://wagi.dev/a/b?c=d", redirect("/a/b?c=d", Some("wagi.dev:80"), 443));
+        assert_eq!("https://wagi.dev:8443/", redirect("/", Some("wagi.dev"), 8443));
+        assert_eq!("https://[::1]/", redirect("/", Some("[::1]:8080"), 443));
+        assert_eq!("https://example.com/x", redirect("/x", None, 443));
+        assert_eq!("https://example.com/x", redirect("/x", Some("evil.com/path@"), 443));
+    }
+
     #[test]
     fn test_parse_host_header_uri() {
         // let module = Module::new("/base".to_string(), "file:///no/such/path.wasm".to_owned());
@@ -322,8 +696,8 @@ mod test {
             &req,
             content_length,
             client_addr,
-            default_host,
-            use_tls,
+            &HostSettings { default_host, use_tls, trusted_proxies: &[] },
+            true,
             &env,
         );
 
@@ -367,4 +741,127 @@ mod test {
         assert!(headers.get("HTTP_AUTHORIZATION").is_none());
         assert!(headers.get("HTTP_CONNECTION").is_none());
     }
+
+    #[test]
+    fn test_forwarded_host_from_trusted_proxies() {
+        let route = RoutePattern::parse("/path/...");
+        let (req, _) = Request::builder()
+            .uri("http://10.0.0.5:3000/path/test?foo=bar")
+            .header("Host", "10.0.0.5:3000")
+            .header("X-Forwarded-Host", "www.example.com, proxy.internal")
+            .header("X-Forwarded-Proto", "https")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        let host_settings = HostSettings { default_host: "localhost:3000", use_tls: false, trusted_proxies: &trusted_proxies };
+        let env = HashMap::new();
+
+        let headers = build_headers(&route, &req, 0, "10.1.2.3:40000".parse().unwrap(), &host_settings, true, &env);
+        assert_eq!("www.example.com", headers["SERVER_NAME"]);
+        assert_eq!("443", headers["SERVER_PORT"]);
+        assert_eq!("https://www.example.com:443/path/test?foo=bar", headers["X_FULL_URL"]);
+
+        // Anyone else could be making the headers up
+        let headers = build_headers(&route, &req, 0, "203.0.113.9:40000".parse().unwrap(), &host_settings, true, &env);
+        assert_eq!("10.0.0.5", headers["SERVER_NAME"]);
+        assert_eq!("http://10.0.0.5:3000/path/test?foo=bar", headers["X_FULL_URL"]);
+
+        assert_eq!(("[::1]".to_owned(), "8080".to_owned()), split_forwarded_host("[::1]:8080", 80));
+        assert_eq!(("[::1]".to_owned(), "80".to_owned()), split_forwarded_host("[::1]", 80));
+    }
+
+    #[test]
+    fn test_path_info_decoding() {
+        let route = RoutePattern::parse("/files/...");
+        let (req, _) = Request::builder()
+            .uri("/files/a%2Fb/c+d%20e")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let host_settings = HostSettings { default_host: "localhost:3000", use_tls: false, trusted_proxies: &[] };
+        let env = HashMap::new();
+
+        let decoded = build_headers(&route, &req, 0, "127.0.0.1:40000".parse().unwrap(), &host_settings, true, &env);
+        // An encoded slash can't be told apart from a real one, and plus signs are not spaces in paths
+        assert_eq!("/a/b/c+d e", decoded["PATH_INFO"]);
+        assert_eq!("/a/b/c+d e", decoded["PATH_TRANSLATED"]);
+        assert_eq!("/a%2Fb/c+d%20e", decoded["X_RAW_PATH_INFO"]);
+
+        let raw = build_headers(&route, &req, 0, "127.0.0.1:40000".parse().unwrap(), &host_settings, false, &env);
+        assert_eq!("/a%2Fb/c+d%20e", raw["PATH_INFO"]);
+        assert_eq!("/a%2Fb/c+d%20e", raw["PATH_TRANSLATED"]);
+        assert_eq!("/a%2Fb/c+d%20e", raw["X_RAW_PATH_INFO"]);
+    }
+
+    #[test]
+    fn test_cookie_env_vars() {
+        let mut hm = hyper::HeaderMap::new();
+        hm.append(COOKIE, "session=abc123; theme=\"dark\"".parse().unwrap());
+        hm.append(COOKIE, "user-id=42; session=ignored; bare".parse().unwrap());
+        hm.append(COOKIE, format!("huge={}", "x".repeat(MAX_COOKIE_VALUE_LEN + 1)).parse().unwrap());
+
+        let vars: HashMap<String, String> = cookie_env_vars(&hm).into_iter().collect();
+
+        assert_eq!(3, vars.len());
+        assert_eq!("abc123", vars["COOKIE_SESSION"]);
+        assert_eq!("dark", vars["COOKIE_THEME"]);
+        assert_eq!("42", vars["COOKIE_USER_ID"]);
+    }
+
+    #[test]
+    fn test_query_env_vars() {
+        let long = "x".repeat(MAX_QUERY_VALUE_LEN + 1);
+        let query = format!("name=J%C3%BCrgen+M&tag=a&tag=b&page-size=10&string=x&empty=&=nameless&long={}", long);
+        let vars: HashMap<String, String> = query_env_vars(&query).into_iter().collect();
+
+        assert_eq!("Jürgen M", vars["QUERY_NAME"]);
+        assert_eq!("a", vars["QUERY_TAG"]);
+        assert_eq!("10", vars["QUERY_PAGE_SIZE"]);
+        assert_eq!("", vars["QUERY_EMPTY"]);
+        assert!(!vars.contains_key("QUERY_LONG"));
+
+        let params: serde_json::Value = serde_json::from_str(&vars["X_QUERY_PARAMS"]).unwrap();
+        assert_eq!(serde_json::json!(["a", "b"]), params["tag"]);
+        assert_eq!(serde_json::json!([long]), params["long"]);
+        assert!(params.get("").is_none());
+    }
+
+    #[test]
+    fn test_check_body_framing() {
+        let headers = |pairs: &[(&str, &str)]| {
+            let mut hm = hyper::HeaderMap::new();
+            for (name, value) in pairs {
+                hm.append(hyper::header::HeaderName::from_str(name).unwrap(), value.parse().unwrap());
+            }
+            hm
+        };
+        assert!(check_body_framing(&headers(&[])).is_ok());
+        assert!(check_body_framing(&headers(&[("Content-Length", "12")])).is_ok());
+        assert!(check_body_framing(&headers(&[("Transfer-Encoding", "Chunked")])).is_ok());
+
+        assert!(check_body_framing(&headers(&[("Content-Length", "12"), ("Transfer-Encoding", "chunked")])).is_err());
+        assert!(check_body_framing(&headers(&[("Content-Length", "12"), ("Content-Length", "12")])).is_err());
+        assert!(check_body_framing(&headers(&[("Content-Length", "12, 13")])).is_err());
+        assert!(check_body_framing(&headers(&[("Content-Length", "+12")])).is_err());
+        assert!(check_body_framing(&headers(&[("Transfer-Encoding", "gzip, chunked")])).is_err());
+        assert!(check_body_framing(&headers(&[("Transfer-Encoding", "chunked"), ("Transfer-Encoding", "chunked")])).is_err());
+    }
+
+    #[test]
+    fn test_response_body_start() {
+        assert_eq!(None, response_body_start(b""));
+        assert_eq!(None, response_body_start(b"Content-Type: text/plain\n"));
+        assert_eq!(Some(26), response_body_start(b"Content-Type: text/plain\n\n"));
+        assert_eq!(Some(28), response_body_start(b"Content-Type: text/plain\r\n\r\nbody"));
+        assert_eq!(None, response_body_start(b"Content-Type: text/plain\r\nStatus: 200\r\n"));
+
+        // An interim response is not the module's headers
+        let early_hints = b"Status: 103\nLink: </style.css>; rel=preload\n\n";
+        assert_eq!(early_hints.len(), informational_responses_len(early_hints));
+        assert_eq!(None, response_body_start(early_hints));
+        let mut output = early_hints.to_vec();
+        output.extend_from_slice(b"Content-Type: text/plain\n\nbody");
+        assert_eq!(Some(output.len() - 4), response_body_start(&output));
+    }
 }
@@ -0,0 +1,134 @@
+//! Caps on how many instances of a module may exist at once.
+//!
+//! A route marked `max_instances = N` holds at most N Store+Instance pairs at a time,
+//! however many requests arrive. Further requests queue until an instance finishes,
+//! so a module with a large memory footprint cannot crowd everything else out of
+//! memory under load. This is separate from any limit on requests: requests for
+//! other routes are not held up.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::MetricsRegistry;
+
+const QUEUE_DEPTH_METRIC: &str = "wagi_module_instance_queue_depth";
+const QUEUED_METRIC: &str = "wagi_module_instance_queued_total";
+const WAIT_TIME_METRIC: &str = "wagi_module_instance_wait_seconds_total";
+
+#[derive(Debug)]
+pub struct InstanceLimit {
+    module: String,
+    max_instances: usize,
+    permits: Arc<Semaphore>,
+    waiting: Mutex<usize>,
+}
+
+impl InstanceLimit {
+    pub fn new(module: &str, max_instances: usize) -> Self {
+        Self {
+            module: module.to_owned(),
+            max_instances,
+            permits: Arc::new(Semaphore::new(max_instances)),
+            waiting: Mutex::new(0),
+        }
+    }
+
+    pub fn max_instances(&self) -> usize {
+        self.max_instances
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.max_instances - self.permits.available_permits()
+    }
+
+    /// Waits until the module may have another instance. The instance slot is
+    /// released when the permit is dropped.
+    pub async fn acquire(&self, metrics: &MetricsRegistry) -> OwnedSemaphorePermit {
+        // Most requests don't have to wait, and aren't counted as queued
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return permit;
+        }
+
+        let labels = [("module", self.module.as_str())];
+        metrics.increment_counter(QUEUED_METRIC, &labels);
+        let started = Instant::now();
+        let queued = QueuedRequest::new(self, metrics);
+        tracing::debug!(module = %self.module, max_instances = self.max_instances, "Module at its instance limit; queueing request");
+        // The semaphore is never closed
+        let permit = self.permits.clone().acquire_owned().await.expect("instance limit semaphore closed");
+        drop(queued);
+        metrics.add_to_counter(WAIT_TIME_METRIC, &labels, started.elapsed().as_secs_f64());
+        permit
+    }
+
+    fn update_waiting(&self, metrics: &MetricsRegistry, change: impl FnOnce(&mut usize)) {
+        let mut waiting = self.waiting.lock().unwrap();
+        change(&mut waiting);
+        metrics.set_gauge(QUEUE_DEPTH_METRIC, &[("module", self.module.as_str())], *waiting as f64);
+    }
+}
+
+// Counts a request in the queue depth while it waits, including if the wait is
+// abandoned because the client went away.
+struct QueuedRequest<'a> {
+    limit: &'a InstanceLimit,
+    metrics: &'a MetricsRegistry,
+}
+
+impl<'a> QueuedRequest<'a> {
+    fn new(limit: &'a InstanceLimit, metrics: &'a MetricsRegistry) -> Self {
+        limit.update_waiting(metrics, |w| *w += 1);
+        Self { limit, metrics }
+    }
+}
+
+impl Drop for QueuedRequest<'_> {
+    fn drop(&mut self) {
+        self.limit.update_waiting(self.metrics, |w| *w -= 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_over_the_limit_queue_until_an_instance_is_released() {
+        let metrics = MetricsRegistry::default();
+        let limit = Arc::new(InstanceLimit::new("heavy.wasm", 1));
+
+        let first = limit.acquire(&metrics).await;
+        assert_eq!(1, limit.in_use());
+        assert_eq!(0.0, metrics.counter_total(QUEUED_METRIC));
+
+        let waiter = {
+            let limit = limit.clone();
+            let metrics = metrics.clone();
+            tokio::spawn(async move { limit.acquire(&metrics).await })
+        };
+        tokio::task::yield_now().await;
+        assert!(metrics.render().contains("wagi_module_instance_queue_depth{module=\"heavy.wasm\"} 1"), "{}", metrics.render());
+
+        drop(first);
+        let second = waiter.await.unwrap();
+        assert_eq!(1, limit.in_use());
+        assert_eq!(1.0, metrics.counter_total(QUEUED_METRIC));
+        assert!(metrics.render().contains("wagi_module_instance_queue_depth{module=\"heavy.wasm\"} 0"), "{}", metrics.render());
+
+        drop(second);
+        assert_eq!(0, limit.in_use());
+    }
+
+    #[tokio::test]
+    async fn abandoned_waits_leave_the_queue() {
+        let metrics = MetricsRegistry::default();
+        let limit = InstanceLimit::new("heavy.wasm", 1);
+        let _held = limit.acquire(&metrics).await;
+
+        let timed_out = tokio::time::timeout(std::time::Duration::from_millis(10), limit.acquire(&metrics)).await;
+        assert!(timed_out.is_err());
+        assert!(metrics.render().contains("wagi_module_instance_queue_depth{module=\"heavy.wasm\"} 0"), "{}", metrics.render());
+    }
+}
@@ -0,0 +1,145 @@
+//! Warm standby instances for latency-sensitive routes.
+//!
+//! A route marked `preinstantiate = true` keeps a small pool of Store+Instance pairs
+//! ready to go. A pooled instance is created with a placeholder WASI context which is
+//! swapped for the real, per-request context when the instance is taken. That is only
+//! safe because nothing in the module runs before then: instantiation runs a module's
+//! start function, so modules that have one are never pooled (see
+//! `has_start_function`). Instances are single use: each one taken from the pool is
+//! replaced in the background.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use wasmtime::{Instance, Store};
+use wasmtime_wasi::WasiCtx;
+
+use crate::wasm_module::WasmModuleSource;
+use crate::wasm_runner::{prepare_wasm_instance, WasmLinkOptions};
+
+pub const DEFAULT_WARM_INSTANCES: usize = 2;
+
+pub struct InstancePool {
+    wasm_module_source: WasmModuleSource,
+    link_options: WasmLinkOptions,
+    capacity: usize,
+    warm: Mutex<Vec<(Store<WasiCtx>, Instance)>>,
+    refilling: AtomicBool,
+}
+
+impl Debug for InstancePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InstancePool")
+            .field("wasm_module_source", &self.wasm_module_source)
+            .field("capacity", &self.capacity)
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+impl InstancePool {
+    /// Creates a pool and synchronously fills it, so that routes are warm as soon
+    /// as the server starts serving.
+    pub fn new(wasm_module_source: &WasmModuleSource, link_options: WasmLinkOptions, capacity: usize) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            wasm_module_source: wasm_module_source.clone(),
+            link_options,
+            capacity,
+            warm: Mutex::new(Vec::with_capacity(capacity)),
+            refilling: AtomicBool::new(false),
+        });
+        pool.refill();
+        pool
+    }
+
+    /// Takes a warm instance if one is available. The caller must replace the
+    /// placeholder WASI context before running anything in the instance.
+    pub fn take(self: &Arc<Self>) -> Option<(Store<WasiCtx>, Instance)> {
+        let taken = self.warm.lock().unwrap().pop();
+        if taken.is_none() {
+            tracing::debug!(module = ?self.wasm_module_source, "Instance pool exhausted");
+        }
+        self.refill_in_background();
+        taken
+    }
+
+    pub fn available(&self) -> usize {
+        self.warm.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Drops the warm instances, for example because the module is being evicted.
+    /// The pool fills again the next time an instance is taken.
+    pub fn clear(&self) {
+        self.warm.lock().unwrap().clear();
+    }
+
+    fn refill_in_background(self: &Arc<Self>) {
+        if self.refilling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let pool = self.clone();
+        std::thread::spawn(move || {
+            pool.refill();
+            pool.refilling.store(false, Ordering::Release);
+        });
+    }
+
+    fn refill(&self) {
+        while self.available() < self.capacity {
+            // Refills happen on a plain thread (or at startup), so blocking is fine
+            let prepared = futures::executor::block_on(
+                prepare_wasm_instance(placeholder_context(), &self.wasm_module_source, self.link_options.clone())
+            );
+            match prepared {
+                Ok(pair) => self.warm.lock().unwrap().push(pair),
+                Err(e) => {
+                    tracing::warn!(error = %e, module = ?self.wasm_module_source, "Failed to pre-instantiate module");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn placeholder_context() -> WasiCtx {
+    wasi_cap_std_sync::WasiCtxBuilder::new().build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_module() -> WasmModuleSource {
+        let wat = br#"(module (func (export "_start")))"#;
+        let engine = WasmModuleSource::new_engine(std::path::Path::new("no-such-cache.toml"), &Default::default())
+            .expect("Test engine should have been created");
+        WasmModuleSource::from_module_bytes(Arc::new(wat.to_vec()), &engine)
+            .expect("Test module should have compiled")
+    }
+
+    #[test]
+    fn pool_is_filled_on_creation() {
+        let pool = InstancePool::new(&test_module(), WasmLinkOptions::none(), 2);
+        assert_eq!(2, pool.available());
+    }
+
+    #[test]
+    fn taken_instances_are_replaced() {
+        let pool = InstancePool::new(&test_module(), WasmLinkOptions::none(), 2);
+        assert!(pool.take().is_some());
+        assert!(pool.take().is_some());
+
+        for _ in 0..100 {
+            if pool.available() == 2 {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("Pool was not refilled in the background");
+    }
+}
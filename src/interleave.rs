@@ -0,0 +1,156 @@
+//! A timestamped record of a module's stdout and stderr, interleaved.
+//!
+//! With `--interleave-output`, each run of a module also writes a copy of its
+//! stdout and stderr to one file, in the order they were written, so that its log
+//! lines can be read alongside its response. This helps with malformed CGI output,
+//! such as headers written after the body has started. Each line starts with the
+//! time since the module started and the stream it went to, and the text is quoted
+//! and escaped so that carriage returns and missing newlines can be seen:
+//!
+//! ```text
+//! +0.000412s stdout "Content-Type: text/plain\n"
+//! +0.000498s stderr "handling /hello\n"
+//! +0.000530s stdout "\n"
+//! +0.000561s stdout "Hello"
+//! ```
+//!
+//! The files go in an `interleaved` directory in the module's log directory, one
+//! per run, named for when the run started. They are not cleaned up, so this is
+//! meant for debugging rather than production.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+const INTERLEAVED_DIR: &str = "interleaved";
+
+// Tells apart runs that start in the same millisecond
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+/// The diagnostic file for one run of a module.
+#[derive(Clone)]
+pub struct InterleavedLog {
+    started: Instant,
+    file: Arc<Mutex<File>>,
+}
+
+impl InterleavedLog {
+    /// Creates a new file in the `interleaved` directory of `log_dir`.
+    pub fn create(log_dir: &Path) -> anyhow::Result<Self> {
+        let dir = log_dir.join(INTERLEAVED_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let run = RUN_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{}.log", timestamp, run));
+        tracing::debug!(path = %path.display(), "Interleaving module output");
+        Self::create_at(&path)
+    }
+
+    /// Creates the file at `path`.
+    pub fn create_at(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            started: Instant::now(),
+            file: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+
+    /// Wraps a stream's destination so that everything written to it is also
+    /// recorded here.
+    pub fn tee<W: Write + Send + Sync>(&self, stream: Stream, destination: W) -> InterleavedWriter<W> {
+        InterleavedWriter {
+            log: self.clone(),
+            stream,
+            destination,
+        }
+    }
+
+    // One line per line written, so that a write holding several lines shows where
+    // each one ends
+    fn record(&self, stream: Stream, bytes: &[u8]) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let mut entry = String::new();
+        for line in bytes.split_inclusive(|b| *b == b'\n') {
+            entry.push_str(&format!("+{:.6}s {} {:?}\n", elapsed, stream.name(), String::from_utf8_lossy(line)));
+        }
+        // Losing the diagnostic copy must not fail the request
+        if let Err(e) = self.file.lock().unwrap().write_all(entry.as_bytes()) {
+            tracing::debug!(error = %e, "Error writing interleaved module output");
+        }
+    }
+}
+
+pub struct InterleavedWriter<W> {
+    log: InterleavedLog,
+    stream: Stream,
+    destination: W,
+}
+
+impl<W: Write> Write for InterleavedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.destination.write(buf)?;
+        self.log.record(self.stream, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.destination.flush()
+    }
+}
+
+/// Writes to the shared buffer that a module's stdout is collected in.
+pub struct SharedBuffer(pub Arc<RwLock<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streams_are_recorded_in_the_order_they_were_written() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let log = InterleavedLog::create(log_dir.path()).unwrap();
+        let stdout = Arc::new(RwLock::new(vec![]));
+        let mut out = log.tee(Stream::Stdout, SharedBuffer(stdout.clone()));
+        let mut err = log.tee(Stream::Stderr, std::io::sink());
+        out.write_all(b"Content-Type: text/plain\r\n").unwrap();
+        err.write_all(b"oops\n").unwrap();
+        out.write_all(b"\nHello").unwrap();
+
+        assert_eq!(b"Content-Type: text/plain\r\n\nHello".to_vec(), *stdout.read().unwrap());
+        let file = std::fs::read_dir(log_dir.path().join(INTERLEAVED_DIR)).unwrap().next().unwrap().unwrap();
+        let text = std::fs::read_to_string(file.path()).unwrap();
+        let records: Vec<&str> = text.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+        assert_eq!(
+            vec![r#"stdout "Content-Type: text/plain\r\n""#, r#"stderr "oops\n""#, r#"stdout "\n""#, r#"stdout "Hello""#],
+            records
+        );
+    }
+}
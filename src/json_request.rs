@@ -0,0 +1,120 @@
+//! Routes that take JSON request bodies.
+//!
+//! A route with `json = true` only accepts request bodies sent as
+//! `application/json`, and answers anything else with `415 Unsupported Media Type`
+//! without running the module. If the route also lists `json_fields`, the body must
+//! be a JSON object, and each listed top-level field that it contains is passed to
+//! the module as a `JSON_<FIELD>` environment variable, so that simple handlers
+//! don't need a JSON parser of their own.
+
+use std::collections::HashMap;
+
+use hyper::{header::CONTENT_TYPE, http::request::Parts, Body, Response};
+
+use crate::http_util::{bad_request, unsupported_media_type};
+
+const JSON_MEDIA_TYPE: &str = "application/json";
+const JSON_FIELD_ENV_PREFIX: &str = "JSON_";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JsonRequestSettings {
+    /// Top-level fields of the body to pass to the module as environment variables.
+    pub fields: Vec<String>,
+}
+
+impl JsonRequestSettings {
+    /// Checks the request, returning the environment variables for the listed
+    /// fields, or the response to reject it with. A request without a body, such
+    /// as a GET, is let through with no variables.
+    pub fn env_vars(&self, req: &Parts, body: &[u8]) -> Result<HashMap<String, String>, Response<Body>> {
+        let content_type = req.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        if body.is_empty() && content_type.is_none() {
+            return Ok(HashMap::new());
+        }
+        if !content_type.map(is_json_media_type).unwrap_or(false) {
+            return Err(unsupported_media_type(JSON_MEDIA_TYPE));
+        }
+        if self.fields.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let document: serde_json::Value = serde_json::from_slice(body)
+            .map_err(|e| bad_request(format!("Request body is not valid JSON: {}", e)))?;
+        let object = document.as_object()
+            .ok_or_else(|| bad_request("Request body must be a JSON object"))?;
+        Ok(self.fields
+            .iter()
+            .filter_map(|field| object.get(field).map(|value| (field_env_var_name(field), env_var_value(value))))
+            .collect())
+    }
+}
+
+// Parameters such as `charset` are allowed, and media types are case-insensitive
+fn is_json_media_type(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case(JSON_MEDIA_TYPE)
+}
+
+fn field_env_var_name(field: &str) -> String {
+    let name: String = field
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}{}", JSON_FIELD_ENV_PREFIX, name)
+}
+
+// Strings are passed without their quotes; anything else as JSON text
+fn env_var_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{Request, StatusCode};
+
+    fn parts(content_type: Option<&str>) -> Parts {
+        let mut builder = Request::post("/api");
+        if let Some(ct) = content_type {
+            builder = builder.header(CONTENT_TYPE, ct);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn settings(fields: &[&str]) -> JsonRequestSettings {
+        JsonRequestSettings { fields: fields.iter().map(|f| f.to_string()).collect() }
+    }
+
+    #[test]
+    fn listed_fields_become_env_vars() {
+        let body = br#"{"user-id": "u1", "count": 3, "tags": ["a"], "secret": "s"}"#;
+        let vars = settings(&["user-id", "count", "tags", "missing"])
+            .env_vars(&parts(Some("application/json; charset=utf-8")), body)
+            .unwrap();
+        assert_eq!(Some(&"u1".to_owned()), vars.get("JSON_USER_ID"));
+        assert_eq!(Some(&"3".to_owned()), vars.get("JSON_COUNT"));
+        assert_eq!(Some(&r#"["a"]"#.to_owned()), vars.get("JSON_TAGS"));
+        assert_eq!(3, vars.len());
+    }
+
+    #[test]
+    fn other_content_types_are_rejected() {
+        let rejection = settings(&[]).env_vars(&parts(Some("text/plain")), b"{}").unwrap_err();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, rejection.status());
+        let rejection = settings(&[]).env_vars(&parts(None), b"{}").unwrap_err();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, rejection.status());
+
+        assert!(settings(&["a"]).env_vars(&parts(None), b"").unwrap().is_empty());
+        assert!(settings(&[]).env_vars(&parts(Some("Application/JSON")), b"not checked").is_ok());
+    }
+
+    #[test]
+    fn bodies_that_are_not_objects_are_rejected_when_fields_are_listed() {
+        let rejection = settings(&["a"]).env_vars(&parts(Some("application/json")), b"{").unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, rejection.status());
+        let rejection = settings(&["a"]).env_vars(&parts(Some("application/json")), b"[1]").unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, rejection.status());
+    }
+}
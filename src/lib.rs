@@ -1,17 +1,62 @@
+pub mod accept_content_types;
+pub mod access_control;
+pub mod admin_access;
+pub mod audit;
+pub mod bench;
 pub(crate) mod bindle_util;
+pub mod build_info;
+pub mod byte_ranges;
+pub mod circuit_breaker;
+pub mod config_defaults;
+pub mod connection_limits;
+pub mod custom_handler;
+pub mod diagnostics;
 pub mod dispatcher;
 pub(crate) mod dynamic_route;
+pub mod error;
+pub mod error_report;
+pub mod executor;
+pub mod experiment;
+pub mod fallback;
 pub mod handler_loader;
+pub mod harden;
 pub mod handlers;
+pub mod header_limits;
+pub mod health_check;
+pub mod host_check;
 pub mod http_util;
+pub mod interleave;
+pub(crate) mod instance_limit;
+pub(crate) mod instance_pool;
+pub mod json_request;
+pub mod log_level;
+pub mod maintenance;
+pub mod memory_stats;
+pub mod metrics;
+pub mod module_eviction;
+pub mod outbound_http;
+pub mod outbound_network;
+pub mod recycle;
 mod request;
+pub mod route_explain;
+pub mod route_refresh;
+pub mod route_toggle;
+pub mod sampling;
+pub mod scheduler;
+pub mod spill;
+pub mod stderr;
+pub mod systemd;
+pub mod tenant;
 mod tls;
+pub mod url_limits;
 pub mod version;
+pub mod volume_overlay;
 pub mod wagi_app;
 pub mod wagi_config;
 pub mod wagi_server;
 pub mod wasm_module;
 pub(crate) mod wasm_runner;
+pub mod watch;
 
 #[cfg(test)]
 mod upstream;
@@ -20,7 +65,7 @@ mod upstream;
 mod test {
     use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
-    use crate::{dispatcher::RoutingTable, wagi_app};
+    use crate::{dispatcher::RoutingTable, wagi_app, wagi_config::WagiConfiguration};
 
     fn test_data_dir() -> PathBuf {
         let project_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -81,6 +126,9 @@ mod test {
     const WAT_MODULE_MAP_FILE: &str = "wat.toml";
     const TEST_HEALTHZ_MODULE_MAP_FILE: &str = "test_healthz_override.toml";
     const TEST_DYNAMIC_ROUTES_MODULE_MAP_FILE: &str = "test_dynamic_routes.toml";
+    const TEST_EXPERIMENT_MODULE_MAP_FILE: &str = "test_experiment.toml";
+    const TEST_AUDIT_MODULE_MAP_FILE: &str = "test_audit.toml";
+    const TEST_FALLBACK_MODULE_MAP_FILE: &str = "test_fallback.toml";
 
     async fn build_routing_table_for_standalone_bindle(bindle_id: &str) -> RoutingTable {
         // Clear any env vars that would cause conflicts if set
@@ -139,6 +187,76 @@ mod test {
         .expect("Error producing HTTP response")
     }
 
+    #[tokio::test]
+    pub async fn builder_configuration_serves_modules() {
+        let modules_toml_path = replace_placeholders(TEST1_MODULE_MAP_FILE, None).await;
+        let configuration = WagiConfiguration::builder()
+            .modules_config_file(modules_toml_path)
+            .build()
+            .expect("Test configuration was not valid");
+        let handlers = crate::handler_loader::load_handlers(&configuration).await
+            .expect("Failed to load handlers");
+        let routing_table = crate::dispatcher::RoutingTable::build(&handlers, configuration.request_global_context())
+            .expect("Failed to build routing table");
+
+        let request = hyper::Request::get("http://127.0.0.1:3000/").body(hyper::body::Body::empty()).unwrap();
+        let response = routing_table.handle_request(request, mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+    }
+
+    // The builder and the command line must not drift apart on the settings the
+    // builder leaves at their defaults.
+    #[test]
+    pub fn builder_defaults_match_the_command_line() {
+        std::env::remove_var("BINDLE_URL");
+        let modules_toml = test_data_dir().join("module-maps").join(TEST1_MODULE_MAP_FILE);
+        let matches = wagi_app::wagi_app_definition().get_matches_from(vec![
+            "wagi",
+            "-c", &modules_toml.display().to_string(),
+        ]);
+        let parsed = wagi_app::parse_configuration_from(matches).expect("Fake command line was not valid");
+        let built = WagiConfiguration::builder().modules_config_file(modules_toml.clone()).build().expect("Test configuration was not valid");
+
+        assert_eq!(parsed.http_configuration.listen_on, built.http_configuration.listen_on);
+        assert_eq!(parsed.http_configuration.default_hostname, built.http_configuration.default_hostname);
+        assert_eq!(parsed.http_configuration.tls.is_some(), built.http_configuration.tls.is_some());
+        assert_eq!(parsed.http_configuration.trusted_proxies.len(), built.http_configuration.trusted_proxies.len());
+        assert_eq!(parsed.http_configuration.connections, built.http_configuration.connections);
+        assert_eq!(parsed.env_vars, built.env_vars);
+        assert_eq!(parsed.wasm_cache_config_file, built.wasm_cache_config_file);
+        assert_eq!(parsed.engine_settings, built.engine_settings);
+        assert_eq!(parsed.compiled_module_cache, built.compiled_module_cache);
+        assert_eq!(parsed.module_idle_ttl, built.module_idle_ttl);
+        assert_eq!(parsed.dynamic_routes_refresh_interval, built.dynamic_routes_refresh_interval);
+        assert_eq!(parsed.shared_module_cache, built.shared_module_cache);
+        assert_eq!(parsed.compress_module_cache, built.compress_module_cache);
+        assert_eq!(parsed.fetch_retry, built.fetch_retry);
+        assert_eq!(parsed.retry_fetch_in_background, built.retry_fetch_in_background);
+        assert_eq!(parsed.missing_parcel, built.missing_parcel);
+        assert_eq!(parsed.bindle_annotations, built.bindle_annotations);
+        assert_eq!(parsed.on_compile_error, built.on_compile_error);
+        assert_eq!(parsed.verbose_log_filter, built.verbose_log_filter);
+        assert_eq!(parsed.default_content_type, built.default_content_type);
+        assert_eq!(parsed.default_charset, built.default_charset);
+        assert_eq!(parsed.watch, built.watch);
+        assert_eq!(parsed.harden, built.harden);
+        assert_eq!(parsed.admin_token, built.admin_token);
+        assert_eq!(parsed.debug_errors, built.debug_errors);
+        assert_eq!(parsed.interleave_output, built.interleave_output);
+        assert_eq!(parsed.overlay_dir, built.overlay_dir);
+        assert_eq!(parsed.allow_missing_volumes, built.allow_missing_volumes);
+        assert_eq!(parsed.response_header_timeout, built.response_header_timeout);
+        assert_eq!(parsed.module_timeout, built.module_timeout);
+        assert_eq!(parsed.trace_headers, built.trace_headers);
+        assert_eq!(parsed.deny_outbound_http, built.deny_outbound_http);
+        assert_eq!(parsed.check_allowed_hosts, built.check_allowed_hosts);
+        assert_eq!(parsed.header_limits, built.header_limits);
+        assert_eq!(parsed.drain_period, built.drain_period);
+        assert_eq!(parsed.recycle, built.recycle);
+        assert_eq!(parsed.health_check, built.health_check);
+        assert_eq!(parsed.bench.is_some(), built.bench.is_some());
+    }
+
     async fn get_plain_text_response_from_module_map(map_file: &str, custom_subs: Option<HashMap<String, String>>, route: &str) -> String {
         let empty_body = hyper::body::Body::empty();
         let uri = format!("http://127.0.0.1:3000{}", route);
@@ -350,6 +468,96 @@ mod test {
         assert_eq!("Oh hi world\r\n", response);
     }
 
+    #[tokio::test]
+    pub async fn can_serve_in_memory_modules() {
+        use crate::wagi_config::{HandlerConfigurationSource, InMemoryModule, WagiConfiguration};
+
+        let entrypoints_module = include_bytes!("../testdata/module-maps/multiple-entrypoints.wasm").to_vec();
+        let modules = vec![
+            InMemoryModule::new("/", "crlf.wat", include_bytes!("../testdata/module-maps/crlf.wat").to_vec()),
+            InMemoryModule::new("/ep1", "multiple-entrypoints", entrypoints_module).with_entrypoint("ep1"),
+        ];
+        let configuration = WagiConfiguration::new(HandlerConfigurationSource::InMemory(modules))
+            .expect("Failed to create configuration");
+        let handlers = crate::handler_loader::load_handlers(&configuration).await
+            .expect("Failed to load handlers");
+        let routing_table = RoutingTable::build(&handlers, configuration.request_global_context())
+            .expect("Failed to build routing table");
+
+        for (route, expected) in [("/", "Oh hi world\r\n"), ("/ep1", "Entrypoint 1\n")] {
+            let request = hyper::Request::get(format!("http://127.0.0.1:3000{}", route))
+                .body(hyper::body::Body::empty())
+                .expect("Failed to construct mock request");
+            let response = routing_table.handle_request(request, mock_client_addr()).await
+                .expect("Error producing HTTP response");
+            assert_eq!(hyper::StatusCode::OK, response.status(), "Non-OK status getting route {}", route);
+            let response_body = hyper::body::to_bytes(response.into_body()).await
+                .expect("Could not get bytes from response body");
+            assert_eq!(expected, std::str::from_utf8(&response_body).expect("Could not read body as string"));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn trusted_modules_are_exempt_from_header_limits() {
+        use crate::header_limits::{HeaderLimits, HeaderValuePolicy};
+        use crate::wagi_config::{HandlerConfigurationSource, InMemoryModule, WagiConfiguration};
+
+        let crlf = include_bytes!("../testdata/module-maps/crlf.wat").to_vec();
+        let modules = vec![
+            InMemoryModule::new("/third-party", "crlf.wat", crlf.clone()),
+            InMemoryModule::new("/first-party", "crlf.wat", crlf).trusted(),
+        ];
+        let mut configuration = WagiConfiguration::new(HandlerConfigurationSource::InMemory(modules))
+            .expect("Failed to create configuration");
+        configuration.header_limits = HeaderLimits { max_value_len: 16, policy: HeaderValuePolicy::Reject };
+        let handlers = crate::handler_loader::load_handlers(&configuration).await
+            .expect("Failed to load handlers");
+        let routing_table = RoutingTable::build(&handlers, configuration.request_global_context())
+            .expect("Failed to build routing table");
+
+        for (route, expected) in [("/third-party", hyper::StatusCode::BAD_REQUEST), ("/first-party", hyper::StatusCode::OK)] {
+            let request = hyper::Request::get(format!("http://127.0.0.1:3000{}", route))
+                .header("x-long", "a".repeat(100))
+                .body(hyper::body::Body::empty())
+                .expect("Failed to construct mock request");
+            let response = routing_table.handle_request(request, mock_client_addr()).await
+                .expect("Error producing HTTP response");
+            assert_eq!(expected, response.status(), "Unexpected status getting route {}", route);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn custom_handlers_are_served_alongside_modules() {
+        use crate::wagi_config::{HandlerConfigurationSource, InMemoryModule, WagiConfiguration};
+
+        let modules = vec![
+            InMemoryModule::new("/...", "crlf.wat", include_bytes!("../testdata/module-maps/crlf.wat").to_vec()),
+        ];
+        let configuration = WagiConfiguration::new(HandlerConfigurationSource::InMemory(modules))
+            .expect("Failed to create configuration")
+            .with_custom_handler("/native/...", |parts: &hyper::http::request::Parts, body: Vec<u8>, _: SocketAddr| {
+                let text = format!("{} {} bytes", parts.uri.path(), body.len());
+                async move { hyper::Response::new(hyper::body::Body::from(text)) }
+            });
+        let handlers = crate::handler_loader::load_handlers(&configuration).await
+            .expect("Failed to load handlers");
+        let routing_table = RoutingTable::build(&handlers, configuration.request_global_context())
+            .expect("Failed to build routing table");
+
+        // The custom route comes before the module's wildcard
+        for (route, expected) in [("/native/page", "/native/page 4 bytes"), ("/other", "Oh hi world\r\n")] {
+            let request = hyper::Request::post(format!("http://127.0.0.1:3000{}", route))
+                .body(hyper::body::Body::from("body"))
+                .expect("Failed to construct mock request");
+            let response = routing_table.handle_request(request, mock_client_addr()).await
+                .expect("Error producing HTTP response");
+            assert_eq!(hyper::StatusCode::OK, response.status(), "Non-OK status getting route {}", route);
+            let response_body = hyper::body::to_bytes(response.into_body()).await
+                .expect("Could not get bytes from response body");
+            assert_eq!(expected, std::str::from_utf8(&response_body).expect("Could not read body as string"));
+        }
+    }
+
     fn parse_ev_line(line: &str) -> Option<(String, String)> {
         line.find('=').and_then(|index| {
             let left = &line[..index];
@@ -679,6 +887,188 @@ mod test {
         assert_eq!("OK", response_text);
     }
 
+    #[tokio::test]
+    pub async fn not_found_falls_back_to_the_next_module() {
+        let response = get_plain_text_response_from_module_map(TEST_FALLBACK_MODULE_MAP_FILE, None, "/anything").await;
+        assert_eq!("Oh hi world\r\n", response);
+
+        // Without a fallback, the 404 is the answer
+        let request = hyper::Request::get("http://127.0.0.1:3000/missing").body(hyper::body::Body::empty());
+        let response = send_request_to_module_map(TEST_FALLBACK_MODULE_MAP_FILE, None, request).await;
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    pub async fn experiment_variants_are_sticky() {
+        let routing_table = build_routing_table_for_module_map(TEST_EXPERIMENT_MODULE_MAP_FILE, None).await;
+        let body_of = |response: hyper::Response<hyper::body::Body>| async move {
+            let bytes = hyper::body::to_bytes(response.into_body()).await.expect("Could not get bytes from response body");
+            String::from_utf8(bytes.to_vec()).expect("Could not read body as string")
+        };
+
+        let request = hyper::Request::get("http://127.0.0.1:3000/checkout")
+            .header("Cookie", "wagi_exp_checkout=two")
+            .body(hyper::body::Body::empty())
+            .unwrap();
+        let response = routing_table.handle_request(request, mock_client_addr()).await.unwrap();
+        assert!(response.headers().get("Set-Cookie").is_none(), "Assigned client should keep its cookie");
+        assert_eq!("Entrypoint 2\n", body_of(response).await);
+
+        let request = hyper::Request::get("http://127.0.0.1:3000/checkout").body(hyper::body::Body::empty()).unwrap();
+        let response = routing_table.handle_request(request, mock_client_addr()).await.unwrap();
+        let cookie = response.headers().get("Set-Cookie").expect("New client should be assigned a variant").to_str().unwrap().to_owned();
+        let expected_body = if cookie.starts_with("wagi_exp_checkout=one;") { "Entrypoint 1\n" } else { "Entrypoint 2\n" };
+        assert_eq!(expected_body, body_of(response).await);
+    }
+
+    #[tokio::test]
+    pub async fn audited_routes_are_recorded() {
+        let routing_table = build_routing_table_for_module_map(TEST_AUDIT_MODULE_MAP_FILE, None).await;
+
+        let request = hyper::Request::post("http://127.0.0.1:3000/pay").body(hyper::body::Body::from("amount=10")).unwrap();
+        let response = routing_table.handle_request(request, mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let request = hyper::Request::get("http://127.0.0.1:3000/other").body(hyper::body::Body::empty()).unwrap();
+        routing_table.handle_request(request, mock_client_addr()).await.unwrap();
+
+        let audit_log = routing_table.global_context().audit_log.path().to_owned();
+        assert_eq!(2, crate::audit::verify_chain(&audit_log).expect("Audit log should be a valid chain"));
+        let entries = audit_entries(&audit_log);
+        assert_eq!("started", entries[0]["event"]);
+        assert_eq!("/pay", entries[0]["route"]);
+        assert_eq!("POST", entries[0]["method"]);
+        assert_eq!("amou", entries[0]["body"]);
+        assert_eq!(true, entries[0]["body_truncated"]);
+        assert_eq!("completed", entries[1]["event"]);
+        assert_eq!(entries[0]["seq"], entries[1]["started_seq"]);
+        assert_eq!(200, entries[1]["status"]);
+    }
+
+    #[tokio::test]
+    pub async fn abandoned_audited_requests_are_recorded() {
+        let routing_table = build_routing_table_for_module_map(TEST_AUDIT_MODULE_MAP_FILE, None).await;
+
+        // As when the client disconnects: hyper drops the future while the module runs
+        let request = hyper::Request::get("http://127.0.0.1:3000/slow").body(hyper::body::Body::empty()).unwrap();
+        let handling = routing_table.handle_request(request, mock_client_addr());
+        tokio::time::timeout(std::time::Duration::from_millis(500), handling).await
+            .expect_err("The module should still have been running");
+
+        // The abandoned entry is written on a blocking thread
+        let audit_log = routing_table.global_context().audit_log.path().to_owned();
+        let mut waited = 0;
+        while crate::audit::verify_chain(&audit_log).unwrap_or_default() < 2 && waited < 50 {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            waited += 1;
+        }
+        let entries = audit_entries(&audit_log);
+        assert_eq!(2, entries.len());
+        assert_eq!("started", entries[0]["event"]);
+        assert_eq!("/slow", entries[0]["route"]);
+        assert_eq!("abandoned", entries[1]["event"]);
+        assert_eq!(entries[0]["seq"], entries[1]["started_seq"]);
+        assert_eq!(0, entries[1]["status"]);
+    }
+
+    fn audit_entries(audit_log: &std::path::Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(audit_log).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    pub async fn dynamic_routes_can_be_refreshed_from_the_local_machine() {
+        let routing_table = build_routing_table_for_module_map(TEST_DYNAMIC_ROUTES_MODULE_MAP_FILE, None).await;
+        let routes_before = routing_table.describe_routes();
+        let live = crate::dispatcher::LiveRoutingTable::new(routing_table);
+        let refresh = |query: &str| hyper::Request::post(format!("http://127.0.0.1:3000/_wagi/routes/refresh{}", query)).body(hyper::body::Body::empty()).unwrap();
+        let local: SocketAddr = "127.0.0.1:7890".parse().unwrap();
+
+        let response = live.handle_request(refresh("?route=/exactparent"), mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+        // A proxy on the same machine makes every client look local
+        let mut proxied = refresh("?route=/exactparent");
+        proxied.headers_mut().insert("x-forwarded-for", hyper::header::HeaderValue::from_static("203.0.113.9"));
+        let response = live.handle_request(proxied, local).await.unwrap();
+        assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+        let response = live.handle_request(refresh("?route=/nope"), local).await.unwrap();
+        assert_eq!(hyper::StatusCode::NOT_FOUND, response.status());
+
+        let before = live.current();
+        let response = live.handle_request(refresh("?route=/exactparent"), local).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        assert!(!std::sync::Arc::ptr_eq(&before, &live.current()), "Refreshing should replace the table");
+        // The module returns the same routes, in the same place, each time
+        assert_eq!(routes_before, live.current().describe_routes());
+    }
+
+    #[tokio::test]
+    pub async fn explain_shows_which_route_a_path_goes_to() {
+        let routing_table = build_routing_table_for_module_map(TEST_DYNAMIC_ROUTES_MODULE_MAP_FILE, None).await;
+        let explain = || hyper::Request::get("http://127.0.0.1:3000/_wagi/routes/explain?path=/exactparent/wildcard/fizz/buzz")
+            .body(hyper::body::Body::empty())
+            .unwrap();
+        let response = routing_table.handle_request(explain(), mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+        let local: SocketAddr = "127.0.0.1:7890".parse().unwrap();
+        let response = routing_table.handle_request(explain(), local).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let explanation: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!("/exactparent/wildcard/...", explanation["selected"]);
+        let routes = explanation["routes"].as_array().unwrap();
+        let selected: Vec<_> = routes.iter().filter(|r| r["selected"] == true).collect();
+        assert_eq!(1, selected.len());
+        assert_eq!("/exactparent", selected[0]["added_by"], "the route comes from the module's _routes");
+        let parent = routes.iter().find(|r| r["route"] == "/exactparent").unwrap();
+        assert_eq!(false, parent["matched"]);
+    }
+
+    #[tokio::test]
+    pub async fn task_status_is_only_shown_to_operators() {
+        let routing_table = build_routing_table_for_module_map(TEST1_MODULE_MAP_FILE, None).await;
+        let tasks = || hyper::Request::get("http://127.0.0.1:3000/_wagi/tasks").body(hyper::body::Body::empty()).unwrap();
+
+        let response = routing_table.handle_request(tasks(), mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+        let local: SocketAddr = "127.0.0.1:7890".parse().unwrap();
+        let response = routing_table.handle_request(tasks(), local).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    pub async fn metrics_are_only_shown_to_operators() {
+        let routing_table = build_routing_table_for_module_map(TEST1_MODULE_MAP_FILE, None).await;
+        let metrics = || hyper::Request::get("http://127.0.0.1:3000/_wagi/metrics").body(hyper::body::Body::empty()).unwrap();
+
+        let response = routing_table.handle_request(metrics(), mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::FORBIDDEN, response.status());
+        let local: SocketAddr = "127.0.0.1:7890".parse().unwrap();
+        let response = routing_table.handle_request(metrics(), local).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    pub async fn version_only_shows_the_inventory_to_operators() {
+        let routing_table = build_routing_table_for_module_map(TEST1_MODULE_MAP_FILE, None).await;
+        let version = || hyper::Request::get("http://127.0.0.1:3000/_wagi/version").body(hyper::body::Body::empty()).unwrap();
+
+        let response = routing_table.handle_request(version(), mock_client_addr()).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(json["wagi_version"].is_string());
+        assert!(json.get("modules").is_none(), "Other clients should not see the modules");
+
+        let local: SocketAddr = "127.0.0.1:7890".parse().unwrap();
+        let response = routing_table.handle_request(version(), local).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(!json["modules"].as_array().unwrap().is_empty());
+    }
+
     // This test is run synchronously because if we use tokio::test, something hangs inside
     // wasi-experimental-http-wasmtime while sending the HTTP request.  (This *doesn't* affect
     // normal use - the library is careful to check for the presence of a Tokio runtime -
@@ -0,0 +1,225 @@
+//! Changing what WAGI logs while it runs.
+//!
+//! WAGI starts with the filter in `RUST_LOG`. On Unix, `SIGUSR1` switches between
+//! that filter and the more verbose one given by `--verbose-log-filter`, and from
+//! the local machine the inbuilt log level route shows or sets the filter:
+//!
+//! ```text
+//! curl -X POST 'http://localhost:3000/_wagi/log-level?filter=wagi=trace,info'
+//! curl -X DELETE 'http://localhost:3000/_wagi/log-level'
+//! ```
+//!
+//! Changes last until WAGI restarts.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use hyper::{http::request::Parts, Body, Method, Response};
+use tracing_subscriber::EnvFilter;
+
+use crate::admin_access::AdminAccess;
+use crate::http_util::{bad_request, forbidden, internal_error, method_not_allowed};
+
+/// The path at which the inbuilt log level handler is mounted.
+pub const LOG_LEVEL_ROUTE: &str = "/_wagi/log-level";
+pub const DEFAULT_VERBOSE_LOG_FILTER: &str = "debug";
+
+type Reload = dyn Fn(EnvFilter) -> anyhow::Result<()> + Send + Sync;
+
+/// The filter of the installed tracing subscriber, which can be replaced.
+#[derive(Clone)]
+pub struct LogLevel {
+    reload: Arc<Reload>,
+    startup: String,
+    current: Arc<Mutex<String>>,
+}
+
+impl std::fmt::Debug for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogLevel")
+            .field("startup", &self.startup)
+            .field("current", &self.current())
+            .finish()
+    }
+}
+
+impl LogLevel {
+    /// Installs the global tracing subscriber, writing to stderr with the filter
+    /// in `RUST_LOG`, and returns the control for its filter.
+    pub fn init_subscriber() -> Self {
+        // With no directives, EnvFilter only lets errors through
+        let startup = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| "error".to_owned());
+        let builder = tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_env_filter(EnvFilter::new(&startup))
+            .with_filter_reloading();
+        let handle = builder.reload_handle();
+        builder.init();
+        Self::new(startup, move |filter| handle.reload(filter).map_err(anyhow::Error::from))
+    }
+
+    pub fn new(startup: String, reload: impl Fn(EnvFilter) -> anyhow::Result<()> + Send + Sync + 'static) -> Self {
+        Self {
+            reload: Arc::new(reload),
+            current: Arc::new(Mutex::new(startup.clone())),
+            startup,
+        }
+    }
+
+    pub fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Replaces the filter. The filter uses the same syntax as `RUST_LOG`.
+    pub fn set(&self, filter: &str) -> anyhow::Result<()> {
+        let parsed = EnvFilter::try_new(filter)
+            .map_err(|e| anyhow::anyhow!("Invalid log filter '{}': {}", filter, e))?;
+        let mut current = self.current.lock().unwrap();
+        (self.reload)(parsed)?;
+        *current = filter.to_owned();
+        // At warn level so that it shows whatever the new filter is
+        tracing::warn!(filter, "Log filter changed");
+        Ok(())
+    }
+
+    /// Goes back to the filter WAGI started with.
+    pub fn reset(&self) -> anyhow::Result<()> {
+        self.set(&self.startup)
+    }
+
+    /// Switches to the verbose filter, or back to the startup one if it is not in
+    /// use. Returns the filter now in use.
+    pub fn toggle(&self, verbose: &str) -> anyhow::Result<String> {
+        if self.current() == self.startup {
+            self.set(verbose)?;
+        } else {
+            self.reset()?;
+        }
+        Ok(self.current())
+    }
+
+    /// Handles a request to the inbuilt log level route. Anyone may see the filter,
+    /// but only clients that `access` allows may change it.
+    pub fn handle_request(&self, parts: &Parts, client_addr: SocketAddr, access: &AdminAccess) -> Response<Body> {
+        let result = match parts.method {
+            Method::GET | Method::HEAD => return self.response(),
+            Method::POST | Method::DELETE if !access.allows(parts, client_addr) => {
+                tracing::info!(client_addr = %client_addr, "Refusing to change log filter for a client that is not an operator");
+                return forbidden();
+            }
+            Method::POST => match filter_param(parts.uri.query().unwrap_or_default()) {
+                Some(filter) if EnvFilter::try_new(&filter).is_err() => return bad_request(format!("invalid log filter '{}'", filter)),
+                Some(filter) => self.set(&filter),
+                None => return bad_request("the filter parameter is required"),
+            },
+            Method::DELETE => self.reset(),
+            _ => return method_not_allowed("GET, HEAD, POST, DELETE"),
+        };
+        match result {
+            Ok(()) => self.response(),
+            Err(e) => internal_error(format!("Could not change log filter: {:#}", e)),
+        }
+    }
+
+    fn response(&self) -> Response<Body> {
+        let body = serde_json::json!({
+            "filter": self.current(),
+            "startup_filter": self.startup,
+        });
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+fn filter_param(query: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "filter")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Toggles between the startup and verbose filters each time the process receives
+/// `SIGUSR1`. Does nothing on platforms without it.
+pub async fn toggle_on_signal(log_level: LogLevel, verbose: String) {
+    imp::toggle_on_signal(log_level, verbose).await
+}
+
+#[cfg(unix)]
+mod imp {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use super::LogLevel;
+
+    pub async fn toggle_on_signal(log_level: LogLevel, verbose: String) {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!(error = %e, "Can't listen for SIGUSR1; the log filter can only be changed at the log level route");
+                return;
+            }
+        };
+        while usr1.recv().await.is_some() {
+            if let Err(e) = log_level.toggle(&verbose) {
+                tracing::error!(error = %format!("{:#}", e), "Received SIGUSR1 but could not change log filter");
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::LogLevel;
+
+    pub async fn toggle_on_signal(_log_level: LogLevel, _verbose: String) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn log_level() -> (LogLevel, Arc<Mutex<Vec<String>>>) {
+        let applied = Arc::new(Mutex::new(vec![]));
+        let recorder = applied.clone();
+        let log_level = LogLevel::new("info".to_owned(), move |filter| {
+            recorder.lock().unwrap().push(filter.to_string());
+            Ok(())
+        });
+        (log_level, applied)
+    }
+
+    #[test]
+    fn toggle_switches_between_startup_and_verbose_filters() {
+        let (log_level, applied) = log_level();
+        assert_eq!("wagi=trace", log_level.toggle("wagi=trace").unwrap());
+        assert_eq!("info", log_level.toggle("wagi=trace").unwrap());
+        assert_eq!(vec!["wagi=trace", "info"], *applied.lock().unwrap());
+    }
+
+    #[test]
+    fn invalid_filters_are_not_applied() {
+        let (log_level, applied) = log_level();
+        log_level.set("wagi=loud").expect_err("loud is not a level");
+        assert_eq!("info", log_level.current());
+        assert!(applied.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_local_clients_can_change_the_filter() {
+        let (log_level, _) = log_level();
+        let parts = |method: &str, uri: &str| hyper::Request::builder().method(method).uri(uri).body(()).unwrap().into_parts().0;
+        let local: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "203.0.113.9:5000".parse().unwrap();
+        let access = AdminAccess::default();
+
+        let res = log_level.handle_request(&parts("POST", "/_wagi/log-level?filter=debug"), remote, &access);
+        assert_eq!(hyper::StatusCode::FORBIDDEN, res.status());
+        let res = log_level.handle_request(&parts("POST", "/_wagi/log-level?filter=debug"), local, &access);
+        assert_eq!(hyper::StatusCode::OK, res.status());
+        assert_eq!("debug", log_level.current());
+        let res = log_level.handle_request(&parts("POST", "/_wagi/log-level"), local, &access);
+        assert_eq!(hyper::StatusCode::BAD_REQUEST, res.status());
+        log_level.handle_request(&parts("DELETE", "/_wagi/log-level"), local, &access);
+        assert_eq!("info", log_level.current());
+    }
+}
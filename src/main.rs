@@ -1,21 +1,131 @@
-use wagi::{wagi_app, wagi_server::WagiServer};
+use std::time::Duration;
 
-#[tokio::main]
-pub async fn main() -> Result<(), anyhow::Error> {
-    let startup_span = tracing::info_span!("total startup").entered();
+use tracing::Instrument;
+use wagi::{
+    dispatcher::RoutingTable,
+    error::{WagiError, WagiResult},
+    handler_loader::WasmHandlerConfiguration,
+    wagi_app,
+    wagi_config::WagiConfiguration,
+    wagi_server::WagiServer,
+};
 
-    let configuration = wagi_app::parse_command_line()?;
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> WagiResult<()> {
+    let configuration = match wagi_app::parse_command_line()?.into_serve() {
+        Some(configuration) => configuration,
+        None => return Ok(()),
+    };
+
+    let runtime = new_runtime()?;
+    if configuration.bench.is_none() && !configuration.harden {
+        // Nothing has to happen between loading modules and serving them, so start
+        // listening straight away and answer 503 until the modules are ready.
+        return runtime.block_on(start_and_serve(configuration));
+    }
+
+    let (handlers, server) = {
+        let _startup_span = tracing::info_span!("total startup").entered();
+        runtime.block_on(prepare(&configuration))?
+    };
+
+    if let Some(settings) = &configuration.bench {
+        println!("Sending {} requests to {}, {} at a time", settings.requests, settings.route, settings.concurrency);
+        let report = runtime.block_on(wagi::bench::run(settings, server.routing_table()))
+            .map_err(WagiError::Runtime)?;
+        println!("{}", report);
+        return Ok(());
+    }
 
+    let runtime = if configuration.harden {
+        // Hardening only restricts the current thread and threads it starts later, so
+        // the worker threads used for startup are retired and serving gets fresh ones.
+        runtime.shutdown_timeout(Duration::from_secs(5));
+        wagi::harden::apply(&configuration, &handlers).map_err(WagiError::Runtime)?;
+        println!("Hardening applied");
+        new_runtime()?
+    } else {
+        runtime
+    };
+
+    runtime.block_on(serve(configuration, handlers, server))
+}
+
+async fn prepare(configuration: &WagiConfiguration) -> WagiResult<(WasmHandlerConfiguration, WagiServer)> {
+    let (handlers, routing_table) = load_routing_table(configuration).await?;
+    let server = WagiServer::new(configuration, routing_table).await
+        .map_err(WagiError::Runtime)?;
+    Ok((handlers, server))
+}
+
+async fn load_routing_table(configuration: &WagiConfiguration) -> WagiResult<(WasmHandlerConfiguration, RoutingTable)> {
     // TODO: this can all go into lib.rs as "build_routing_table"
-    let handlers = wagi::handler_loader::load_handlers(&configuration).await?;
+    let handlers = wagi::handler_loader::load_handlers(configuration).await?;
     // Possibly this should go into a 'routing table builder' so we cleanly separate
     // prep-time and serve-time responsibilities.
-    let routing_table = wagi::dispatcher::RoutingTable::build(&handlers, configuration.request_global_context())?;
+    RoutingTable::build_on_blocking_thread(handlers, configuration.request_global_context()).await
+}
 
-    let server = WagiServer::new(&configuration, routing_table).await?;
+async fn serve(configuration: WagiConfiguration, handlers: WasmHandlerConfiguration, server: WagiServer) -> WagiResult<()> {
+    start_background_tasks(&configuration, handlers, &server);
+    println!("Ready: serving on http://{}", configuration.http_configuration.listen_on);
+    wagi::systemd::notify_ready();
+    server.serve().await.map_err(WagiError::Runtime)
+}
 
-    drop(startup_span);
+async fn start_and_serve(configuration: WagiConfiguration) -> WagiResult<()> {
+    let server = WagiServer::starting(&configuration);
+    let serving = server.serve();
+    tokio::pin!(serving);
+    println!("Starting: answering 503 on http://{} until modules are loaded", configuration.http_configuration.listen_on);
 
+    // Compiling modules keeps a thread busy, so it runs as its own task to leave the
+    // listener free to answer
+    let startup = {
+        let configuration = configuration.clone();
+        tokio::spawn(async move { load_routing_table(&configuration).await }.instrument(tracing::info_span!("total startup")))
+    };
+    let (handlers, routing_table) = tokio::select! {
+        loaded = startup => loaded.map_err(|e| WagiError::Runtime(anyhow::Error::new(e)))??,
+        // Failed to listen, or shut down before modules were loaded
+        served = &mut serving => return served.map_err(WagiError::Runtime),
+    };
+    server.ready(routing_table);
+    start_background_tasks(&configuration, handlers, &server);
     println!("Ready: serving on http://{}", configuration.http_configuration.listen_on);
-    server.serve().await
+    wagi::systemd::notify_ready();
+    serving.await.map_err(WagiError::Runtime)
+}
+
+fn start_background_tasks(configuration: &WagiConfiguration, handlers: WasmHandlerConfiguration, server: &WagiServer) {
+    wagi::scheduler::start(&handlers.tasks, server.routing_table().current().global_context().clone());
+    // With outbound HTTP denied, no module can reach its allowed hosts anyway
+    if configuration.check_allowed_hosts && !configuration.deny_outbound_http {
+        wagi::host_check::start(&handlers, &configuration.outbound_network);
+    }
+    if configuration.watch {
+        tokio::spawn(wagi::watch::watch_and_rebuild(configuration.clone(), handlers, server.routing_table()));
+    }
+    tokio::spawn(wagi::diagnostics::dump_state_on_signal(configuration.clone(), server.routing_table()));
+    tokio::spawn(wagi::systemd::watchdog(server.routing_table()));
+    if let Some(log_level) = &configuration.log_level {
+        tokio::spawn(wagi::log_level::toggle_on_signal(log_level.clone(), configuration.verbose_log_filter.clone()));
+    }
+    if let Some(idle_ttl) = configuration.module_idle_ttl {
+        tokio::spawn(wagi::module_eviction::evict_idle_modules(idle_ttl, server.routing_table()));
+    }
+    if let Some(interval) = configuration.dynamic_routes_refresh_interval {
+        tokio::spawn(wagi::route_refresh::refresh_periodically(interval, server.routing_table()));
+    }
+}
+
+fn new_runtime() -> WagiResult<tokio::runtime::Runtime> {
+    tokio::runtime::Runtime::new()
+        .map_err(|e| WagiError::Runtime(anyhow::Error::new(e).context("Error starting async runtime")))
 }
@@ -0,0 +1,242 @@
+//! Maintenance mode.
+//!
+//! While a route is in maintenance, requests to it get `503 Service Unavailable`
+//! with the maintenance page instead of running its module. The whole server can
+//! be put in maintenance with `--maintenance`, and from the local machine the
+//! inbuilt maintenance route puts the whole server or one route in and out of it,
+//! optionally for a limited time:
+//!
+//! ```text
+//! curl -X POST 'http://localhost:3000/_wagi/maintenance?route=/shop/...&duration=600'
+//! curl -X DELETE 'http://localhost:3000/_wagi/maintenance?route=/shop/...'
+//! ```
+//!
+//! Inbuilt routes, such as the health check, are never in maintenance. Like route
+//! toggles, runtime changes last until WAGI restarts, including across watch mode
+//! reloads.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Response, StatusCode};
+
+use crate::wagi_config::duration_from_secs;
+
+/// The path at which the inbuilt maintenance handler is mounted.
+pub const MAINTENANCE_ROUTE: &str = "/_wagi/maintenance";
+
+/// What requests get while their route is in maintenance.
+#[derive(Clone, Debug)]
+pub struct MaintenancePage {
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl Default for MaintenancePage {
+    fn default() -> Self {
+        Self {
+            content_type: "text/plain".to_owned(),
+            body: b"Down for maintenance".to_vec(),
+        }
+    }
+}
+
+impl MaintenancePage {
+    /// Reads the page from a file. Files ending in `.html` or `.htm` are served as
+    /// HTML, and anything else as plain text.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let body = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Could not read maintenance page {}: {}", path.display(), e))?;
+        let is_html = matches!(path.extension().and_then(|e| e.to_str()), Some("html") | Some("htm"));
+        let content_type = if is_html { "text/html; charset=utf-8" } else { "text/plain; charset=utf-8" };
+        Ok(Self {
+            content_type: content_type.to_owned(),
+            body,
+        })
+    }
+}
+
+/// The maintenance page, and whether the whole server starts in maintenance.
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceSettings {
+    pub page: MaintenancePage,
+    pub at_startup: bool,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum Scope {
+    Server,
+    Route(String),
+}
+
+/// What is in maintenance, and until when. A window without an end lasts until
+/// it is ended at the maintenance route.
+#[derive(Clone, Debug, Default)]
+pub struct Maintenance {
+    page: Arc<MaintenancePage>,
+    windows: Arc<Mutex<HashMap<Scope, Option<Instant>>>>,
+}
+
+impl Maintenance {
+    pub fn new(settings: &MaintenanceSettings) -> Self {
+        let maintenance = Self {
+            page: Arc::new(settings.page.clone()),
+            windows: Arc::default(),
+        };
+        if settings.at_startup {
+            maintenance.enter(None, None);
+        }
+        maintenance
+    }
+
+    /// Puts the route, or the whole server if there is no route, in maintenance.
+    pub fn enter(&self, route: Option<&str>, duration: Option<Duration>) {
+        let until = duration.map(|d| Instant::now() + d);
+        self.windows.lock().unwrap().insert(scope(route), until);
+        tracing::info!(route = route.unwrap_or("(all)"), ?duration, "Entered maintenance");
+    }
+
+    /// Takes the route, or the whole server, out of maintenance. Routes put in
+    /// maintenance on their own stay in it when the whole server comes out.
+    pub fn exit(&self, route: Option<&str>) {
+        self.windows.lock().unwrap().remove(&scope(route));
+        tracing::info!(route = route.unwrap_or("(all)"), "Left maintenance");
+    }
+
+    pub fn is_active(&self, route: &str) -> bool {
+        self.active_until(route, Instant::now()).is_some()
+    }
+
+    /// The response for a request to the route, if it is in maintenance. If the
+    /// maintenance has an end, the response's Retry-After says when.
+    pub fn response_for(&self, route: &str) -> Option<Response<Body>> {
+        let now = Instant::now();
+        let until = self.active_until(route, now)?;
+        let mut builder = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(hyper::header::CONTENT_TYPE, &self.page.content_type);
+        if let Some(until) = until {
+            builder = builder.header(hyper::header::RETRY_AFTER, seconds_until(until, now));
+        }
+        Some(builder.body(Body::from(self.page.body.clone())).unwrap())
+    }
+
+    // If the route is in maintenance, when that ends. If both the server and the
+    // route are, it ends when the later of them does. Windows that have ended are
+    // forgotten.
+    fn active_until(&self, route: &str, now: Instant) -> Option<Option<Instant>> {
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, until| until.map(|u| u > now).unwrap_or(true));
+        let server = windows.get(&Scope::Server).copied();
+        let route = windows.get(&Scope::Route(route.to_owned())).copied();
+        match (server, route) {
+            (Some(a), Some(b)) => Some(a.zip(b).map(|(a, b)| a.max(b))),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// What is in maintenance now, as JSON.
+    pub fn render_json(&self) -> String {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, until| until.map(|u| u > now).unwrap_or(true));
+        let remaining = |until: &Option<Instant>| until.map(|u| seconds_until(u, now));
+        let mut routes: Vec<_> = windows
+            .iter()
+            .filter_map(|(scope, until)| match scope {
+                Scope::Route(route) => Some(serde_json::json!({ "route": route, "remaining_seconds": remaining(until) })),
+                Scope::Server => None,
+            })
+            .collect();
+        routes.sort_by_key(|r| r["route"].as_str().unwrap_or_default().to_owned());
+        let server = windows.get(&Scope::Server).map(|until| serde_json::json!({ "remaining_seconds": remaining(until) }));
+        serde_json::json!({ "server": server, "routes": routes }).to_string()
+    }
+}
+
+// Rounded up so that clients don't come back too early
+fn seconds_until(until: Instant, now: Instant) -> u64 {
+    let remaining = until.saturating_duration_since(now);
+    remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+fn scope(route: Option<&str>) -> Scope {
+    match route {
+        Some(route) => Scope::Route(route.to_owned()),
+        None => Scope::Server,
+    }
+}
+
+/// A requested change to maintenance, from the query string of a POST or DELETE.
+#[derive(Debug, PartialEq)]
+pub struct MaintenanceRequest {
+    pub route: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl MaintenanceRequest {
+    pub fn parse(query: &str) -> anyhow::Result<Self> {
+        let mut request = Self { route: None, duration: None };
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "route" => request.route = Some(value.into_owned()),
+                "duration" => match value.parse::<f64>().ok().and_then(duration_from_secs) {
+                    Some(duration) if !duration.is_zero() => request.duration = Some(duration),
+                    _ => anyhow::bail!("duration must be a number of seconds greater than zero, up to 100 years, not '{}'", value),
+                },
+                _ => (),
+            }
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_server_or_single_routes_can_be_in_maintenance() {
+        let maintenance = Maintenance::default();
+        assert!(maintenance.response_for("/shop").is_none());
+
+        maintenance.enter(Some("/shop"), None);
+        let res = maintenance.response_for("/shop").expect("/shop should be in maintenance");
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert!(res.headers().get(hyper::header::RETRY_AFTER).is_none());
+        assert!(!maintenance.is_active("/blog"));
+
+        maintenance.enter(None, Some(Duration::from_secs(60)));
+        let res = maintenance.response_for("/blog").expect("the server should be in maintenance");
+        assert_eq!("60", res.headers()[hyper::header::RETRY_AFTER]);
+
+        maintenance.exit(None);
+        assert!(!maintenance.is_active("/blog"));
+        assert!(maintenance.is_active("/shop"));
+    }
+
+    #[test]
+    fn maintenance_ends_by_itself_after_its_duration() {
+        let maintenance = Maintenance::default();
+        maintenance.enter(Some("/shop"), Some(Duration::from_secs(60)));
+        let now = Instant::now();
+        assert!(maintenance.active_until("/shop", now).is_some());
+        assert!(maintenance.active_until("/shop", now + Duration::from_secs(61)).is_none());
+        assert!(!maintenance.is_active("/shop"), "the ended window should be forgotten");
+    }
+
+    #[test]
+    fn maintenance_requests_are_parsed_from_the_query() {
+        assert_eq!(
+            MaintenanceRequest { route: Some("/shop/...".to_owned()), duration: Some(Duration::from_secs(600)) },
+            MaintenanceRequest::parse("route=%2Fshop%2F...&duration=600").unwrap()
+        );
+        assert_eq!(MaintenanceRequest { route: None, duration: None }, MaintenanceRequest::parse("").unwrap());
+        MaintenanceRequest::parse("duration=soon").expect_err("duration must be a number");
+        MaintenanceRequest::parse("duration=0").expect_err("duration must be positive");
+        MaintenanceRequest::parse("duration=1e20").expect_err("duration too long to hold");
+        MaintenanceRequest::parse("duration=1e18").expect_err("duration too long to add to the current time");
+    }
+}
@@ -0,0 +1,94 @@
+//! How much memory modules use.
+//!
+//! After each request, WAGI records how big the module's linear memory had grown
+//! (a module's memory never shrinks, so this is the most it used). For each route
+//! the metrics route then shows the largest size seen and the 95th percentile of
+//! recent requests, which is what capacity planning for memory-heavy modules needs.
+//! Each request's figure is also logged at debug level.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::metrics::MetricsRegistry;
+
+pub(crate) const MEMORY_MAX_METRIC: &str = "wagi_module_memory_max_bytes";
+pub(crate) const MEMORY_P95_METRIC: &str = "wagi_module_memory_p95_bytes";
+// The percentile is taken over this many of a route's most recent requests
+const RECENT_SAMPLES: usize = 1000;
+
+/// The memory use of recent requests to each route.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    routes: Arc<Mutex<HashMap<String, RouteMemory>>>,
+}
+
+#[derive(Debug, Default)]
+struct RouteMemory {
+    max: u64,
+    recent: VecDeque<u64>,
+}
+
+impl RouteMemory {
+    fn record(&mut self, bytes: u64) {
+        self.max = self.max.max(bytes);
+        if self.recent.len() == RECENT_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(bytes);
+    }
+
+    // Nearest-rank percentile
+    fn p95(&self) -> u64 {
+        let mut sorted: Vec<u64> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * 95 + 99) / 100;
+        sorted.get(rank.saturating_sub(1)).copied().unwrap_or(0)
+    }
+}
+
+impl MemoryStats {
+    /// Records one request's memory use, and updates the route's gauges.
+    pub fn record(&self, route: &str, module: &str, bytes: u64, metrics: &MetricsRegistry) {
+        tracing::debug!(route, module, memory_bytes = bytes, "Module memory use");
+        let (max, p95) = {
+            let mut routes = self.routes.lock().unwrap();
+            let stats = routes.entry(route.to_owned()).or_default();
+            stats.record(bytes);
+            (stats.max, stats.p95())
+        };
+        let labels = [("route", route)];
+        metrics.set_gauge(MEMORY_MAX_METRIC, &labels, max as f64);
+        metrics.set_gauge(MEMORY_P95_METRIC, &labels, p95 as f64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn max_and_p95_are_kept_per_route() {
+        let stats = MemoryStats::default();
+        let metrics = MetricsRegistry::default();
+        for bytes in 1..=100 {
+            stats.record("/a", "a.wasm", bytes * 65536, &metrics);
+        }
+        stats.record("/b", "b.wasm", 131072, &metrics);
+
+        let text = metrics.render();
+        assert!(text.contains("wagi_module_memory_max_bytes{route=\"/a\"} 6553600"));
+        assert!(text.contains("wagi_module_memory_p95_bytes{route=\"/a\"} 6225920"));
+        assert!(text.contains("wagi_module_memory_p95_bytes{route=\"/b\"} 131072"));
+    }
+
+    #[test]
+    fn the_percentile_is_of_recent_requests() {
+        let mut route = RouteMemory::default();
+        route.record(1_000_000);
+        for _ in 0..RECENT_SAMPLES {
+            route.record(10);
+        }
+        assert_eq!(10, route.p95());
+        assert_eq!(1_000_000, route.max);
+    }
+}
@@ -0,0 +1,148 @@
+//! A minimal in-process metrics registry.
+//!
+//! This deliberately avoids pulling in a full metrics stack: we only need a handful
+//! of counters and gauges, rendered in the Prometheus text exposition format by the
+//! inbuilt metrics route.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// The path at which the inbuilt metrics handler is mounted.
+pub const METRICS_ROUTE: &str = "/_wagi/metrics";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+#[derive(Debug)]
+struct MetricFamily {
+    kind: MetricKind,
+    // Keyed by the rendered label set so that output is stable between scrapes.
+    values: BTreeMap<String, f64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricsRegistry {
+    families: Arc<Mutex<BTreeMap<String, MetricFamily>>>,
+}
+
+impl MetricsRegistry {
+    pub fn increment_counter(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add_to_counter(name, labels, 1.0)
+    }
+
+    pub fn add_to_counter(&self, name: &str, labels: &[(&str, &str)], amount: f64) {
+        self.update(name, MetricKind::Counter, labels, |v| *v += amount)
+    }
+
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, MetricKind::Gauge, labels, |v| *v = value)
+    }
+
+    /// The sum of a counter over all its label sets, or zero if it has not been recorded.
+    pub fn counter_total(&self, name: &str) -> f64 {
+        let families = self.families.lock().unwrap();
+        match families.get(name) {
+            Some(family) if family.kind == MetricKind::Counter => family.values.values().sum(),
+            _ => 0.0,
+        }
+    }
+
+    fn update(&self, name: &str, kind: MetricKind, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+        let mut families = self.families.lock().unwrap();
+        let family = families
+            .entry(name.to_owned())
+            .or_insert_with(|| MetricFamily { kind, values: BTreeMap::new() });
+        if family.kind != kind {
+            tracing::warn!(metric = name, "Metric updated with inconsistent kind; ignoring");
+            return;
+        }
+        f(family.values.entry(render_labels(labels)).or_insert(0.0));
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut text = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            // Writing to a String cannot fail.
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (labels, value) in &family.values {
+                let _ = writeln!(text, "{}{} {}", name, labels, value);
+            }
+        }
+        text
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_accumulate_per_label_set() {
+        let registry = MetricsRegistry::default();
+        registry.increment_counter("requests_total", &[("route", "/a")]);
+        registry.increment_counter("requests_total", &[("route", "/a")]);
+        registry.increment_counter("requests_total", &[("route", "/b")]);
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE requests_total counter"));
+        assert!(text.contains("requests_total{route=\"/a\"} 2"));
+        assert!(text.contains("requests_total{route=\"/b\"} 1"));
+    }
+
+    #[test]
+    fn counter_totals_sum_over_label_sets() {
+        let registry = MetricsRegistry::default();
+        registry.add_to_counter("seconds_total", &[("module", "a")], 1.5);
+        registry.add_to_counter("seconds_total", &[("module", "b")], 2.0);
+        registry.set_gauge("open", &[], 1.0);
+
+        assert_eq!(3.5, registry.counter_total("seconds_total"));
+        assert_eq!(0.0, registry.counter_total("open"));
+        assert_eq!(0.0, registry.counter_total("missing_total"));
+    }
+
+    #[test]
+    fn gauges_are_overwritten() {
+        let registry = MetricsRegistry::default();
+        registry.set_gauge("open", &[], 1.0);
+        registry.set_gauge("open", &[], 0.0);
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE open gauge"));
+        assert!(text.contains("open 0"));
+        assert!(!text.contains("open 1"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!("{route=\"/a\\\"b\"}", render_labels(&[("route", "/a\"b")]));
+    }
+}
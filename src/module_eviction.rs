@@ -0,0 +1,44 @@
+//! Dropping compiled modules that are not being used.
+//!
+//! With `--module-idle-ttl`, a route's compiled module (and any warm instances of
+//! it) is dropped once the route has gone that long without a request, and is
+//! compiled again from the module bytes on the next request. This keeps memory
+//! use in line with the routes that are actually busy, at the cost of a slower
+//! first request after a quiet spell.
+
+use std::time::Duration;
+
+use crate::dispatcher::LiveRoutingTable;
+
+// Idle modules are looked for several times per TTL, so a module is evicted
+// soon after its TTL runs out, but not more often than this.
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const SWEEPS_PER_TTL: u32 = 4;
+
+/// Periodically evicts the modules of routes that have been idle for `idle_ttl`.
+/// Runs until the process exits.
+pub async fn evict_idle_modules(idle_ttl: Duration, routing_table: LiveRoutingTable) {
+    let interval = sweep_interval(idle_ttl);
+    loop {
+        tokio::time::sleep(interval).await;
+        let evicted = routing_table.current().evict_idle_modules(idle_ttl);
+        if evicted > 0 {
+            tracing::debug!(evicted, "Evicted idle modules");
+        }
+    }
+}
+
+fn sweep_interval(idle_ttl: Duration) -> Duration {
+    (idle_ttl / SWEEPS_PER_TTL).max(MIN_SWEEP_INTERVAL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sweeps_are_a_fraction_of_the_ttl_but_not_too_frequent() {
+        assert_eq!(Duration::from_secs(150), sweep_interval(Duration::from_secs(600)));
+        assert_eq!(MIN_SWEEP_INTERVAL, sweep_interval(Duration::from_secs(2)));
+    }
+}
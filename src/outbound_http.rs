@@ -0,0 +1,904 @@
+//! Outbound HTTP requests from modules.
+//!
+//! This is the host side of the `wasi_experimental_http` module used by the
+//! `wasi-experimental-http` guest library, replacing the `wasi-experimental-http-wasmtime`
+//! crate, which had no way to add headers. It keeps that crate's ABI, error codes and
+//! `allowed_hosts` matching, so modules built against it see no difference. The guest passes a URL, method, headers (as
+//! `name:value` lines) and body, and gets back a status code and a handle from which it
+//! reads the response headers and body. Requests may only go to the module's
+//! `allowed_hosts`.
+//!
+//! WAGI adds trace headers identifying the incoming request to every outbound request,
+//! so upstream services can correlate their logs with WAGI's. Headers the module sets
+//! itself are never overwritten. Each outbound request is also logged and counted per
+//! module and upstream host, so operators can see which upstream is slowing a module down.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::Bytes;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION, PROXY_AUTHORIZATION};
+use reqwest::StatusCode;
+use url::Url;
+use wasmtime::{Caller, Linker, Memory};
+use wasmtime_wasi::WasiCtx;
+
+use crate::metrics::MetricsRegistry;
+use crate::outbound_network::{AddressNotPermitted, OutboundNetwork};
+
+const MODULE_NAME: &str = "wasi_experimental_http";
+const MAX_REDIRECTS: usize = 10;
+pub(crate) const ALLOW_ALL_HOSTS: &str = "insecure:allow-all";
+
+const X_REQUEST_ID: &str = "x-request-id";
+const TRACEPARENT: &str = "traceparent";
+
+const OUTBOUND_REQUESTS_METRIC: &str = "wagi_outbound_requests_total";
+const OUTBOUND_DURATION_METRIC: &str = "wagi_outbound_request_duration_seconds_total";
+
+/// The error codes defined by the `wasi_experimental_http` ABI. Zero means success.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HttpError {
+    InvalidHandle = 1,
+    MemoryNotFound = 2,
+    MemoryAccessError = 3,
+    BufferTooSmall = 4,
+    HeaderNotFound = 5,
+    Utf8Error = 6,
+    DestinationNotAllowed = 7,
+    InvalidMethod = 8,
+    InvalidEncoding = 9,
+    InvalidUrl = 10,
+    RequestError = 11,
+    TooManySessions = 13,
+}
+
+fn to_status(result: Result<(), HttpError>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as u32,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OutboundHttpSettings {
+    pub allowed_hosts: Option<Vec<String>>,
+    pub max_concurrent_requests: Option<u32>,
+    /// The client requests are sent with, and the addresses it may connect to.
+    pub network: OutboundNetwork,
+}
+
+/// Which trace headers to add to outbound requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceHeaders {
+    /// `X-Request-Id`: the incoming request's ID if it had one, otherwise a new one.
+    pub request_id: bool,
+    /// W3C `traceparent`: continues the incoming request's trace if it had one,
+    /// otherwise starts a new one.
+    pub traceparent: bool,
+}
+
+impl Default for TraceHeaders {
+    fn default() -> Self {
+        Self {
+            request_id: true,
+            traceparent: true,
+        }
+    }
+}
+
+impl TraceHeaders {
+    pub fn none() -> Self {
+        Self {
+            request_id: false,
+            traceparent: false,
+        }
+    }
+
+    /// Parses a comma-separated list of header names, or `none`.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut result = Self::none();
+        for name in text.split(',').map(|s| s.trim().to_lowercase()) {
+            match name.as_str() {
+                "" | "none" => (),
+                X_REQUEST_ID => result.request_id = true,
+                TRACEPARENT => result.traceparent = true,
+                other => anyhow::bail!("Unknown trace header '{}': expected x-request-id, traceparent or none", other),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// What outbound requests need to know about the incoming request a module is handling:
+/// which module it is, the trace headers to send, and where to record metrics.
+#[derive(Clone, Debug)]
+pub struct OutboundRequestContext {
+    module: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    metrics: MetricsRegistry,
+}
+
+impl OutboundRequestContext {
+    pub fn for_request(module: &str, incoming: &HeaderMap, trace_headers: TraceHeaders, metrics: MetricsRegistry) -> Self {
+        let mut headers = vec![];
+        if trace_headers.request_id {
+            let request_id = incoming
+                .get(X_REQUEST_ID)
+                .cloned()
+                .unwrap_or_else(|| HeaderValue::from_str(&random_hex(16)).unwrap());
+            headers.push((HeaderName::from_static(X_REQUEST_ID), request_id));
+        }
+        if trace_headers.traceparent {
+            let (trace_id, flags) = incoming
+                .get(TRACEPARENT)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_traceparent)
+                .unwrap_or_else(|| (random_hex(16), "00".to_owned()));
+            let traceparent = format!("00-{}-{}-{}", trace_id, random_hex(8), flags);
+            headers.push((HeaderName::from_static(TRACEPARENT), HeaderValue::from_str(&traceparent).unwrap()));
+        }
+        Self {
+            module: module.to_owned(),
+            headers,
+            metrics,
+        }
+    }
+}
+
+tokio::task_local! {
+    static REQUEST_CONTEXT: OutboundRequestContext;
+}
+
+/// Runs a module with the context of the request it is handling. Outbound requests
+/// made outside this (for example, by `_routes`) get no trace headers and are not
+/// counted in metrics.
+pub async fn with_request_context<F: Future>(context: OutboundRequestContext, run: F) -> F::Output {
+    REQUEST_CONTEXT.scope(context, run).await
+}
+
+fn current_context() -> Option<OutboundRequestContext> {
+    REQUEST_CONTEXT.try_with(|c| c.clone()).ok()
+}
+
+fn record_outbound_request(context: Option<&OutboundRequestContext>, method: &str, host: &str, outcome: &str, elapsed: Duration) {
+    let module = context.map(|c| c.module.as_str()).unwrap_or("");
+    tracing::info!(module, method, host, outcome, elapsed_ms = elapsed.as_millis() as u64, "Outbound HTTP request");
+    if let Some(context) = context {
+        let labels = [("module", module), ("host", host)];
+        context.metrics.increment_counter(OUTBOUND_REQUESTS_METRIC, &[labels[0], labels[1], ("status", outcome)]);
+        context.metrics.add_to_counter(OUTBOUND_DURATION_METRIC, &labels, elapsed.as_secs_f64());
+    }
+}
+
+// The host and, if it is not the default for the scheme, the port.
+fn host_label(url: &Url) -> String {
+    let host = url.host_str().unwrap_or("");
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    }
+}
+
+// Returns (trace-id, trace-flags) from a `version-traceid-parentid-flags` header, if valid.
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let is_hex = |s: &str, len: usize| s.len() == len && s.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    match parts.as_slice() {
+        [version, trace_id, parent_id, flags, ..]
+            if is_hex(version, 2) && *version != "ff"
+                && is_hex(trace_id, 32) && trace_id.chars().any(|c| c != '0')
+                && is_hex(parent_id, 16) && parent_id.chars().any(|c| c != '0')
+                && is_hex(flags, 2) =>
+        {
+            Some((trace_id.to_string(), flags.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn random_hex(bytes: usize) -> String {
+    (0..bytes).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+struct OutboundResponse {
+    headers: HeaderMap,
+    body: Vec<u8>,
+    read: usize,
+}
+
+#[derive(Default)]
+struct ResponseTable {
+    next_handle: u32,
+    responses: HashMap<u32, OutboundResponse>,
+}
+
+// A (pointer, length) pair in guest memory.
+#[derive(Clone, Copy)]
+struct GuestBuffer {
+    ptr: u32,
+    len: u32,
+}
+
+impl GuestBuffer {
+    fn new(ptr: u32, len: u32) -> Self {
+        Self { ptr, len }
+    }
+}
+
+/// Adds the `wasi_experimental_http` functions to the linker. Each instance should have
+/// its own linker, as response handles are not shared between instances.
+pub fn add_to_linker(linker: &mut Linker<WasiCtx>, settings: OutboundHttpSettings) -> anyhow::Result<()> {
+    let settings = Arc::new(settings);
+    let table = Arc::new(Mutex::new(ResponseTable::default()));
+
+    let t = table.clone();
+    linker.func_wrap(MODULE_NAME, "close", move |handle: u32| -> u32 {
+        match t.lock().unwrap().responses.remove(&handle) {
+            Some(_) => 0,
+            None => HttpError::InvalidHandle as u32,
+        }
+    })?;
+
+    let t = table.clone();
+    linker.func_wrap10_async(
+        MODULE_NAME,
+        "req",
+        move |mut caller: Caller<'_, WasiCtx>,
+              url_ptr: u32, url_len: u32,
+              method_ptr: u32, method_len: u32,
+              headers_ptr: u32, headers_len: u32,
+              body_ptr: u32, body_len: u32,
+              status_code_ptr: u32, handle_ptr: u32| {
+            let settings = settings.clone();
+            let table = t.clone();
+            Box::new(async move {
+                let request = GuestRequest {
+                    url: GuestBuffer::new(url_ptr, url_len),
+                    method: GuestBuffer::new(method_ptr, method_len),
+                    headers: GuestBuffer::new(headers_ptr, headers_len),
+                    body: GuestBuffer::new(body_ptr, body_len),
+                };
+                to_status(req(&mut caller, &settings, &table, request, status_code_ptr, handle_ptr).await)
+            })
+        },
+    )?;
+
+    let t = table.clone();
+    linker.func_wrap(
+        MODULE_NAME,
+        "header_get",
+        move |mut caller: Caller<'_, WasiCtx>, handle: u32, name_ptr: u32, name_len: u32, value_ptr: u32, value_len: u32, written_ptr: u32| -> u32 {
+            to_status(header_get(&mut caller, &t, handle, GuestBuffer::new(name_ptr, name_len), GuestBuffer::new(value_ptr, value_len), written_ptr))
+        },
+    )?;
+
+    let t = table.clone();
+    linker.func_wrap(
+        MODULE_NAME,
+        "headers_get_all",
+        move |mut caller: Caller<'_, WasiCtx>, handle: u32, buf_ptr: u32, buf_len: u32, written_ptr: u32| -> u32 {
+            to_status(headers_get_all(&mut caller, &t, handle, GuestBuffer::new(buf_ptr, buf_len), written_ptr))
+        },
+    )?;
+
+    let t = table;
+    linker.func_wrap(
+        MODULE_NAME,
+        "body_read",
+        move |mut caller: Caller<'_, WasiCtx>, handle: u32, buf_ptr: u32, buf_len: u32, read_ptr: u32| -> u32 {
+            to_status(body_read(&mut caller, &t, handle, GuestBuffer::new(buf_ptr, buf_len), read_ptr))
+        },
+    )?;
+
+    Ok(())
+}
+
+struct GuestRequest {
+    url: GuestBuffer,
+    method: GuestBuffer,
+    headers: GuestBuffer,
+    body: GuestBuffer,
+}
+
+async fn req(
+    caller: &mut Caller<'_, WasiCtx>,
+    settings: &OutboundHttpSettings,
+    table: &Mutex<ResponseTable>,
+    request: GuestRequest,
+    status_code_ptr: u32,
+    handle_ptr: u32,
+) -> Result<(), HttpError> {
+    let url = read_string(caller, request.url)?;
+    let method = read_string(caller, request.method)?;
+    let headers = read_string(caller, request.headers)?;
+    let body = read_bytes(caller, request.body)?;
+
+    if let Some(max) = settings.max_concurrent_requests {
+        if table.lock().unwrap().responses.len() >= max as usize {
+            return Err(HttpError::TooManySessions);
+        }
+    }
+
+    let url = Url::parse(&url).map_err(|_| HttpError::InvalidUrl)?;
+    let context = current_context();
+    let host = host_label(&url);
+    if let Err(e) = check_destination(settings, &url) {
+        record_outbound_request(context.as_ref(), &method, &host, "denied", Duration::ZERO);
+        return Err(e);
+    }
+    let method = reqwest::Method::from_str(&method).map_err(|_| HttpError::InvalidMethod)?;
+    let mut headers = parse_guest_headers(&headers)?;
+    for (name, value) in context.iter().flat_map(|c| c.headers.iter()) {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+
+    let started = Instant::now();
+    let result = send(settings, method.clone(), url.clone(), headers, body).await;
+    let denied = match &result {
+        Err(SendError::Redirect(e)) => *e == HttpError::DestinationNotAllowed,
+        Err(SendError::Client(e)) => AddressNotPermitted::is_cause_of(e),
+        Ok(_) => false,
+    };
+    let outcome = match &result {
+        Ok((status, _, _)) => status.to_string(),
+        Err(SendError::Client(e)) if denied => {
+            tracing::warn!(%url, error = %format_error_chain(e), "Outbound HTTP request refused");
+            "denied".to_owned()
+        }
+        Err(SendError::Client(e)) => {
+            tracing::error!(%url, error = %e, "Outbound HTTP request failed");
+            "error".to_owned()
+        }
+        // Already logged
+        Err(SendError::Redirect(_)) if denied => "denied".to_owned(),
+        Err(SendError::Redirect(_)) => "error".to_owned(),
+    };
+    record_outbound_request(context.as_ref(), method.as_str(), &host, &outcome, started.elapsed());
+    let (status, headers, body) = result.map_err(|e| match e {
+        SendError::Redirect(e) => e,
+        SendError::Client(_) if denied => HttpError::DestinationNotAllowed,
+        SendError::Client(_) => HttpError::RequestError,
+    })?;
+
+    let handle = {
+        let mut table = table.lock().unwrap();
+        let handle = table.next_handle;
+        table.next_handle = table.next_handle.wrapping_add(1);
+        table.responses.insert(handle, OutboundResponse { headers, body, read: 0 });
+        handle
+    };
+
+    write_bytes(caller, status_code_ptr, &status.to_le_bytes())?;
+    write_bytes(caller, handle_ptr, &handle.to_le_bytes())
+}
+
+// reqwest's message for a failed connection does not say why it failed
+fn format_error_chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        text.push_str(": ");
+        text.push_str(&e.to_string());
+        source = e.source();
+    }
+    text
+}
+
+// Checks that the module may send a request to the URL, whether it gave the URL
+// itself or was redirected to it.
+fn check_destination(settings: &OutboundHttpSettings, url: &Url) -> Result<(), HttpError> {
+    if !is_allowed(url, settings.allowed_hosts.as_deref()) {
+        tracing::warn!(%url, "Module tried to send a request to a host that is not in allowed_hosts");
+        return Err(HttpError::DestinationNotAllowed);
+    }
+    if let Err(e) = settings.network.check_url(url) {
+        tracing::warn!(%url, error = %e, "Module tried to send a request to an address that outbound requests may not use");
+        return Err(HttpError::DestinationNotAllowed);
+    }
+    Ok(())
+}
+
+enum SendError {
+    Client(reqwest::Error),
+    // A redirect that can't or mustn't be followed
+    Redirect(HttpError),
+}
+
+// Sends the request, following redirects as a browser would. The client does not
+// follow them itself, so that each one can be checked against the module's
+// allowed_hosts.
+async fn send(settings: &OutboundHttpSettings, mut method: reqwest::Method, mut url: Url, mut headers: HeaderMap, body: Vec<u8>) -> Result<(u16, HeaderMap, Vec<u8>), SendError> {
+    let mut body = Bytes::from(body);
+    let mut redirects = 0;
+    loop {
+        let response = settings
+            .network
+            .client()
+            .request(method.clone(), url.clone())
+            .headers(headers.clone())
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(SendError::Client)?;
+        let status = response.status();
+        let location = response.headers().get(LOCATION).cloned();
+        let location = match location {
+            Some(location) if is_followed_redirect(status) => location,
+            _ => {
+                let headers = response.headers().clone();
+                let body = response.bytes().await.map_err(SendError::Client)?;
+                return Ok((status.as_u16(), headers, body.to_vec()));
+            }
+        };
+
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            tracing::error!(%url, "Outbound HTTP request redirected too many times");
+            return Err(SendError::Redirect(HttpError::RequestError));
+        }
+        let next = location
+            .to_str()
+            .ok()
+            .and_then(|l| url.join(l).ok())
+            .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+            .ok_or(SendError::Redirect(HttpError::InvalidUrl))?;
+        check_destination(settings, &next).map_err(SendError::Redirect)?;
+
+        let becomes_get = match status {
+            StatusCode::SEE_OTHER => method != reqwest::Method::HEAD,
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND => method == reqwest::Method::POST,
+            _ => false,
+        };
+        if becomes_get {
+            method = reqwest::Method::GET;
+            body = Bytes::new();
+            headers.remove(CONTENT_TYPE);
+            headers.remove(CONTENT_LENGTH);
+        }
+        // Credentials are only for the host the module gave them to
+        if next.origin() != url.origin() {
+            for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION] {
+                headers.remove(name);
+            }
+        }
+        url = next;
+    }
+}
+
+fn is_followed_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER | StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+fn header_get(
+    caller: &mut Caller<'_, WasiCtx>,
+    table: &Mutex<ResponseTable>,
+    handle: u32,
+    name: GuestBuffer,
+    value: GuestBuffer,
+    written_ptr: u32,
+) -> Result<(), HttpError> {
+    let name = read_string(caller, name)?;
+    let table = table.lock().unwrap();
+    let response = table.responses.get(&handle).ok_or(HttpError::InvalidHandle)?;
+    let header_value = response.headers.get(name.as_str()).ok_or(HttpError::HeaderNotFound)?;
+    write_sized(caller, value, written_ptr, header_value.as_bytes())
+}
+
+fn headers_get_all(
+    caller: &mut Caller<'_, WasiCtx>,
+    table: &Mutex<ResponseTable>,
+    handle: u32,
+    buffer: GuestBuffer,
+    written_ptr: u32,
+) -> Result<(), HttpError> {
+    let table = table.lock().unwrap();
+    let response = table.responses.get(&handle).ok_or(HttpError::InvalidHandle)?;
+    let mut text = vec![];
+    for (name, value) in response.headers.iter() {
+        text.extend_from_slice(name.as_str().as_bytes());
+        text.push(b':');
+        text.extend_from_slice(value.as_bytes());
+        text.push(b'\n');
+    }
+    write_sized(caller, buffer, written_ptr, &text)
+}
+
+fn body_read(
+    caller: &mut Caller<'_, WasiCtx>,
+    table: &Mutex<ResponseTable>,
+    handle: u32,
+    buffer: GuestBuffer,
+    read_ptr: u32,
+) -> Result<(), HttpError> {
+    let mut table = table.lock().unwrap();
+    let response = table.responses.get_mut(&handle).ok_or(HttpError::InvalidHandle)?;
+    let end = response.body.len().min(response.read + buffer.len as usize);
+    let chunk = &response.body[response.read..end];
+    write_bytes(caller, buffer.ptr, chunk)?;
+    write_bytes(caller, read_ptr, &(chunk.len() as u32).to_le_bytes())?;
+    response.read = end;
+    Ok(())
+}
+
+fn is_allowed(url: &Url, allowed_hosts: Option<&[String]>) -> bool {
+    let host = match url.host_str() {
+        Some(h) => h,
+        None => return false,
+    };
+    allowed_hosts.unwrap_or_default().iter().any(|allowed| {
+        allowed == ALLOW_ALL_HOSTS
+            || Url::parse(allowed).ok().as_ref().and_then(|u| u.host_str()) == Some(host)
+    })
+}
+
+fn parse_guest_headers(text: &str) -> Result<HeaderMap, HttpError> {
+    let mut headers = HeaderMap::new();
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let (name, value) = line.split_once(':').ok_or(HttpError::InvalidEncoding)?;
+        let name = HeaderName::from_str(name.trim()).map_err(|_| HttpError::InvalidEncoding)?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| HttpError::InvalidEncoding)?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+fn memory(caller: &mut Caller<'_, WasiCtx>) -> Result<Memory, HttpError> {
+    caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or(HttpError::MemoryNotFound)
+}
+
+fn read_bytes(caller: &mut Caller<'_, WasiCtx>, buffer: GuestBuffer) -> Result<Vec<u8>, HttpError> {
+    let memory = memory(caller)?;
+    let mut bytes = vec![0; buffer.len as usize];
+    memory
+        .read(&*caller, buffer.ptr as usize, &mut bytes)
+        .map_err(|_| HttpError::MemoryAccessError)?;
+    Ok(bytes)
+}
+
+fn read_string(caller: &mut Caller<'_, WasiCtx>, buffer: GuestBuffer) -> Result<String, HttpError> {
+    String::from_utf8(read_bytes(caller, buffer)?).map_err(|_| HttpError::Utf8Error)
+}
+
+fn write_bytes(caller: &mut Caller<'_, WasiCtx>, ptr: u32, bytes: &[u8]) -> Result<(), HttpError> {
+    let memory = memory(caller)?;
+    memory
+        .write(&mut *caller, ptr as usize, bytes)
+        .map_err(|_| HttpError::MemoryAccessError)
+}
+
+// Writes the bytes to the buffer, and their length to `written_ptr`.
+fn write_sized(caller: &mut Caller<'_, WasiCtx>, buffer: GuestBuffer, written_ptr: u32, bytes: &[u8]) -> Result<(), HttpError> {
+    if bytes.len() > buffer.len as usize {
+        return Err(HttpError::BufferTooSmall);
+    }
+    write_bytes(caller, buffer.ptr, bytes)?;
+    write_bytes(caller, written_ptr, &(bytes.len() as u32).to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trace_headers_are_parsed() {
+        assert_eq!(TraceHeaders::default(), TraceHeaders::parse("x-request-id,traceparent").unwrap());
+        assert_eq!(TraceHeaders::none(), TraceHeaders::parse("none").unwrap());
+        assert!(TraceHeaders::parse("X-Request-ID").unwrap().request_id);
+        assert!(!TraceHeaders::parse("X-Request-ID").unwrap().traceparent);
+        assert!(TraceHeaders::parse("b3").is_err());
+    }
+
+    #[test]
+    fn incoming_trace_is_continued() {
+        let mut incoming = HeaderMap::new();
+        incoming.insert(X_REQUEST_ID, HeaderValue::from_static("abc123"));
+        incoming.insert(TRACEPARENT, HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+
+        let context = OutboundRequestContext::for_request("test", &incoming, TraceHeaders::default(), MetricsRegistry::default());
+        let get = |name: &str| context.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.to_str().unwrap().to_owned());
+
+        assert_eq!(Some("abc123".to_owned()), get(X_REQUEST_ID));
+        let traceparent = get(TRACEPARENT).unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+    }
+
+    #[test]
+    fn invalid_traceparent_starts_a_new_trace() {
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("garbage").is_none());
+
+        let context = OutboundRequestContext::for_request("test", &HeaderMap::new(), TraceHeaders::default(), MetricsRegistry::default());
+        let traceparent = context.headers.iter().find(|(n, _)| n == TRACEPARENT).unwrap().1.to_str().unwrap();
+        assert!(parse_traceparent(traceparent).is_some());
+    }
+
+    #[test]
+    fn outbound_requests_are_counted_per_module_and_host() {
+        let metrics = MetricsRegistry::default();
+        let context = OutboundRequestContext::for_request("site", &HeaderMap::new(), TraceHeaders::none(), metrics.clone());
+        let host = host_label(&Url::parse("http://api.example.com:8080/widgets").unwrap());
+        record_outbound_request(Some(&context), "GET", &host, "200", Duration::from_millis(250));
+        record_outbound_request(Some(&context), "GET", &host, "200", Duration::from_millis(250));
+
+        let text = metrics.render();
+        assert!(text.contains("wagi_outbound_requests_total{module=\"site\",host=\"api.example.com:8080\",status=\"200\"} 2"));
+        assert!(text.contains("wagi_outbound_request_duration_seconds_total{module=\"site\",host=\"api.example.com:8080\"} 0.5"));
+    }
+
+    #[test]
+    fn only_allowed_hosts_can_be_called() {
+        let url = Url::parse("https://api.example.com/widgets").unwrap();
+        let hosts = |h: &[&str]| h.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(!is_allowed(&url, None));
+        assert!(!is_allowed(&url, Some(&hosts(&[]))));
+        assert!(is_allowed(&url, Some(&hosts(&["https://api.example.com"]))));
+        assert!(!is_allowed(&url, Some(&hosts(&["https://example.com"]))));
+        assert!(is_allowed(&url, Some(&hosts(&[ALLOW_ALL_HOSTS]))));
+    }
+
+    #[test]
+    fn guest_headers_are_parsed() {
+        let headers = parse_guest_headers("content-type:application/json\nx-api-key: secret\n").unwrap();
+        assert_eq!("application/json", headers["content-type"]);
+        assert_eq!("secret", headers["x-api-key"]);
+        assert!(parse_guest_headers("no colon here").is_err());
+    }
+
+    // Forwards to the host functions, which only see guest memory when called from
+    // a module.
+    const GUEST: &str = r#"(module
+        (import "wasi_experimental_http" "req" (func $req (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "wasi_experimental_http" "header_get" (func $header_get (param i32 i32 i32 i32 i32 i32) (result i32)))
+        (import "wasi_experimental_http" "headers_get_all" (func $headers_get_all (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_experimental_http" "body_read" (func $body_read (param i32 i32 i32 i32) (result i32)))
+        (import "wasi_experimental_http" "close" (func $close (param i32) (result i32)))
+        (memory (export "memory") 1)
+        (func (export "req") (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)
+            (call $req (local.get 0) (local.get 1) (local.get 2) (local.get 3) (local.get 4)
+                (local.get 5) (local.get 6) (local.get 7) (local.get 8) (local.get 9)))
+        (func (export "header_get") (param i32 i32 i32 i32 i32 i32) (result i32)
+            (call $header_get (local.get 0) (local.get 1) (local.get 2) (local.get 3) (local.get 4) (local.get 5)))
+        (func (export "headers_get_all") (param i32 i32 i32 i32) (result i32)
+            (call $headers_get_all (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+        (func (export "body_read") (param i32 i32 i32 i32) (result i32)
+            (call $body_read (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+        (func (export "close") (param i32) (result i32)
+            (call $close (local.get 0)))
+    )"#;
+
+    // Where the test puts things in guest memory
+    const URL_PTR: u32 = 0;
+    const METHOD_PTR: u32 = 512;
+    const NAME_PTR: u32 = 768;
+    const STATUS_PTR: u32 = 1024;
+    const HEADERS_PTR: u32 = 1536;
+    const HANDLE_PTR: u32 = 1028;
+    const WRITTEN_PTR: u32 = 1032;
+    const OUTPUT_PTR: u32 = 2048;
+
+    struct Guest {
+        store: wasmtime::Store<WasiCtx>,
+        instance: wasmtime::Instance,
+        memory: Memory,
+    }
+
+    impl Guest {
+        async fn new(allowed_hosts: &[&str]) -> Self {
+            let mut config = wasmtime::Config::new();
+            config.async_support(true);
+            let engine = wasmtime::Engine::new(&config).unwrap();
+            let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+            let mut linker = Linker::new(&engine);
+            let settings = OutboundHttpSettings {
+                allowed_hosts: Some(allowed_hosts.iter().map(|h| h.to_string()).collect()),
+                max_concurrent_requests: None,
+                network: OutboundNetwork::default(),
+            };
+            add_to_linker(&mut linker, settings).unwrap();
+            let mut store = wasmtime::Store::new(&engine, wasi_cap_std_sync::WasiCtxBuilder::new().build());
+            let instance = linker.instantiate_async(&mut store, &module).await.unwrap();
+            let memory = instance.get_memory(&mut store, "memory").unwrap();
+            Self { store, instance, memory }
+        }
+
+        fn put(&mut self, ptr: u32, bytes: &[u8]) -> GuestBuffer {
+            self.memory.write(&mut self.store, ptr as usize, bytes).unwrap();
+            GuestBuffer::new(ptr, bytes.len() as u32)
+        }
+
+        fn get(&self, ptr: u32, len: u32) -> Vec<u8> {
+            let mut bytes = vec![0; len as usize];
+            self.memory.read(&self.store, ptr as usize, &mut bytes).unwrap();
+            bytes
+        }
+
+        fn get_u32(&self, ptr: u32) -> u32 {
+            u32::from_le_bytes(self.get(ptr, 4).try_into().unwrap())
+        }
+
+        // The bytes a call wrote to the output buffer
+        fn output(&self) -> String {
+            String::from_utf8(self.get(OUTPUT_PTR, self.get_u32(WRITTEN_PTR))).unwrap()
+        }
+
+        async fn req(&mut self, url: GuestBuffer, headers: GuestBuffer) -> u32 {
+            let method = self.put(METHOD_PTR, b"GET");
+            let func = self.instance
+                .get_typed_func::<(u32, u32, u32, u32, u32, u32, u32, u32, u32, u32), u32, _>(&mut self.store, "req")
+                .unwrap();
+            let params = (url.ptr, url.len, method.ptr, method.len, headers.ptr, headers.len, 0, 0, STATUS_PTR, HANDLE_PTR);
+            func.call_async(&mut self.store, params).await.unwrap()
+        }
+
+        async fn get_url(&mut self, url: &str) -> u32 {
+            self.get_url_with_headers(url, "").await
+        }
+
+        async fn get_url_with_headers(&mut self, url: &str, headers: &str) -> u32 {
+            let url = self.put(URL_PTR, url.as_bytes());
+            let headers = self.put(HEADERS_PTR, headers.as_bytes());
+            self.req(url, headers).await
+        }
+
+        async fn header_get(&mut self, handle: u32, name: &str, len: u32) -> u32 {
+            let name = self.put(NAME_PTR, name.as_bytes());
+            let func = self.instance
+                .get_typed_func::<(u32, u32, u32, u32, u32, u32), u32, _>(&mut self.store, "header_get")
+                .unwrap();
+            func.call_async(&mut self.store, (handle, name.ptr, name.len, OUTPUT_PTR, len, WRITTEN_PTR)).await.unwrap()
+        }
+
+        async fn call4(&mut self, name: &str, handle: u32, len: u32) -> u32 {
+            let func = self.instance
+                .get_typed_func::<(u32, u32, u32, u32), u32, _>(&mut self.store, name)
+                .unwrap();
+            func.call_async(&mut self.store, (handle, OUTPUT_PTR, len, WRITTEN_PTR)).await.unwrap()
+        }
+
+        async fn close(&mut self, handle: u32) -> u32 {
+            let func = self.instance.get_typed_func::<u32, u32, _>(&mut self.store, "close").unwrap();
+            func.call_async(&mut self.store, handle).await.unwrap()
+        }
+    }
+
+    async fn upstream() -> std::net::SocketAddr {
+        let make_service = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|req: hyper::Request<hyper::Body>| async move {
+                let body: String = match req.uri().path() {
+                    // The request headers, one per line
+                    "/echo" => req.headers().iter().map(|(n, v)| format!("{}: {}\n", n, v.to_str().unwrap_or(""))).collect(),
+                    _ => "hello world".to_owned(),
+                };
+                let redirect = |location: &str| hyper::Response::builder().status(302).header("location", location);
+                let response = match req.uri().path() {
+                    "/here" => redirect("/final"),
+                    "/away" => redirect("http://example.invalid/final"),
+                    "/loop" => redirect("/loop"),
+                    _ => hyper::Response::builder().header("x-test", "hello"),
+                };
+                Ok::<_, std::convert::Infallible>(response.body(hyper::Body::from(body)).unwrap())
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn bad_guest_input_is_reported() {
+        let mut guest = Guest::new(&["http://127.0.0.1"]).await;
+        let url = guest.put(URL_PTR, b"http://\xff");
+        assert_eq!(HttpError::Utf8Error as u32, guest.req(url, GuestBuffer::new(0, 0)).await);
+        assert_eq!(HttpError::MemoryAccessError as u32, guest.req(GuestBuffer::new(65530, 100), GuestBuffer::new(0, 0)).await);
+        assert_eq!(HttpError::InvalidUrl as u32, guest.get_url("not a url").await);
+        assert_eq!(HttpError::DestinationNotAllowed as u32, guest.get_url("http://example.invalid/").await);
+
+        assert_eq!(HttpError::InvalidHandle as u32, guest.header_get(99, "x-test", 100).await);
+        assert_eq!(HttpError::InvalidHandle as u32, guest.call4("headers_get_all", 99, 100).await);
+        assert_eq!(HttpError::InvalidHandle as u32, guest.call4("body_read", 99, 100).await);
+        assert_eq!(HttpError::InvalidHandle as u32, guest.close(99).await);
+    }
+
+    #[tokio::test]
+    async fn responses_are_read_through_guest_memory() {
+        let addr = upstream().await;
+        let mut guest = Guest::new(&["http://127.0.0.1"]).await;
+        assert_eq!(0, guest.get_url(&format!("http://{}/", addr)).await);
+        assert_eq!(200, guest.get_u32(STATUS_PTR));
+        let handle = guest.get_u32(HANDLE_PTR);
+
+        assert_eq!(HttpError::BufferTooSmall as u32, guest.header_get(handle, "x-test", 2).await);
+        assert_eq!(0, guest.header_get(handle, "x-test", 100).await);
+        assert_eq!("hello", guest.output());
+        assert_eq!(HttpError::HeaderNotFound as u32, guest.header_get(handle, "x-missing", 100).await);
+        assert_eq!(HttpError::BufferTooSmall as u32, guest.call4("headers_get_all", handle, 2).await);
+        assert_eq!(0, guest.call4("headers_get_all", handle, 1000).await);
+        assert!(guest.output().contains("x-test:hello\n"));
+
+        // The body is read in as many pieces as the guest likes
+        assert_eq!(0, guest.call4("body_read", handle, 5).await);
+        assert_eq!("hello", guest.output());
+        assert_eq!(0, guest.call4("body_read", handle, 100).await);
+        assert_eq!(" world", guest.output());
+        assert_eq!(0, guest.call4("body_read", handle, 100).await);
+        assert_eq!("", guest.output());
+
+        assert_eq!(0, guest.close(handle).await);
+        assert_eq!(HttpError::InvalidHandle as u32, guest.close(handle).await);
+    }
+
+    #[tokio::test]
+    async fn redirects_are_only_followed_to_allowed_hosts() {
+        let addr = upstream().await;
+        let mut guest = Guest::new(&["http://127.0.0.1"]).await;
+        assert_eq!(0, guest.get_url(&format!("http://{}/here", addr)).await);
+        assert_eq!(200, guest.get_u32(STATUS_PTR));
+        let handle = guest.get_u32(HANDLE_PTR);
+        assert_eq!(0, guest.call4("body_read", handle, 100).await);
+        assert_eq!("hello world", guest.output());
+
+        assert_eq!(HttpError::DestinationNotAllowed as u32, guest.get_url(&format!("http://{}/away", addr)).await);
+        assert_eq!(HttpError::RequestError as u32, guest.get_url(&format!("http://{}/loop", addr)).await);
+    }
+
+    // What modules built against wasi-experimental-http-wasmtime 0.10 rely on.
+    #[tokio::test]
+    async fn behaves_as_wasi_experimental_http_wasmtime_did() {
+        let codes: Vec<u32> = [
+            HttpError::InvalidHandle, HttpError::MemoryNotFound, HttpError::MemoryAccessError,
+            HttpError::BufferTooSmall, HttpError::HeaderNotFound, HttpError::Utf8Error,
+            HttpError::DestinationNotAllowed, HttpError::InvalidMethod, HttpError::InvalidEncoding,
+            HttpError::InvalidUrl, HttpError::RequestError, HttpError::TooManySessions,
+        ].iter().map(|e| *e as u32).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 13], codes);
+
+        // Only the host of an allowed_hosts entry counts, not its scheme or port
+        let url = Url::parse("http://api.example.com:8080/widgets").unwrap();
+        assert!(is_allowed(&url, Some(&["https://api.example.com".to_owned()])));
+
+        // Without trace headers, the request goes as the module gave it
+        let addr = upstream().await;
+        let mut guest = Guest::new(&["http://127.0.0.1"]).await;
+        let context = OutboundRequestContext::for_request("test", &HeaderMap::new(), TraceHeaders::none(), MetricsRegistry::default());
+        let url = format!("http://{}/echo", addr);
+        assert_eq!(0, with_request_context(context, guest.get_url_with_headers(&url, "x-api-key:secret\n")).await);
+        let handle = guest.get_u32(HANDLE_PTR);
+        assert_eq!(0, guest.call4("body_read", handle, 1000).await);
+        let sent = guest.output();
+        assert!(sent.contains("x-api-key: secret\n"));
+        assert!(!sent.contains(X_REQUEST_ID));
+        assert!(!sent.contains(TRACEPARENT));
+    }
+
+    #[tokio::test]
+    async fn trace_headers_do_not_replace_the_modules_own() {
+        let addr = upstream().await;
+        let mut guest = Guest::new(&["http://127.0.0.1"]).await;
+        let context = OutboundRequestContext::for_request("test", &HeaderMap::new(), TraceHeaders::default(), MetricsRegistry::default());
+        let url = format!("http://{}/echo", addr);
+        assert_eq!(0, with_request_context(context, guest.get_url_with_headers(&url, "x-request-id:mine\n")).await);
+        let handle = guest.get_u32(HANDLE_PTR);
+        assert_eq!(0, guest.call4("body_read", handle, 1000).await);
+        let sent = guest.output();
+        assert!(sent.contains("x-request-id: mine\n"));
+        assert_eq!(1, sent.matches(X_REQUEST_ID).count());
+        assert!(sent.contains("traceparent: 00-"));
+    }
+}
@@ -0,0 +1,375 @@
+//! Which addresses modules' outbound HTTP requests may connect to.
+//!
+//! `allowed_hosts` limits the host names a module may use, but not where those
+//! names lead: a name can resolve to a private address, or be changed to resolve
+//! to one after WAGI starts. The policy here is applied to the addresses the
+//! names resolve to, at the moment the client connects, and to addresses written
+//! directly in URLs, including in redirects. Names can also be resolved with
+//! given DNS servers rather than the system's.
+//!
+//! A module can also be given its own certificate authorities to trust, for
+//! internal services with private CAs, without the other modules trusting them,
+//! and its own source address to send requests from, so that on hosts with several
+//! interfaces its traffic can be routed and filtered apart from other modules'.
+
+use std::error::Error as StdError;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+use url::{Host, Url};
+
+use crate::access_control::IpNetwork;
+
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+// Loopback, private, link-local, shared (carrier-grade NAT), unspecified,
+// multicast and reserved ranges
+const PRIVATE_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Where outbound requests may connect.
+#[derive(Clone, Debug, Default)]
+pub struct OutboundNetworkPolicy {
+    /// DNS servers to resolve names with. If empty, the system resolver is used.
+    pub dns_servers: Vec<SocketAddr>,
+    /// Whether to refuse loopback, private and other non-public addresses.
+    pub block_private: bool,
+    /// If not empty, the only networks that may be connected to. These may include
+    /// private networks even if `block_private` is set.
+    pub allowed_networks: Vec<IpNetwork>,
+}
+
+impl OutboundNetworkPolicy {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if !self.allowed_networks.is_empty() {
+            return self.allowed_networks.iter().any(|n| n.contains(ip));
+        }
+        !(self.block_private && is_private(ip))
+    }
+
+    fn restricts_addresses(&self) -> bool {
+        self.block_private || !self.allowed_networks.is_empty()
+    }
+
+    fn check_url(&self, url: &Url) -> Result<(), AddressNotPermitted> {
+        let ip = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => return Ok(()),
+        };
+        if self.permits(ip) {
+            Ok(())
+        } else {
+            Err(AddressNotPermitted { host: ip.to_string(), addresses: vec![ip] })
+        }
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    PRIVATE_NETWORKS
+        .iter()
+        .any(|n| n.parse::<IpNetwork>().expect("private networks should be valid").contains(ip))
+}
+
+/// The error when a request would connect to an address the policy refuses.
+#[derive(Debug)]
+pub struct AddressNotPermitted {
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl std::fmt::Display for AddressNotPermitted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addresses: Vec<String> = self.addresses.iter().map(|a| a.to_string()).collect();
+        write!(f, "Outbound requests may not connect to {} ({})", self.host, addresses.join(", "))
+    }
+}
+
+impl StdError for AddressNotPermitted {}
+
+impl AddressNotPermitted {
+    /// Whether the error, or any error it was caused by, is an `AddressNotPermitted`.
+    pub fn is_cause_of(error: &(dyn StdError + 'static)) -> bool {
+        let mut current = Some(error);
+        while let Some(e) = current {
+            if e.is::<Self>() {
+                return true;
+            }
+            current = e.source();
+        }
+        false
+    }
+}
+
+/// The certificate authorities a module's outbound HTTPS requests trust, beyond
+/// or instead of the system's.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutboundTls {
+    /// PEM files, each holding one or more CA certificates.
+    pub ca_certificates: Vec<PathBuf>,
+    /// Whether to trust only these CAs, and not the system's.
+    pub ca_certificates_only: bool,
+}
+
+impl OutboundTls {
+    /// Reads and parses the certificates.
+    pub fn certificates(&self) -> anyhow::Result<Vec<reqwest::Certificate>> {
+        let mut certificates = vec![];
+        for path in &self.ca_certificates {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read CA certificates from {}", path.display()))?;
+            let blocks = pem_certificates(&text);
+            if blocks.is_empty() {
+                anyhow::bail!("{} does not contain any PEM certificates", path.display());
+            }
+            for block in blocks {
+                let certificate = reqwest::Certificate::from_pem(block.as_bytes())
+                    .with_context(|| format!("Invalid certificate in {}", path.display()))?;
+                certificates.push(certificate);
+            }
+        }
+        Ok(certificates)
+    }
+}
+
+// Certificate::from_pem only reads the first certificate in a bundle
+fn pem_certificates(text: &str) -> Vec<&str> {
+    let mut blocks = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find(PEM_CERTIFICATE_BEGIN) {
+        let end = match rest[start..].find(PEM_CERTIFICATE_END) {
+            Some(end) => start + end + PEM_CERTIFICATE_END.len(),
+            None => break,
+        };
+        blocks.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    blocks
+}
+
+/// The HTTP client that modules' outbound requests are sent with.
+#[derive(Clone, Debug)]
+pub struct OutboundNetwork {
+    policy: Arc<OutboundNetworkPolicy>,
+    client: reqwest::Client,
+}
+
+impl Default for OutboundNetwork {
+    fn default() -> Self {
+        Self::build(Arc::new(OutboundNetworkPolicy::default()), None, None)
+            .expect("Failed to build the default outbound HTTP client")
+    }
+}
+
+impl OutboundNetwork {
+    pub fn new(policy: OutboundNetworkPolicy) -> anyhow::Result<Self> {
+        Self::build(Arc::new(policy), None, None)
+    }
+
+    /// A client with the same policy, which trusts the given CAs. Each call makes
+    /// a new client, with its own connection pool.
+    pub fn with_tls(&self, tls: &OutboundTls) -> anyhow::Result<Self> {
+        Self::build(self.policy.clone(), Some(tls), None)
+    }
+
+    /// A client with the same policy for one module, which trusts the module's CAs
+    /// and sends from its source address, if it has either. Otherwise the module
+    /// shares this client.
+    pub fn for_module(&self, tls: Option<&OutboundTls>, source_ip: Option<IpAddr>) -> anyhow::Result<Self> {
+        if tls.is_none() && source_ip.is_none() {
+            return Ok(self.clone());
+        }
+        Self::build(self.policy.clone(), tls, source_ip)
+    }
+
+    fn build(policy: Arc<OutboundNetworkPolicy>, tls: Option<&OutboundTls>, source_ip: Option<IpAddr>) -> anyhow::Result<Self> {
+        // The client doesn't know which module a request is for, so it can't check
+        // redirects against the module's allowed_hosts. Outbound HTTP follows them
+        // itself, checking each one as it would a new request.
+        let mut builder = reqwest::Client::builder()
+            .local_address(source_ip)
+            .redirect(reqwest::redirect::Policy::none());
+        if !policy.dns_servers.is_empty() || policy.restricts_addresses() {
+            let dns = match policy.dns_servers.as_slice() {
+                [] => None,
+                servers => Some(pinned_resolver(servers)?),
+            };
+            let resolver = PolicyResolver { policy: policy.clone(), dns };
+            builder = builder.dns_resolver(Arc::new(resolver));
+        }
+        if let Some(tls) = tls {
+            for certificate in tls.certificates()? {
+                builder = builder.add_root_certificate(certificate);
+            }
+            builder = builder.tls_built_in_root_certs(!tls.ca_certificates_only);
+        }
+        let client = builder.build()?;
+        Ok(Self { policy, client })
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Checks a URL whose host is an IP address. Names are checked when they are
+    /// resolved.
+    pub fn check_url(&self, url: &Url) -> Result<(), AddressNotPermitted> {
+        self.policy.check_url(url)
+    }
+}
+
+/// Checks that the address belongs to this host, so that requests can be sent
+/// from it.
+pub fn check_source_ip(ip: IpAddr) -> anyhow::Result<()> {
+    std::net::UdpSocket::bind(SocketAddr::new(ip, 0))
+        .with_context(|| format!("{} is not an address of this host", ip))?;
+    Ok(())
+}
+
+fn pinned_resolver(servers: &[SocketAddr]) -> anyhow::Result<TokioAsyncResolver> {
+    let mut name_servers = NameServerConfigGroup::new();
+    for server in servers {
+        name_servers.merge(NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true));
+    }
+    let config = ResolverConfig::from_parts(None, vec![], name_servers);
+    Ok(TokioAsyncResolver::tokio(config, ResolverOpts::default())?)
+}
+
+// Resolves names, then drops the addresses the policy refuses. Because the client
+// connects only to the addresses returned here, a name cannot be re-pointed between
+// the check and the connection.
+struct PolicyResolver {
+    policy: Arc<OutboundNetworkPolicy>,
+    dns: Option<TokioAsyncResolver>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        let dns = self.dns.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_owned();
+            let resolved: Vec<IpAddr> = match dns {
+                Some(dns) => dns.lookup_ip(host.as_str()).await?.iter().collect(),
+                None => tokio::net::lookup_host((host.as_str(), 0)).await?.map(|a| a.ip()).collect(),
+            };
+            let (permitted, refused): (Vec<IpAddr>, Vec<IpAddr>) = resolved.into_iter().partition(|ip| policy.permits(*ip));
+            if !refused.is_empty() {
+                tracing::warn!(%host, ?refused, "Not connecting to addresses that outbound requests may not use");
+            }
+            if permitted.is_empty() {
+                return Err(Box::new(AddressNotPermitted { host, addresses: refused }) as Box<dyn StdError + Send + Sync>);
+            }
+            let addrs: Addrs = Box::new(permitted.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn private_addresses_can_be_blocked() {
+        let policy = OutboundNetworkPolicy { block_private: true, ..Default::default() };
+        for private in ["127.0.0.1", "10.1.2.3", "169.254.169.254", "192.168.0.10", "::1", "fd00::1", "::ffff:10.0.0.1"] {
+            assert!(!policy.permits(ip(private)), "{} should be blocked", private);
+        }
+        assert!(policy.permits(ip("93.184.216.34")));
+        assert!(policy.permits(ip("2606:2800:220:1:248:1893:25c8:1946")));
+        assert!(OutboundNetworkPolicy::default().permits(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn allowed_networks_are_the_only_ones_permitted() {
+        let policy = OutboundNetworkPolicy {
+            block_private: true,
+            allowed_networks: vec!["10.20.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(policy.permits(ip("10.20.1.1")));
+        assert!(!policy.permits(ip("10.21.1.1")));
+        assert!(!policy.permits(ip("93.184.216.34")));
+    }
+
+    #[test]
+    fn source_addresses_must_belong_to_the_host() {
+        check_source_ip(ip("127.0.0.1")).unwrap();
+        // TEST-NET-3 is never assigned to a host
+        check_source_ip(ip("203.0.113.77")).expect_err("the address is not this host's");
+    }
+
+    #[test]
+    fn addresses_in_urls_are_checked() {
+        let policy = OutboundNetworkPolicy { block_private: true, ..Default::default() };
+        let url = |u: &str| Url::parse(u).unwrap();
+        assert!(policy.check_url(&url("http://169.254.169.254/latest/meta-data")).is_err());
+        assert!(policy.check_url(&url("http://[::1]:8080/")).is_err());
+        // Names are checked when they are resolved
+        assert!(policy.check_url(&url("http://localhost/")).is_ok());
+        assert!(policy.check_url(&url("https://93.184.216.34/")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn names_that_resolve_only_to_refused_addresses_fail() {
+        let resolver = PolicyResolver {
+            policy: Arc::new(OutboundNetworkPolicy { block_private: true, ..Default::default() }),
+            dns: None,
+        };
+        let error = match resolver.resolve("localhost".parse().unwrap()).await {
+            Ok(_) => panic!("localhost should not be resolved"),
+            Err(e) => e,
+        };
+        assert!(AddressNotPermitted::is_cause_of(&*error));
+    }
+
+    #[test]
+    fn every_certificate_in_a_bundle_is_trusted() {
+        let bundle = std::fs::read_to_string("testdata/certs/ca-bundle.pem").unwrap();
+        assert_eq!(2, pem_certificates(&bundle).len());
+        let tls = OutboundTls {
+            ca_certificates: vec![PathBuf::from("testdata/certs/ca-bundle.pem")],
+            ca_certificates_only: true,
+        };
+        assert_eq!(2, tls.certificates().unwrap().len());
+        OutboundNetwork::default().with_tls(&tls).expect("should build a client trusting the bundle");
+    }
+
+    #[test]
+    fn files_without_certificates_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.pem");
+        std::fs::write(&path, "not a certificate").unwrap();
+        let tls = OutboundTls { ca_certificates: vec![path], ca_certificates_only: false };
+        tls.certificates().expect_err("there are no certificates in the file");
+        let missing = OutboundTls { ca_certificates: vec![dir.path().join("missing.pem")], ca_certificates_only: false };
+        missing.certificates().expect_err("the file does not exist");
+    }
+}
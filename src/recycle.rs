@@ -0,0 +1,151 @@
+//! Restarting WAGI every so often.
+//!
+//! A long-lived WAGI can slowly grow, as caches fill and memory fragments. With
+//! `--recycle-after-requests N` or `--recycle-after SECONDS`, WAGI shuts down once
+//! it has handled `N` requests or been running that long, as if it had been sent
+//! `SIGTERM`: it drains for `--drain-period`, waits for requests in progress and
+//! exits with status 0, for its supervisor to start a fresh process. Each limit
+//! is brought forward by a random amount, up to `--recycle-jitter` of it, so that
+//! replicas started together don't all restart together.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::Notify;
+
+use crate::wagi_config::duration_from_secs;
+
+const DEFAULT_RECYCLE_JITTER: f64 = 0.1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecycleSettings {
+    /// Recycle after handling this many requests.
+    pub after_requests: Option<u64>,
+    /// Recycle after running for this long.
+    pub after: Option<Duration>,
+    /// The most each limit is brought forward by, as a fraction of it.
+    pub jitter: f64,
+}
+
+impl RecycleSettings {
+    /// Builds the settings from the command line values. There are none unless
+    /// there is a request or time limit.
+    pub fn parse(after_requests: Option<&str>, after: Option<&str>, jitter: Option<&str>) -> anyhow::Result<Option<Self>> {
+        let after_requests = match after_requests {
+            Some(n) => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => anyhow::bail!("Invalid recycle request count '{}': must be a whole number greater than zero", n),
+            },
+            None => None,
+        };
+        let after = match after {
+            Some(s) => match s.parse::<f64>().ok().and_then(duration_from_secs) {
+                Some(after) if !after.is_zero() => Some(after),
+                _ => anyhow::bail!("Invalid recycle time '{}': must be a positive number of seconds, up to 100 years", s),
+            },
+            None => None,
+        };
+        if after_requests.is_none() && after.is_none() {
+            if jitter.is_some() {
+                anyhow::bail!("--recycle-jitter can only be used with --recycle-after-requests or --recycle-after");
+            }
+            return Ok(None);
+        }
+        let jitter = match jitter {
+            Some(j) => match j.parse::<f64>() {
+                Ok(j) if (0.0..1.0).contains(&j) => j,
+                _ => anyhow::bail!("Invalid recycle jitter '{}': must be a fraction from 0 up to, but not including, 1", j),
+            },
+            None => DEFAULT_RECYCLE_JITTER,
+        };
+        Ok(Some(Self { after_requests, after, jitter }))
+    }
+}
+
+/// Counts the requests a server handles, and says when it is time to recycle.
+#[derive(Clone, Debug)]
+pub struct Recycler {
+    request_limit: Option<u64>,
+    time_limit: Option<Duration>,
+    handled: Arc<AtomicU64>,
+    limit_reached: Arc<Notify>,
+}
+
+impl Recycler {
+    /// Picks this process's limits, with their jitter.
+    pub fn new(settings: &RecycleSettings) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut jittered = |limit: f64| limit * (1.0 - rng.gen_range(0.0..=settings.jitter));
+        Self {
+            request_limit: settings.after_requests.map(|n| (jittered(n as f64) as u64).max(1)),
+            time_limit: settings.after.map(|t| Duration::from_secs_f64(jittered(t.as_secs_f64()))),
+            handled: Arc::default(),
+            limit_reached: Arc::default(),
+        }
+    }
+
+    pub fn request_handled(&self) {
+        let handled = self.handled.fetch_add(1, Ordering::Relaxed) + 1;
+        if Some(handled) == self.request_limit {
+            self.limit_reached.notify_one();
+        }
+    }
+
+    /// Completes when the server has handled its requests or run for its time,
+    /// with which it was.
+    pub async fn wait(&self) -> String {
+        let time_limit = async {
+            match self.time_limit {
+                Some(limit) => tokio::time::sleep(limit).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = self.limit_reached.notified() => format!("{} requests", self.handled.load(Ordering::Relaxed)),
+            _ = time_limit => format!("{} seconds", self.time_limit.unwrap_or_default().as_secs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recycling_is_off_unless_there_is_a_limit() {
+        assert_eq!(None, RecycleSettings::parse(None, None, None).unwrap());
+        RecycleSettings::parse(None, None, Some("0.2")).expect_err("jitter with nothing to jitter");
+        RecycleSettings::parse(Some("0"), None, None).expect_err("zero requests");
+        RecycleSettings::parse(None, Some("-5"), None).expect_err("negative time");
+        RecycleSettings::parse(None, Some("1e20"), None).expect_err("time too long to hold");
+        RecycleSettings::parse(Some("10"), None, Some("1")).expect_err("jitter must be under 1");
+
+        let settings = RecycleSettings::parse(Some("1000"), Some("3600"), None).unwrap().unwrap();
+        assert_eq!(Some(Duration::from_secs(3600)), settings.after);
+        assert_eq!(DEFAULT_RECYCLE_JITTER, settings.jitter);
+    }
+
+    #[test]
+    fn jitter_only_brings_limits_forward() {
+        let settings = RecycleSettings { after_requests: Some(1000), after: Some(Duration::from_secs(100)), jitter: 0.5 };
+        for _ in 0..100 {
+            let recycler = Recycler::new(&settings);
+            assert!((500..=1000).contains(&recycler.request_limit.unwrap()));
+            let time_limit = recycler.time_limit.unwrap();
+            assert!(time_limit >= Duration::from_secs(50) && time_limit <= Duration::from_secs(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn recycling_waits_for_the_request_limit() {
+        let settings = RecycleSettings { after_requests: Some(3), after: None, jitter: 0.0 };
+        let recycler = Recycler::new(&settings);
+        for _ in 0..3 {
+            recycler.request_handled();
+        }
+        let reason = tokio::time::timeout(Duration::from_secs(5), recycler.wait()).await.expect("the limit was reached");
+        assert_eq!("3 requests", reason);
+    }
+}
@@ -1,8 +1,32 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::{Arc, RwLock}, time::Duration};
+
+use crate::access_control::IpNetwork;
+use crate::admin_access::AdminAccess;
+use crate::audit::AuditLog;
+use crate::circuit_breaker::CircuitBreakerSettings;
+use crate::custom_handler::CustomHandlers;
+use crate::diagnostics::InFlightRequests;
+use crate::header_limits::HeaderLimits;
+use crate::health_check::HealthCheckSettings;
+use crate::log_level::LogLevel;
+use crate::maintenance::Maintenance;
+use crate::memory_stats::MemoryStats;
+use crate::route_toggle::RouteToggles;
+use crate::sampling::RequestSampler;
+use crate::spill::SpillSettings;
+use crate::scheduler::TaskStatusTable;
+use crate::tenant::LogQuota;
+use crate::url_limits::UrlLimits;
+use crate::metrics::MetricsRegistry;
+use crate::outbound_http::TraceHeaders;
+use crate::outbound_network::OutboundNetwork;
 
 #[derive(Clone, Debug)]
 pub struct RequestContext {
     pub client_addr: SocketAddr,
+    /// If set, the module's stdout buffer is sent here as soon as it is created,
+    /// so that the server can watch for the header block while the module runs.
+    pub stdout_watch: Option<tokio::sync::mpsc::UnboundedSender<Arc<RwLock<Vec<u8>>>>>,
 }
 
 #[derive(Clone, Debug)]
@@ -10,5 +34,53 @@ pub struct RequestGlobalContext {
     pub base_log_dir: PathBuf,
     pub default_host: String,
     pub use_tls: bool,
+    /// Proxies whose `X-Forwarded-Host` and `X-Forwarded-Proto` headers are believed.
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// Who may use the inbuilt routes that change how WAGI serves.
+    pub admin_access: AdminAccess,
     pub global_env_vars: HashMap<String, String>,
+    pub circuit_breaker: Option<CircuitBreakerSettings>,
+    pub metrics: MetricsRegistry,
+    pub default_content_type: Option<String>,
+    pub default_charset: Option<String>,
+    pub response_header_timeout: Option<Duration>,
+    pub module_timeout: Option<Duration>,
+    pub trace_headers: TraceHeaders,
+    /// Whether to show the error and the end of the module's stderr in 500 responses.
+    pub debug_errors: bool,
+    /// Whether to record each run's stdout and stderr, interleaved, in its log directory.
+    pub interleave_output: bool,
+    /// Which requests are recorded in full, if any.
+    pub sampler: Option<RequestSampler>,
+    /// If set, large response bodies go to files rather than staying in memory.
+    pub spill: Option<SpillSettings>,
+    /// Where each request's copy of the volumes of modules with `volume_overlay` goes.
+    pub overlay_dir: PathBuf,
+    /// Whether to serve modules whose volume host directories are missing.
+    pub allow_missing_volumes: bool,
+    pub in_flight: InFlightRequests,
+    pub task_status: TaskStatusTable,
+    /// How much memory recent requests to each route used.
+    pub memory_stats: MemoryStats,
+    /// Routes taken in or out of service at runtime.
+    pub route_toggles: RouteToggles,
+    /// The server or routes in maintenance.
+    pub maintenance: Maintenance,
+    pub health_check: Option<HealthCheckSettings>,
+    /// The limit on the size of `base_log_dir`, if the tenant has one.
+    pub log_quota: Option<LogQuota>,
+    /// Where requests to audited routes are recorded.
+    pub audit_log: AuditLog,
+    /// The control for the log filter, if WAGI installed the tracing subscriber.
+    pub log_level: Option<LogLevel>,
+    /// The client for modules' outbound HTTP requests.
+    pub outbound_network: OutboundNetwork,
+    /// If set, no module gets the outbound HTTP functions.
+    pub deny_outbound_http: bool,
+    /// What is done with request header values that are too long or hold control characters.
+    pub header_limits: HeaderLimits,
+    /// The limits on request URLs, checked before routing.
+    pub url_limits: UrlLimits,
+    /// Native handlers registered by the program embedding WAGI.
+    pub custom_handlers: CustomHandlers,
 }
@@ -0,0 +1,103 @@
+//! Why a path goes to the route it does.
+//!
+//! The inbuilt explain handler lists every route in matching order, including
+//! routes added by modules' `_routes` functions, with whether each one matches a
+//! path and why, and which one would handle it:
+//!
+//! ```text
+//! curl 'http://localhost:3000/_wagi/routes/explain?path=/blog/2021/hello'
+//! ```
+//!
+//! The first route that matches handles the request, so a later route that also
+//! matches never sees it. The reasons point out the usual surprises: exact routes
+//! don't match subpaths or a different trailing slash, and `/...` only matches
+//! whole path segments, so `/blog/...` matches `/blog` and `/blog/x` but not
+//! `/blogs`.
+
+use crate::dispatcher::RoutePattern;
+
+/// The path at which the inbuilt explain handler is mounted.
+pub const EXPLAIN_ROUTE: &str = "/_wagi/routes/explain";
+
+/// The path to explain, from the query string of a GET.
+#[derive(Debug, PartialEq)]
+pub struct ExplainRequest {
+    pub path: String,
+}
+
+impl ExplainRequest {
+    pub fn parse(query: &str) -> anyhow::Result<Self> {
+        let path = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "path")
+            .map(|(_, value)| value.into_owned())
+            .ok_or_else(|| anyhow::anyhow!("the path parameter is required"))?;
+        if !path.starts_with('/') {
+            anyhow::bail!("path must start with '/', not '{}'", path);
+        }
+        Ok(Self { path })
+    }
+}
+
+/// Whether the pattern matches the path, and why.
+pub fn explain_match(pattern: &RoutePattern, path: &str) -> (bool, String) {
+    let matched = pattern.is_match(path);
+    let reason = match pattern {
+        RoutePattern::Exact(route) if route == path => "the path is exactly the route".to_owned(),
+        RoutePattern::Exact(route) if format!("{}/", route) == path => {
+            "the path ends with a slash and the route does not; exact routes don't ignore trailing slashes".to_owned()
+        }
+        RoutePattern::Exact(route) if format!("{}/", path) == *route => {
+            "the route ends with a slash and the path does not; exact routes don't ignore trailing slashes".to_owned()
+        }
+        RoutePattern::Exact(route) if path.starts_with(&format!("{}/", route.trim_end_matches('/'))) => {
+            "the path is below the route, but exact routes don't match subpaths; end the route with /... to match them".to_owned()
+        }
+        RoutePattern::Exact(_) => "the path is not the route".to_owned(),
+        RoutePattern::Prefix(prefix) if prefix == path => "the path is the route without its /...".to_owned(),
+        RoutePattern::Prefix(prefix) if prefix.is_empty() => "/... matches every path".to_owned(),
+        RoutePattern::Prefix(prefix) if matched => format!("the path is below {}/", prefix),
+        RoutePattern::Prefix(prefix) if prefix.ends_with('/') && path.starts_with(prefix.as_str()) => format!(
+            "the route's prefix {} ends with a slash, so it only matches that path or paths below {}/",
+            prefix, prefix
+        ),
+        RoutePattern::Prefix(prefix) if path.starts_with(prefix.as_str()) => format!(
+            "the path starts with {} but not {}/; /... only matches whole path segments",
+            prefix, prefix
+        ),
+        RoutePattern::Prefix(prefix) => format!("the path is neither {} nor below it", prefix),
+    };
+    (matched, reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn explain(route: &str, path: &str) -> (bool, String) {
+        explain_match(&RoutePattern::parse(route), path)
+    }
+
+    #[test]
+    fn reasons_point_out_near_misses() {
+        assert_eq!((true, "the path is exactly the route".to_owned()), explain("/blog", "/blog"));
+        assert!(explain("/blog", "/blog/").1.contains("trailing slashes"));
+        assert!(explain("/blog/", "/blog").1.contains("trailing slashes"));
+        assert!(explain("/blog", "/blog/post").1.contains("don't match subpaths"));
+        assert_eq!((true, "the path is the route without its /...".to_owned()), explain("/blog/...", "/blog"));
+        assert_eq!((true, "the path is below /blog/".to_owned()), explain("/blog/...", "/blog/post"));
+        assert_eq!((true, "/... matches every path".to_owned()), explain("/...", "/anything"));
+        let (matched, reason) = explain("/blog/...", "/blogs");
+        assert!(!matched);
+        assert!(reason.contains("whole path segments"), "{}", reason);
+        let (matched, reason) = explain("/blog//...", "/blog/post");
+        assert!(!matched);
+        assert!(reason.contains("ends with a slash"), "{}", reason);
+    }
+
+    #[test]
+    fn the_path_is_required() {
+        assert_eq!(ExplainRequest { path: "/a b".to_owned() }, ExplainRequest::parse("path=%2Fa+b").unwrap());
+        ExplainRequest::parse("").expect_err("path is required");
+        ExplainRequest::parse("path=blog").expect_err("path must be absolute");
+    }
+}
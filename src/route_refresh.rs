@@ -0,0 +1,80 @@
+//! Asking modules for their routes again without restarting.
+//!
+//! WAGI calls each module's `_routes` entrypoint when it builds the routing table.
+//! If a module's routes come from data, such as pages in a CMS, the module can be
+//! asked again, and its routes replaced, from the local machine:
+//!
+//! ```text
+//! curl -X POST 'http://localhost:3000/_wagi/routes/refresh?module=cms.wasm'
+//! ```
+//!
+//! or every so often with `--refresh-dynamic-routes`. The new routes replace the
+//! old ones all at once: requests see either the old routes or the new ones, never
+//! a mix. If any selected module's `_routes` fails, the old routes stay.
+
+use std::time::Duration;
+
+use crate::dispatcher::LiveRoutingTable;
+
+/// The path at which the inbuilt route refresh handler is mounted.
+pub const ROUTES_REFRESH_ROUTE: &str = "/_wagi/routes/refresh";
+
+/// Which modules to ask for their routes again, from the query string of a POST.
+/// Modules are selected by name or by the route they are configured at; with
+/// neither, every module is asked.
+#[derive(Debug, Default, PartialEq)]
+pub struct RefreshRequest {
+    pub modules: Vec<String>,
+    pub routes: Vec<String>,
+}
+
+impl RefreshRequest {
+    pub fn parse(query: &str) -> Self {
+        let mut request = Self::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "module" => request.modules.push(value.into_owned()),
+                "route" => request.routes.push(value.into_owned()),
+                _ => (),
+            }
+        }
+        request
+    }
+
+    /// A request for every module.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn selects(&self, module: &str, route: &str) -> bool {
+        (self.modules.is_empty() && self.routes.is_empty())
+            || self.modules.iter().any(|m| m == module)
+            || self.routes.iter().any(|r| r == route)
+    }
+}
+
+/// Periodically asks every module for its routes again. Runs until the process exits.
+pub async fn refresh_periodically(interval: Duration, routing_table: LiveRoutingTable) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = routing_table.refresh_dynamic_routes(RefreshRequest::all()).await {
+            tracing::error!(error = %format!("{:#}", e), "Could not refresh dynamic routes; keeping the existing routes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modules_can_be_selected_by_name_or_route() {
+        let request = RefreshRequest::parse("module=cms.wasm&route=%2Fblog%2F...&other=1");
+        assert_eq!(vec!["cms.wasm"], request.modules);
+        assert!(request.selects("cms.wasm", "/"));
+        assert!(request.selects("blog.wasm", "/blog/..."));
+        assert!(!request.selects("shop.wasm", "/shop"));
+
+        assert!(RefreshRequest::parse("").selects("shop.wasm", "/shop"));
+    }
+}
@@ -0,0 +1,92 @@
+//! Taking routes in and out of service without changing the configuration.
+//!
+//! A module entry with `enabled = false` is loaded but answers 503 until it is
+//! enabled. The inbuilt routes handler lists every route with its state, and from
+//! the local machine a route can be enabled or disabled at runtime:
+//!
+//! ```text
+//! curl -X POST 'http://localhost:3000/_wagi/routes?route=/reports/...&enabled=false'
+//! ```
+//!
+//! Runtime changes last until WAGI restarts, including across watch mode reloads,
+//! and take precedence over the configuration.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The path at which the inbuilt routes handler is mounted.
+pub const ROUTES_ROUTE: &str = "/_wagi/routes";
+
+/// Routes enabled or disabled at runtime, by route pattern.
+#[derive(Clone, Debug, Default)]
+pub struct RouteToggles {
+    overrides: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl RouteToggles {
+    /// Whether the route is in service, given whether its configuration enables it.
+    pub fn is_enabled(&self, route: &str, configured: bool) -> bool {
+        self.overrides.lock().unwrap().get(route).copied().unwrap_or(configured)
+    }
+
+    pub fn set(&self, route: &str, enabled: bool) {
+        self.overrides.lock().unwrap().insert(route.to_owned(), enabled);
+    }
+}
+
+/// A requested change to a route's state, from the query string of a POST.
+#[derive(Debug, PartialEq)]
+pub struct ToggleRequest {
+    pub route: String,
+    pub enabled: bool,
+}
+
+impl ToggleRequest {
+    pub fn parse(query: &str) -> anyhow::Result<Self> {
+        let mut route = None;
+        let mut enabled = None;
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "route" => route = Some(value.into_owned()),
+                "enabled" => match value.as_ref() {
+                    "true" => enabled = Some(true),
+                    "false" => enabled = Some(false),
+                    other => anyhow::bail!("enabled must be true or false, not '{}'", other),
+                },
+                _ => (),
+            }
+        }
+        Ok(Self {
+            route: route.ok_or_else(|| anyhow::anyhow!("the route parameter is required"))?,
+            enabled: enabled.ok_or_else(|| anyhow::anyhow!("the enabled parameter is required"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runtime_changes_override_the_configuration() {
+        let toggles = RouteToggles::default();
+        assert!(toggles.is_enabled("/a", true));
+        assert!(!toggles.is_enabled("/b", false));
+
+        toggles.set("/a", false);
+        toggles.set("/b", true);
+        assert!(!toggles.is_enabled("/a", true));
+        assert!(toggles.is_enabled("/b", false));
+    }
+
+    #[test]
+    fn toggle_requests_are_parsed_from_the_query() {
+        assert_eq!(
+            ToggleRequest { route: "/reports/...".to_owned(), enabled: false },
+            ToggleRequest::parse("route=%2Freports%2F...&enabled=false").unwrap()
+        );
+        ToggleRequest::parse("route=/a").expect_err("enabled is required");
+        ToggleRequest::parse("enabled=true").expect_err("route is required");
+        ToggleRequest::parse("route=/a&enabled=yes").expect_err("enabled must be a boolean");
+    }
+}
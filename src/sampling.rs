@@ -0,0 +1,310 @@
+//! Recording everything about a sample of requests.
+//!
+//! Stderr and the interleaved output show what a module wrote, but not what it was
+//! given. With `--sample-every N`, WAGI records one in every `N` requests to each
+//! route in full: the environment and arguments the module was run with, its
+//! stdout and stderr, interleaved as with `--interleave-output`, and the status it
+//! answered with or the error it failed with. With `--sample-header NAME[=VALUE]`,
+//! it also records every request that has that header, so that a client can ask
+//! for its own request to be recorded.
+//!
+//! Each sample is a directory in the `samples` directory of the log directory,
+//! named for when the request arrived, holding `request.json` and `output.log`.
+//! Once the `samples` directory reaches `--sample-quota`, no more samples are
+//! recorded until space is freed. The environment holds the request's headers,
+//! including any cookies or credentials, and the global environment variables, so
+//! the samples must be kept as private as the modules' secrets.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyper::header::HeaderName;
+use hyper::http::request::Parts;
+use hyper::StatusCode;
+use serde::Serialize;
+
+use crate::interleave::InterleavedLog;
+
+const SAMPLES_DIR: &str = "samples";
+const DEFAULT_SAMPLE_QUOTA: u64 = 100 << 20;
+
+// Tells apart samples that start in the same millisecond
+static SAMPLE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplingSettings {
+    /// Record one in this many requests to each route.
+    pub every: Option<u64>,
+    /// Record every request that has this header.
+    pub header: Option<SampleHeader>,
+    /// The limit on the size of the samples directory.
+    pub quota: u64,
+}
+
+/// A request header, and optionally the value it must have, that asks for the
+/// request to be sampled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleHeader {
+    pub name: HeaderName,
+    pub value: Option<String>,
+}
+
+impl SamplingSettings {
+    /// Builds the settings from the command line values. There are none unless
+    /// requests are sampled by count or by header.
+    pub fn parse(every: Option<&str>, header: Option<&str>, quota: Option<&str>) -> anyhow::Result<Option<Self>> {
+        let every = match every {
+            Some(n) => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => anyhow::bail!("Invalid sample rate '{}': must be a whole number greater than zero", n),
+            },
+            None => None,
+        };
+        let header = header.map(SampleHeader::parse).transpose()?;
+        if every.is_none() && header.is_none() {
+            if quota.is_some() {
+                anyhow::bail!("--sample-quota can only be used with --sample-every or --sample-header");
+            }
+            return Ok(None);
+        }
+        let quota = match quota {
+            Some(q) => crate::tenant::parse_size(q)?,
+            None => DEFAULT_SAMPLE_QUOTA,
+        };
+        Ok(Some(Self { every, header, quota }))
+    }
+}
+
+impl SampleHeader {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let (name, value) = match text.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (text, None),
+        };
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| anyhow::anyhow!("Invalid sample header '{}': expected NAME or NAME=VALUE", text))?;
+        Ok(Self { name, value })
+    }
+
+    fn matches(&self, parts: &Parts) -> bool {
+        parts.headers.get_all(&self.name).iter().any(|v| match &self.value {
+            Some(value) => v.as_bytes() == value.as_bytes(),
+            None => true,
+        })
+    }
+}
+
+/// Decides which requests are sampled, and starts recording them.
+#[derive(Clone, Debug)]
+pub struct RequestSampler {
+    settings: SamplingSettings,
+    dir: PathBuf,
+    counts: Arc<Mutex<HashMap<String, u64>>>,
+    over_quota: Arc<AtomicBool>,
+}
+
+impl RequestSampler {
+    pub fn new(settings: SamplingSettings, log_dir: &Path) -> Self {
+        Self {
+            settings,
+            dir: log_dir.join(SAMPLES_DIR),
+            counts: Arc::default(),
+            over_quota: Arc::default(),
+        }
+    }
+
+    /// Starts a sample of the request, if it is to be sampled and there is space.
+    /// Not being able to record a sample never fails the request.
+    pub fn start(&self, route: &str, module: &str, parts: &Parts) -> Option<Sample> {
+        if !self.is_sampled(route, parts) || !self.has_space() {
+            return None;
+        }
+        match Sample::create(&self.dir, route, module, parts) {
+            Ok(sample) => Some(sample),
+            Err(e) => {
+                tracing::warn!(%route, error = %format!("{:#}", e), "Could not record request sample");
+                None
+            }
+        }
+    }
+
+    fn is_sampled(&self, route: &str, parts: &Parts) -> bool {
+        if matches!(&self.settings.header, Some(header) if header.matches(parts)) {
+            return true;
+        }
+        match self.settings.every {
+            Some(every) => {
+                let mut counts = self.counts.lock().unwrap();
+                let count = counts.entry(route.to_owned()).or_default();
+                *count += 1;
+                *count % every == 0
+            }
+            None => false,
+        }
+    }
+
+    // Warns when the directory first reaches the quota, rather than on every
+    // sampled request after that
+    fn has_space(&self) -> bool {
+        let (_, used) = crate::diagnostics::directory_usage(&self.dir);
+        let exceeded = used >= self.settings.quota;
+        if exceeded != self.over_quota.swap(exceeded, Ordering::Relaxed) {
+            if exceeded {
+                tracing::warn!(dir = %self.dir.display(), quota = self.settings.quota, "Request samples are over their quota; no more requests will be sampled");
+            } else {
+                tracing::info!(dir = %self.dir.display(), quota = self.settings.quota, "Request samples are under their quota again; sampling requests");
+            }
+        }
+        !exceeded
+    }
+}
+
+/// The record of one sampled request. What has been recorded is written to
+/// `request.json` when the sample is dropped, so a request that fails part way
+/// through still leaves a sample.
+pub struct Sample {
+    dir: PathBuf,
+    started: Instant,
+    output: InterleavedLog,
+    record: SampleRecord,
+}
+
+#[derive(Serialize)]
+struct SampleRecord {
+    route: String,
+    module: String,
+    method: String,
+    uri: String,
+    env: BTreeMap<String, String>,
+    argv: Vec<String>,
+    status: Option<u16>,
+    error: Option<String>,
+    duration_seconds: f64,
+}
+
+impl Sample {
+    fn create(samples_dir: &Path, route: &str, module: &str, parts: &Parts) -> anyhow::Result<Self> {
+        let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let sample = SAMPLE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = samples_dir.join(format!("{}-{}", timestamp, sample));
+        std::fs::create_dir_all(&dir)?;
+        tracing::debug!(path = %dir.display(), %route, "Sampling request");
+        Ok(Self {
+            output: InterleavedLog::create_at(&dir.join("output.log"))?,
+            dir,
+            started: Instant::now(),
+            record: SampleRecord {
+                route: route.to_owned(),
+                module: module.to_owned(),
+                method: parts.method.to_string(),
+                uri: parts.uri.to_string(),
+                env: BTreeMap::new(),
+                argv: vec![],
+                status: None,
+                error: None,
+                duration_seconds: 0.0,
+            },
+        })
+    }
+
+    /// Where the module's stdout and stderr are recorded.
+    pub fn output(&self) -> InterleavedLog {
+        self.output.clone()
+    }
+
+    pub fn record_env(&mut self, env: &HashMap<String, String>, argv: &[String]) {
+        self.record.env = env.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        self.record.argv = argv.to_vec();
+    }
+
+    pub fn record_status(&mut self, status: StatusCode) {
+        self.record.status = Some(status.as_u16());
+    }
+
+    pub fn record_error(&mut self, error: &anyhow::Error) {
+        self.record.error = Some(format!("{:#}", error));
+    }
+
+    fn write(&mut self) -> anyhow::Result<()> {
+        self.record.duration_seconds = self.started.elapsed().as_secs_f64();
+        std::fs::write(self.dir.join("request.json"), serde_json::to_vec_pretty(&self.record)?)?;
+        Ok(())
+    }
+}
+
+impl Drop for Sample {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            tracing::warn!(path = %self.dir.display(), error = %format!("{:#}", e), "Could not write request sample");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Parts {
+        let mut builder = hyper::Request::get("http://example.com/a?b=c");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn sampling_is_off_unless_requested() {
+        assert_eq!(None, SamplingSettings::parse(None, None, None).unwrap());
+        SamplingSettings::parse(None, None, Some("10Mi")).expect_err("a quota with nothing to sample");
+        SamplingSettings::parse(Some("0"), None, None).expect_err("zero is not a rate");
+        SamplingSettings::parse(None, Some("bad header"), None).expect_err("not a header name");
+
+        let settings = SamplingSettings::parse(None, Some("X-Wagi-Sample=yes"), None).unwrap().unwrap();
+        assert_eq!(DEFAULT_SAMPLE_QUOTA, settings.quota);
+        assert_eq!(Some("yes".to_owned()), settings.header.unwrap().value);
+    }
+
+    #[test]
+    fn one_in_every_n_requests_per_route_is_sampled() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = SamplingSettings::parse(Some("3"), Some("x-sample=1"), None).unwrap().unwrap();
+        let sampler = RequestSampler::new(settings, dir.path());
+        let plain = request(&[]);
+
+        let sampled: Vec<_> = (0..6).map(|_| sampler.is_sampled("/a", &plain)).collect();
+        assert_eq!(vec![false, false, true, false, false, true], sampled);
+        assert!(!sampler.is_sampled("/b", &plain), "each route has its own count");
+        assert!(sampler.is_sampled("/b", &request(&[("X-Sample", "1")])));
+        assert!(!sampler.is_sampled("/b", &request(&[("X-Sample", "0")])));
+    }
+
+    #[test]
+    fn samples_are_written_when_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = SamplingSettings::parse(None, Some("x-sample"), None).unwrap().unwrap();
+        let sampler = RequestSampler::new(settings, dir.path());
+        let mut sample = sampler.start("/a", "a.wasm", &request(&[("x-sample", "")])).expect("should be sampled");
+        sample.record_env(&HashMap::from([("PATH_INFO".to_owned(), "/a".to_owned())]), &["/a".to_owned()]);
+        sample.record_status(StatusCode::NOT_FOUND);
+        let sample_dir = sample.dir.clone();
+        drop(sample);
+
+        let record: serde_json::Value = serde_json::from_slice(&std::fs::read(sample_dir.join("request.json")).unwrap()).unwrap();
+        assert_eq!("/a", record["route"]);
+        assert_eq!("/a", record["env"]["PATH_INFO"]);
+        assert_eq!(404, record["status"]);
+        assert!(sample_dir.join("output.log").exists());
+    }
+
+    #[test]
+    fn nothing_is_sampled_over_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = SamplingSettings::parse(Some("1"), None, Some("1")).unwrap().unwrap();
+        let sampler = RequestSampler::new(settings, dir.path());
+        drop(sampler.start("/a", "a.wasm", &request(&[])).expect("the directory starts empty"));
+        assert!(sampler.start("/a", "a.wasm", &request(&[])).is_none());
+    }
+}
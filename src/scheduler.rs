@@ -0,0 +1,363 @@
+//! Scheduled background tasks (`[[task]]` in `modules.toml`).
+//!
+//! A task is a module that WAGI runs on a cron-style schedule rather than in
+//! response to a request, for jobs like cleaning up or refreshing data. Tasks get
+//! the same WASI setup as request handlers (volumes, outbound HTTP, stderr handling
+//! and timeouts) but no request: stdin is empty and what they write to stdout is
+//! discarded. Schedules are in UTC. A run that is still going when the next one is
+//! due is not overlapped; the missed run is skipped.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use wasi_cap_std_sync::WasiCtxBuilder;
+
+use crate::handler_loader::WasmTaskConfigurationEntry;
+use crate::outbound_http::{with_request_context, OutboundRequestContext};
+use crate::request::RequestGlobalContext;
+use crate::wasm_runner::{preopen_volumes, prepare_stdio_streams, prepare_wasm_instance, run_prepared_wasm_instance, WasmLinkOptions};
+
+/// The path at which the inbuilt task status handler is mounted.
+pub const TASKS_ROUTE: &str = "/_wagi/tasks";
+
+const TASK_RUNS_METRIC: &str = "wagi_task_runs_total";
+const DEFAULT_TASK_ENTRYPOINT: &str = "_start";
+
+/// A cron schedule: minute, hour, day of month, month and day of week.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // Cron matches either day field if both are restricted, and both otherwise
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl Schedule {
+    /// Parses a five-field cron expression such as `*/15 * * * *` or `0 3 * * 1-5`,
+    /// or one of `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly`. Fields
+    /// may be `*`, numbers, ranges (`1-5`), steps (`*/10`, `0-30/5`) and lists of
+    /// these. Days of the week run from 0 (Sunday) to 6; 7 is also Sunday.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let expanded = match text.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            anyhow::bail!("Invalid schedule '{}': expected 5 fields (minute hour day-of-month month day-of-week)", text);
+        }
+        let field = |index: usize, name: &str, min: u32, max: u32| {
+            parse_field(fields[index], min, max)
+                .with_context(|| format!("Invalid {} field '{}' in schedule '{}'", name, fields[index], text))
+        };
+        let mut days_of_week = field(4, "day of week", 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            text: text.to_owned(),
+            minutes: field(0, "minute", 0, 59)?,
+            hours: field(1, "hour", 0, 23)?,
+            days_of_month: field(2, "day of month", 1, 31)?,
+            months: field(3, "month", 1, 12)?,
+            days_of_week,
+            days_of_month_restricted: !fields[2].starts_with('*'),
+            days_of_week_restricted: !fields[4].starts_with('*'),
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The first time after `after` that the schedule fires, or `None` if it never
+    /// does (e.g. `0 0 31 2 *`).
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.naive_utc().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        // Every schedule that fires at all fires within any eight-year window
+        let limit = start + chrono::Duration::days(8 * 366);
+        let mut t = start;
+        while t <= limit {
+            if !has_bit(self.months, t.month()) {
+                t = start_of_next_month(t)?;
+            } else if !self.day_matches(t.date()) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has_bit(self.hours, t.hour()) {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + chrono::Duration::hours(1);
+            } else if !has_bit(self.minutes, t.minute()) {
+                t += chrono::Duration::minutes(1);
+            } else {
+                return Some(Utc.from_utc_datetime(&t));
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let dom = has_bit(self.days_of_month, date.day());
+        let dow = has_bit(self.days_of_week, date.weekday().num_days_from_sunday());
+        if self.days_of_month_restricted && self.days_of_week_restricted {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+}
+
+fn has_bit(bits: u64, n: u32) -> bool {
+    bits & (1 << n) != 0
+}
+
+fn start_of_next_month(t: NaiveDateTime) -> Option<NaiveDateTime> {
+    let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+    NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)
+}
+
+// Returns a bit set with bit n set if the field matches n.
+fn parse_field(text: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().map_err(|_| anyhow::anyhow!("invalid step '{}'", step))?)),
+            None => (part, None),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (parse_number(a, min, max)?, parse_number(b, min, max)?)
+        } else {
+            let n = parse_number(range, min, max)?;
+            // `5/15` means every 15 starting at 5
+            (n, if step.is_some() { max } else { n })
+        };
+        if first > last {
+            anyhow::bail!("range {}-{} is backwards", first, last);
+        }
+        let step = match step {
+            Some(0) => anyhow::bail!("step must be at least 1"),
+            Some(s) => s,
+            None => 1,
+        };
+        for n in (first..=last).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_number(text: &str, min: u32, max: u32) -> anyhow::Result<u32> {
+    match text.parse::<u32>() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(anyhow::anyhow!("'{}' is not a number from {} to {}", text, min, max)),
+    }
+}
+
+/// The state of each scheduled task, for the task status route.
+#[derive(Clone, Debug, Default)]
+pub struct TaskStatusTable {
+    tasks: Arc<Mutex<BTreeMap<String, TaskStatus>>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct TaskStatus {
+    name: String,
+    module: String,
+    schedule: String,
+    running: bool,
+    next_run: Option<String>,
+    last_started: Option<String>,
+    last_duration_seconds: Option<f64>,
+    last_outcome: Option<String>,
+    last_error: Option<String>,
+    runs: u64,
+    failures: u64,
+}
+
+impl TaskStatusTable {
+    fn update(&self, name: &str, f: impl FnOnce(&mut TaskStatus)) {
+        let mut tasks = self.tasks.lock().unwrap();
+        f(tasks.entry(name.to_owned()).or_default())
+    }
+
+    pub fn render_json(&self) -> String {
+        let tasks = self.tasks.lock().unwrap();
+        let tasks: Vec<&TaskStatus> = tasks.values().collect();
+        // Serializing plain values cannot fail.
+        serde_json::to_string_pretty(&serde_json::json!({ "tasks": tasks })).unwrap_or_default()
+    }
+}
+
+/// Starts running each task on its schedule, in the background.
+pub fn start(tasks: &[WasmTaskConfigurationEntry], global_context: RequestGlobalContext) {
+    for task in tasks {
+        global_context.task_status.update(&task.info.name, |s| {
+            s.name = task.info.name.clone();
+            s.module = task.info.module.clone();
+            s.schedule = task.info.schedule.text().to_owned();
+        });
+        tokio::spawn(run_on_schedule(task.clone(), global_context.clone()));
+    }
+}
+
+async fn run_on_schedule(task: WasmTaskConfigurationEntry, global_context: RequestGlobalContext) {
+    let name = task.info.name.clone();
+    let status = &global_context.task_status;
+    let mut after = Utc::now();
+    loop {
+        let next = match task.info.schedule.next_after(after) {
+            Some(n) => n,
+            None => {
+                tracing::warn!(task = %name, schedule = task.info.schedule.text(), "Task schedule never fires; task will not run");
+                return;
+            }
+        };
+        status.update(&name, |s| s.next_run = Some(next.to_rfc3339()));
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let started = Instant::now();
+        status.update(&name, |s| {
+            s.running = true;
+            s.last_started = Some(Utc::now().to_rfc3339());
+        });
+        tracing::info!(task = %name, "Running scheduled task");
+        let result = run_task(&task, &global_context).await;
+        let elapsed = started.elapsed();
+
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        global_context.metrics.increment_counter(TASK_RUNS_METRIC, &[("task", name.as_str()), ("outcome", outcome)]);
+        let error = result.err().map(|e| format!("{:#}", e));
+        match &error {
+            None => tracing::info!(task = %name, ?elapsed, "Scheduled task completed"),
+            Some(e) => tracing::error!(task = %name, ?elapsed, error = %e, "Scheduled task failed"),
+        }
+        status.update(&name, |s| {
+            s.running = false;
+            s.runs += 1;
+            s.last_duration_seconds = Some(elapsed.as_secs_f64());
+            s.last_outcome = Some(outcome.to_owned());
+            if error.is_some() {
+                s.failures += 1;
+            }
+            s.last_error = error;
+        });
+
+        // Never fire twice for the same slot, even if the clock went backwards
+        after = std::cmp::max(Utc::now(), next);
+    }
+}
+
+async fn run_task(task: &WasmTaskConfigurationEntry, global_context: &RequestGlobalContext) -> anyhow::Result<()> {
+    let info = &task.info;
+    let redirects = prepare_stdio_streams(vec![], global_context, task_log_key(&info.name), info.stderr, &info.name, None, None)?;
+
+    let mut env: HashMap<String, String> = global_context.global_env_vars.clone();
+    env.insert("WAGI_TASK_NAME".to_owned(), info.name.clone());
+    let env: Vec<(String, String)> = env.into_iter().collect();
+    let builder = WasiCtxBuilder::new()
+        .args(&[info.name.clone()])?
+        .envs(&env)?
+        .stderr(redirects.streams.stderr)
+        .stdout(redirects.streams.stdout)
+        .stdin(Box::new(redirects.streams.stdin));
+    let ctx = preopen_volumes(builder, &info.volume_mounts)?.build();
+
+    let link_options = WasmLinkOptions::none()
+        .with_http(info.allowed_hosts.clone(), info.http_max_concurrency)
+        .with_outbound_network(global_context.outbound_network.clone())
+        .with_http_denied(global_context.deny_outbound_http);
+    let (store, instance) = prepare_wasm_instance(ctx, &task.module, link_options).await?;
+
+    let entrypoint = info.entrypoint.clone().unwrap_or_else(|| DEFAULT_TASK_ENTRYPOINT.to_owned());
+    let outbound_context = OutboundRequestContext::for_request(
+        &info.name,
+        &hyper::HeaderMap::new(),
+        global_context.trace_headers,
+        global_context.metrics.clone(),
+    );
+    let run = with_request_context(outbound_context, run_prepared_wasm_instance(instance, store, &entrypoint, &info.name));
+    let result = match info.timeout {
+        None => run.await,
+        Some(timeout) => tokio::time::timeout(timeout, run)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Task did not complete within {:?}", timeout))),
+    };
+    result.map(|_memory_bytes| ()).map_err(|e| {
+        let stderr_tail = redirects.stderr_tail.lines();
+        if stderr_tail.is_empty() {
+            e
+        } else {
+            e.context(format!("Last lines of task stderr:\n{}", stderr_tail.join("\n")))
+        }
+    })
+}
+
+// Tasks log to their own directory, named so it cannot collide with a route's
+fn task_log_key(name: &str) -> String {
+    let safe_name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    format!("task-{}", safe_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(schedule: &str, after: &str) -> String {
+        Schedule::parse(schedule).unwrap().next_after(at(after)).unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn steps_ranges_and_lists_are_understood() {
+        assert_eq!("2024-03-01T10:15:00+00:00", next("*/15 * * * *", "2024-03-01T10:07:30Z"));
+        assert_eq!("2024-03-01T10:30:00+00:00", next("*/15 * * * *", "2024-03-01T10:15:00Z"));
+        assert_eq!("2024-03-01T12:05:00+00:00", next("5 9-17/3 * * *", "2024-03-01T10:07:00Z"));
+        assert_eq!("2024-03-01T10:20:00+00:00", next("0,20,40 * * * *", "2024-03-01T10:07:00Z"));
+    }
+
+    #[test]
+    fn schedules_roll_over_days_months_and_years() {
+        assert_eq!("2024-03-02T00:00:00+00:00", next("@daily", "2024-03-01T10:00:00Z"));
+        assert_eq!("2024-04-01T00:00:00+00:00", next("@monthly", "2024-03-01T10:00:00Z"));
+        assert_eq!("2025-01-01T00:00:00+00:00", next("@yearly", "2024-03-01T10:00:00Z"));
+        assert_eq!("2028-02-29T00:00:00+00:00", next("0 0 29 2 *", "2024-03-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        // 2024-03-04 is a Monday, and comes before the 15th
+        assert_eq!("2024-03-04T00:00:00+00:00", next("0 0 15 * 1", "2024-03-01T10:00:00Z"));
+        // 7 is Sunday too; 2024-03-03 is a Sunday
+        assert_eq!("2024-03-03T00:00:00+00:00", next("0 0 * * 7", "2024-03-01T10:00:00Z"));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        Schedule::parse("* * * *").expect_err("four fields");
+        Schedule::parse("60 * * * *").expect_err("minute out of range");
+        Schedule::parse("*/0 * * * *").expect_err("zero step");
+        Schedule::parse("30-10 * * * *").expect_err("backwards range");
+        assert_eq!(None, Schedule::parse("0 0 31 2 *").unwrap().next_after(at("2024-03-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn task_log_directories_are_safe_names() {
+        assert_eq!("task-nightly_cleanup_v2", task_log_key("nightly cleanup/v2"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Keeping large responses out of memory.
+//!
+//! A module's stdout is collected in memory until it finishes, and then sent as
+//! the response. With `--spill-responses-over SIZE`, once a module has written its
+//! headers and its output passes `SIZE`, the body written so far, and everything
+//! after it, goes to a temporary file in `--spill-dir` instead, and the response is
+//! streamed from the file. The headers stay in memory, so timeouts that watch for
+//! them work as before. The file has no name, so it is removed as soon as the
+//! response has been sent, or if WAGI stops.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures::StreamExt;
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::http_util::response_body_start;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpillSettings {
+    /// How much output a module may write before its body goes to a file.
+    pub threshold: usize,
+    /// Where the files go.
+    pub dir: PathBuf,
+}
+
+/// The file a module's response body went to, if it went to one.
+#[derive(Clone, Default)]
+pub struct SpilledBody(Arc<Mutex<Option<File>>>);
+
+impl SpilledBody {
+    /// Takes the file, ready to be read from the start.
+    pub fn take(&self) -> std::io::Result<Option<File>> {
+        match self.0.lock().unwrap().take() {
+            Some(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Some(file))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Writes a module's stdout to the shared buffer until it passes the threshold,
+/// and its body to a file after that.
+pub struct SpillingWriter {
+    buffer: Arc<RwLock<Vec<u8>>>,
+    spilled: SpilledBody,
+    settings: SpillSettings,
+}
+
+impl SpillingWriter {
+    pub fn new(buffer: Arc<RwLock<Vec<u8>>>, spilled: SpilledBody, settings: SpillSettings) -> Self {
+        Self { buffer, spilled, settings }
+    }
+}
+
+impl Write for SpillingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut spilled = self.spilled.0.lock().unwrap();
+        if let Some(file) = spilled.as_mut() {
+            file.write_all(buf)?;
+            return Ok(buf.len());
+        }
+        let mut buffer = self.buffer.write().unwrap();
+        buffer.extend_from_slice(buf);
+        // Output with no complete header block yet stays in memory, as the headers
+        // are needed to compose the response
+        if buffer.len() > self.settings.threshold {
+            if let Some(start) = response_body_start(&buffer) {
+                let mut file = tempfile::tempfile_in(&self.settings.dir)?;
+                file.write_all(&buffer[start..])?;
+                buffer.truncate(start);
+                buffer.shrink_to_fit();
+                tracing::debug!(dir = %self.settings.dir.display(), "Spilling response body to a file");
+                *spilled = Some(file);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.spilled.0.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A response body of `head` followed by the contents of the file, read as the
+/// client takes them.
+pub fn body(head: Vec<u8>, file: File) -> Body {
+    stream_body(head, tokio::fs::File::from_std(file))
+}
+
+/// The bytes in `range` of the body that `body` would give, for a range request.
+pub fn range_body(head: Vec<u8>, mut file: File, range: Range<u64>) -> std::io::Result<Body> {
+    let head_len = head.len() as u64;
+    let head_part = head[range.start.min(head_len) as usize..range.end.min(head_len) as usize].to_vec();
+    let file_start = range.start.saturating_sub(head_len);
+    let file_end = range.end.saturating_sub(head_len);
+    file.seek(SeekFrom::Start(file_start))?;
+    Ok(stream_body(head_part, tokio::fs::File::from_std(file).take(file_end - file_start)))
+}
+
+fn stream_body(head: Vec<u8>, reader: impl AsyncRead + Send + Unpin + 'static) -> Body {
+    let chunks = futures::stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match reader.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(chunk), Some(reader)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let head = futures::stream::once(async move { Ok::<_, std::io::Error>(head) });
+    Body::wrap_stream(head.chain(chunks))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn writer(threshold: usize) -> (SpillingWriter, Arc<RwLock<Vec<u8>>>, SpilledBody) {
+        let buffer = Arc::new(RwLock::new(vec![]));
+        let spilled = SpilledBody::default();
+        let settings = SpillSettings { threshold, dir: std::env::temp_dir() };
+        (SpillingWriter::new(buffer.clone(), spilled.clone(), settings), buffer, spilled)
+    }
+
+    #[test]
+    fn small_responses_stay_in_memory() {
+        let (mut writer, buffer, spilled) = writer(64);
+        writer.write_all(b"Content-Type: text/plain\n\nhello").unwrap();
+        assert_eq!(b"Content-Type: text/plain\n\nhello".to_vec(), *buffer.read().unwrap());
+        assert!(spilled.take().unwrap().is_none());
+    }
+
+    #[test]
+    fn bodies_over_the_threshold_go_to_a_file() {
+        let (mut writer, buffer, spilled) = writer(16);
+        writer.write_all(b"Status: 103\n\n").unwrap();
+        writer.write_all(b"Content-Type: text/plain\n").unwrap();
+        assert!(spilled.0.lock().unwrap().is_none(), "the header block is not complete");
+        writer.write_all(b"\nhello ").unwrap();
+        writer.write_all(b"world").unwrap();
+
+        assert_eq!(b"Status: 103\n\nContent-Type: text/plain\n\n".to_vec(), *buffer.read().unwrap());
+        let mut body = String::new();
+        std::io::Read::read_to_string(&mut spilled.take().unwrap().unwrap(), &mut body).unwrap();
+        assert_eq!("hello world", body);
+    }
+
+    #[tokio::test]
+    async fn spilled_bodies_are_streamed_after_the_head() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&vec![b'x'; CHUNK_SIZE + 1]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let bytes = hyper::body::to_bytes(body(b"head".to_vec(), file)).await.unwrap();
+        assert_eq!(CHUNK_SIZE + 5, bytes.len());
+        assert!(bytes.starts_with(b"headx"));
+    }
+
+    #[tokio::test]
+    async fn ranges_can_span_the_head_and_the_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"0123456789").unwrap();
+        let bytes = hyper::body::to_bytes(range_body(b"head".to_vec(), file.try_clone().unwrap(), 2..8).unwrap()).await.unwrap();
+        assert_eq!(b"ad0123".to_vec(), bytes.to_vec());
+        let bytes = hyper::body::to_bytes(range_body(b"head".to_vec(), file, 10..14).unwrap()).await.unwrap();
+        assert_eq!(b"6789".to_vec(), bytes.to_vec());
+    }
+}
@@ -0,0 +1,280 @@
+//! Where a module's standard error goes.
+//!
+//! The CGI spec leaves STDERR to the server (RFC 3875 sections 4.2 and 6.1). By
+//! default each module appends to a `module.stderr` file in its log directory, but a
+//! module can instead write to the server's own stderr, to the system log, or nowhere.
+//! Wherever it goes, the last few lines are also kept in memory so that they can be
+//! reported if the module fails.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+
+const STDERR_FILE: &str = "module.stderr";
+
+/// How many lines of stderr are kept for reporting failures.
+pub const STDERR_TAIL_LINES: usize = 20;
+// Bounds the memory used by a module that writes a long line without a newline
+const MAX_TAIL_LINE_LENGTH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StderrDestination {
+    /// Append to `module.stderr` in the module's log directory.
+    #[default]
+    File,
+    /// Write to the WAGI process's stderr.
+    Inherit,
+    /// Send each line to the system log (Unix only).
+    Syslog,
+    /// Throw it away.
+    Discard,
+}
+
+impl std::str::FromStr for StderrDestination {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Self::File),
+            "inherit" => Ok(Self::Inherit),
+            "syslog" => Ok(Self::Syslog),
+            "discard" => Ok(Self::Discard),
+            _ => Err(anyhow::anyhow!("Unknown stderr destination '{}': expected file, inherit, syslog or discard", s)),
+        }
+    }
+}
+
+impl StderrDestination {
+    /// Opens the destination for one run of a module. `log_dir` is only used (and
+    /// created) for `File`; `module_name` identifies the module in the system log.
+    pub fn open(&self, log_dir: &Path, module_name: &str) -> anyhow::Result<Box<dyn Write + Send + Sync>> {
+        match self {
+            Self::File => {
+                tracing::info!(log_dir = %log_dir.display(), "Using log dir");
+                std::fs::create_dir_all(log_dir)?;
+                let file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(log_dir.join(STDERR_FILE))?;
+                Ok(Box::new(file))
+            }
+            Self::Inherit => Ok(Box::new(std::io::stderr())),
+            Self::Syslog => match syslog::SyslogWriter::connect(module_name) {
+                Ok(writer) => Ok(Box::new(writer)),
+                Err(e) => {
+                    // Losing the module's diagnostics would be worse than putting them
+                    // somewhere unexpected
+                    tracing::warn!(module = module_name, error = %e, "Cannot write to the system log; sending module stderr to the server's stderr");
+                    Ok(Box::new(std::io::stderr()))
+                }
+            },
+            Self::Discard => Ok(Box::new(std::io::sink())),
+        }
+    }
+}
+
+/// The last lines a module wrote to stderr during one request.
+#[derive(Clone, Debug, Default)]
+pub struct StderrTail {
+    inner: Arc<Mutex<TailBuffer>>,
+}
+
+#[derive(Debug, Default)]
+struct TailBuffer {
+    lines: VecDeque<String>,
+    partial: Vec<u8>,
+}
+
+impl StderrTail {
+    /// The kept lines, oldest first, including any unfinished last line.
+    pub fn lines(&self) -> Vec<String> {
+        let buffer = self.inner.lock().unwrap();
+        let mut lines: Vec<String> = buffer.lines.iter().cloned().collect();
+        if !buffer.partial.is_empty() {
+            lines.push(String::from_utf8_lossy(&buffer.partial).into_owned());
+        }
+        let excess = lines.len().saturating_sub(STDERR_TAIL_LINES);
+        lines.split_off(excess)
+    }
+
+    fn record(&self, bytes: &[u8]) {
+        let mut buffer = self.inner.lock().unwrap();
+        for chunk in bytes.split_inclusive(|b| *b == b'\n') {
+            let (text, complete) = match chunk.strip_suffix(b"\n") {
+                Some(text) => (text, true),
+                None => (chunk, false),
+            };
+            let room = MAX_TAIL_LINE_LENGTH.saturating_sub(buffer.partial.len());
+            buffer.partial.extend_from_slice(&text[..text.len().min(room)]);
+            if complete {
+                let line = String::from_utf8_lossy(&buffer.partial).trim_end_matches('\r').to_owned();
+                buffer.partial.clear();
+                buffer.lines.push_back(line);
+                if buffer.lines.len() > STDERR_TAIL_LINES {
+                    buffer.lines.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Wraps a destination so that everything written to it is also recorded here.
+    pub fn tee(&self, destination: Box<dyn Write + Send + Sync>) -> TeeWriter {
+        TeeWriter {
+            destination,
+            tail: self.clone(),
+        }
+    }
+}
+
+pub struct TeeWriter {
+    destination: Box<dyn Write + Send + Sync>,
+    tail: StderrTail,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.destination.write(buf)?;
+        self.tail.record(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.destination.flush()
+    }
+}
+
+#[cfg(unix)]
+mod syslog {
+    use std::io::Write;
+    use std::os::unix::net::UnixDatagram;
+
+    // The sockets on which Linux and macOS syslog daemons listen
+    const SYSLOG_SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog"];
+    // Facility `user`, severity `notice`
+    const PRIORITY: u8 = 8 + 5;
+
+    /// Sends each complete line written to it as a syslog message. Any partial last
+    /// line is sent when the writer is dropped, that is, when the module finishes.
+    pub struct SyslogWriter {
+        socket: UnixDatagram,
+        prefix: String,
+        pending: Vec<u8>,
+    }
+
+    impl SyslogWriter {
+        pub fn connect(module_name: &str) -> anyhow::Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            let connected = SYSLOG_SOCKETS.iter().any(|path| socket.connect(path).is_ok());
+            if !connected {
+                anyhow::bail!("no syslog socket found at {}", SYSLOG_SOCKETS.join(" or "));
+            }
+            Ok(Self {
+                socket,
+                prefix: super::message_prefix(PRIORITY, std::process::id(), module_name),
+                pending: vec![],
+            })
+        }
+
+        fn send(&self, line: &[u8]) {
+            let mut message = self.prefix.clone().into_bytes();
+            message.extend_from_slice(line);
+            // A full or restarting syslog daemon must not fail the request
+            if let Err(e) = self.socket.send(&message) {
+                tracing::debug!(error = %e, "Error sending module stderr to the system log");
+            }
+        }
+    }
+
+    impl Write for SyslogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                self.send(&line[..end]);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for SyslogWriter {
+        fn drop(&mut self) {
+            if !self.pending.is_empty() {
+                self.send(&self.pending);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod syslog {
+    pub struct SyslogWriter;
+
+    impl SyslogWriter {
+        pub fn connect(_module_name: &str) -> anyhow::Result<Self> {
+            anyhow::bail!("the system log is only supported on Unix")
+        }
+    }
+
+    impl std::io::Write for SyslogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+// The RFC 3164 header, without a timestamp or hostname, which the local syslog
+// daemon fills in. The tag is "wagi" so that all modules can be filtered together.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn message_prefix(priority: u8, pid: u32, module_name: &str) -> String {
+    format!("<{}>wagi[{}]: {}: ", priority, pid, module_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn destinations_parse_from_toml_and_features() {
+        #[derive(Deserialize)]
+        struct Entry {
+            stderr: StderrDestination,
+        }
+        let entry: Entry = toml::from_str("stderr = \"syslog\"").unwrap();
+        assert_eq!(StderrDestination::Syslog, entry.stderr);
+        assert_eq!(StderrDestination::Discard, "discard".parse().unwrap());
+        "console".parse::<StderrDestination>().expect_err("unknown destinations should be rejected");
+    }
+
+    #[test]
+    fn tail_keeps_the_last_lines() {
+        let tail = StderrTail::default();
+        let mut writer = tail.tee(Box::new(std::io::sink()));
+        for i in 0..STDERR_TAIL_LINES + 5 {
+            write!(writer, "line {}\r\n", i).unwrap();
+        }
+        write!(writer, "unfinished").unwrap();
+
+        let lines = tail.lines();
+        assert_eq!(STDERR_TAIL_LINES, lines.len());
+        assert_eq!("line 6", lines[0]);
+        assert_eq!("line 24", lines[STDERR_TAIL_LINES - 2]);
+        assert_eq!("unfinished", lines[STDERR_TAIL_LINES - 1]);
+    }
+
+    #[test]
+    fn syslog_messages_are_tagged_with_the_module() {
+        assert_eq!("<13>wagi[42]: hello.wasm: ", message_prefix(13, 42, "hello.wasm"));
+    }
+}
@@ -0,0 +1,137 @@
+//! Telling systemd how WAGI is doing.
+//!
+//! When systemd starts WAGI as a `Type=notify` service, WAGI tells it when it is
+//! ready to serve requests, once the modules are loaded. If the service also sets
+//! `WatchdogSec=`, WAGI checks itself at half that interval and pings the watchdog
+//! each time the check passes. The check sends a request to the inbuilt health check
+//! route, if there is one, straight to the routing table. A WAGI that is wedged, or
+//! whose health check fails, stops pinging, and systemd restarts it.
+//!
+//! Outside systemd, or on platforms without Unix sockets, this does nothing.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::{Body, Request};
+
+use crate::dispatcher::LiveRoutingTable;
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// Tells systemd that WAGI is serving requests.
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that WAGI is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Pings the systemd watchdog for as long as WAGI passes its self-check, if the
+/// service has a watchdog.
+pub async fn watchdog(routing_table: LiveRoutingTable) {
+    let interval = match watchdog_interval(std::env::var(WATCHDOG_USEC_ENV).ok().as_deref(), std::env::var(WATCHDOG_PID_ENV).ok().as_deref()) {
+        Some(interval) => interval,
+        None => return,
+    };
+    tracing::info!(?interval, "Pinging the systemd watchdog");
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match tokio::time::timeout(interval, self_check(&routing_table)).await {
+            Ok(Ok(())) => notify("WATCHDOG=1"),
+            Ok(Err(e)) => tracing::error!(error = %format!("{:#}", e), "Self-check failed; not pinging the systemd watchdog"),
+            Err(_) => tracing::error!("Self-check did not finish in time; not pinging the systemd watchdog"),
+        }
+    }
+}
+
+// Half the watchdog timeout, so that one slow check doesn't get WAGI restarted.
+// systemd sets WATCHDOG_PID to say which process the watchdog is for, which
+// might not be this one if WAGI was started by a wrapper.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => {
+            tracing::warn!("Ignoring invalid {}", WATCHDOG_USEC_ENV);
+            None
+        }
+    }
+}
+
+async fn self_check(routing_table: &LiveRoutingTable) -> anyhow::Result<()> {
+    let current = routing_table.current();
+    let settings = match &current.global_context().health_check {
+        Some(settings) => settings,
+        // Getting this far shows that the async runtime is still running tasks
+        None => return Ok(()),
+    };
+    let uri = format!("http://{}{}", current.global_context().default_host, settings.path);
+    let request = Request::get(uri).body(Body::empty())?;
+    let client_addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let response = current.handle_request(request, client_addr).await?;
+    if response.status() != settings.status {
+        anyhow::bail!("Health check answered {} rather than {}", response.status(), settings.status);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn notify(state: &str) {
+    let socket_path = match std::env::var_os(NOTIFY_SOCKET_ENV) {
+        Some(path) => path,
+        None => return,
+    };
+    // Abstract socket names need Linux-specific addressing that the standard
+    // library doesn't offer
+    if socket_path.to_string_lossy().starts_with('@') {
+        tracing::warn!(socket = %socket_path.to_string_lossy(), "Can't notify systemd through an abstract socket");
+        return;
+    }
+    let result = std::os::unix::net::UnixDatagram::unbound()
+        .and_then(|socket| socket.send_to(state.as_bytes(), &socket_path));
+    if let Err(e) = result {
+        tracing::warn!(socket = %socket_path.to_string_lossy(), %state, error = %e, "Error notifying systemd");
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_watchdog_is_pinged_twice_per_timeout() {
+        let own_pid = std::process::id().to_string();
+        assert_eq!(Some(Duration::from_secs(15)), watchdog_interval(Some("30000000"), None));
+        assert_eq!(Some(Duration::from_secs(15)), watchdog_interval(Some("30000000"), Some(&own_pid)));
+        assert_eq!(None, watchdog_interval(Some("30000000"), Some("1")), "the watchdog is for another process");
+        assert_eq!(None, watchdog_interval(None, None));
+        assert_eq!(None, watchdog_interval(Some("0"), None));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notifications_are_sent_to_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        std::env::set_var(NOTIFY_SOCKET_ENV, &path);
+        notify_ready();
+        std::env::remove_var(NOTIFY_SOCKET_ENV);
+
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(b"READY=1", &buf[..len]);
+    }
+}
@@ -0,0 +1,172 @@
+//! Keeping tenants that share a host apart.
+//!
+//! When several WAGI instances serving different tenants or applications share the
+//! same cache and log directories, `--tenant` gives each its own subdirectory of
+//! both, `<dir>/tenants/<id>`, readable only by the user WAGI runs as. Quotas limit
+//! how much of the disk each tenant's cache and logs can use, so that one tenant
+//! cannot fill the disk for the others.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+
+const TENANTS_DIR: &str = "tenants";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantSettings {
+    pub id: String,
+    /// The most bytes the tenant's asset and module cache may hold.
+    pub cache_quota: Option<u64>,
+    /// The most bytes the tenant's log directory may hold.
+    pub log_quota: Option<u64>,
+}
+
+impl TenantSettings {
+    /// Builds the settings from the command line values.
+    pub fn parse(id: &str, cache_quota: Option<&str>, log_quota: Option<&str>) -> anyhow::Result<Self> {
+        validate_tenant_id(id)?;
+        Ok(Self {
+            id: id.to_owned(),
+            cache_quota: cache_quota.map(parse_size).transpose().context("Invalid tenant cache quota")?,
+            log_quota: log_quota.map(parse_size).transpose().context("Invalid tenant log quota")?,
+        })
+    }
+
+    /// Creates the tenant's own subdirectory of `dir`, if it doesn't exist, and
+    /// returns its path.
+    pub fn partition(&self, dir: &Path) -> anyhow::Result<PathBuf> {
+        let tenant_dir = dir.join(TENANTS_DIR).join(&self.id);
+        std::fs::create_dir_all(&tenant_dir)
+            .with_context(|| format!("Error creating tenant directory {}", tenant_dir.display()))?;
+        restrict_to_owner(&tenant_dir)
+            .with_context(|| format!("Error setting permissions on tenant directory {}", tenant_dir.display()))?;
+        Ok(tenant_dir)
+    }
+}
+
+// Tenant IDs become directory names, so they must not be able to point outside
+// the tenants directory
+fn validate_tenant_id(id: &str) -> anyhow::Result<()> {
+    let valid_chars = id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if id.is_empty() || id == "." || id == ".." || !valid_chars {
+        anyhow::bail!("Invalid tenant ID '{}': must be letters, digits, '-', '_' and '.'", id);
+    }
+    Ok(())
+}
+
+/// Parses a size in bytes, optionally with a binary suffix: `Ki`, `Mi`, `Gi` or `Ti`.
+pub fn parse_size(text: &str) -> anyhow::Result<u64> {
+    let (number, multiplier) = match text.find(|c: char| !c.is_ascii_digit()) {
+        None => (text, 1),
+        Some(i) => {
+            let multiplier = match &text[i..] {
+                "Ki" => 1 << 10,
+                "Mi" => 1 << 20,
+                "Gi" => 1 << 30,
+                "Ti" => 1 << 40,
+                _ => anyhow::bail!("'{}' is not a size: expected a number of bytes, optionally followed by Ki, Mi, Gi or Ti", text),
+            };
+            (&text[..i], multiplier)
+        }
+    };
+    number.parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a size: expected a number of bytes greater than zero", text))
+}
+
+/// A limit on the size of a log directory. Once the directory reaches it, module
+/// stderr is discarded until space is freed, for example by log rotation.
+#[derive(Clone, Debug)]
+pub struct LogQuota {
+    bytes: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl LogQuota {
+    pub fn new(bytes: u64) -> Self {
+        Self { bytes, exceeded: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Whether more can be written to the log directory. Warns when the directory
+    /// first reaches the quota, rather than on every request after that.
+    pub fn allows_writing(&self, dir: &Path) -> bool {
+        let (_, used) = crate::diagnostics::directory_usage(dir);
+        let exceeded = used >= self.bytes;
+        if exceeded != self.exceeded.swap(exceeded, Ordering::Relaxed) {
+            if exceeded {
+                tracing::warn!(log_dir = %dir.display(), quota = self.bytes, "Log directory is over its quota; discarding module stderr");
+            } else {
+                tracing::info!(log_dir = %dir.display(), quota = self.bytes, "Log directory is under its quota again; writing module stderr");
+            }
+        }
+        !exceeded
+    }
+}
+
+#[cfg(unix)]
+fn restrict_to_owner(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tenant_ids_cannot_leave_the_tenants_directory() {
+        TenantSettings::parse("acme-prod_1.2", None, None).expect("Should be a valid ID");
+        TenantSettings::parse("", None, None).expect_err("ID must not be empty");
+        TenantSettings::parse("..", None, None).expect_err("ID must not be the parent directory");
+        TenantSettings::parse("../other", None, None).expect_err("ID must not contain separators");
+        TenantSettings::parse("a b", None, None).expect_err("ID must not contain spaces");
+    }
+
+    #[test]
+    fn sizes_can_have_binary_suffixes() {
+        assert_eq!(4096, parse_size("4096").unwrap());
+        assert_eq!(512 * 1024 * 1024, parse_size("512Mi").unwrap());
+        assert_eq!(2 << 30, parse_size("2Gi").unwrap());
+        parse_size("0").expect_err("size must be positive");
+        parse_size("12MB").expect_err("only binary suffixes are accepted");
+        parse_size("Mi").expect_err("size needs a number");
+    }
+
+    #[test]
+    fn each_tenant_gets_its_own_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = TenantSettings::parse("a", None, None).unwrap().partition(dir.path()).unwrap();
+        let b = TenantSettings::parse("b", None, None).unwrap().partition(dir.path()).unwrap();
+        assert_eq!(dir.path().join("tenants").join("a"), a);
+        assert_ne!(a, b);
+        assert!(a.is_dir());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o700, std::fs::metadata(&a).unwrap().permissions().mode() & 0o777);
+        }
+    }
+
+    #[test]
+    fn log_quota_stops_writes_until_space_is_freed() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = LogQuota::new(8);
+        assert!(quota.allows_writing(dir.path()));
+
+        std::fs::write(dir.path().join("module.stderr"), "123456789").unwrap();
+        assert!(!quota.allows_writing(dir.path()));
+
+        std::fs::write(dir.path().join("module.stderr"), "").unwrap();
+        assert!(quota.allows_writing(dir.path()));
+    }
+}
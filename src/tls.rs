@@ -8,14 +8,90 @@ use core::task::{Context, Poll};
 use std::future::Future;
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use std::{fs, io, sync::Arc};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::rustls::{self, CipherSuite, ProducesTickets, ProtocolVersion, ServerConfig};
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::{Accept, TlsAcceptor};
 
+use crate::wagi_config::TlsConfiguration;
+
+/// The longest `--tls-ticket-rotation` can be. rustls replaces the keys of each
+/// of its ticketers this often anyway.
+pub const MAX_TICKET_ROTATION: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// The TLS versions WAGI can serve, newest first.
+pub const TLS_VERSIONS: &[ProtocolVersion] = &[ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
+
+/// Parses a comma-separated list of TLS versions, such as `1.2,1.3`.
+pub fn parse_tls_versions(text: &str) -> anyhow::Result<Vec<ProtocolVersion>> {
+    let mut versions = vec![];
+    for name in text.split(',').map(str::trim) {
+        let version = match name {
+            "1.3" => ProtocolVersion::TLSv1_3,
+            "1.2" => ProtocolVersion::TLSv1_2,
+            _ => anyhow::bail!("Unsupported TLS version '{}': expected 1.2 or 1.3", name),
+        };
+        if !versions.contains(&version) {
+            versions.push(version);
+        }
+    }
+    Ok(versions)
+}
+
+/// All the cipher suites WAGI can use, in rustls' order of preference.
+pub fn all_cipher_suites() -> Vec<CipherSuite> {
+    rustls::ALL_CIPHERSUITES.iter().map(|s| s.suite).collect()
+}
+
+/// Parses a comma-separated list of cipher suites. Suites can be given by their
+/// IANA names (`TLS_AES_128_GCM_SHA256`, `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`)
+/// or rustls' names for them (`TLS13_AES_128_GCM_SHA256`), in any case.
+pub fn parse_cipher_suites(text: &str) -> anyhow::Result<Vec<CipherSuite>> {
+    let mut suites = vec![];
+    for name in text.split(',').map(str::trim) {
+        let suite = all_cipher_suites()
+            .into_iter()
+            .find(|suite| {
+                let rustls_name = format!("{:?}", suite);
+                let iana_name = rustls_name.replacen("TLS13_", "TLS_", 1);
+                name.eq_ignore_ascii_case(&rustls_name) || name.eq_ignore_ascii_case(&iana_name)
+            })
+            .ok_or_else(|| anyhow::anyhow!(
+                "Unsupported cipher suite '{}': expected one of {}",
+                name,
+                all_cipher_suites().iter().map(|s| format!("{:?}", s).replacen("TLS13_", "TLS_", 1)).collect::<Vec<_>>().join(", ")
+            ))?;
+        if !suites.contains(&suite) {
+            suites.push(suite);
+        }
+    }
+    Ok(suites)
+}
+
+/// Checks that clients could connect with each of the TLS versions using one of
+/// the cipher suites.
+pub fn check_cipher_suites(versions: &[ProtocolVersion], suites: &[CipherSuite]) -> anyhow::Result<()> {
+    for version in versions {
+        let usable = supported_cipher_suites(suites).iter().any(|s| s.usable_for_version(*version));
+        if !usable {
+            anyhow::bail!("None of the TLS cipher suites can be used with {:?}", version);
+        }
+    }
+    Ok(())
+}
+
+fn supported_cipher_suites(suites: &[CipherSuite]) -> Vec<&'static rustls::SupportedCipherSuite> {
+    suites
+        .iter()
+        .filter_map(|suite| rustls::ALL_CIPHERSUITES.iter().copied().find(|s| s.suite == *suite))
+        .collect()
+}
+
 fn error(err: String) -> io::Error {
     io::Error::new(io::ErrorKind::Other, err)
 }
@@ -24,35 +100,55 @@ pub(crate) struct TlsHyperAcceptor {
     listener: TcpListener,
     acceptor: TlsAcceptor,
     in_progress_stream: Option<Accept<TcpStream>>,
+    tcp_nodelay: bool,
 }
 
 impl TlsHyperAcceptor {
     pub(crate) async fn new(
         addr: impl ToSocketAddrs,
-        cert_file: impl AsRef<Path>,
-        key_file: impl AsRef<Path>,
+        tls: &TlsConfiguration,
+        tcp_nodelay: bool,
     ) -> io::Result<Self> {
         let listener = TcpListener::bind(addr).await?;
         let tls_cfg = {
             // Load public certificate.
-            let certs = load_certs(cert_file)?;
+            let certs = load_certs(&tls.cert_path)?;
             // Load private key.
-            let key = load_private_key(key_file)?;
+            let key = load_private_key(&tls.key_path)?;
             // Do not use client certificate authentication.
             let mut cfg = ServerConfig::new(rustls::NoClientAuth::new());
-            // Select a certificate to use.
-            cfg.set_single_cert(certs, key)
+            // Select a certificate to use, stapling the OCSP response if there is one.
+            let ocsp = match &tls.ocsp_response_path {
+                Some(path) => fs::read(path).map_err(|e| {
+                    error(format!("failed to read OCSP response {}: {}", path.display(), e))
+                })?,
+                None => vec![],
+            };
+            cfg.set_single_cert_with_ocsp_and_sct(certs, key, ocsp, vec![])
                 .map_err(|e| error(format!("{}", e)))?;
+            cfg.versions = tls.versions.clone();
+            cfg.ciphersuites = supported_cipher_suites(&tls.cipher_suites);
             // Configure ALPN to accept HTTP/1.1 (and not http2 due to differences in header
             // requirements, namely the HOST header). If we want to add http2 in the future, we can
             // add `b"h2".to_vec()` to the list
             cfg.set_protocols(&[b"http/1.1".to_vec()]);
+            // Let repeat clients skip the full handshake. The session cache and the
+            // first ticket key are set up here, before the first client connects.
+            cfg.session_storage = if tls.session_cache_size == 0 {
+                Arc::new(rustls::NoServerSessionStorage {})
+            } else {
+                rustls::ServerSessionMemoryCache::new(tls.session_cache_size)
+            };
+            if tls.session_tickets {
+                cfg.ticketer = Arc::new(RotatingTicketer::new(tls.ticket_rotation));
+            }
             Arc::new(cfg)
         };
         Ok(TlsHyperAcceptor {
             listener,
             acceptor: tls_cfg.into(),
             in_progress_stream: None,
+            tcp_nodelay,
         })
     }
 }
@@ -77,6 +173,9 @@ impl hyper::server::accept::Accept for TlsHyperAcceptor {
                     Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
                     Poll::Pending => return Poll::Pending,
                 };
+                if let Err(e) = socket.set_nodelay(self.tcp_nodelay) {
+                    tracing::warn!(error = %e, "Can't set TCP_NODELAY on new connection");
+                }
                 self.acceptor.accept(socket)
             }
         };
@@ -107,6 +206,70 @@ impl hyper::server::accept::Accept for TlsHyperAcceptor {
     }
 }
 
+/// Issues session tickets, replacing the key that protects them every `rotation`.
+/// Tickets issued under the previous key are still accepted, so a ticket can be
+/// used for at least one rotation period and at most two.
+struct RotatingTicketer {
+    rotation: Duration,
+    keys: Mutex<TicketKeys>,
+}
+
+struct TicketKeys {
+    current: Arc<dyn ProducesTickets>,
+    previous: Option<Arc<dyn ProducesTickets>>,
+    rotated_at: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration) -> Self {
+        Self {
+            rotation,
+            keys: Mutex::new(TicketKeys {
+                current: rustls::Ticketer::new(),
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn keys_at(&self, now: Instant) -> (Arc<dyn ProducesTickets>, Option<Arc<dyn ProducesTickets>>) {
+        let mut keys = self.keys.lock().unwrap();
+        let elapsed = now.saturating_duration_since(keys.rotated_at);
+        if elapsed >= self.rotation {
+            tracing::debug!("Rotating TLS session ticket key");
+            // After two or more periods, tickets under the current key are too old as well
+            let previous = if elapsed < self.rotation * 2 {
+                Some(keys.current.clone())
+            } else {
+                None
+            };
+            keys.previous = previous;
+            keys.current = rustls::Ticketer::new();
+            keys.rotated_at = now;
+        }
+        (keys.current.clone(), keys.previous.clone())
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.rotation.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys_at(Instant::now()).0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (current, previous) = self.keys_at(Instant::now());
+        current.decrypt(cipher).or_else(|| previous?.decrypt(cipher))
+    }
+}
+
 // Load public certificate from file.
 fn load_certs(filename: impl AsRef<Path>) -> io::Result<Vec<rustls::Certificate>> {
     // Open certificate file.
@@ -146,3 +309,39 @@ fn load_private_key(filename: impl AsRef<Path>) -> io::Result<rustls::PrivateKey
     }
     Ok(keys[0].clone())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cipher_suites_can_be_given_by_either_name() {
+        let suites = parse_cipher_suites("TLS_AES_256_GCM_SHA384, tls13_aes_256_gcm_sha384,TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256").unwrap();
+        assert_eq!(vec![CipherSuite::TLS13_AES_256_GCM_SHA384, CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256], suites);
+        parse_cipher_suites("TLS_RSA_WITH_RC4_128_SHA").expect_err("RC4 should not be supported");
+    }
+
+    #[test]
+    fn each_version_needs_a_cipher_suite() {
+        let tls13_only = parse_cipher_suites("TLS_AES_128_GCM_SHA256").unwrap();
+        check_cipher_suites(&parse_tls_versions("1.3").unwrap(), &tls13_only).unwrap();
+        check_cipher_suites(&parse_tls_versions("1.2,1.3").unwrap(), &tls13_only).expect_err("TLS 1.2 has no suite");
+        check_cipher_suites(TLS_VERSIONS, &all_cipher_suites()).unwrap();
+        parse_tls_versions("1.1").expect_err("TLS 1.1 should not be supported");
+    }
+
+    #[test]
+    fn tickets_outlive_one_rotation_but_not_two() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(60));
+        let start = ticketer.keys.lock().unwrap().rotated_at;
+        let ticket = ticketer.keys_at(start).0.encrypt(b"session").unwrap();
+
+        let (current, previous) = ticketer.keys_at(start + Duration::from_secs(61));
+        assert!(current.decrypt(&ticket).is_none());
+        assert_eq!(Some(b"session".to_vec()), previous.unwrap().decrypt(&ticket));
+
+        let (current, previous) = ticketer.keys_at(start + Duration::from_secs(122));
+        assert!(current.decrypt(&ticket).is_none());
+        assert!(previous.unwrap().decrypt(&ticket).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Limits on request URLs.
+//!
+//! Route matching walks the path, and modules get it in several environment
+//! variables, so a path that is very long, or made of thousands of slashes, costs
+//! the router and every module it reaches. WAGI answers a request whose path and
+//! query are longer than `--max-url-length`, or whose path has more segments than
+//! `--max-path-segments`, with `414 URI Too Long` before looking for its route, so
+//! no module ever sees it. The body of the 414 can be replaced with a file of your
+//! own with `--url-too-long-page`.
+//!
+//! Paths with a `%` that doesn't start a percent-encoded byte, or with an encoded
+//! NUL (`%00`), which modules written in C would take as the end of the string, are
+//! answered with `400 Bad Request` in the same way.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode, Uri};
+
+pub const DEFAULT_MAX_URL_LENGTH: usize = 8192;
+pub const DEFAULT_MAX_PATH_SEGMENTS: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct UrlLimits {
+    pub max_length: usize,
+    pub max_segments: usize,
+    too_long_page: Arc<TooLongPage>,
+}
+
+#[derive(Debug)]
+struct TooLongPage {
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+/// Why a URL was refused.
+#[derive(Debug, PartialEq)]
+pub enum UrlRejection {
+    TooLong,
+    TooManySegments,
+    Malformed(&'static str),
+}
+
+impl Default for UrlLimits {
+    fn default() -> Self {
+        Self {
+            max_length: DEFAULT_MAX_URL_LENGTH,
+            max_segments: DEFAULT_MAX_PATH_SEGMENTS,
+            too_long_page: Arc::new(TooLongPage {
+                content_type: "text/plain; charset=utf-8",
+                body: b"URL too long".to_vec(),
+            }),
+        }
+    }
+}
+
+impl UrlLimits {
+    /// Answers URLs over the limits with the contents of the file. Files ending in
+    /// `.html` or `.htm` are served as HTML, and anything else as plain text.
+    pub fn with_too_long_page(mut self, path: &Path) -> anyhow::Result<Self> {
+        let body = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Could not read URL too long page {}: {}", path.display(), e))?;
+        let is_html = matches!(path.extension().and_then(|e| e.to_str()), Some("html") | Some("htm"));
+        let content_type = if is_html { "text/html; charset=utf-8" } else { "text/plain; charset=utf-8" };
+        self.too_long_page = Arc::new(TooLongPage { content_type, body });
+        Ok(self)
+    }
+
+    pub fn check(&self, uri: &Uri) -> Result<(), UrlRejection> {
+        let length = uri.path_and_query().map(|p| p.as_str().len()).unwrap_or_default();
+        if length > self.max_length {
+            return Err(UrlRejection::TooLong);
+        }
+        let path = uri.path();
+        if path.matches('/').count() > self.max_segments {
+            return Err(UrlRejection::TooManySegments);
+        }
+        check_percent_encoding(path)
+    }
+
+    pub fn response(&self, rejection: &UrlRejection) -> Response<Body> {
+        match rejection {
+            UrlRejection::TooLong | UrlRejection::TooManySegments => {
+                let mut res = Response::new(Body::from(self.too_long_page.body.clone()));
+                *res.status_mut() = StatusCode::URI_TOO_LONG;
+                res.headers_mut().insert(CONTENT_TYPE, hyper::header::HeaderValue::from_static(self.too_long_page.content_type));
+                res
+            }
+            UrlRejection::Malformed(reason) => crate::http_util::bad_request(reason),
+        }
+    }
+}
+
+fn check_percent_encoding(path: &str) -> Result<(), UrlRejection> {
+    let bytes = path.as_bytes();
+    for (i, _) in bytes.iter().enumerate().filter(|(_, b)| **b == b'%') {
+        match bytes.get(i + 1..i + 3) {
+            Some([a, b]) if a.is_ascii_hexdigit() && b.is_ascii_hexdigit() => {
+                if *a == b'0' && *b == b'0' {
+                    return Err(UrlRejection::Malformed("The path holds an encoded NUL"));
+                }
+            }
+            _ => return Err(UrlRejection::Malformed("The path holds a % that does not start a percent-encoded byte")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(limits: &UrlLimits, uri: &str) -> Result<(), UrlRejection> {
+        limits.check(&uri.parse().unwrap())
+    }
+
+    #[test]
+    fn urls_over_the_limits_are_refused() {
+        let limits = UrlLimits { max_length: 20, max_segments: 3, ..UrlLimits::default() };
+        assert_eq!(Ok(()), check(&limits, "/a/b/c?d=e"));
+        assert_eq!(Err(UrlRejection::TooLong), check(&limits, "/a?b=cccccccccccccccccc"));
+        assert_eq!(Err(UrlRejection::TooManySegments), check(&limits, "/a/b/c/d"));
+        assert_eq!(Err(UrlRejection::TooManySegments), check(&limits, "////"), "empty segments count too");
+    }
+
+    #[test]
+    fn malformed_percent_encoding_is_refused() {
+        let limits = UrlLimits::default();
+        assert_eq!(Ok(()), check(&limits, "/a%20b/%C3%A9"));
+        assert!(matches!(check(&limits, "/a%2"), Err(UrlRejection::Malformed(_))));
+        assert!(matches!(check(&limits, "/a%zz"), Err(UrlRejection::Malformed(_))));
+        assert!(matches!(check(&limits, "/a%00b"), Err(UrlRejection::Malformed(_))));
+    }
+
+    #[test]
+    fn the_too_long_page_can_be_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = dir.path().join("414.html");
+        std::fs::write(&page, "<h1>Too long</h1>").unwrap();
+        let limits = UrlLimits::default().with_too_long_page(&page).unwrap();
+        let res = limits.response(&UrlRejection::TooLong);
+        assert_eq!(StatusCode::URI_TOO_LONG, res.status());
+        assert_eq!("text/html; charset=utf-8", res.headers()[CONTENT_TYPE]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Copy-on-write volumes.
+//!
+//! A module with `volume_overlay` set does not get its volumes' host directories.
+//! Each request gets a private copy of them, taken when the request starts, and
+//! the module's writes go to the copy. With `discard`, the copy is thrown away when
+//! the request ends. With `commit`, the files the module created, changed or
+//! deleted are applied to the host directories if the module succeeds (that is, it
+//! runs to completion and its response is not a 5xx), and thrown away if not, so
+//! a failed request never leaves partial changes behind.
+//!
+//! Commits only touch the files that the request changed, so requests that change
+//! different files don't undo each other. If two requests change the same file,
+//! the last to finish wins. Symbolic links are not copied.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// What happens to a request's changes to its volumes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeOverlay {
+    /// Throw them away when the request ends.
+    Discard,
+    /// Apply them to the host directories if the module succeeds.
+    Commit,
+}
+
+impl std::str::FromStr for VolumeOverlay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "discard" => Ok(Self::Discard),
+            "commit" => Ok(Self::Commit),
+            _ => Err(anyhow::anyhow!("Unknown volume overlay '{}': expected discard or commit", s)),
+        }
+    }
+}
+
+/// One request's copies of a module's volumes. The copies are deleted when this
+/// is dropped.
+pub struct OverlayVolumes {
+    // Held for its Drop, which deletes the copies
+    _dir: tempfile::TempDir,
+    /// Guest path to the host path of its copy, for preopening.
+    volumes: HashMap<String, String>,
+    snapshots: Vec<Snapshot>,
+}
+
+// What a host directory held when it was copied
+struct Snapshot {
+    host: PathBuf,
+    copy: PathBuf,
+    files: HashMap<PathBuf, Vec<u8>>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl OverlayVolumes {
+    /// Copies the host directory of each volume into a new directory under `parent`.
+    /// A host directory that can't be read is left out, as it would be without an
+    /// overlay.
+    pub fn snapshot(volumes: &HashMap<String, String>, parent: &Path) -> anyhow::Result<Self> {
+        let dir = tempfile::tempdir_in(parent)?;
+        let mut copies = HashMap::new();
+        let mut snapshots = vec![];
+        for (index, (guest, host)) in volumes.iter().enumerate() {
+            if !Path::new(host).is_dir() {
+                tracing::error!(%host, %guest, "Volume host directory is not a readable directory");
+                continue;
+            }
+            let copy = dir.path().join(index.to_string());
+            let mut snapshot = Snapshot {
+                host: PathBuf::from(host),
+                copy: copy.clone(),
+                files: HashMap::new(),
+                dirs: HashSet::new(),
+            };
+            copy_tree(Path::new(host), &copy, Path::new(""), &mut snapshot)?;
+            copies.insert(guest.clone(), copy.display().to_string());
+            snapshots.push(snapshot);
+        }
+        Ok(Self {
+            _dir: dir,
+            volumes: copies,
+            snapshots,
+        })
+    }
+
+    pub fn volumes(&self) -> &HashMap<String, String> {
+        &self.volumes
+    }
+
+    /// Applies the files created, changed and deleted in the copies to the host
+    /// directories.
+    pub fn commit(self) -> anyhow::Result<()> {
+        for snapshot in &self.snapshots {
+            snapshot.commit()?;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    fn commit(&self) -> anyhow::Result<()> {
+        let mut now_files = HashMap::new();
+        let mut now_dirs = HashSet::new();
+        collect_tree(&self.copy, Path::new(""), &mut now_files, &mut now_dirs)?;
+
+        let mut dirs: Vec<_> = now_dirs.difference(&self.dirs).collect();
+        dirs.sort();
+        for dir in dirs {
+            fs::create_dir_all(self.host.join(dir))?;
+        }
+        for (relative, hash) in &now_files {
+            if self.files.get(relative) != Some(hash) {
+                tracing::trace!(host = %self.host.display(), file = %relative.display(), "Committing changed volume file");
+                fs::copy(self.copy.join(relative), self.host.join(relative))?;
+            }
+        }
+        for relative in self.files.keys().filter(|f| !now_files.contains_key(*f)) {
+            match fs::remove_file(self.host.join(relative)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        // Deepest first. A directory that another request has put files in stays.
+        let mut removed_dirs: Vec<_> = self.dirs.difference(&now_dirs).collect();
+        removed_dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+        for dir in removed_dirs {
+            let _ = fs::remove_dir(self.host.join(dir));
+        }
+        Ok(())
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path, relative: &Path, snapshot: &mut Snapshot) -> anyhow::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = relative.join(entry.file_name());
+        if file_type.is_dir() {
+            snapshot.dirs.insert(name.clone());
+            copy_tree(&entry.path(), &to.join(entry.file_name()), &name, snapshot)?;
+        } else if file_type.is_file() {
+            let content = fs::read(entry.path())?;
+            fs::write(to.join(entry.file_name()), &content)?;
+            snapshot.files.insert(name, Sha256::digest(&content).to_vec());
+        } else {
+            tracing::debug!(path = %entry.path().display(), "Not copying symbolic link or special file into volume overlay");
+        }
+    }
+    Ok(())
+}
+
+fn collect_tree(dir: &Path, relative: &Path, files: &mut HashMap<PathBuf, Vec<u8>>, dirs: &mut HashSet<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = relative.join(entry.file_name());
+        if file_type.is_dir() {
+            dirs.insert(name.clone());
+            collect_tree(&entry.path(), &name, files, dirs)?;
+        } else if file_type.is_file() {
+            files.insert(name, Sha256::digest(&fs::read(entry.path())?).to_vec());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn volume(host: &Path) -> HashMap<String, String> {
+        vec![("/data".to_owned(), host.display().to_string())].into_iter().collect()
+    }
+
+    fn write_through_overlay(overlay: &OverlayVolumes) {
+        let copy = PathBuf::from(&overlay.volumes()["/data"]);
+        fs::write(copy.join("changed.txt"), "new").unwrap();
+        fs::remove_file(copy.join("deleted.txt")).unwrap();
+        fs::create_dir(copy.join("sub")).unwrap();
+        fs::write(copy.join("sub/created.txt"), "created").unwrap();
+    }
+
+    fn host_dir() -> tempfile::TempDir {
+        let host = tempfile::tempdir().unwrap();
+        fs::write(host.path().join("changed.txt"), "old").unwrap();
+        fs::write(host.path().join("deleted.txt"), "doomed").unwrap();
+        fs::write(host.path().join("kept.txt"), "kept").unwrap();
+        host
+    }
+
+    #[test]
+    fn discarded_changes_do_not_reach_the_host() {
+        let host = host_dir();
+        let overlay = OverlayVolumes::snapshot(&volume(host.path()), &std::env::temp_dir()).unwrap();
+        write_through_overlay(&overlay);
+        drop(overlay);
+        assert_eq!("old", fs::read_to_string(host.path().join("changed.txt")).unwrap());
+        assert!(host.path().join("deleted.txt").exists());
+        assert!(!host.path().join("sub").exists());
+    }
+
+    #[test]
+    fn committed_changes_reach_the_host() {
+        let host = host_dir();
+        let overlay = OverlayVolumes::snapshot(&volume(host.path()), &std::env::temp_dir()).unwrap();
+        write_through_overlay(&overlay);
+        // Made by another request after this one started
+        fs::write(host.path().join("other.txt"), "other").unwrap();
+        overlay.commit().unwrap();
+        assert_eq!("new", fs::read_to_string(host.path().join("changed.txt")).unwrap());
+        assert!(!host.path().join("deleted.txt").exists());
+        assert_eq!("created", fs::read_to_string(host.path().join("sub/created.txt")).unwrap());
+        assert_eq!("kept", fs::read_to_string(host.path().join("kept.txt")).unwrap());
+        assert!(host.path().join("other.txt").exists(), "Files the request did not touch should be left alone");
+    }
+}
@@ -1,11 +1,33 @@
-use clap::{App, Arg, ArgMatches, ArgGroup};
+use clap::{App, AppSettings, Arg, ArgMatches, ArgGroup, Shell, SubCommand};
 use core::convert::TryFrom;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 use crate::{
+    audit::AUDIT_LOG_FILE,
+    bench::BenchSettings,
     bindle_util::BindleConnectionInfo,
+    circuit_breaker::CircuitBreakerSettings,
+    connection_limits::ConnectionSettings,
+    custom_handler::CustomHandlers,
+    error::{WagiError, WagiResult},
+    handler_loader::{CompileErrorPolicy, FetchRetryPolicy, MissingParcelPolicy, RegistryCredentials, SchemeResolvers},
+    header_limits::{HeaderLimits, HeaderValuePolicy},
+    health_check::HealthCheckSettings,
+    log_level::{LogLevel, DEFAULT_VERBOSE_LOG_FILTER},
+    maintenance::{MaintenancePage, MaintenanceSettings},
+    outbound_http::TraceHeaders,
+    outbound_network::{OutboundNetwork, OutboundNetworkPolicy},
+    recycle::RecycleSettings,
+    sampling::SamplingSettings,
+    spill::SpillSettings,
+    tenant::{parse_size, TenantSettings},
+    url_limits::UrlLimits,
+    tls,
+    wasm_module::EngineSettings,
     wagi_config::{
-        HandlerConfigurationSource, HttpConfiguration, TlsConfiguration, WagiConfiguration,
+        duration_from_secs, timeout_from_secs, HandlerConfigurationSource, HttpConfiguration, TlsConfiguration, WagiConfiguration,
+        DEFAULT_HOSTNAME, DEFAULT_LISTEN_ON, DEFAULT_WASM_CACHE_CONFIG_FILE,
     },
 };
 
@@ -32,11 +54,13 @@ const ARG_BINDLE_ID: &str = "bindle";
 const ARG_BINDLE_URL: &str = "BINDLE_URL";
 const ARG_BINDLE_STANDALONE_DIR: &str = "bindle_path";
 const ARG_BINDLE_INSECURE: &str = "bindle_insecure";
+const ARG_BINDLE_ANNOTATIONS: &str = "bindle_annotations";
 const ARG_BINDLE_HTTP_USER: &str = "BINDLE_HTTP_USER";
 const ARG_BINDLE_HTTP_PASSWORD: &str = "BINDLE_HTTP_PASSWORD";
 
 // Arguments for serving from local Wasm files specified in a modules.toml
 const ARG_MODULES_CONFIG: &str = "config";
+const ARG_MODULES_DIR: &str = "dir";
 
 // Wasm execution environment
 const ARG_ENV_VARS: &str = "env_vars";
@@ -45,13 +69,106 @@ const ARG_ENV_FILES: &str = "env_files";
 // HTTP configuration
 const ARG_LISTEN_ON: &str = "listen";
 const ARG_DEFAULT_HOSTNAME: &str = "hostname";
+const ARG_TRUSTED_PROXIES: &str = "trusted_proxies";
+const ARG_ADMIN_TOKEN: &str = "WAGI_ADMIN_TOKEN";
+const ARG_MAX_CONNECTIONS: &str = "max_connections";
+const ARG_KEEP_ALIVE_TIMEOUT: &str = "keep_alive_timeout";
+const ARG_MAX_REQUESTS_PER_CONNECTION: &str = "max_requests_per_connection";
+const ARG_TCP_NODELAY: &str = "tcp_nodelay";
 const ARG_TLS_CERT_FILE: &str = "tls_cert_file";
 const ARG_TLS_KEY_FILE: &str = "tls_key_file";
+const ARG_TLS_SESSION_CACHE_SIZE: &str = "tls_session_cache_size";
+const ARG_NO_TLS_SESSION_TICKETS: &str = "no_tls_session_tickets";
+const ARG_TLS_TICKET_ROTATION: &str = "tls_ticket_rotation";
+const ARG_TLS_VERSIONS: &str = "tls_versions";
+const ARG_TLS_CIPHER_SUITES: &str = "tls_cipher_suites";
+const ARG_TLS_OCSP_RESPONSE: &str = "tls_ocsp_response";
+const ARG_HTTP_REDIRECT_LISTEN_ON: &str = "http_redirect_listen";
 
 // Program configuration
 const ARG_WASM_CACHE_CONFIG_FILE: &str = "cache";
+const ARG_WASM_CACHE_SIZE_LIMIT: &str = "wasm_cache_size_limit";
+const ARG_COMPILED_MODULE_CACHE: &str = "compiled_module_cache";
+const ARG_NO_PARALLEL_COMPILATION: &str = "no_parallel_compilation";
+const ARG_CRANELIFT_OPT_LEVEL: &str = "cranelift_opt_level";
+const ARG_CRANELIFT_FLAGS: &str = "cranelift_flags";
+const ARG_MODULE_IDLE_TTL: &str = "module_idle_ttl";
+const ARG_REFRESH_DYNAMIC_ROUTES: &str = "refresh_dynamic_routes";
 const ARG_REMOTE_MODULE_CACHE_DIR: &str = "module_cache";
+const ARG_SHARED_MODULE_CACHE: &str = "shared_module_cache";
+const ARG_COMPRESS_MODULE_CACHE: &str = "compress_module_cache";
+const ARG_REGISTRY_CREDENTIALS_FILE: &str = "registry_credentials";
+const ARG_NO_AMBIENT_REGISTRY_CREDENTIALS: &str = "no_ambient_registry_credentials";
 const ARG_LOG_DIR: &str = "log_dir";
+const ARG_AUDIT_LOG: &str = "audit_log";
+const ARG_VERBOSE_LOG_FILTER: &str = "verbose_log_filter";
+const ARG_TENANT: &str = "tenant";
+const ARG_TENANT_CACHE_QUOTA: &str = "tenant_cache_quota";
+const ARG_TENANT_LOG_QUOTA: &str = "tenant_log_quota";
+const ARG_ALLOW_MISSING_VOLUMES: &str = "allow_missing_volumes";
+
+// Response defaults
+const ARG_DEFAULT_CONTENT_TYPE: &str = "default_content_type";
+const ARG_DEFAULT_CHARSET: &str = "default_charset";
+
+// Resilience
+const ARG_RESPONSE_HEADER_TIMEOUT: &str = "response_header_timeout";
+const ARG_MODULE_TIMEOUT: &str = "module_timeout";
+const ARG_CIRCUIT_BREAKER_THRESHOLD: &str = "circuit_breaker_threshold";
+const ARG_CIRCUIT_BREAKER_COOLDOWN: &str = "circuit_breaker_cooldown";
+const ARG_HARDEN: &str = "harden";
+const ARG_TRACE_HEADERS: &str = "trace_headers";
+const ARG_OUTBOUND_DNS_SERVERS: &str = "outbound_dns_servers";
+const ARG_OUTBOUND_BLOCK_PRIVATE: &str = "outbound_block_private";
+const ARG_OUTBOUND_ALLOWED_NETWORKS: &str = "outbound_allowed_networks";
+const ARG_DENY_OUTBOUND_HTTP: &str = "deny_outbound_http";
+const ARG_CHECK_ALLOWED_HOSTS: &str = "check_allowed_hosts";
+const ARG_MAX_HEADER_VALUE_LENGTH: &str = "max_header_value_length";
+const ARG_HEADER_VALUE_POLICY: &str = "header_value_policy";
+const ARG_SPILL_RESPONSES_OVER: &str = "spill_responses_over";
+const ARG_SPILL_DIR: &str = "spill_dir";
+const ARG_OVERLAY_DIR: &str = "overlay_dir";
+const ARG_MAX_URL_LENGTH: &str = "max_url_length";
+const ARG_MAX_PATH_SEGMENTS: &str = "max_path_segments";
+const ARG_URL_TOO_LONG_PAGE: &str = "url_too_long_page";
+const ARG_DRAIN_PERIOD: &str = "drain_period";
+const ARG_RECYCLE_AFTER_REQUESTS: &str = "recycle_after_requests";
+const ARG_RECYCLE_AFTER: &str = "recycle_after";
+const ARG_RECYCLE_JITTER: &str = "recycle_jitter";
+const ARG_FETCH_ATTEMPTS: &str = "fetch_attempts";
+const ARG_FETCH_RETRY_DELAY: &str = "fetch_retry_delay";
+const ARG_RETRY_FETCH_IN_BACKGROUND: &str = "retry_fetch_in_background";
+const ARG_MISSING_PARCEL: &str = "missing_parcel";
+const ARG_ON_COMPILE_ERROR: &str = "on_compile_error";
+const ARG_HEALTH_CHECK_PATH: &str = "health_check_path";
+const ARG_HEALTH_CHECK_BODY: &str = "health_check_body";
+const ARG_HEALTH_CHECK_STATUS: &str = "health_check_status";
+const ARG_NO_HEALTH_CHECK: &str = "no_health_check";
+const ARG_MAINTENANCE: &str = "maintenance";
+const ARG_MAINTENANCE_PAGE: &str = "maintenance_page";
+
+// Development
+const SUBCOMMAND_DEV: &str = "dev";
+const ARG_WATCH: &str = "watch";
+const ARG_DEBUG_ERRORS: &str = "debug_errors";
+const ARG_INTERLEAVE_OUTPUT: &str = "interleave_output";
+
+// Request sampling
+const ARG_SAMPLE_EVERY: &str = "sample_every";
+const ARG_SAMPLE_HEADER: &str = "sample_header";
+const ARG_SAMPLE_QUOTA: &str = "sample_quota";
+
+// Tooling
+const SUBCOMMAND_COMPLETIONS: &str = "completions";
+const ARG_SHELL: &str = "shell";
+const SUBCOMMAND_CONFIG: &str = "config";
+const SUBCOMMAND_PRINT_DEFAULTS: &str = "print-defaults";
+
+// Benchmarking
+const SUBCOMMAND_BENCH: &str = "bench";
+const ARG_BENCH_ROUTE: &str = "bench_route";
+const ARG_BENCH_REQUESTS: &str = "bench_requests";
+const ARG_BENCH_CONCURRENCY: &str = "bench_concurrency";
 
 // Groups
 const GROUP_MODULE_SOURCE: &str = "module_source";
@@ -62,6 +179,8 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
     .version(clap::crate_version!())
     .author("DeisLabs")
     .about(ABOUT)
+    // The tooling subcommands don't serve, so don't need modules
+    .setting(AppSettings::SubcommandsNegateReqs)
     .arg(
         Arg::with_name(ARG_MODULES_CONFIG)
             .short("c")
@@ -70,6 +189,13 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to the modules.toml configuration file")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name(ARG_MODULES_DIR)
+            .long("dir")
+            .value_name("DIR")
+            .help("A directory of .wasm files to serve without a modules.toml. Each foo.wasm is served at /foo, and index.wasm at /. A foo.toml beside foo.wasm can hold its modules.toml settings.")
+            .takes_value(true),
+    )
     .arg(
         Arg::with_name(ARG_BINDLE_ID)
             .short("b")
@@ -81,7 +207,7 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
     )
     .group(
         ArgGroup::with_name(GROUP_MODULE_SOURCE)
-            .args(&[ARG_MODULES_CONFIG, ARG_BINDLE_ID])
+            .args(&[ARG_MODULES_CONFIG, ARG_MODULES_DIR, ARG_BINDLE_ID])
             .required(true)
     )
     .arg(
@@ -129,6 +255,14 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .required(false)
             .takes_value(false),
     )
+    .arg(
+        Arg::with_name(ARG_BINDLE_ANNOTATIONS)
+            .long("bindle-annotations")
+            .value_name("KEYS")
+            .takes_value(true)
+            .requires(ARG_BINDLE_ID)
+            .help("a comma-separated list of invoice annotations to pass to modules as X_BINDLE_ANNOTATION_<KEY> environment variables, and to show at /_wagi/version"),
+    )
     .arg(
         Arg::with_name(ARG_WASM_CACHE_CONFIG_FILE)
             .long("cache")
@@ -136,6 +270,55 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to the cache.toml configuration file for configuring the Wasm optimization cache")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name(ARG_WASM_CACHE_SIZE_LIMIT)
+            .long("wasm-cache-size-limit")
+            .value_name("SIZE")
+            .takes_value(true)
+            .help("a soft limit on the total size of the Wasm optimization cache, such as 512Mi or 2Gi. Overrides files-total-size-soft-limit in the cache.toml")
+    )
+    .arg(
+        Arg::with_name(ARG_COMPILED_MODULE_CACHE)
+            .long("compiled-module-cache")
+            .value_name("DIR")
+            .takes_value(true)
+            .help("a directory to save compiled modules in, named for the module's SHA256 digest, and to load them from at startup instead of compiling them again. Only WAGI should be able to write to it, as WAGI runs the code in these files")
+    )
+    .arg(
+        Arg::with_name(ARG_NO_PARALLEL_COMPILATION)
+            .long("no-parallel-compilation")
+            .help("compile each module on a single thread. This is slower, but uses less memory on small devices")
+    )
+    .arg(
+        Arg::with_name(ARG_CRANELIFT_OPT_LEVEL)
+            .long("cranelift-opt-level")
+            .value_name("LEVEL")
+            .takes_value(true)
+            .possible_values(&["none", "speed", "speed_and_size"])
+            .help("how hard Cranelift optimizes compiled modules. Default: speed")
+    )
+    .arg(
+        Arg::with_name(ARG_CRANELIFT_FLAGS)
+            .long("cranelift-flag")
+            .value_name("NAME=VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("sets a Cranelift code generator setting. Can be given several times. These settings are for working around platform problems; a wrong one can make modules misbehave")
+    )
+    .arg(
+        Arg::with_name(ARG_MODULE_IDLE_TTL)
+            .long("module-idle-ttl")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("drop a route's compiled module when the route has had no requests for this long, and compile it again on the next request. Default: modules are kept")
+    )
+    .arg(
+        Arg::with_name(ARG_REFRESH_DYNAMIC_ROUTES)
+            .long("refresh-dynamic-routes")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("call every module's _routes again this often, and serve the routes they return. Default: modules are only asked at startup")
+    )
     .arg(
         Arg::with_name(ARG_LISTEN_ON)
             .short("l")
@@ -151,6 +334,48 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .takes_value(true)
             .help("the hostname (and the port if not :80) that is to be considered the default. Default: localhost:3000"),
     )
+    .arg(
+        Arg::with_name(ARG_TRUSTED_PROXIES)
+            .long("trusted-proxies")
+            .value_name("NETWORKS")
+            .takes_value(true)
+            .help("a comma-separated list of networks in CIDR notation, such as 10.0.0.0/8. For requests from these addresses, the X-Forwarded-Host and X-Forwarded-Proto headers decide SERVER_NAME, SERVER_PORT and X_FULL_URL"),
+    )
+    .arg(
+        Arg::with_name(ARG_ADMIN_TOKEN)
+            .long("admin-token")
+            .value_name("WAGI_ADMIN_TOKEN")
+            .env("WAGI_ADMIN_TOKEN")
+            .hide_env_values(true)
+            .takes_value(true)
+            .help("a token that requests to the admin routes (/_wagi/routes, /_wagi/maintenance and /_wagi/log-level changes) must carry as 'Authorization: Bearer <token>'. Without it, only direct requests from the same machine may use them"),
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_CONNECTIONS)
+            .long("max-connections")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("the most client connections to have open at once. Further clients wait to be accepted until one closes. Default: no limit"),
+    )
+    .arg(
+        Arg::with_name(ARG_KEEP_ALIVE_TIMEOUT)
+            .long("keep-alive-timeout")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("close a connection once it has been idle between requests for this long. 0 turns keep-alive off. Default: idle connections are kept until the client closes them"),
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_REQUESTS_PER_CONNECTION)
+            .long("max-requests-per-connection")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("close a connection after answering this many requests on it. Default: no limit"),
+    )
+    .arg(
+        Arg::with_name(ARG_TCP_NODELAY)
+            .long("tcp-nodelay")
+            .help("set TCP_NODELAY on client connections, so that small responses are sent without waiting to be batched"),
+    )
     .arg(
         Arg::with_name(ARG_REMOTE_MODULE_CACHE_DIR)
             .long("module-cache")
@@ -158,6 +383,30 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to a directory where modules can be cached after fetching from remote locations. Default is to create a tempdir.")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name(ARG_SHARED_MODULE_CACHE)
+            .long("shared-module-cache")
+            .requires(ARG_REMOTE_MODULE_CACHE_DIR)
+            .help("the module cache directory is shared with other WAGI processes, for example replicas using a network filesystem. Each bindle parcel is then fetched by only one process, while the others wait for it")
+    )
+    .arg(
+        Arg::with_name(ARG_COMPRESS_MODULE_CACHE)
+            .long("compress-module-cache")
+            .help("store modules and invoices in the module cache compressed with zstd, to save disk space. Assets are not compressed, because they are mounted into modules")
+    )
+    .arg(
+        Arg::with_name(ARG_REGISTRY_CREDENTIALS_FILE)
+            .long("registry-credentials")
+            .value_name("CREDENTIALS_FILE")
+            .env("WAGI_REGISTRY_CREDENTIALS")
+            .takes_value(true)
+            .help("the path to a TOML file of credentials for pulling modules from OCI registries, listed per registry host. Values can refer to environment variables as ${NAME}")
+    )
+    .arg(
+        Arg::with_name(ARG_NO_AMBIENT_REGISTRY_CREDENTIALS)
+            .long("no-ambient-registry-credentials")
+            .help("do not use the Docker configuration or credential helpers of the WAGI user for OCI registries. Registries without configured credentials are pulled from anonymously")
+    )
     .arg(
         Arg::with_name(ARG_LOG_DIR)
             .long("log-dir")
@@ -166,6 +415,45 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to a directory where module logs should be stored. This directory will have a separate subdirectory created within it per running module. Default is to create a tempdir.")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name(ARG_AUDIT_LOG)
+            .long("audit-log")
+            .value_name("AUDIT_LOG_FILE")
+            .env("WAGI_AUDIT_LOG")
+            .takes_value(true)
+            .help("the file to append audit entries to for routes with audit = true. Default is audit.log in the log directory")
+    )
+    .arg(
+        Arg::with_name(ARG_VERBOSE_LOG_FILTER)
+            .long("verbose-log-filter")
+            .value_name("FILTER")
+            .takes_value(true)
+            .help("the log filter, in RUST_LOG syntax, that SIGUSR1 switches to. Sending SIGUSR1 again switches back to RUST_LOG. Default: debug")
+    )
+    .arg(
+        Arg::with_name(ARG_TENANT)
+            .long("tenant")
+            .value_name("TENANT_ID")
+            .env("WAGI_TENANT")
+            .takes_value(true)
+            .help("the tenant or application this instance serves. Its module cache and logs are kept in a tenants/TENANT_ID subdirectory of the module cache and log directories, readable only by the WAGI user")
+    )
+    .arg(
+        Arg::with_name(ARG_TENANT_CACHE_QUOTA)
+            .long("tenant-cache-quota")
+            .value_name("SIZE")
+            .takes_value(true)
+            .requires(ARG_TENANT)
+            .help("the most the tenant's module cache may hold, in bytes or with a Ki, Mi, Gi or Ti suffix. Fetches that would exceed it fail")
+    )
+    .arg(
+        Arg::with_name(ARG_TENANT_LOG_QUOTA)
+            .long("tenant-log-quota")
+            .value_name("SIZE")
+            .takes_value(true)
+            .requires(ARG_TENANT)
+            .help("the most the tenant's log directory may hold, in bytes or with a Ki, Mi, Gi or Ti suffix. Module stderr that would go to the log directory is discarded while it is full")
+    )
     .arg(
         Arg::with_name(ARG_TLS_CERT_FILE)
             .long("tls-cert")
@@ -184,6 +472,62 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .help("the path to the certificate key to use for https, if this is not set, normal http will be used. The key should be in PKCS#8 format")
             .requires(ARG_TLS_CERT_FILE)
     )
+    .arg(
+        Arg::with_name(ARG_TLS_SESSION_CACHE_SIZE)
+            .long("tls-session-cache-size")
+            .value_name("SESSIONS")
+            .takes_value(true)
+            .requires(ARG_TLS_CERT_FILE)
+            .help("how many TLS sessions to remember so that returning clients can resume them by session ID. 0 turns this off. Default is 256")
+    )
+    .arg(
+        Arg::with_name(ARG_NO_TLS_SESSION_TICKETS)
+            .long("no-tls-session-tickets")
+            .requires(ARG_TLS_CERT_FILE)
+            .conflicts_with(ARG_TLS_TICKET_ROTATION)
+            .help("don't give clients TLS session tickets to resume their sessions with")
+    )
+    .arg(
+        Arg::with_name(ARG_TLS_TICKET_ROTATION)
+            .long("tls-ticket-rotation")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .requires(ARG_TLS_CERT_FILE)
+            .help("how many seconds to use a TLS session ticket key for before replacing it. Tickets can be used for between one and two of these periods. At most 21600 (6 hours), which is the default")
+    )
+    .arg(
+        Arg::with_name(ARG_TLS_VERSIONS)
+            .long("tls-versions")
+            .value_name("VERSIONS")
+            .takes_value(true)
+            .requires(ARG_TLS_CERT_FILE)
+            .help("a comma-separated list of the TLS versions to accept, from 1.2 and 1.3. Default is both")
+    )
+    .arg(
+        Arg::with_name(ARG_TLS_CIPHER_SUITES)
+            .long("tls-cipher-suites")
+            .value_name("SUITES")
+            .takes_value(true)
+            .requires(ARG_TLS_CERT_FILE)
+            .help("a comma-separated list of the TLS cipher suites to accept, by their IANA names such as TLS_AES_256_GCM_SHA384. Default is every suite WAGI supports")
+    )
+    .arg(
+        Arg::with_name(ARG_TLS_OCSP_RESPONSE)
+            .long("tls-ocsp-response")
+            .value_name("OCSP_RESPONSE")
+            .env("WAGI_TLS_OCSP_RESPONSE")
+            .takes_value(true)
+            .requires(ARG_TLS_CERT_FILE)
+            .help("the path to a DER-encoded OCSP response for the certificate, to staple to TLS handshakes. It is read at startup")
+    )
+    .arg(
+        Arg::with_name(ARG_HTTP_REDIRECT_LISTEN_ON)
+            .long("http-redirect-listen")
+            .value_name("IP_PORT")
+            .takes_value(true)
+            .requires(ARG_TLS_CERT_FILE)
+            .help("an IP address and port to also listen on for plain HTTP, answering every request with a redirect to the same URL over HTTPS, for example 0.0.0.0:80")
+    )
     .arg(
         Arg::with_name(ARG_ENV_VARS)
             .long("env")
@@ -201,23 +545,403 @@ pub fn wagi_app_definition() -> App<'static, 'static> {
             .multiple(true)
             .help("Read a file of NAME=VALUE pairs and parse it into environment variables for the guest module. Multiple files can be specified. See also '--env'.")
     )
+    .arg(
+        Arg::with_name(ARG_DEFAULT_CONTENT_TYPE)
+            .long("default-content-type")
+            .value_name("CONTENT_TYPE")
+            .takes_value(true)
+            .help("the Content-Type to send when a module writes a body without one. Modules can override this with 'default_content_type'. Default: such responses are an error")
+    )
+    .arg(
+        Arg::with_name(ARG_DEFAULT_CHARSET)
+            .long("default-charset")
+            .value_name("CHARSET")
+            .takes_value(true)
+            .help("a charset to add to text/* responses that don't specify one. Modules can override this with 'default_charset'")
+    )
+    .arg(
+        Arg::with_name(ARG_CIRCUIT_BREAKER_THRESHOLD)
+            .long("circuit-breaker-threshold")
+            .value_name("FAILURES")
+            .takes_value(true)
+            .help("if set, a route whose module fails this many times in a row is taken out of service (returning 503) for the cool-down period. Default: circuit breaking is disabled")
+    )
+    .arg(
+        Arg::with_name(ARG_CIRCUIT_BREAKER_COOLDOWN)
+            .long("circuit-breaker-cooldown")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .requires(ARG_CIRCUIT_BREAKER_THRESHOLD)
+            .help("the number of seconds a route stays out of service after its circuit breaker opens. Default: 30")
+    )
+    .arg(
+        Arg::with_name(ARG_RESPONSE_HEADER_TIMEOUT)
+            .long("response-header-timeout")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("how long to wait for a module to write its response headers before answering 504 Gateway Timeout. Once the headers are written there is no limit on writing the body. Default: no limit")
+    )
+    .arg(
+        Arg::with_name(ARG_MODULE_TIMEOUT)
+            .long("module-timeout")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("how long a module may run before it is stopped and the client gets 504 Gateway Timeout. Modules can override this with `timeout`. Default: no limit")
+    )
+    .arg(
+        Arg::with_name(ARG_TRACE_HEADERS)
+            .long("trace-headers")
+            .value_name("HEADERS")
+            .takes_value(true)
+            .help("the trace headers to add to modules' outbound HTTP requests, as a comma-separated list of x-request-id and traceparent, or none. Default: x-request-id,traceparent")
+    )
+    .arg(
+        Arg::with_name(ARG_OUTBOUND_DNS_SERVERS)
+            .long("outbound-dns-server")
+            .value_name("ADDRESS")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("a DNS server, as IP or IP:PORT, to resolve the hosts of modules' outbound HTTP requests with instead of the system resolver. Can be given more than once")
+    )
+    .arg(
+        Arg::with_name(ARG_OUTBOUND_BLOCK_PRIVATE)
+            .long("outbound-block-private")
+            .help("refuse modules' outbound HTTP requests to loopback, private, link-local and other non-public addresses, including hosts in allowed_hosts that resolve to them")
+    )
+    .arg(
+        Arg::with_name(ARG_OUTBOUND_ALLOWED_NETWORKS)
+            .long("outbound-allowed-networks")
+            .value_name("NETWORKS")
+            .takes_value(true)
+            .help("a comma-separated list of networks in CIDR notation, such as 203.0.113.0/24,10.20.0.0/16. Modules' outbound HTTP requests may only connect to addresses in these networks. Networks listed here are allowed even with --outbound-block-private")
+    )
+    .arg(
+        Arg::with_name(ARG_DENY_OUTBOUND_HTTP)
+            .long("deny-outbound-http")
+            .help("do not give any module or scheduled task the outbound HTTP functions, whatever their allowed_hosts. Modules that import them fail to instantiate")
+    )
+    .arg(
+        Arg::with_name(ARG_CHECK_ALLOWED_HOSTS)
+            .long("check-allowed-hosts")
+            .help("at startup, send a HEAD request to each module's allowed_hosts and log a warning for any that can't be reached")
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_HEADER_VALUE_LENGTH)
+            .long("max-header-value-length")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("the longest request header value passed to modules. Default: 8192")
+    )
+    .arg(
+        Arg::with_name(ARG_HEADER_VALUE_POLICY)
+            .long("header-value-policy")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["drop", "truncate", "reject"])
+            .help("what to do with request header values that are too long or hold control characters: drop the value, truncate it, or reject the request with 400 Bad Request. Default: drop")
+    )
+    .arg(
+        Arg::with_name(ARG_SPILL_RESPONSES_OVER)
+            .long("spill-responses-over")
+            .value_name("SIZE")
+            .takes_value(true)
+            .help("once a module's output passes this size, such as 8Mi, write the rest of its response body to a temporary file and stream the response from it, rather than holding it all in memory")
+    )
+    .arg(
+        Arg::with_name(ARG_SPILL_DIR)
+            .long("spill-dir")
+            .value_name("DIR")
+            .takes_value(true)
+            .requires(ARG_SPILL_RESPONSES_OVER)
+            .help("the directory for the temporary files of --spill-responses-over. Default: the system temporary directory")
+    )
+    .arg(
+        Arg::with_name(ARG_OVERLAY_DIR)
+            .long("overlay-dir")
+            .value_name("DIR")
+            .takes_value(true)
+            .help("the directory for each request's copy of the volumes of modules with volume_overlay set. Default: the system temporary directory")
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_URL_LENGTH)
+            .long("max-url-length")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("the longest request path and query string WAGI accepts; longer ones are answered with 414 URI Too Long before routing. Default: 8192")
+    )
+    .arg(
+        Arg::with_name(ARG_MAX_PATH_SEGMENTS)
+            .long("max-path-segments")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("the most segments a request path may have; paths with more are answered with 414 URI Too Long before routing. Default: 128")
+    )
+    .arg(
+        Arg::with_name(ARG_URL_TOO_LONG_PAGE)
+            .long("url-too-long-page")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("a file to send as the body of 414 URI Too Long responses. Files ending in .html or .htm are served as HTML, and anything else as plain text")
+    )
+    .arg(
+        Arg::with_name(ARG_DRAIN_PERIOD)
+            .long("drain-period")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("after a shutdown signal, keep accepting connections for this long, answering 503 Service Unavailable with Retry-After, so that load balancers can stop sending traffic before WAGI stops listening. Default: 0")
+    )
+    .arg(
+        Arg::with_name(ARG_RECYCLE_AFTER_REQUESTS)
+            .long("recycle-after-requests")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("shut down, draining as for a shutdown signal, after handling this many requests, for the service manager to start a fresh WAGI")
+    )
+    .arg(
+        Arg::with_name(ARG_RECYCLE_AFTER)
+            .long("recycle-after")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("shut down, draining as for a shutdown signal, after running for this long, for the service manager to start a fresh WAGI")
+    )
+    .arg(
+        Arg::with_name(ARG_RECYCLE_JITTER)
+            .long("recycle-jitter")
+            .value_name("FRACTION")
+            .takes_value(true)
+            .help("bring the recycle limits forward by a random amount up to this fraction of them, so that replicas don't restart together. Default: 0.1")
+    )
+    .arg(
+        Arg::with_name(ARG_FETCH_ATTEMPTS)
+            .long("fetch-attempts")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("how many times to try fetching a remote module or bindle parcel before giving up. Default: 3")
+    )
+    .arg(
+        Arg::with_name(ARG_FETCH_RETRY_DELAY)
+            .long("fetch-retry-delay")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .help("how long to wait before the first retry of a failed fetch. Each later wait is twice as long. Default: 0.5")
+    )
+    .arg(
+        Arg::with_name(ARG_RETRY_FETCH_IN_BACKGROUND)
+            .long("retry-fetch-in-background")
+            .help("if a module in the modules.toml file can't be fetched, start anyway and keep trying to fetch it in the background. Its route answers 503 Service Unavailable until the module arrives")
+    )
+    .arg(
+        Arg::with_name(ARG_MISSING_PARCEL)
+            .long("missing-parcel")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["fail", "skip", "retry"])
+            .help("what to do when a parcel that a bindle handler needs can't be fetched: fail to start, start without the handler, or start and keep trying to fetch it in the background. The handler's route answers 503 Service Unavailable until it has its parcels. Default: fail")
+    )
+    .arg(
+        Arg::with_name(ARG_ON_COMPILE_ERROR)
+            .long("on-compile-error")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&["fail", "skip"])
+            .help("what to do when a module can't be compiled: fail to start, or start without it, its route answering 503 Service Unavailable. Every module is compiled either way, and each failure is reported with its route, source and digest. Default: fail")
+    )
+    .arg(
+        Arg::with_name(ARG_HEALTH_CHECK_PATH)
+            .long("health-check-path")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("the path of the inbuilt health check route. Default: /healthz")
+    )
+    .arg(
+        Arg::with_name(ARG_HEALTH_CHECK_BODY)
+            .long("health-check-body")
+            .value_name("TEXT")
+            .takes_value(true)
+            .help("the body of the health check response. Default: OK")
+    )
+    .arg(
+        Arg::with_name(ARG_HEALTH_CHECK_STATUS)
+            .long("health-check-status")
+            .value_name("CODE")
+            .takes_value(true)
+            .help("the HTTP status of the health check response. Default: 200")
+    )
+    .arg(
+        Arg::with_name(ARG_NO_HEALTH_CHECK)
+            .long("no-health-check")
+            .conflicts_with_all(&[ARG_HEALTH_CHECK_PATH, ARG_HEALTH_CHECK_BODY, ARG_HEALTH_CHECK_STATUS])
+            .help("don't serve the inbuilt health check route, so that a module can handle its path instead")
+    )
+    .arg(
+        Arg::with_name(ARG_MAINTENANCE)
+            .long("maintenance")
+            .help("start with every module and custom route in maintenance, answering 503 with the maintenance page until maintenance is ended at /_wagi/maintenance")
+    )
+    .arg(
+        Arg::with_name(ARG_MAINTENANCE_PAGE)
+            .long("maintenance-page")
+            .value_name("MAINTENANCE_PAGE")
+            .takes_value(true)
+            .help("the path to a file to answer requests with while their route is in maintenance. Files ending in .html or .htm are served as HTML. Default is a short plain text message")
+    )
+    .arg(
+        Arg::with_name(ARG_HARDEN)
+            .long("harden")
+            .help("(Linux only) once modules are loaded, restrict the WAGI process's filesystem access with Landlock and block dangerous syscalls with seccomp")
+    )
+    .arg(
+        Arg::with_name(ARG_ALLOW_MISSING_VOLUMES)
+            .long("allow-missing-volumes")
+            .help("start even if a module's volume host directory does not exist or is not a directory, logging a warning and running the module without that volume. By default WAGI refuses to start")
+    )
+    .arg(
+        Arg::with_name(ARG_DEBUG_ERRORS)
+            .long("debug-errors")
+            .help("when a module fails or writes an invalid response, send the error and the last lines of the module's stderr in the 500 response. Do not use in production: it can reveal internal details to clients")
+    )
+    .arg(
+        Arg::with_name(ARG_INTERLEAVE_OUTPUT)
+            .long("interleave-output")
+            .help("for each run of a module, write its stdout and stderr, timestamped and in the order they were written, to a file in the interleaved directory of the module's log directory. For debugging malformed output; the files are not cleaned up")
+    )
+    .arg(
+        Arg::with_name(ARG_SAMPLE_EVERY)
+            .long("sample-every")
+            .value_name("N")
+            .takes_value(true)
+            .help("record one in every N requests to each route in full, with the module's environment, arguments, stdout and stderr, in the samples directory of the log directory. The samples include request headers and environment variables, so keep them private")
+    )
+    .arg(
+        Arg::with_name(ARG_SAMPLE_HEADER)
+            .long("sample-header")
+            .value_name("NAME[=VALUE]")
+            .takes_value(true)
+            .help("record in full every request that has this header, or this header with this value, as --sample-every does")
+    )
+    .arg(
+        Arg::with_name(ARG_SAMPLE_QUOTA)
+            .long("sample-quota")
+            .value_name("SIZE")
+            .takes_value(true)
+            .help("stop recording samples once the samples directory reaches this size, such as 512Mi. Default: 100Mi")
+    )
+    .subcommand(
+        SubCommand::with_name(SUBCOMMAND_DEV)
+            .about("Run as a local development server")
+            .arg(
+                Arg::with_name(ARG_WATCH)
+                    .long("watch")
+                    .help("rebuild modules that have a build_command in the modules.toml when their source files change, and reload them without restarting the server")
+            )
+    )
+    .subcommand(
+        SubCommand::with_name(SUBCOMMAND_BENCH)
+            .about("Load test a route in-process, without going over the network, and report throughput, time spent instantiating and running modules, and memory use")
+            .arg(
+                Arg::with_name(ARG_BENCH_ROUTE)
+                    .long("route")
+                    .value_name("PATH")
+                    .takes_value(true)
+                    .default_value("/")
+                    .help("the path (and optionally query string) to request")
+            )
+            .arg(
+                Arg::with_name(ARG_BENCH_REQUESTS)
+                    .long("requests")
+                    .short("n")
+                    .value_name("N")
+                    .takes_value(true)
+                    .default_value("1000")
+                    .help("the total number of requests to send")
+            )
+            .arg(
+                Arg::with_name(ARG_BENCH_CONCURRENCY)
+                    .long("concurrency")
+                    .value_name("C")
+                    .takes_value(true)
+                    .default_value("10")
+                    .help("how many requests to have in flight at once")
+            )
+    )
+    .subcommand(
+        SubCommand::with_name(SUBCOMMAND_COMPLETIONS)
+            .about("Print a script that completes WAGI's options and subcommands in a shell")
+            .arg(
+                Arg::with_name(ARG_SHELL)
+                    .value_name("SHELL")
+                    .required(true)
+                    .possible_values(&Shell::variants())
+                    .help("the shell to print the script for")
+            )
+    )
+    .subcommand(
+        SubCommand::with_name(SUBCOMMAND_CONFIG)
+            .about("Work with WAGI's settings")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name(SUBCOMMAND_PRINT_DEFAULTS)
+                    .about("Print the options that have defaults, with their default values and what they do")
+            )
+    )
+}
+
+/// What WAGI has been asked to do.
+pub enum WagiCommand {
+    /// Serve the modules, or load them for `dev` or `bench`.
+    Serve(Box<WagiConfiguration>),
+    /// Print a shell completion script.
+    Completions(Shell),
+    /// Print the annotated default settings.
+    PrintDefaults,
 }
 
-pub fn parse_command_line() -> anyhow::Result<WagiConfiguration> {
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+impl WagiCommand {
+    /// Runs the commands that only print something. Returns the configuration if
+    /// the command is to serve.
+    pub fn into_serve(self) -> Option<WagiConfiguration> {
+        match self {
+            Self::Serve(configuration) => Some(*configuration),
+            Self::Completions(shell) => {
+                wagi_app_definition().gen_completions_to("wagi", shell, &mut std::io::stdout());
+                None
+            }
+            Self::PrintDefaults => {
+                print!("{}", crate::config_defaults::annotated_defaults());
+                None
+            }
+        }
+    }
+}
+
+pub fn parse_command_line() -> WagiResult<WagiCommand> {
     let wagi_app = wagi_app_definition();
 
     let matches = wagi_app.get_matches();
-    parse_configuration_from(matches)
+    if let Some(command) = parse_tooling_command(&matches) {
+        return Ok(command);
+    }
+    let log_level = LogLevel::init_subscriber();
+    let mut configuration = parse_configuration_from(matches).map_err(WagiError::Config)?;
+    configuration.log_level = Some(log_level);
+    Ok(WagiCommand::Serve(Box::new(configuration)))
+}
+
+fn parse_tooling_command(matches: &ArgMatches) -> Option<WagiCommand> {
+    if let Some(completions) = matches.subcommand_matches(SUBCOMMAND_COMPLETIONS) {
+        // clap only accepts the shells it has variants for
+        let shell = completions.value_of(ARG_SHELL)?.parse().ok()?;
+        return Some(WagiCommand::Completions(shell));
+    }
+    let config = matches.subcommand_matches(SUBCOMMAND_CONFIG)?;
+    config.subcommand_matches(SUBCOMMAND_PRINT_DEFAULTS).map(|_| WagiCommand::PrintDefaults)
 }
 
 pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfiguration> {
     let addr: SocketAddr = matches
         .value_of(ARG_LISTEN_ON)
-        .unwrap_or("127.0.0.1:3000")
+        .unwrap_or(DEFAULT_LISTEN_ON)
         .parse()
         .unwrap();
 
@@ -226,12 +950,20 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
     // We have to pass a cache file configuration path to a Wasmtime engine.
     let cache_config_path = matches
         .value_of(ARG_WASM_CACHE_CONFIG_FILE)
-        .unwrap_or("cache.toml")
+        .unwrap_or(DEFAULT_WASM_CACHE_CONFIG_FILE)
         .to_owned();
 
     let hostname = matches
         .value_of(ARG_DEFAULT_HOSTNAME)
-        .unwrap_or("localhost:3000");
+        .unwrap_or(DEFAULT_HOSTNAME);
+    let trusted_proxies = matches
+        .value_of(ARG_TRUSTED_PROXIES)
+        .into_iter()
+        .flat_map(|networks| networks.split(','))
+        .filter(|network| !network.trim().is_empty())
+        .map(|network| network.parse())
+        .collect::<anyhow::Result<_>>()
+        .map_err(|e| e.context("Invalid --trusted-proxies"))?;
 
     // TODO: this means that we effectively default to no caching between
     // runs - this seems non-optimal
@@ -252,15 +984,116 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
         }
     };
 
+    let tenant = match matches.value_of(ARG_TENANT) {
+        Some(id) => Some(TenantSettings::parse(
+            id,
+            matches.value_of(ARG_TENANT_CACHE_QUOTA),
+            matches.value_of(ARG_TENANT_LOG_QUOTA),
+        )?),
+        None => None,
+    };
+    let (mc, log_dir) = match &tenant {
+        Some(t) => (t.partition(&mc)?, t.partition(&log_dir)?),
+        None => (mc, log_dir),
+    };
+
+    let audit_log = match matches.value_of(ARG_AUDIT_LOG) {
+        Some(path) => std::path::PathBuf::from(path),
+        None => log_dir.join(AUDIT_LOG_FILE),
+    };
+
+    let verbose_log_filter = matches.value_of(ARG_VERBOSE_LOG_FILTER).unwrap_or(DEFAULT_VERBOSE_LOG_FILTER).to_owned();
+    tracing_subscriber::EnvFilter::try_new(&verbose_log_filter)
+        .map_err(|e| anyhow::anyhow!("Invalid --verbose-log-filter '{}': {}", verbose_log_filter, e))?;
+
     let env_vars = merge_env_vars(&matches)?;
 
     tracing::debug!(?env_vars, "Env vars are set");
 
-    let tls_cert = matches.value_of(ARG_TLS_CERT_FILE);
-    let tls_key = matches.value_of(ARG_TLS_KEY_FILE);
-
     let handlers = parse_handler_configuration_source(&matches)?;
-    let tls_config = parse_tls_config(tls_cert, tls_key)?;
+    let tls_config = parse_tls_config(&matches)?;
+    let circuit_breaker = parse_circuit_breaker_settings(&matches)?;
+    let response_header_timeout = parse_timeout(&matches, ARG_RESPONSE_HEADER_TIMEOUT)?;
+    let module_timeout = parse_timeout(&matches, ARG_MODULE_TIMEOUT)?;
+    let module_idle_ttl = parse_timeout(&matches, ARG_MODULE_IDLE_TTL)?;
+    let dynamic_routes_refresh_interval = parse_timeout(&matches, ARG_REFRESH_DYNAMIC_ROUTES)?;
+    let drain_period = parse_drain_period(&matches)?;
+    let recycle = RecycleSettings::parse(
+        matches.value_of(ARG_RECYCLE_AFTER_REQUESTS),
+        matches.value_of(ARG_RECYCLE_AFTER),
+        matches.value_of(ARG_RECYCLE_JITTER),
+    )?;
+    let connections = ConnectionSettings::parse(
+        matches.value_of(ARG_MAX_CONNECTIONS),
+        matches.value_of(ARG_KEEP_ALIVE_TIMEOUT),
+        matches.value_of(ARG_MAX_REQUESTS_PER_CONNECTION),
+        matches.is_present(ARG_TCP_NODELAY),
+    )?;
+    let fetch_retry = parse_fetch_retry_policy(&matches)?;
+    let retry_fetch_in_background = matches.is_present(ARG_RETRY_FETCH_IN_BACKGROUND);
+    let missing_parcel = match matches.value_of(ARG_MISSING_PARCEL) {
+        None => MissingParcelPolicy::default(),
+        Some(s) => s.parse()?,
+    };
+    let bindle_annotations: Vec<String> = matches
+        .value_of(ARG_BINDLE_ANNOTATIONS)
+        .map(|keys| keys.split(',').map(|k| k.trim().to_owned()).filter(|k| !k.is_empty()).collect())
+        .unwrap_or_default();
+    let on_compile_error = match matches.value_of(ARG_ON_COMPILE_ERROR) {
+        None => CompileErrorPolicy::default(),
+        Some(s) => s.parse()?,
+    };
+    let health_check = parse_health_check_settings(&matches)?;
+    let sampling = SamplingSettings::parse(
+        matches.value_of(ARG_SAMPLE_EVERY),
+        matches.value_of(ARG_SAMPLE_HEADER),
+        matches.value_of(ARG_SAMPLE_QUOTA),
+    )?;
+    let maintenance = MaintenanceSettings {
+        page: match matches.value_of(ARG_MAINTENANCE_PAGE) {
+            Some(path) => MaintenancePage::load(std::path::Path::new(path))?,
+            None => MaintenancePage::default(),
+        },
+        at_startup: matches.is_present(ARG_MAINTENANCE),
+    };
+    let engine_settings = parse_engine_settings(&matches)?;
+    let registry_credentials = RegistryCredentials::load(
+        matches.value_of(ARG_REGISTRY_CREDENTIALS_FILE).map(std::path::Path::new),
+        !matches.is_present(ARG_NO_AMBIENT_REGISTRY_CREDENTIALS),
+    )?;
+    let watch = matches.subcommand_matches(SUBCOMMAND_DEV).map(|m| m.is_present(ARG_WATCH)).unwrap_or(false);
+    let harden = matches.is_present(ARG_HARDEN);
+    let bench = match matches.subcommand_matches(SUBCOMMAND_BENCH) {
+        Some(m) => Some(parse_bench_settings(m)?),
+        None => None,
+    };
+    let trace_headers = match matches.value_of(ARG_TRACE_HEADERS) {
+        Some(h) => TraceHeaders::parse(h)?,
+        None => TraceHeaders::default(),
+    };
+    let outbound_network = OutboundNetwork::new(parse_outbound_network_policy(&matches)?)?;
+    let header_limits = parse_header_limits(&matches)?;
+    let url_limits = parse_url_limits(&matches)?;
+    let spill = parse_spill_settings(&matches)?;
+    let admin_token = match matches.value_of(ARG_ADMIN_TOKEN).map(str::trim) {
+        Some("") => anyhow::bail!("Invalid --admin-token: must not be empty"),
+        token => token.map(|t| t.to_owned()),
+    };
+    let overlay_dir = matches.value_of(ARG_OVERLAY_DIR).map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    if !overlay_dir.is_dir() {
+        anyhow::bail!("Invalid overlay directory {}: must be an existing directory", overlay_dir.display());
+    }
+    if harden && watch {
+        // Hardening blocks running the build commands that watch mode relies on
+        anyhow::bail!("--harden cannot be used with dev --watch");
+    }
+    if harden && retry_fetch_in_background {
+        // Hardening restarts the async runtime after loading, which would stop the background fetches
+        anyhow::bail!("--harden cannot be used with --retry-fetch-in-background");
+    }
+    if harden && missing_parcel == MissingParcelPolicy::Retry {
+        anyhow::bail!("--harden cannot be used with --missing-parcel retry");
+    }
 
     let configuration = WagiConfiguration {
         handlers,
@@ -269,15 +1102,79 @@ pub fn parse_configuration_from(matches: ArgMatches) -> anyhow::Result<WagiConfi
             listen_on: addr,
             default_hostname: hostname.to_owned(),
             tls: tls_config,
+            trusted_proxies,
+            connections,
         },
         wasm_cache_config_file: std::path::PathBuf::from(cache_config_path),
+        engine_settings,
+        compiled_module_cache: matches.value_of(ARG_COMPILED_MODULE_CACHE).map(std::path::PathBuf::from),
+        module_idle_ttl,
+        dynamic_routes_refresh_interval,
         asset_cache_dir: mc,
+        shared_module_cache: matches.is_present(ARG_SHARED_MODULE_CACHE),
+        compress_module_cache: matches.is_present(ARG_COMPRESS_MODULE_CACHE),
+        registry_credentials,
+        fetch_retry,
+        retry_fetch_in_background,
+        missing_parcel,
+        bindle_annotations,
+        on_compile_error,
         log_dir,
+        audit_log,
+        log_level: None,
+        verbose_log_filter,
+        tenant,
+        circuit_breaker,
+        default_content_type: matches.value_of(ARG_DEFAULT_CONTENT_TYPE).map(|s| s.to_owned()),
+        default_charset: matches.value_of(ARG_DEFAULT_CHARSET).map(|s| s.to_owned()),
+        response_header_timeout,
+        module_timeout,
+        watch,
+        harden,
+        admin_token,
+        debug_errors: matches.is_present(ARG_DEBUG_ERRORS),
+        interleave_output: matches.is_present(ARG_INTERLEAVE_OUTPUT),
+        sampling,
+        spill,
+        overlay_dir,
+        allow_missing_volumes: matches.is_present(ARG_ALLOW_MISSING_VOLUMES),
+        trace_headers,
+        outbound_network,
+        deny_outbound_http: matches.is_present(ARG_DENY_OUTBOUND_HTTP),
+        check_allowed_hosts: matches.is_present(ARG_CHECK_ALLOWED_HOSTS),
+        header_limits,
+        url_limits,
+        drain_period,
+        recycle,
+        health_check,
+        maintenance,
+        bench,
+        custom_handlers: CustomHandlers::default(),
+        scheme_resolvers: SchemeResolvers::default(),
     };
 
     Ok(configuration)
 }
 
+fn parse_bench_settings(matches: &ArgMatches) -> anyhow::Result<BenchSettings> {
+    let count = |arg: &str, what: &str| -> anyhow::Result<usize> {
+        let text = matches.value_of(arg).unwrap_or_default();
+        match text.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(anyhow::anyhow!("Invalid {} '{}': must be a whole number greater than zero", what, text)),
+        }
+    };
+    let route = matches.value_of(ARG_BENCH_ROUTE).unwrap_or("/");
+    if !route.starts_with('/') {
+        anyhow::bail!("Invalid bench route '{}': must start with '/'", route);
+    }
+    Ok(BenchSettings {
+        route: route.to_owned(),
+        requests: count(ARG_BENCH_REQUESTS, "number of requests")?,
+        concurrency: count(ARG_BENCH_CONCURRENCY, "concurrency")?,
+    })
+}
+
 fn parse_bindle_connection_info(
     url: url::Url,
     matches: &ArgMatches,
@@ -295,9 +1192,17 @@ fn parse_handler_configuration_source(
 ) -> anyhow::Result<HandlerConfigurationSource> {
     // The following rules are enforced at the clap app/arg level:
     //
-    // * You MUST have a modules file OR a bindle ID, but not both
+    // * You MUST have a modules file OR a modules directory OR a bindle ID,
+    //   and only one of them
     // * If you have a bindle ID (i.e. do NOT have a modules file), you MUST
     //   have a Bindle server URL OR standalone directory, but not both
+    if let Some(modules_dir) = matches.value_of(ARG_MODULES_DIR).ignore_if_empty() {
+        let modules_dir_path = std::path::PathBuf::from(modules_dir);
+        if !modules_dir_path.is_dir() {
+            anyhow::bail!("Module directory {} does not exist or is not a directory", modules_dir);
+        }
+        return Ok(HandlerConfigurationSource::ModuleDir(modules_dir_path));
+    }
     match (
         matches.value_of(ARG_BINDLE_ID).ignore_if_empty(),
         matches.value_of(ARG_BINDLE_STANDALONE_DIR).ignore_if_empty(),
@@ -365,28 +1270,36 @@ fn parse_handler_configuration_source(
     }
 }
 
-fn parse_tls_config(
-    tls_cert_file: Option<&str>,
-    tls_key_file: Option<&str>,
-) -> anyhow::Result<Option<TlsConfiguration>> {
+fn parse_tls_config(matches: &ArgMatches) -> anyhow::Result<Option<TlsConfiguration>> {
+    let tls_cert_file = matches.value_of(ARG_TLS_CERT_FILE);
+    let tls_key_file = matches.value_of(ARG_TLS_KEY_FILE);
     match (tls_cert_file, tls_key_file) {
         (Some(cert), Some(key)) => {
-            let cert_path = std::path::PathBuf::from(cert);
-            let key_path = std::path::PathBuf::from(key);
-            if !cert_path.is_file() {
-                Err(anyhow::anyhow!(
-                    "TLS certificate file does not exist or is not a file"
-                ))
-            } else if !key_path.is_file() {
-                Err(anyhow::anyhow!(
-                    "TLS key file does not exist or is not a file"
-                ))
-            } else {
-                Ok(Some(TlsConfiguration {
-                    cert_path,
-                    key_path,
-                }))
+            let mut tls = TlsConfiguration::new(cert, key);
+            if let Some(s) = matches.value_of(ARG_TLS_SESSION_CACHE_SIZE) {
+                tls.session_cache_size = s.parse::<usize>()
+                    .map_err(|_| anyhow::anyhow!("Invalid TLS session cache size '{}': must be a whole number of sessions", s))?;
+            }
+            tls.session_tickets = !matches.is_present(ARG_NO_TLS_SESSION_TICKETS);
+            if let Some(s) = matches.value_of(ARG_TLS_TICKET_ROTATION) {
+                tls.ticket_rotation = match s.parse::<u64>() {
+                    Ok(secs) if secs > 0 && Duration::from_secs(secs) <= tls::MAX_TICKET_ROTATION => Duration::from_secs(secs),
+                    _ => anyhow::bail!("Invalid TLS ticket rotation '{}': must be a whole number of seconds from 1 to {}", s, tls::MAX_TICKET_ROTATION.as_secs()),
+                };
             }
+            if let Some(s) = matches.value_of(ARG_TLS_VERSIONS) {
+                tls.versions = tls::parse_tls_versions(s)?;
+            }
+            if let Some(s) = matches.value_of(ARG_TLS_CIPHER_SUITES) {
+                tls.cipher_suites = tls::parse_cipher_suites(s)?;
+            }
+            tls.ocsp_response_path = matches.value_of(ARG_TLS_OCSP_RESPONSE).map(std::path::PathBuf::from);
+            if let Some(s) = matches.value_of(ARG_HTTP_REDIRECT_LISTEN_ON) {
+                tls.http_redirect_listen_on = Some(s.parse::<SocketAddr>()
+                    .map_err(|e| anyhow::anyhow!("Invalid HTTP redirect listen address '{}': {}", s, e))?);
+            }
+            tls.validate()?;
+            Ok(Some(tls))
         }
         (None, None) => Ok(None),
         // Should be impossible from arg requirements
@@ -396,6 +1309,176 @@ fn parse_tls_config(
     }
 }
 
+fn parse_drain_period(matches: &ArgMatches) -> anyhow::Result<Duration> {
+    match matches.value_of(ARG_DRAIN_PERIOD) {
+        None => Ok(Duration::ZERO),
+        Some(s) => s.parse::<f64>().ok().and_then(duration_from_secs)
+            .ok_or_else(|| anyhow::anyhow!("Invalid drain period '{}': must be a number of seconds, from zero up to 100 years", s)),
+    }
+}
+
+fn parse_fetch_retry_policy(matches: &ArgMatches) -> anyhow::Result<FetchRetryPolicy> {
+    let defaults = FetchRetryPolicy::default();
+    let attempts = match matches.value_of(ARG_FETCH_ATTEMPTS) {
+        None => defaults.attempts,
+        Some(s) => match s.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => anyhow::bail!("Invalid fetch attempts '{}': must be a whole number greater than zero", s),
+        },
+    };
+    let first_delay = match matches.value_of(ARG_FETCH_RETRY_DELAY) {
+        None => defaults.first_delay,
+        Some(s) => match s.parse::<f64>().ok().and_then(duration_from_secs) {
+            Some(delay) => delay,
+            None => anyhow::bail!("Invalid fetch retry delay '{}': must be a number of seconds, from zero up to 100 years", s),
+        },
+    };
+    Ok(FetchRetryPolicy { attempts, first_delay })
+}
+
+fn parse_header_limits(matches: &ArgMatches) -> anyhow::Result<HeaderLimits> {
+    let defaults = HeaderLimits::default();
+    let max_value_len = match matches.value_of(ARG_MAX_HEADER_VALUE_LENGTH) {
+        None => defaults.max_value_len,
+        Some(s) => match s.parse::<usize>() {
+            Ok(len) if len > 0 => len,
+            _ => anyhow::bail!("Invalid max header value length '{}': must be a number of bytes, at least 1", s),
+        },
+    };
+    let policy = match matches.value_of(ARG_HEADER_VALUE_POLICY) {
+        None => defaults.policy,
+        Some(s) => HeaderValuePolicy::parse(s)?,
+    };
+    Ok(HeaderLimits { max_value_len, policy })
+}
+
+fn parse_url_limits(matches: &ArgMatches) -> anyhow::Result<UrlLimits> {
+    let mut limits = UrlLimits::default();
+    if let Some(s) = matches.value_of(ARG_MAX_URL_LENGTH) {
+        limits.max_length = match s.parse::<usize>() {
+            Ok(len) if len > 0 => len,
+            _ => anyhow::bail!("Invalid max URL length '{}': must be a number of bytes, at least 1", s),
+        };
+    }
+    if let Some(s) = matches.value_of(ARG_MAX_PATH_SEGMENTS) {
+        limits.max_segments = match s.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => anyhow::bail!("Invalid max path segments '{}': must be a whole number, at least 1", s),
+        };
+    }
+    match matches.value_of(ARG_URL_TOO_LONG_PAGE) {
+        Some(path) => limits.with_too_long_page(std::path::Path::new(path)),
+        None => Ok(limits),
+    }
+}
+
+fn parse_spill_settings(matches: &ArgMatches) -> anyhow::Result<Option<SpillSettings>> {
+    let threshold = match matches.value_of(ARG_SPILL_RESPONSES_OVER) {
+        Some(size) => usize::try_from(parse_size(size)?)?,
+        None => return Ok(None),
+    };
+    let dir = matches.value_of(ARG_SPILL_DIR).map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    if !dir.is_dir() {
+        anyhow::bail!("Invalid spill directory {}: must be an existing directory", dir.display());
+    }
+    Ok(Some(SpillSettings { threshold, dir }))
+}
+
+fn parse_outbound_network_policy(matches: &ArgMatches) -> anyhow::Result<OutboundNetworkPolicy> {
+    let dns_servers = matches
+        .values_of(ARG_OUTBOUND_DNS_SERVERS)
+        .into_iter()
+        .flatten()
+        .map(|server| match server.parse::<std::net::IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+            Err(_) => server.parse::<SocketAddr>().map_err(|_| anyhow::anyhow!("Invalid --outbound-dns-server '{}': must be IP or IP:PORT", server)),
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let allowed_networks = matches
+        .value_of(ARG_OUTBOUND_ALLOWED_NETWORKS)
+        .into_iter()
+        .flat_map(|networks| networks.split(','))
+        .filter(|network| !network.trim().is_empty())
+        .map(|network| network.parse())
+        .collect::<anyhow::Result<_>>()
+        .map_err(|e| e.context("Invalid --outbound-allowed-networks"))?;
+    Ok(OutboundNetworkPolicy {
+        dns_servers,
+        block_private: matches.is_present(ARG_OUTBOUND_BLOCK_PRIVATE),
+        allowed_networks,
+    })
+}
+
+fn parse_engine_settings(matches: &ArgMatches) -> anyhow::Result<EngineSettings> {
+    let cranelift_opt_level = matches.value_of(ARG_CRANELIFT_OPT_LEVEL).map(|level| match level {
+        "none" => wasmtime::OptLevel::None,
+        "speed_and_size" => wasmtime::OptLevel::SpeedAndSize,
+        _ => wasmtime::OptLevel::Speed,
+    });
+    let cranelift_flags = matches
+        .values_of(ARG_CRANELIFT_FLAGS)
+        .into_iter()
+        .flatten()
+        .map(|flag| match flag.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_owned(), value.trim().to_owned())),
+            _ => Err(anyhow::anyhow!("Invalid Cranelift setting '{}': must be NAME=VALUE", flag)),
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(EngineSettings {
+        parallel_compilation: !matches.is_present(ARG_NO_PARALLEL_COMPILATION),
+        cranelift_opt_level,
+        cranelift_flags,
+        cache_size_limit: matches.value_of(ARG_WASM_CACHE_SIZE_LIMIT).map(|s| s.to_owned()),
+    })
+}
+
+fn parse_health_check_settings(matches: &ArgMatches) -> anyhow::Result<Option<HealthCheckSettings>> {
+    if matches.is_present(ARG_NO_HEALTH_CHECK) {
+        return Ok(None);
+    }
+    HealthCheckSettings::parse(
+        matches.value_of(ARG_HEALTH_CHECK_PATH),
+        matches.value_of(ARG_HEALTH_CHECK_BODY),
+        matches.value_of(ARG_HEALTH_CHECK_STATUS),
+    ).map(Some)
+}
+
+fn parse_circuit_breaker_settings(
+    matches: &ArgMatches,
+) -> anyhow::Result<Option<CircuitBreakerSettings>> {
+    let failure_threshold = match matches.value_of(ARG_CIRCUIT_BREAKER_THRESHOLD) {
+        None => return Ok(None),
+        Some(t) => t.parse::<u32>()
+            .map_err(|e| anyhow::anyhow!("Invalid circuit breaker threshold '{}': {}", t, e))?,
+    };
+    if failure_threshold == 0 {
+        return Err(anyhow::anyhow!("Circuit breaker threshold must be greater than zero"));
+    }
+    let cooldown_secs = match matches.value_of(ARG_CIRCUIT_BREAKER_COOLDOWN) {
+        None => 30,
+        Some(c) => c.parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid circuit breaker cool-down '{}': {}", c, e))?,
+    };
+    Ok(Some(CircuitBreakerSettings {
+        failure_threshold,
+        cooldown: Duration::from_secs(cooldown_secs),
+    }))
+}
+
+/// Parses a timeout option given in (possibly fractional) seconds, if it is present.
+fn parse_timeout(matches: &ArgMatches, arg: &str) -> anyhow::Result<Option<Duration>> {
+    match matches.value_of(arg) {
+        None => Ok(None),
+        Some(s) => {
+            let secs: f64 = s.parse()
+                .map_err(|_| anyhow::anyhow!("{} must be a number of seconds, got '{}'", arg, s))?;
+            let timeout = timeout_from_secs(secs)
+                .map_err(|e| anyhow::anyhow!("Invalid {}: {}", arg, e))?;
+            Ok(Some(timeout))
+        }
+    }
+}
+
 /// Merge environment variables defined in a file with those defined on the CLI.
 fn merge_env_vars(matches: &ArgMatches) -> anyhow::Result<HashMap<String, String>> {
     let mut env_vars: HashMap<String, String> = match matches.values_of(ARG_ENV_FILES) {
@@ -518,6 +1601,206 @@ mod test {
         parse_env_var("=bar").expect_err("Missing key should fail");
     }
 
+    #[test]
+    fn test_harden_cannot_be_used_with_watch() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--harden", "dev", "--watch"]);
+        parse_configuration_from(matches).expect_err("--harden with dev --watch should fail");
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--harden"]);
+        let configuration = parse_configuration_from(matches).expect("--harden alone should parse");
+        assert!(configuration.harden);
+    }
+
+    #[test]
+    fn test_drain_period() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml"]);
+        assert_eq!(Duration::ZERO, parse_configuration_from(matches).unwrap().drain_period);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--drain-period", "2.5"]);
+        assert_eq!(Duration::from_millis(2500), parse_configuration_from(matches).unwrap().drain_period);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--drain-period", "-1"]);
+        parse_configuration_from(matches).expect_err("negative drain period should fail");
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--drain-period", "1e20"]);
+        parse_configuration_from(matches).expect_err("a drain period too long to hold should fail");
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--admin-token", "s3cret"]);
+        assert_eq!(Some("s3cret".to_owned()), parse_configuration_from(matches).unwrap().admin_token);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--admin-token", " "]);
+        parse_configuration_from(matches).expect_err("an empty token should fail");
+    }
+
+    #[test]
+    fn test_recycle_settings() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml"]);
+        assert_eq!(None, parse_configuration_from(matches).unwrap().recycle);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--recycle-after", "86400", "--recycle-jitter", "0.25"]);
+        let recycle = parse_configuration_from(matches).unwrap().recycle.unwrap();
+        assert_eq!(Some(Duration::from_secs(86400)), recycle.after);
+        assert_eq!(None, recycle.after_requests);
+    }
+
+    #[test]
+    fn test_connection_settings() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--max-connections", "512", "--keep-alive-timeout", "7.5", "--tcp-nodelay"]);
+        let connections = parse_configuration_from(matches).unwrap().http_configuration.connections;
+        assert_eq!(Some(512), connections.max_connections);
+        assert_eq!(Some(Duration::from_millis(7500)), connections.keep_alive_timeout);
+        assert_eq!(None, connections.max_requests_per_connection);
+        assert!(connections.tcp_nodelay);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--max-requests-per-connection", "none"]);
+        parse_configuration_from(matches).expect_err("the request limit must be a number");
+    }
+
+    #[test]
+    fn test_engine_settings() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml"]);
+        let settings = parse_configuration_from(matches).unwrap().engine_settings;
+        assert!(settings.parallel_compilation);
+        assert!(settings.cranelift_flags.is_empty());
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec![
+                "wagi", "-c", "examples/modules.toml",
+                "--no-parallel-compilation",
+                "--cranelift-opt-level", "none",
+                "--cranelift-flag", "enable_simd=false",
+                "--cranelift-flag", "has_lse=false",
+                "--wasm-cache-size-limit", "256Mi",
+            ]);
+        let settings = parse_configuration_from(matches).unwrap().engine_settings;
+        assert!(!settings.parallel_compilation);
+        assert!(matches!(settings.cranelift_opt_level, Some(wasmtime::OptLevel::None)));
+        assert_eq!(vec![
+            ("enable_simd".to_owned(), "false".to_owned()),
+            ("has_lse".to_owned(), "false".to_owned()),
+        ], settings.cranelift_flags);
+        assert_eq!(Some("256Mi".to_owned()), settings.cache_size_limit);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--cranelift-flag", "enable_simd"]);
+        parse_configuration_from(matches).expect_err("a Cranelift setting needs a value");
+    }
+
+    #[test]
+    fn test_health_check_settings() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--health-check-path", "/status", "--health-check-body", "healthy"]);
+        let health_check = parse_configuration_from(matches).unwrap().health_check.expect("health check should be served");
+        assert_eq!("/status", health_check.path);
+        assert_eq!("healthy", health_check.body);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--no-health-check"]);
+        assert!(parse_configuration_from(matches).unwrap().health_check.is_none());
+
+        let result = wagi_app_definition()
+            .get_matches_from_safe(vec!["wagi", "-c", "examples/modules.toml", "--no-health-check", "--health-check-path", "/status"]);
+        assert!(result.is_err(), "--no-health-check should conflict with the other health check options");
+    }
+
+    #[test]
+    fn test_fetch_retry_settings() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--fetch-attempts", "5", "--fetch-retry-delay", "2"]);
+        let configuration = parse_configuration_from(matches).unwrap();
+        assert_eq!(FetchRetryPolicy { attempts: 5, first_delay: Duration::from_secs(2) }, configuration.fetch_retry);
+        assert!(!configuration.retry_fetch_in_background);
+        assert_eq!(MissingParcelPolicy::Fail, configuration.missing_parcel);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--fetch-attempts", "0"]);
+        parse_configuration_from(matches).expect_err("at least one attempt is needed");
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--fetch-retry-delay", "1e20"]);
+        parse_configuration_from(matches).expect_err("a delay too long to hold should fail");
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--retry-fetch-in-background", "--harden"]);
+        parse_configuration_from(matches).expect_err("hardening would stop the background fetches");
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--missing-parcel", "skip"]);
+        assert_eq!(MissingParcelPolicy::Skip, parse_configuration_from(matches).unwrap().missing_parcel);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--on-compile-error", "skip"]);
+        assert_eq!(CompileErrorPolicy::Skip, parse_configuration_from(matches).unwrap().on_compile_error);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--missing-parcel", "retry", "--harden"]);
+        parse_configuration_from(matches).expect_err("hardening would stop the background fetches");
+    }
+
+    #[test]
+    fn tooling_subcommands_do_not_need_modules() {
+        let matches = wagi_app_definition().get_matches_from(vec!["wagi", "config", "print-defaults"]);
+        assert!(matches!(parse_tooling_command(&matches), Some(WagiCommand::PrintDefaults)));
+        let matches = wagi_app_definition().get_matches_from(vec!["wagi", "completions", "zsh"]);
+        assert!(matches!(parse_tooling_command(&matches), Some(WagiCommand::Completions(Shell::Zsh))));
+        wagi_app_definition()
+            .get_matches_from_safe(vec!["wagi", "--listen", "127.0.0.1:3001"])
+            .expect_err("serving needs a modules file or bindle");
+    }
+
+    #[test]
+    fn test_tenant_settings() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let log_dir = tempfile::tempdir().unwrap();
+        let matches = wagi_app_definition()
+            .get_matches_from(vec![
+                "wagi", "-c", "examples/modules.toml",
+                "--module-cache", &cache_dir.path().to_string_lossy(),
+                "--log-dir", &log_dir.path().to_string_lossy(),
+                "--tenant", "acme", "--tenant-cache-quota", "1Gi",
+            ]);
+        let configuration = parse_configuration_from(matches).unwrap();
+        assert_eq!(cache_dir.path().join("tenants").join("acme"), configuration.asset_cache_dir);
+        assert_eq!(log_dir.path().join("tenants").join("acme"), configuration.log_dir);
+        let tenant = configuration.tenant.expect("tenant should be set");
+        assert_eq!(Some(1 << 30), tenant.cache_quota);
+        assert_eq!(None, tenant.log_quota);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "--tenant", "../other"]);
+        parse_configuration_from(matches).expect_err("tenant ID must not escape the tenants directory");
+    }
+
+    #[test]
+    fn test_bench_settings() {
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "bench", "--route", "/hello?x=1", "-n", "50"]);
+        let bench = parse_configuration_from(matches).expect("bench should parse").bench.expect("bench should be set");
+        assert_eq!("/hello?x=1", bench.route);
+        assert_eq!(50, bench.requests);
+        assert_eq!(10, bench.concurrency);
+
+        let matches = wagi_app_definition()
+            .get_matches_from(vec!["wagi", "-c", "examples/modules.toml", "bench", "--concurrency", "0"]);
+        parse_configuration_from(matches).expect_err("zero concurrency should fail");
+    }
+
     #[tokio::test]
     async fn test_env_var_merge() {
         // Make sure that env vars are correctly merged together.
@@ -1,9 +1,34 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::{
+    access_control::IpNetwork,
+    admin_access::AdminAccess,
+    audit::{AuditLog, AUDIT_LOG_FILE},
+    bench::BenchSettings,
     bindle_util::BindleConnectionInfo,
-    handler_loader::WasmCompilationSettings,
+    circuit_breaker::CircuitBreakerSettings,
+    connection_limits::ConnectionSettings,
+    custom_handler::{CustomHandler, CustomHandlers},
+    diagnostics::InFlightRequests,
+    handler_loader::{Cache, CompileErrorPolicy, FetchRetryPolicy, LocalDirCache, MissingParcelPolicy, RegistryCredentials, SchemeResolver, SchemeResolvers, WasmCompilationSettings},
+    header_limits::HeaderLimits,
+    health_check::HealthCheckSettings,
+    log_level::{LogLevel, DEFAULT_VERBOSE_LOG_FILTER},
+    maintenance::{Maintenance, MaintenanceSettings},
+    memory_stats::MemoryStats,
+    metrics::MetricsRegistry,
+    outbound_http::TraceHeaders,
+    outbound_network::OutboundNetwork,
+    recycle::RecycleSettings,
     request::RequestGlobalContext,
+    route_toggle::RouteToggles,
+    sampling::{RequestSampler, SamplingSettings},
+    scheduler::TaskStatusTable,
+    spill::SpillSettings,
+    tenant::{LogQuota, TenantSettings},
+    url_limits::UrlLimits,
+    tls,
+    wasm_module::EngineSettings,
 };
 
 // TODO: figure out how to re-apply the Debug trait here (and on HandlerConfigurationSource)
@@ -15,15 +40,136 @@ pub struct WagiConfiguration {
     pub env_vars: HashMap<String, String>,
     pub http_configuration: HttpConfiguration,
     pub wasm_cache_config_file: PathBuf,
+    pub engine_settings: EngineSettings,
+    /// Where compiled modules are kept across restarts, if anywhere.
+    pub compiled_module_cache: Option<PathBuf>,
+    /// How long a route's compiled module is kept after it was last used.
+    pub module_idle_ttl: Option<Duration>,
+    /// How often modules are asked for their routes again.
+    pub dynamic_routes_refresh_interval: Option<Duration>,
     pub asset_cache_dir: PathBuf,
+    /// Whether other processes use the asset cache directory at the same time.
+    pub shared_module_cache: bool,
+    /// Whether to compress modules in the asset cache directory.
+    pub compress_module_cache: bool,
+    /// Credentials for pulling `oci:` modules.
+    pub registry_credentials: RegistryCredentials,
+    pub fetch_retry: FetchRetryPolicy,
+    /// Whether to serve while modules that could not be fetched are fetched again.
+    pub retry_fetch_in_background: bool,
+    /// What to do when a parcel that a bindle handler needs can't be fetched.
+    pub missing_parcel: MissingParcelPolicy,
+    /// The invoice annotations passed to a bindle's modules and shown at the version route.
+    pub bindle_annotations: Vec<String>,
+    /// What to do when a module can't be compiled.
+    pub on_compile_error: CompileErrorPolicy,
     pub log_dir: PathBuf,
+    /// Where audited routes' requests are recorded.
+    pub audit_log: PathBuf,
+    /// The control for the log filter, if WAGI installed the tracing subscriber.
+    pub log_level: Option<LogLevel>,
+    /// The log filter that `SIGUSR1` switches to.
+    pub verbose_log_filter: String,
+    /// The tenant this instance serves. The cache and log directories above are
+    /// already the tenant's own.
+    pub tenant: Option<TenantSettings>,
+    pub circuit_breaker: Option<CircuitBreakerSettings>,
+    pub default_content_type: Option<String>,
+    pub default_charset: Option<String>,
+    pub watch: bool,
+    pub harden: bool,
+    /// If set, requests to the admin routes must carry this as a bearer token.
+    pub admin_token: Option<String>,
+    pub debug_errors: bool,
+    /// Whether to record each run's stdout and stderr, interleaved, in its log directory.
+    pub interleave_output: bool,
+    /// Which requests to record in full in the samples directory of the log directory.
+    pub sampling: Option<SamplingSettings>,
+    /// If set, large response bodies go to files rather than staying in memory.
+    pub spill: Option<SpillSettings>,
+    /// Where each request's copy of the volumes of modules with `volume_overlay` goes.
+    pub overlay_dir: PathBuf,
+    pub allow_missing_volumes: bool,
+    pub response_header_timeout: Option<Duration>,
+    pub module_timeout: Option<Duration>,
+    pub trace_headers: TraceHeaders,
+    /// The client for modules' outbound HTTP requests, which applies the DNS and
+    /// address settings.
+    pub outbound_network: OutboundNetwork,
+    /// If set, no module gets the outbound HTTP functions, whatever its `allowed_hosts`.
+    pub deny_outbound_http: bool,
+    /// Whether to check at startup that modules' allowed hosts can be reached.
+    pub check_allowed_hosts: bool,
+    /// What is done with request header values that are too long or hold control characters.
+    pub header_limits: HeaderLimits,
+    /// The limits on request URLs, and the page for URLs over them.
+    pub url_limits: UrlLimits,
+    /// How long to keep answering 503 to new connections after a shutdown signal.
+    pub drain_period: Duration,
+    /// If set, WAGI shuts down after handling so many requests or running so long.
+    pub recycle: Option<RecycleSettings>,
+    /// The inbuilt health check route, if it is served.
+    pub health_check: Option<HealthCheckSettings>,
+    /// The maintenance page, and whether to start in maintenance.
+    pub maintenance: MaintenanceSettings,
+    /// If set, WAGI runs a load test against the loaded modules instead of serving.
+    pub bench: Option<BenchSettings>,
+    /// Native handlers registered by the program embedding WAGI.
+    pub custom_handlers: CustomHandlers,
+    /// How modules are fetched for each scheme of module reference, including any
+    /// schemes registered by the program embedding WAGI.
+    pub scheme_resolvers: SchemeResolvers,
 }
 
+pub const DEFAULT_LISTEN_ON: &str = "127.0.0.1:3000";
+pub const DEFAULT_HOSTNAME: &str = "localhost:3000";
+pub const DEFAULT_WASM_CACHE_CONFIG_FILE: &str = "cache.toml";
+pub const DEFAULT_TLS_SESSION_CACHE_SIZE: usize = 256;
+
 #[derive(Clone)]
 pub enum HandlerConfigurationSource {
     ModuleConfigFile(PathBuf),
+    ModuleDir(PathBuf),
     StandaloneBindle(PathBuf, bindle::Id),
     RemoteBindle(BindleConnectionInfo, bindle::Id),
+    InMemory(Vec<InMemoryModule>),
+}
+
+/// A module supplied directly by an embedder or test, rather than loaded from a
+/// file, registry or bindle.
+#[derive(Clone, Debug)]
+pub struct InMemoryModule {
+    pub route: String,
+    /// Identifies the module in logs and at the version route.
+    pub name: String,
+    /// The module as WebAssembly binary or text.
+    pub content: Arc<Vec<u8>>,
+    pub entrypoint: Option<String>,
+    /// Whether the module is exempt from the global timeouts and request header limits.
+    pub trusted: bool,
+}
+
+impl InMemoryModule {
+    pub fn new(route: impl Into<String>, name: impl Into<String>, content: impl Into<Vec<u8>>) -> Self {
+        Self {
+            route: route.into(),
+            name: name.into(),
+            content: Arc::new(content.into()),
+            entrypoint: None,
+            trusted: false,
+        }
+    }
+
+    pub fn with_entrypoint(mut self, entrypoint: impl Into<String>) -> Self {
+        self.entrypoint = Some(entrypoint.into());
+        self
+    }
+
+    /// Exempts the module from the global timeouts and request header limits.
+    pub fn trusted(mut self) -> Self {
+        self.trusted = true;
+        self
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -31,27 +177,507 @@ pub struct HttpConfiguration {
     pub listen_on: SocketAddr,
     pub default_hostname: String,
     pub tls: Option<TlsConfiguration>,
+    /// Proxies whose `X-Forwarded-Host` and `X-Forwarded-Proto` headers are believed.
+    pub trusted_proxies: Vec<IpNetwork>,
+    pub connections: ConnectionSettings,
 }
 
 #[derive(Clone, Debug)]
 pub struct TlsConfiguration {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
+    /// How many sessions to keep for resumption by session ID. 0 turns this off.
+    pub session_cache_size: usize,
+    /// Whether to give clients session tickets to resume with.
+    pub session_tickets: bool,
+    /// How long a session ticket key is used before it is replaced.
+    pub ticket_rotation: Duration,
+    /// The TLS versions to accept.
+    pub versions: Vec<tokio_rustls::rustls::ProtocolVersion>,
+    /// The cipher suites to accept.
+    pub cipher_suites: Vec<tokio_rustls::rustls::CipherSuite>,
+    /// A DER-encoded OCSP response for the certificate, to staple to handshakes.
+    pub ocsp_response_path: Option<PathBuf>,
+    /// Where to listen for plain HTTP requests, to redirect them to HTTPS.
+    pub http_redirect_listen_on: Option<SocketAddr>,
+}
+
+impl TlsConfiguration {
+    /// Serves the certificate and key in the given PEM files, with the same
+    /// defaults as the command line for everything else.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            session_cache_size: DEFAULT_TLS_SESSION_CACHE_SIZE,
+            session_tickets: true,
+            ticket_rotation: tls::MAX_TICKET_ROTATION,
+            versions: tls::TLS_VERSIONS.to_vec(),
+            cipher_suites: tls::all_cipher_suites(),
+            ocsp_response_path: None,
+            http_redirect_listen_on: None,
+        }
+    }
+
+    /// Checks that the files exist and that the settings can be served.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.cert_path.is_file() {
+            anyhow::bail!("TLS certificate file does not exist or is not a file");
+        }
+        if !self.key_path.is_file() {
+            anyhow::bail!("TLS key file does not exist or is not a file");
+        }
+        if self.ticket_rotation.is_zero() || self.ticket_rotation > tls::MAX_TICKET_ROTATION {
+            anyhow::bail!("TLS ticket rotation must be from 1 to {} seconds", tls::MAX_TICKET_ROTATION.as_secs());
+        }
+        if self.versions.is_empty() {
+            anyhow::bail!("At least one TLS version must be accepted");
+        }
+        tls::check_cipher_suites(&self.versions, &self.cipher_suites)?;
+        if let Some(path) = &self.ocsp_response_path {
+            if !path.is_file() {
+                anyhow::bail!("TLS OCSP response file does not exist or is not a file");
+            }
+        }
+        Ok(())
+    }
 }
 
 impl WagiConfiguration {
+    /// A configuration with the same defaults as the command line, for serving the
+    /// given handlers. The module cache and log directories are new temporary
+    /// directories, which are not removed afterwards.
+    pub fn new(handlers: HandlerConfigurationSource) -> anyhow::Result<Self> {
+        Self::with_dirs(handlers, tempfile::tempdir()?.into_path(), tempfile::tempdir()?.into_path())
+    }
+
+    fn with_dirs(handlers: HandlerConfigurationSource, log_dir: PathBuf, asset_cache_dir: PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            handlers,
+            env_vars: HashMap::new(),
+            http_configuration: HttpConfiguration {
+                listen_on: DEFAULT_LISTEN_ON.parse()?,
+                default_hostname: DEFAULT_HOSTNAME.to_owned(),
+                tls: None,
+                trusted_proxies: vec![],
+                connections: ConnectionSettings::default(),
+            },
+            wasm_cache_config_file: PathBuf::from(DEFAULT_WASM_CACHE_CONFIG_FILE),
+            engine_settings: EngineSettings::default(),
+            compiled_module_cache: None,
+            module_idle_ttl: None,
+            dynamic_routes_refresh_interval: None,
+            asset_cache_dir,
+            shared_module_cache: false,
+            compress_module_cache: false,
+            registry_credentials: RegistryCredentials::load(None, true)?,
+            fetch_retry: FetchRetryPolicy::default(),
+            retry_fetch_in_background: false,
+            missing_parcel: MissingParcelPolicy::default(),
+            bindle_annotations: vec![],
+            on_compile_error: CompileErrorPolicy::default(),
+            audit_log: log_dir.join(AUDIT_LOG_FILE),
+            log_dir,
+            log_level: None,
+            verbose_log_filter: DEFAULT_VERBOSE_LOG_FILTER.to_owned(),
+            tenant: None,
+            circuit_breaker: None,
+            default_content_type: None,
+            default_charset: None,
+            watch: false,
+            harden: false,
+            admin_token: None,
+            debug_errors: false,
+            interleave_output: false,
+            sampling: None,
+            spill: None,
+            overlay_dir: std::env::temp_dir(),
+            allow_missing_volumes: false,
+            bench: None,
+            response_header_timeout: None,
+            module_timeout: None,
+            trace_headers: TraceHeaders::default(),
+            outbound_network: OutboundNetwork::default(),
+            deny_outbound_http: false,
+            check_allowed_hosts: false,
+            header_limits: HeaderLimits::default(),
+            url_limits: UrlLimits::default(),
+            drain_period: Duration::ZERO,
+            recycle: None,
+            health_check: Some(HealthCheckSettings::default()),
+            maintenance: MaintenanceSettings::default(),
+            custom_handlers: CustomHandlers::default(),
+            scheme_resolvers: SchemeResolvers::default(),
+        })
+    }
+
+    /// Starts a configuration for a program embedding WAGI. See
+    /// [`WagiConfigurationBuilder`].
+    pub fn builder() -> WagiConfigurationBuilder {
+        WagiConfigurationBuilder::default()
+    }
+
+    /// Serves the route with a native handler as well as the configured modules.
+    pub fn with_custom_handler(mut self, route: impl Into<String>, handler: impl CustomHandler + 'static) -> Self {
+        self.custom_handlers.add(route, handler);
+        self
+    }
+
+    /// Fetches modules whose references have the schemes the resolver handles.
+    pub fn with_scheme_resolver(mut self, resolver: impl SchemeResolver + 'static) -> Self {
+        self.scheme_resolvers.add(resolver);
+        self
+    }
+
     pub fn request_global_context(&self) -> RequestGlobalContext {
         RequestGlobalContext {
             base_log_dir: self.log_dir.clone(),
             default_host: self.http_configuration.default_hostname.to_owned(),
             use_tls: self.http_configuration.tls.is_some(),
+            trusted_proxies: self.http_configuration.trusted_proxies.clone(),
+            admin_access: AdminAccess::new(self.admin_token.clone(), self.http_configuration.trusted_proxies.clone()),
             global_env_vars: self.env_vars.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            metrics: MetricsRegistry::default(),
+            default_content_type: self.default_content_type.clone(),
+            default_charset: self.default_charset.clone(),
+            response_header_timeout: self.response_header_timeout,
+            module_timeout: self.module_timeout,
+            trace_headers: self.trace_headers,
+            outbound_network: self.outbound_network.clone(),
+            deny_outbound_http: self.deny_outbound_http,
+            header_limits: self.header_limits.clone(),
+            url_limits: self.url_limits.clone(),
+            custom_handlers: self.custom_handlers.clone(),
+            debug_errors: self.debug_errors,
+            interleave_output: self.interleave_output,
+            sampler: self.sampling.clone().map(|s| RequestSampler::new(s, &self.log_dir)),
+            spill: self.spill.clone(),
+            overlay_dir: self.overlay_dir.clone(),
+            allow_missing_volumes: self.allow_missing_volumes,
+            in_flight: InFlightRequests::default(),
+            task_status: TaskStatusTable::default(),
+            memory_stats: MemoryStats::default(),
+            route_toggles: RouteToggles::default(),
+            maintenance: Maintenance::new(&self.maintenance),
+            health_check: self.health_check.clone(),
+            log_quota: self.tenant.as_ref().and_then(|t| t.log_quota).map(LogQuota::new),
+            audit_log: AuditLog::new(&self.audit_log),
+            log_level: self.log_level.clone(),
+        }
+    }
+
+    pub fn module_cache(&self) -> Cache {
+        let mut backend = LocalDirCache::new(&self.asset_cache_dir);
+        if self.shared_module_cache {
+            backend = backend.shared();
+        }
+        if self.compress_module_cache {
+            backend = backend.compressed();
         }
+        if let Some(quota) = self.tenant.as_ref().and_then(|t| t.cache_quota) {
+            backend = backend.with_quota(quota);
+        }
+        Cache::new(backend)
     }
 
     pub fn wasm_compilation_settings(&self) -> WasmCompilationSettings {
         WasmCompilationSettings {
             cache_config_path: self.wasm_cache_config_file.clone(),
+            engine: self.engine_settings.clone(),
+            evict_idle_modules: self.module_idle_ttl.is_some(),
+            compiled_module_cache: self.compiled_module_cache.clone(),
+            on_compile_error: self.on_compile_error,
+        }
+    }
+}
+
+/// Builds a [`WagiConfiguration`] in code rather than from the command line.
+///
+/// Anything not set gets the same default as the command line, except that the
+/// module cache and log directories default to new temporary directories. The
+/// settings are checked when the configuration is built, as the command line's are
+/// when it is parsed:
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let configuration = wagi::wagi_config::WagiConfiguration::builder()
+///     .modules_config_file("modules.toml")
+///     .listen_on("0.0.0.0:8443".parse()?)
+///     .env_var("GREETING", "hello")
+///     .tls(wagi::wagi_config::TlsConfiguration::new("cert.pem", "key.pem"))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Settings without a builder method can be changed on the built configuration.
+///
+/// As on the command line, if no log or asset cache directory is given, `build`
+/// creates a temporary one, which is left in place afterwards so that the logs can
+/// still be read. Each call creates new ones, so a program that builds many
+/// configurations should give its own directories.
+#[derive(Default)]
+pub struct WagiConfigurationBuilder {
+    modules: Option<ModuleSource>,
+    bindle_insecure: bool,
+    bindle_credentials: Option<(String, String)>,
+    env_vars: HashMap<String, String>,
+    listen_on: Option<SocketAddr>,
+    default_hostname: Option<String>,
+    tls: Option<TlsConfiguration>,
+    trusted_proxies: Vec<IpNetwork>,
+    log_dir: Option<PathBuf>,
+    asset_cache_dir: Option<PathBuf>,
+    custom_handlers: CustomHandlers,
+    scheme_resolvers: SchemeResolvers,
+}
+
+// Kept as given until the configuration is built, so that all the checking
+// happens in one place
+enum ModuleSource {
+    ConfigFile(PathBuf),
+    Dir(PathBuf),
+    StandaloneBindle(PathBuf, String),
+    RemoteBindle(String, String),
+    InMemory(Vec<InMemoryModule>),
+}
+
+impl WagiConfigurationBuilder {
+    /// Serves the modules listed in a `modules.toml` file. Each way of giving the
+    /// modules replaces any given before.
+    pub fn modules_config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.modules = Some(ModuleSource::ConfigFile(path.into()));
+        self
+    }
+
+    /// Serves each `.wasm` file in a directory at a route named after it.
+    pub fn modules_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.modules = Some(ModuleSource::Dir(path.into()));
+        self
+    }
+
+    /// Serves a bindle from a standalone bindle directory.
+    pub fn standalone_bindle(mut self, bindle_dir: impl Into<PathBuf>, bindle_id: impl Into<String>) -> Self {
+        self.modules = Some(ModuleSource::StandaloneBindle(bindle_dir.into(), bindle_id.into()));
+        self
+    }
+
+    /// Serves a bindle from a Bindle server.
+    pub fn remote_bindle(mut self, bindle_url: impl Into<String>, bindle_id: impl Into<String>) -> Self {
+        self.modules = Some(ModuleSource::RemoteBindle(bindle_url.into(), bindle_id.into()));
+        self
+    }
+
+    /// Connects to the Bindle server with HTTP basic authentication.
+    pub fn bindle_credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.bindle_credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Skips checking the Bindle server's TLS certificate.
+    pub fn bindle_insecure(mut self, insecure: bool) -> Self {
+        self.bindle_insecure = insecure;
+        self
+    }
+
+    /// Serves modules supplied by the program.
+    pub fn in_memory_modules(mut self, modules: Vec<InMemoryModule>) -> Self {
+        self.modules = Some(ModuleSource::InMemory(modules));
+        self
+    }
+
+    /// Sets an environment variable for every module.
+    pub fn env_var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env_vars.insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets environment variables for every module.
+    pub fn env_vars<K: Into<String>, V: Into<String>>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self {
+        self.env_vars.extend(vars.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    pub fn listen_on(mut self, addr: SocketAddr) -> Self {
+        self.listen_on = Some(addr);
+        self
+    }
+
+    /// The host name modules see when requests don't have a `Host` header.
+    pub fn default_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.default_hostname = Some(hostname.into());
+        self
+    }
+
+    /// Serves HTTPS instead of HTTP.
+    pub fn tls(mut self, tls: TlsConfiguration) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Believes `X-Forwarded-Host` and `X-Forwarded-Proto` from these proxies.
+    pub fn trusted_proxies(mut self, proxies: Vec<IpNetwork>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    pub fn log_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.log_dir = Some(dir.into());
+        self
+    }
+
+    /// Where fetched modules and assets are cached.
+    pub fn asset_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.asset_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Serves the route with a native handler as well as the modules.
+    pub fn custom_handler(mut self, route: impl Into<String>, handler: impl CustomHandler + 'static) -> Self {
+        self.custom_handlers.add(route, handler);
+        self
+    }
+
+    /// Fetches modules whose references have the schemes the resolver handles.
+    pub fn scheme_resolver(mut self, resolver: impl SchemeResolver + 'static) -> Self {
+        self.scheme_resolvers.add(resolver);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<WagiConfiguration> {
+        let handlers = match self.modules {
+            None => anyhow::bail!("No modules to serve: set a modules file, a modules directory, a bindle or in-memory modules"),
+            Some(ModuleSource::ConfigFile(path)) => {
+                if !path.is_file() {
+                    anyhow::bail!("Module file {} does not exist or is not a file", path.display());
+                }
+                HandlerConfigurationSource::ModuleConfigFile(path)
+            }
+            Some(ModuleSource::Dir(path)) => {
+                if !path.is_dir() {
+                    anyhow::bail!("Module directory {} does not exist or is not a directory", path.display());
+                }
+                HandlerConfigurationSource::ModuleDir(path)
+            }
+            Some(ModuleSource::StandaloneBindle(dir, id)) => {
+                if !dir.is_dir() {
+                    anyhow::bail!("Bindle directory {} does not exist or is not a directory", dir.display());
+                }
+                HandlerConfigurationSource::StandaloneBindle(dir, bindle::Id::try_from(id.as_str())?)
+            }
+            Some(ModuleSource::RemoteBindle(url, id)) => {
+                let url = url::Url::parse(&url).map_err(|e| anyhow::anyhow!("Invalid Bindle server URL: {}", e))?;
+                let (username, password) = match self.bindle_credentials {
+                    Some((username, password)) => (Some(username), Some(password)),
+                    None => (None, None),
+                };
+                HandlerConfigurationSource::RemoteBindle(
+                    BindleConnectionInfo::new(url, self.bindle_insecure, username, password),
+                    bindle::Id::try_from(id.as_str())?,
+                )
+            }
+            Some(ModuleSource::InMemory(modules)) => HandlerConfigurationSource::InMemory(modules),
+        };
+        if let Some(key) = self.env_vars.keys().find(|k| k.is_empty() || k.contains('=')) {
+            anyhow::bail!("Invalid environment variable name '{}'", key);
+        }
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+        }
+
+        let log_dir = match self.log_dir {
+            Some(dir) => dir,
+            None => tempfile::tempdir()?.into_path(),
+        };
+        let asset_cache_dir = match self.asset_cache_dir {
+            Some(dir) => dir,
+            None => tempfile::tempdir()?.into_path(),
+        };
+        let mut configuration = WagiConfiguration::with_dirs(handlers, log_dir, asset_cache_dir)?;
+        configuration.env_vars = self.env_vars;
+        configuration.http_configuration = HttpConfiguration {
+            listen_on: self.listen_on.unwrap_or(configuration.http_configuration.listen_on),
+            default_hostname: self.default_hostname.unwrap_or(configuration.http_configuration.default_hostname),
+            tls: self.tls,
+            trusted_proxies: self.trusted_proxies,
+            connections: configuration.http_configuration.connections,
+        };
+        configuration.custom_handlers = self.custom_handlers;
+        configuration.scheme_resolvers = self.scheme_resolvers;
+        Ok(configuration)
+    }
+}
+
+/// The longest time, in seconds, that a setting may give: 100 years. `Duration`
+/// can't hold 2^64 seconds at all, and much less than that can't be added to the
+/// current time.
+pub const MAX_DURATION_SECS: f64 = 100.0 * 365.0 * 24.0 * 60.0 * 60.0;
+
+/// Converts a (possibly fractional) number of seconds, if it is zero or more and no
+/// more than `MAX_DURATION_SECS`.
+pub fn duration_from_secs(secs: f64) -> Option<Duration> {
+    if secs.is_finite() && (0.0..=MAX_DURATION_SECS).contains(&secs) {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Converts a timeout given in (possibly fractional) seconds, rejecting values that
+/// are not positive or are longer than `MAX_DURATION_SECS`.
+pub fn timeout_from_secs(secs: f64) -> anyhow::Result<Duration> {
+    match duration_from_secs(secs) {
+        Some(timeout) if secs > 0.0 => Ok(timeout),
+        _ => anyhow::bail!("Timeout must be a positive number of seconds, up to 100 years, got {}", secs),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builder_uses_command_line_defaults() {
+        let configuration = WagiConfiguration::builder()
+            .in_memory_modules(vec![InMemoryModule::new("/", "empty", "(module)")])
+            .env_var("GREETING", "hello")
+            .build()
+            .unwrap();
+        assert_eq!(DEFAULT_LISTEN_ON.parse::<SocketAddr>().unwrap(), configuration.http_configuration.listen_on);
+        assert_eq!(DEFAULT_HOSTNAME, configuration.http_configuration.default_hostname);
+        assert!(configuration.http_configuration.tls.is_none());
+        assert_eq!("hello", configuration.env_vars["GREETING"]);
+        assert_eq!(configuration.log_dir.join(AUDIT_LOG_FILE), configuration.audit_log);
+    }
+
+    #[test]
+    fn durations_are_limited() {
+        assert_eq!(Duration::from_millis(1500), timeout_from_secs(1.5).unwrap());
+        assert_eq!(Some(Duration::ZERO), duration_from_secs(0.0));
+        for secs in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e20, MAX_DURATION_SECS * 2.0] {
+            timeout_from_secs(secs).expect_err(&secs.to_string());
         }
+        assert_eq!(None, duration_from_secs(1e20));
+        assert_eq!(None, duration_from_secs(-0.5));
+    }
+
+    #[test]
+    fn builder_checks_settings() {
+        WagiConfiguration::builder().build().expect_err("there are no modules");
+        WagiConfiguration::builder()
+            .modules_config_file("no/such/modules.toml")
+            .build()
+            .expect_err("the modules file does not exist");
+        WagiConfiguration::builder()
+            .in_memory_modules(vec![])
+            .env_var("", "value")
+            .build()
+            .expect_err("the environment variable has no name");
+        WagiConfiguration::builder()
+            .in_memory_modules(vec![])
+            .tls(TlsConfiguration::new("no/such/cert.pem", "no/such/key.pem"))
+            .build()
+            .expect_err("the certificate does not exist");
     }
 }
@@ -1,30 +1,172 @@
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use crate::dispatcher::RoutingTable;
+use crate::connection_limits::{Connection, ConnectionSettings, LimitedIncoming};
+use crate::dispatcher::{LiveRoutingTable, RoutingTable};
+use crate::http_util::{https_redirect, internal_error, service_unavailable};
+use crate::recycle::Recycler;
 use crate::{tls, wagi_config::TlsConfiguration};
 use crate::wagi_config::WagiConfiguration;
 
 use hyper::{
-    server::conn::AddrStream,
+    server::conn::{AddrIncoming, AddrStream},
     service::{make_service_fn, service_fn},
 };
 use hyper::{Body, Response, Server};
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 
+// What clients are told while the server is starting or draining. Compiling modules
+// and load balancer health checks both take seconds, not minutes.
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Where the server is in its lifecycle. Requests are only handled when it is
+/// `Ready`; otherwise they get a 503 with a Retry-After header, so that clients
+/// and load balancers can tell "come back shortly" from "gone".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerState {
+    /// Listening, but modules are still being loaded.
+    Starting,
+    Ready,
+    /// Shutting down: waiting for requests in progress to finish.
+    Draining,
+}
+
+/// The server's state, shared with the tasks serving its connections.
+#[derive(Clone, Debug)]
+pub struct ServerStateHandle {
+    state: Arc<RwLock<ServerState>>,
+}
+
+impl ServerStateHandle {
+    fn new(state: ServerState) -> Self {
+        Self {
+            state: Arc::new(RwLock::new(state)),
+        }
+    }
+
+    pub fn get(&self) -> ServerState {
+        *self.state.read().unwrap()
+    }
+
+    fn set(&self, state: ServerState) {
+        *self.state.write().unwrap() = state;
+    }
+
+    // The response for a request the server isn't in a state to handle.
+    fn unavailable_response(&self) -> Option<Response<Body>> {
+        match self.get() {
+            ServerState::Ready => None,
+            ServerState::Starting => Some(service_unavailable(RETRY_AFTER)),
+            ServerState::Draining => {
+                let mut res = service_unavailable(RETRY_AFTER);
+                // Send the client's next request to another server
+                res.headers_mut().insert(hyper::header::CONNECTION, hyper::header::HeaderValue::from_static("close"));
+                Some(res)
+            }
+        }
+    }
+}
+
 pub struct WagiServer {
-    routing_table: RoutingTable,
+    routing_table: LiveRoutingTable,
     tls: Option<TlsConfiguration>,
     address: SocketAddr,
+    default_hostname: String,
+    state: ServerStateHandle,
+    drain_period: Duration,
+    recycler: Option<Recycler>,
+    connections: ConnectionSettings,
 }
 
 impl WagiServer {
     pub async fn new(configuration: &WagiConfiguration, routing_table: RoutingTable) -> anyhow::Result<Self> {
-        Ok(Self {
-            routing_table,
+        Ok(Self::with_state(configuration, routing_table, ServerState::Ready))
+    }
+
+    /// A server that can listen before its modules are loaded, answering 503
+    /// until `ready` is called.
+    pub fn starting(configuration: &WagiConfiguration) -> Self {
+        let placeholder = RoutingTable::empty(configuration.request_global_context());
+        Self::with_state(configuration, placeholder, ServerState::Starting)
+    }
+
+    fn with_state(configuration: &WagiConfiguration, routing_table: RoutingTable, state: ServerState) -> Self {
+        Self {
+            routing_table: LiveRoutingTable::new(routing_table),
             tls: configuration.http_configuration.tls.clone(),
             address: configuration.http_configuration.listen_on,
-        })
+            default_hostname: configuration.http_configuration.default_hostname.clone(),
+            state: ServerStateHandle::new(state),
+            drain_period: configuration.drain_period,
+            recycler: configuration.recycle.as_ref().map(Recycler::new),
+            connections: configuration.http_configuration.connections.clone(),
+        }
+    }
+
+    /// Starts handling requests with the given routes.
+    pub fn ready(&self, routing_table: RoutingTable) {
+        self.routing_table.replace(routing_table);
+        self.state.set(ServerState::Ready);
+    }
+
+    pub fn state(&self) -> ServerStateHandle {
+        self.state.clone()
+    }
+
+    // Completes when the server should stop accepting connections: after a shutdown
+    // signal, or the server's time to recycle, and the drain period. New requests get
+    // a 503 from the signal onwards.
+    async fn drain(&self) {
+        match &self.recycler {
+            Some(recycler) => tokio::select! {
+                _ = shutdown_signal() => (),
+                reason = recycler.wait() => println!("Recycling after {}: shutting down once requests in progress have finished", reason),
+            },
+            None => shutdown_signal().await,
+        }
+        crate::systemd::notify_stopping();
+        self.state.set(ServerState::Draining);
+        if !self.drain_period.is_zero() {
+            println!("Answering 503 for {:?} before closing the listener", self.drain_period);
+            tokio::time::sleep(self.drain_period).await;
+        }
+    }
+
+    // Listens for plain HTTP, redirecting every request to the same URL on the HTTPS
+    // port, until `stop` completes
+    fn start_https_redirects(&self, address: SocketAddr, stop: tokio::sync::oneshot::Receiver<()>) -> anyhow::Result<()> {
+        let https_port = self.address.port();
+        let default_hostname = self.default_hostname.clone();
+        let mk_svc = make_service_fn(move |_conn: &AddrStream| {
+            let default_hostname = default_hostname.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let (parts, _) = req.into_parts();
+                    let res = https_redirect(&parts, &default_hostname, https_port);
+                    async move { Ok::<_, std::convert::Infallible>(res) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&address)
+            .map_err(|e| anyhow::anyhow!("Could not listen for HTTP redirects on {}: {}", address, e))?
+            .serve(mk_svc)
+            .with_graceful_shutdown(async {
+                let _ = stop.await;
+            });
+        tracing::info!(%address, "Redirecting HTTP requests to HTTPS");
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!(error = %e, "HTTP redirect listener failed");
+            }
+        });
+        Ok(())
+    }
+
+    /// A handle through which the routing table can be replaced while serving.
+    pub fn routing_table(&self) -> LiveRoutingTable {
+        self.routing_table.clone()
     }
 
     pub async fn serve(&self) -> anyhow::Result<()> {
@@ -34,15 +176,21 @@ impl WagiServer {
         // either. This means these services are basically the same, but with different connection types
         match &self.tls {
             Some(tls) => {
-                let mk_svc = make_service_fn(move |conn: &TlsStream<TcpStream>| {
-                    let (inner, _) = conn.get_ref();
+                let mk_svc = make_service_fn(move |conn: &Connection<TlsStream<TcpStream>>| {
+                    let (inner, _) = conn.get_ref().get_ref();
                     // We are mapping the error because the normal error types are not cloneable and
                     // service functions do not like captured vars, even when moved
                     let addr_res = inner.peer_addr().map_err(|e| e.to_string());
                     let r = self.routing_table.clone();
+                    let state = self.state.clone();
+                    let recycler = self.recycler.clone();
+                    let requests = conn.requests();
                     Box::pin(async move {
                         Ok::<_, std::convert::Infallible>(service_fn(move |req| {
                             let r2 = r.clone();
+                            let state = state.clone();
+                            let recycler = recycler.clone();
+                            let requests = requests.clone();
                             // NOTE: There isn't much in the way of error handling we can do here as
                             // this function needs to return an infallible future. Based on the
                             // documentation of the underlying getpeername function
@@ -50,40 +198,139 @@ impl WagiServer {
                             // https://docs.microsoft.com/en-us/windows/win32/api/winsock/nf-winsock-getpeername)
                             // the only error that will probably occur here is an interrupted connection
                             let a_res = addr_res.clone();
-                            async move {
+                            requests.track(async move {
+                                if let Some(res) = state.unavailable_response() {
+                                    return Ok(res);
+                                }
+                                if let Some(recycler) = &recycler {
+                                    recycler.request_handled();
+                                }
                                 match a_res {
                                     Ok(addr) => r2.handle_request(req, addr).await,
                                     Err(e) => {
                                         tracing::error!(error = %e, "Socket connection error on new connection");
-                                        Ok(Response::builder()
-                                            .status(hyper::http::StatusCode::INTERNAL_SERVER_ERROR)
-                                            .body(Body::from("Socket connection error"))
-                                            .unwrap())
+                                        Ok(internal_error("Socket connection error"))
                                     }
                                 }
-                            }
+                            })
                         }))
                     })
                 });
-                Server::builder(tls::TlsHyperAcceptor::new(&self.address, &tls.cert_path, &tls.key_path).await?)
+                // The redirect listener stops when the HTTPS server does, including if it fails
+                let (_stop_redirects, redirects_stopped) = tokio::sync::oneshot::channel::<()>();
+                if let Some(redirect_address) = tls.http_redirect_listen_on {
+                    self.start_https_redirects(redirect_address, redirects_stopped)?;
+                }
+                let acceptor = tls::TlsHyperAcceptor::new(&self.address, tls, self.connections.tcp_nodelay).await?;
+                Server::builder(LimitedIncoming::new(acceptor, &self.connections))
+                    .http1_keepalive(self.connections.keep_alive())
                     .serve(mk_svc)
+                    .with_graceful_shutdown(self.drain())
                     .await?;
             },
             None => {
-                let mk_svc = make_service_fn(move |conn: &AddrStream| {
-                    let addr = conn.remote_addr();
+                let mk_svc = make_service_fn(move |conn: &Connection<AddrStream>| {
+                    let addr = conn.get_ref().remote_addr();
                     let r = self.routing_table.clone();
+                    let state = self.state.clone();
+                    let recycler = self.recycler.clone();
+                    let requests = conn.requests();
                     async move {
                         Ok::<_, std::convert::Infallible>(service_fn(move |req| {
                             let r2 = r.clone();
-                            async move { r2.handle_request(req, addr).await }
+                            let state = state.clone();
+                            let recycler = recycler.clone();
+                            requests.clone().track(async move {
+                                if let Some(res) = state.unavailable_response() {
+                                    return Ok(res);
+                                }
+                                if let Some(recycler) = &recycler {
+                                    recycler.request_handled();
+                                }
+                                r2.handle_request(req, addr).await
+                            })
                         }))
                     }
                 });
-                Server::bind(&self.address).serve(mk_svc).await?;
+                let mut incoming = AddrIncoming::bind(&self.address)
+                    .map_err(|e| anyhow::anyhow!("Could not listen on {}: {}", self.address, e))?;
+                incoming.set_nodelay(self.connections.tcp_nodelay);
+                Server::builder(LimitedIncoming::new(incoming, &self.connections))
+                    .http1_keepalive(self.connections.keep_alive())
+                    .serve(mk_svc)
+                    .with_graceful_shutdown(self.drain())
+                    .await?;
             },
         }
     
         Ok(())
     }
 }
+
+/// Completes when the process is asked to stop: Ctrl+C or SIGTERM on Unix, and
+/// Ctrl+C or Ctrl+Break on Windows. The server then stops accepting connections
+/// and waits for requests in progress to finish.
+async fn shutdown_signal() {
+    let signal_name = wait_for_shutdown_signal().await;
+    println!("Received {}: shutting down once requests in progress have finished", signal_name);
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = ctrl_c() => "Ctrl+C",
+            _ = terminate.recv() => "SIGTERM",
+        },
+        Err(e) => {
+            tracing::warn!(error = %e, "Can't listen for SIGTERM; only Ctrl+C will shut down the server");
+            ctrl_c().await
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn wait_for_shutdown_signal() -> &'static str {
+    match tokio::signal::windows::ctrl_break() {
+        Ok(mut ctrl_break) => tokio::select! {
+            _ = ctrl_c() => "Ctrl+C",
+            _ = ctrl_break.recv() => "Ctrl+Break",
+        },
+        Err(e) => {
+            tracing::warn!(error = %e, "Can't listen for Ctrl+Break; only Ctrl+C will shut down the server");
+            ctrl_c().await
+        }
+    }
+}
+
+async fn ctrl_c() -> &'static str {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        // Without a handler the default behaviour (terminating the process) still applies
+        tracing::warn!(error = %e, "Can't listen for Ctrl+C");
+        futures::future::pending::<()>().await;
+    }
+    "Ctrl+C"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_only_handled_when_ready() {
+        let state = ServerStateHandle::new(ServerState::Starting);
+        let res = state.unavailable_response().expect("starting servers should refuse requests");
+        assert_eq!(hyper::StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("5", res.headers()[hyper::header::RETRY_AFTER]);
+
+        state.set(ServerState::Ready);
+        assert!(state.unavailable_response().is_none());
+
+        state.set(ServerState::Draining);
+        let res = state.unavailable_response().expect("draining servers should refuse requests");
+        assert_eq!(hyper::StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("close", res.headers()[hyper::header::CONNECTION]);
+    }
+}
@@ -1,53 +1,375 @@
-use std::{fmt::Debug, sync::{Arc, RwLock}, path::Path};
+use std::{fmt::Debug, io::Write, sync::{Arc, Mutex, Once, RwLock}, path::{Path, PathBuf}, time::{Duration, Instant}};
 
-use wasi_common::pipe::{ReadPipe, WritePipe};
+use anyhow::Context;
+
+use wasi_common::pipe::ReadPipe;
 use wasmtime::*;
 
+/// How often the epoch of each engine advances. Running modules yield back to the
+/// server once per tick.
+pub const EPOCH_TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Settings for the Wasmtime engine, mostly for small devices such as arm64 or
+/// musl deployments where the defaults use too much memory or disk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EngineSettings {
+    /// Compile a module's functions on several threads at once.
+    pub parallel_compilation: bool,
+    pub cranelift_opt_level: Option<OptLevel>,
+    /// Cranelift settings, by name, as for `wasmtime --cranelift-set`.
+    pub cranelift_flags: Vec<(String, String)>,
+    /// A soft limit on the total size of the compiled module cache, such as `512Mi`.
+    pub cache_size_limit: Option<String>,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        Self {
+            parallel_compilation: true,
+            cranelift_opt_level: None,
+            cranelift_flags: vec![],
+            cache_size_limit: None,
+        }
+    }
+}
+
 // In future this might be pre-instantiated or something like that, so we will
 // just abstract it to be safe.
 #[derive(Clone)]
 pub enum WasmModuleSource {
     Compiled(Module, Engine),
+    /// A module that couldn't be fetched at startup, and is being fetched again
+    /// in the background.
+    Pending(PendingModule),
+    /// A module whose compiled code is dropped while it is not being used.
+    Evictable(EvictableModule),
 }
 
 impl Debug for WasmModuleSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Compiled(m, _) => f.write_fmt(format_args!("Compiled(Module={:?})", m.name())),
+            Self::Pending(p) => f.write_fmt(format_args!("Pending(Module={}, Loaded={})", p.name, p.is_loaded())),
+            Self::Evictable(e) => f.write_fmt(format_args!("Evictable(Compiled={})", e.is_compiled())),
+        }
+    }
+}
+
+/// The slot that a module fetched in the background is put into once it is
+/// compiled.
+#[derive(Clone)]
+pub struct PendingModule {
+    name: String,
+    compiled: Arc<RwLock<Option<(Module, Engine)>>>,
+}
+
+impl PendingModule {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            compiled: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.compiled.read().unwrap().is_some()
+    }
+
+    pub fn fill(&self, module: Module, engine: Engine) {
+        *self.compiled.write().unwrap() = Some((module, engine));
+    }
+
+    /// Whether nothing but the background fetch holds the slot any more.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.compiled) == 1
+    }
+}
+
+/// A compiled module that is dropped once it has been idle for a while, and
+/// compiled again from the module bytes the next time it is used. Recompiling
+/// is usually quick, because Wasmtime's compilation cache still has the code.
+#[derive(Clone)]
+pub struct EvictableModule {
+    bytes: Arc<Vec<u8>>,
+    engine: Engine,
+    state: Arc<Mutex<EvictableState>>,
+    // Held while the module is recompiled, so that requests that arrive meanwhile
+    // wait for it rather than compiling it again themselves
+    recompiling: Arc<tokio::sync::Mutex<()>>,
+}
+
+struct EvictableState {
+    compiled: Option<Module>,
+    last_used: Instant,
+}
+
+impl EvictableModule {
+    async fn compiled(&self) -> anyhow::Result<Module> {
+        if let Some(module) = self.touch() {
+            return Ok(module);
+        }
+        let _recompiling = self.recompiling.lock().await;
+        if let Some(module) = self.touch() {
+            return Ok(module);
+        }
+        // Compiling takes a while, so it is done off the threads serving requests,
+        // and without holding the state lock that eviction and status checks take
+        tracing::debug!("Recompiling evicted module");
+        let (engine, bytes) = (self.engine.clone(), self.bytes.clone());
+        let module = tokio::task::spawn_blocking(move || Module::new(&engine, &**bytes)).await??;
+        let mut state = self.state.lock().unwrap();
+        state.compiled = Some(module.clone());
+        state.last_used = Instant::now();
+        Ok(module)
+    }
+
+    // Marks the module as used, and returns it if it is compiled.
+    fn touch(&self) -> Option<Module> {
+        let mut state = self.state.lock().unwrap();
+        state.last_used = Instant::now();
+        state.compiled.clone()
+    }
+
+    pub fn is_compiled(&self) -> bool {
+        self.state.lock().unwrap().compiled.is_some()
+    }
+
+    /// Drops the compiled module if it has not been used for `idle_ttl`, and
+    /// returns whether it did. Instances already running keep their own reference.
+    fn evict_if_idle(&self, idle_ttl: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.compiled.is_some() && state.last_used.elapsed() >= idle_ttl {
+            state.compiled = None;
+            true
+        } else {
+            false
         }
     }
 }
 
 impl WasmModuleSource {
-    /// Create a new Wasm Engine and configure it.
-    fn new_engine(cache_config_path: &Path) -> anyhow::Result<Engine> {
+    /// A Wasm engine configured with these settings, whose epoch advances every
+    /// `EPOCH_TICK_INTERVAL`. Every load of the handlers with the same settings
+    /// shares one engine, so watch mode reloads don't add an engine each time.
+    pub fn new_engine(cache_config_path: &Path, settings: &EngineSettings) -> anyhow::Result<Engine> {
+        let mut engines = ENGINES.lock().unwrap();
+        let existing = engines
+            .iter()
+            .find(|e| e.cache_config_path == cache_config_path && e.settings == *settings);
+        if let Some(existing) = existing {
+            return Ok(existing.engine.clone());
+        }
+        let engine = Self::configure_engine(cache_config_path, settings)?;
+        engines.push(SharedEngine {
+            cache_config_path: cache_config_path.to_owned(),
+            settings: settings.clone(),
+            engine: engine.clone(),
+        });
+        EPOCH_TICKER.call_once(start_epoch_ticker);
+        Ok(engine)
+    }
+
+    fn configure_engine(cache_config_path: &Path, settings: &EngineSettings) -> anyhow::Result<Engine> {
         let mut config = Config::default();
 
         // Enable multi memory and module linking support.
         config.wasm_multi_memory(true);
         config.wasm_module_linking(true);
 
-        if let Ok(p) = std::fs::canonicalize(cache_config_path) {
-            config.cache_config_load(p)?;
-        };
+        // Modules run asynchronously and yield at every epoch tick, so a module
+        // that runs for a long time doesn't monopolise a server thread, and a
+        // module that overruns its deadline can be abandoned.
+        config.async_support(true);
+        config.epoch_interruption(true);
+
+        config.parallel_compilation(settings.parallel_compilation);
+        if let Some(level) = settings.cranelift_opt_level {
+            config.cranelift_opt_level(level);
+        }
+        for (name, value) in &settings.cranelift_flags {
+            // Cranelift settings are unsafe because some of them can produce code
+            // that misbehaves. Operators set them deliberately, knowing their platform.
+            unsafe { config.cranelift_flag_set(name, value) }
+                .with_context(|| format!("Invalid Cranelift setting {}={}", name, value))?;
+        }
+
+        match std::fs::canonicalize(cache_config_path) {
+            Ok(p) => load_cache_config(&mut config, &p, settings)?,
+            Err(_) if settings.cache_size_limit.is_some() => {
+                tracing::warn!(path = %cache_config_path.display(), "No Wasm cache configuration file, so the cache size limit has no effect");
+            }
+            Err(_) => (),
+        }
 
-        Engine::new(&config)
+        Ok(Engine::new(&config)?)
     }
 
     pub fn from_module_bytes(
         data: Arc<Vec<u8>>,
-        cache_config_path: &Path,
+        engine: &Engine,
+    ) -> anyhow::Result<WasmModuleSource> {
+        let module = wasmtime::Module::new(engine, &**data)?;
+        Ok(WasmModuleSource::Compiled(module, engine.clone()))
+    }
+
+    /// Compiles the module now, so that errors show up at startup, but allows
+    /// the compiled module to be evicted when it is idle.
+    pub fn evictable_from_module_bytes(
+        data: Arc<Vec<u8>>,
+        engine: &Engine,
     ) -> anyhow::Result<WasmModuleSource> {
-        let engine = Self::new_engine(cache_config_path)?;
-        let module = wasmtime::Module::new(&engine, &**data)?;
-        Ok(WasmModuleSource::Compiled(module, engine))
+        let module = wasmtime::Module::new(engine, &**data)?;
+        Ok(Self::evictable(data, module, engine))
+    }
+
+    /// An evictable module that has already been compiled from `data`.
+    pub fn evictable(data: Arc<Vec<u8>>, module: Module, engine: &Engine) -> WasmModuleSource {
+        WasmModuleSource::Evictable(EvictableModule {
+            bytes: data,
+            engine: engine.clone(),
+            state: Arc::new(Mutex::new(EvictableState {
+                compiled: Some(module),
+                last_used: Instant::now(),
+            })),
+            recompiling: Arc::new(tokio::sync::Mutex::new(())),
+        })
     }
 
-    pub fn get_compiled_module(&self) -> anyhow::Result<(Module, Engine)> {
+    /// The compiled module, and the engine it was compiled with. Wasmtime's `Module`
+    /// and `Engine` are reference-counted handles, so these share the compiled code
+    /// rather than copying it. An evicted module is recompiled first.
+    pub async fn get_compiled_module(&self) -> anyhow::Result<(Module, Engine)> {
         match self {
             Self::Compiled(m, e) => Ok((m.clone(), e.clone())),
+            Self::Pending(p) => p.compiled.read().unwrap().clone()
+                .ok_or_else(|| anyhow::anyhow!("Module {} has not been fetched yet", p.name)),
+            Self::Evictable(e) => Ok((e.compiled().await?, e.engine.clone())),
+        }
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        matches!(self, Self::Evictable(e) if !e.is_compiled())
+    }
+
+    /// Drops the compiled module if it can be evicted and has been idle for
+    /// `idle_ttl`. Returns whether it was dropped.
+    pub fn evict_if_idle(&self, idle_ttl: Duration) -> bool {
+        match self {
+            Self::Evictable(e) => e.evict_if_idle(idle_ttl),
+            _ => false,
+        }
+    }
+
+    /// Whether the module can be run yet.
+    pub fn is_loaded(&self) -> bool {
+        match self {
+            Self::Compiled(..) | Self::Evictable(_) => true,
+            Self::Pending(p) => p.is_loaded(),
+        }
+    }
+}
+
+// Wasmtime only reads cache settings from a file, so WAGI's settings are merged
+// into a copy of the operator's file.
+fn load_cache_config(config: &mut Config, path: &Path, settings: &EngineSettings) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading Wasm cache configuration {}", path.display()))?;
+    let effective_text = effective_cache_config(&text, settings)
+        .with_context(|| format!("Error in Wasm cache configuration {}", path.display()))?;
+    let mut effective = tempfile::NamedTempFile::new()?;
+    effective.write_all(effective_text.as_bytes())?;
+    config.cache_config_load(effective.path())?;
+    Ok(())
+}
+
+fn effective_cache_config(text: &str, settings: &EngineSettings) -> anyhow::Result<String> {
+    let mut cache_config: toml::Value = toml::from_str(text)?;
+    let cache = cache_config
+        .get_mut("cache")
+        .and_then(|c| c.as_table_mut())
+        .ok_or_else(|| anyhow::anyhow!("there is no [cache] section"))?;
+    // Compiled code only runs on the platform it was compiled for. The directory
+    // may be on a network filesystem shared with servers on other platforms.
+    if let Some(dir) = cache.get("directory").and_then(|d| d.as_str()) {
+        let per_target = Path::new(dir).join(target_key());
+        cache.insert("directory".to_owned(), per_target.display().to_string().into());
+    }
+    if let Some(limit) = &settings.cache_size_limit {
+        cache.insert("files-total-size-soft-limit".to_owned(), limit.clone().into());
+    }
+    Ok(toml::to_string(&cache_config)?)
+}
+
+/// Whether the module, in binary or text format, has a start function, which runs
+/// as soon as the module is instantiated. Modules that can't be parsed have none;
+/// compiling them reports the error.
+pub fn has_start_function(bytes: &[u8]) -> bool {
+    let binary = match wat::parse_bytes(bytes) {
+        Ok(binary) => binary,
+        Err(_) => return false,
+    };
+    // Each section after the magic number and version is an ID byte, then the
+    // section's size as a LEB128 number, then its contents
+    let mut rest = binary.get(8..).unwrap_or_default();
+    while let Some((&id, after_id)) = rest.split_first() {
+        if id == START_SECTION_ID {
+            return true;
+        }
+        let (size, contents) = match read_leb128_u32(after_id) {
+            Some(read) => read,
+            None => return false,
+        };
+        rest = contents.get(size as usize..).unwrap_or_default();
+    }
+    false
+}
+
+const START_SECTION_ID: u8 = 8;
+
+fn read_leb128_u32(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    let mut value = 0u32;
+    for (index, byte) in bytes.iter().enumerate().take(5) {
+        value |= u32::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[index + 1..]));
         }
     }
+    None
+}
+
+/// Identifies the platform compiled code is for, such as `aarch64-linux-musl`.
+pub fn target_key() -> String {
+    let env = if cfg!(target_env = "musl") {
+        "musl"
+    } else if cfg!(target_env = "gnu") {
+        "gnu"
+    } else if cfg!(target_env = "msvc") {
+        "msvc"
+    } else {
+        "none"
+    };
+    format!("{}-{}-{}", std::env::consts::ARCH, std::env::consts::OS, env)
+}
+
+// The engines made so far, and the settings they were made with. They are kept
+// for the life of the process, as there is normally only one.
+static ENGINES: Mutex<Vec<SharedEngine>> = Mutex::new(Vec::new());
+static EPOCH_TICKER: Once = Once::new();
+
+struct SharedEngine {
+    cache_config_path: PathBuf,
+    settings: EngineSettings,
+    engine: Engine,
+}
+
+// One thread, running for the life of the process, advances every engine's epoch.
+fn start_epoch_ticker() {
+    std::thread::spawn(|| loop {
+        std::thread::sleep(EPOCH_TICK_INTERVAL);
+        for shared in ENGINES.lock().unwrap().iter() {
+            shared.engine.increment_epoch();
+        }
+    });
 }
 
 // This is currently separated out because it has different ownership
@@ -56,11 +378,61 @@ impl WasmModuleSource {
 // because that is misleading about the semantics.)
 pub struct IOStreamRedirects {
     pub stdin: ReadPipe<std::io::Cursor<Vec<u8>>>,
-    pub stdout: WritePipe<Vec<u8>>,
-    pub stderr: wasi_cap_std_sync::file::File,
+    pub stdout: Box<dyn wasi_common::WasiFile>,
+    pub stderr: Box<dyn wasi_common::WasiFile>,
 }
 
 pub struct IORedirectionInfo {
     pub streams: IOStreamRedirects,
     pub stdout_mutex: Arc<RwLock<Vec<u8>>>,
+    /// The file the response body went to, if it was too large to keep in memory.
+    pub spilled: crate::spill::SpilledBody,
+    pub stderr_tail: crate::stderr::StderrTail,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cache_config_is_kept_per_target_and_size_limited() {
+        let settings = EngineSettings {
+            cache_size_limit: Some("512Mi".to_owned()),
+            ..EngineSettings::default()
+        };
+        let text = "[cache]\nenabled = true\ndirectory = \"/mnt/shared/wasm-cache\"\n";
+        let effective: toml::Value = toml::from_str(&effective_cache_config(text, &settings).unwrap()).unwrap();
+
+        let expected_dir = Path::new("/mnt/shared/wasm-cache").join(target_key());
+        assert_eq!(Some(expected_dir.display().to_string().as_str()), effective["cache"]["directory"].as_str());
+        assert_eq!(Some("512Mi"), effective["cache"]["files-total-size-soft-limit"].as_str());
+        assert_eq!(Some(true), effective["cache"]["enabled"].as_bool());
+
+        effective_cache_config("[other]\n", &settings).expect_err("a cache config needs a [cache] section");
+    }
+
+    #[test]
+    fn start_functions_are_found() {
+        assert!(has_start_function(br#"(module (func $init) (start $init))"#));
+        assert!(!has_start_function(br#"(module (func (export "_start")))"#));
+        // The start section's ID inside another section doesn't count
+        let binary = wat::parse_str(r#"(module (memory 1) (data (i32.const 0) "\08\08"))"#).unwrap();
+        assert!(!has_start_function(&binary));
+        assert!(!has_start_function(b"not a module"));
+    }
+
+    #[tokio::test]
+    async fn idle_modules_are_evicted_and_recompiled_on_use() {
+        let engine = Engine::default();
+        let wat = br#"(module (func (export "_start")))"#.to_vec();
+        let source = WasmModuleSource::evictable_from_module_bytes(Arc::new(wat), &engine).unwrap();
+
+        assert!(!source.evict_if_idle(Duration::from_secs(60)), "recently used modules should be kept");
+        assert!(source.evict_if_idle(Duration::ZERO));
+        assert!(!source.evict_if_idle(Duration::ZERO), "there should be nothing left to evict");
+
+        source.get_compiled_module().await.expect("evicted module should be recompiled");
+        assert!(!source.evict_if_idle(Duration::from_secs(60)), "recompiled module should count as used");
+        assert!(source.evict_if_idle(Duration::ZERO));
+    }
 }
@@ -1,20 +1,30 @@
+use std::collections::HashMap;
+use std::io::Write;
 use std::sync::{Arc, RwLock};
 
+use wasi_cap_std_sync::{Dir, WasiCtxBuilder};
 use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasi_common::WasiFile;
 use wasmtime::*;
 use wasmtime_wasi::*;
 
 use tracing::debug;
 
+use crate::interleave::{InterleavedLog, SharedBuffer, Stream};
+use crate::outbound_http::OutboundHttpSettings;
+use crate::outbound_network::OutboundNetwork;
 use crate::request::RequestGlobalContext;
+use crate::spill::{SpilledBody, SpillingWriter, SpillSettings};
+use crate::stderr::{StderrDestination, StderrTail};
 use crate::wasm_module::WasmModuleSource;
 
-const STDERR_FILE: &str = "module.stderr";
-
 #[derive(Clone, Default)]
 pub struct WasmLinkOptions {
     pub http_allowed_hosts: Option<Vec<String>>,
     pub http_max_concurrency: Option<u32>,
+    pub outbound_network: OutboundNetwork,
+    /// If set, the outbound HTTP functions are not linked at all.
+    pub http_denied: bool,
 }
 
 impl WasmLinkOptions {
@@ -33,40 +43,74 @@ impl WasmLinkOptions {
         result
     }
 
+    pub fn with_outbound_network(self, network: OutboundNetwork) -> Self {
+        Self {
+            outbound_network: network,
+            ..self
+        }
+    }
+
+    pub fn with_http_denied(self, denied: bool) -> Self {
+        Self {
+            http_denied: denied,
+            ..self
+        }
+    }
+
     pub fn apply_to(&self, linker: &mut Linker<WasiCtx>) -> anyhow::Result<()> {
-        let context = wasi_experimental_http_wasmtime::HttpCtx {
+        if self.http_denied {
+            return Ok(());
+        }
+        let settings = OutboundHttpSettings {
             allowed_hosts: self.http_allowed_hosts.clone(),
             max_concurrent_requests: self.http_max_concurrency,
+            network: self.outbound_network.clone(),
         };
-
-        let http = wasi_experimental_http_wasmtime::HttpState::new()?;
-        http.add_to_linker(linker, move |_| context.clone())
+        crate::outbound_http::add_to_linker(linker, settings)
     }
 }
 
+/// Sets up the module's stdin, stdout and stderr. If `sample_output` is given, the
+/// module's output is also recorded there, as well as anywhere it otherwise goes.
+/// If `spill` is given, a large response body goes to a file rather than staying
+/// in `stdout_mutex`.
 pub fn prepare_stdio_streams(
     body: Vec<u8>,
     global_context: &RequestGlobalContext,
     handler_id: String,
+    stderr: StderrDestination,
+    module_name: &str,
+    sample_output: Option<InterleavedLog>,
+    spill: Option<&SpillSettings>,
 ) -> Result<crate::wasm_module::IORedirectionInfo, Error> {
     let stdin = ReadPipe::from(body);
     let stdout_buf: Vec<u8> = vec![];
     let stdout_mutex = Arc::new(RwLock::new(stdout_buf));
-    let stdout = WritePipe::from_shared(stdout_mutex.clone());
     let log_dir = global_context.base_log_dir.join(handler_id);
-
-    // The spec does not say what to do with STDERR.
-    // See specifically sections 4.2 and 6.1 of RFC 3875.
-    // Currently, we will attach to wherever logs go.
-    tracing::info!(log_dir = %log_dir.display(), "Using log dir");
-    std::fs::create_dir_all(&log_dir)?;
-    let stderr = cap_std::fs::File::from_std(
-        std::fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(log_dir.join(STDERR_FILE))?,
-    );
-    let stderr = wasi_cap_std_sync::file::File::from_cap_std(stderr);
+    let log_quota_full = matches!(&global_context.log_quota, Some(quota) if !quota.allows_writing(&global_context.base_log_dir));
+    let stderr = match stderr {
+        StderrDestination::File if log_quota_full => StderrDestination::Discard,
+        _ => stderr,
+    };
+
+    let stderr_tail = StderrTail::default();
+    let stderr = stderr_tail.tee(stderr.open(&log_dir, module_name)?);
+    let interleaved = if global_context.interleave_output && !log_quota_full {
+        Some(InterleavedLog::create(&log_dir)?)
+    } else {
+        None
+    };
+    let spilled = SpilledBody::default();
+    let mut stdout: Box<dyn Write + Send + Sync> = match spill {
+        Some(settings) => Box::new(SpillingWriter::new(stdout_mutex.clone(), spilled.clone(), settings.clone())),
+        None => Box::new(SharedBuffer(stdout_mutex.clone())),
+    };
+    let mut stderr: Box<dyn Write + Send + Sync> = Box::new(stderr);
+    for log in interleaved.into_iter().chain(sample_output) {
+        stdout = Box::new(log.tee(Stream::Stdout, stdout));
+        stderr = Box::new(log.tee(Stream::Stderr, stderr));
+    }
+    let (stdout, stderr): (Box<dyn WasiFile>, Box<dyn WasiFile>) = (Box::new(WritePipe::new(stdout)), Box::new(WritePipe::new(stderr)));
 
     Ok(crate::wasm_module::IORedirectionInfo {
         streams: crate::wasm_module::IOStreamRedirects {
@@ -75,20 +119,43 @@ pub fn prepare_stdio_streams(
             stderr,
         },
         stdout_mutex,
+        spilled,
+        stderr_tail,
     })
 }
 
+/// Maps each guest path in `volumes` to its host directory. A directory that can't
+/// be opened is logged and left out rather than failing the run.
+pub fn preopen_volumes(mut builder: WasiCtxBuilder, volumes: &HashMap<String, String>) -> Result<WasiCtxBuilder, Error> {
+    for (guest, host) in volumes {
+        debug!(%host, %guest, "Mapping volume from host to guest");
+        match Dir::open_ambient_dir(host, ambient_authority()) {
+            Ok(dir) => {
+                builder = builder.preopened_dir(dir, guest)?;
+            }
+            Err(e) => tracing::error!(%host, %guest, error = %e, "Error opening directory"),
+        };
+    }
+    Ok(builder)
+}
+
 pub fn new_store(ctx: WasiCtx, engine: &Engine) -> Result<Store<WasiCtx>, anyhow::Error> {
-    Ok(Store::new(engine, ctx))
+    let mut store = Store::new(engine, ctx);
+    // Give control back to the async runtime at every epoch tick.
+    store.epoch_deadline_async_yield_and_update(1);
+    Ok(store)
 }
 
-pub fn prepare_wasm_instance(
+pub async fn prepare_wasm_instance(
     ctx: WasiCtx,
     wasm_module: &WasmModuleSource,
     link_options: WasmLinkOptions,
 ) -> Result<(Store<WasiCtx>, Instance), Error> {
-    debug!("Cloning module object");
-    let (module, engine) = wasm_module.get_compiled_module()?;
+    debug!("Getting compiled module");
+    let (module, engine) = wasm_module.get_compiled_module().await?;
+    // Each instance gets a linker of its own, because the outbound HTTP functions
+    // keep the instance's response handles in state captured when they are linked
+    // in, and one instance must not be able to read another's responses.
     let mut store = new_store(ctx, &engine)?;
 
     debug!("Configuring linker");
@@ -97,32 +164,42 @@ pub fn prepare_wasm_instance(
     link_options.apply_to(&mut linker)?;
 
     debug!("instantiating module in linker");
-    let instance = linker.instantiate(&mut store, &module)?;
+    let instance = linker.instantiate_async(&mut store, &module).await?;
     Ok((store, instance))
 }
 
-pub fn run_prepared_wasm_instance(
+pub async fn run_prepared_wasm_instance(
     instance: Instance,
     mut store: Store<WasiCtx>,
     entrypoint: &str,
     wasm_module_name: &str,
-) -> Result<(), Error> {
+) -> Result<u64, Error> {
     let start = instance.get_func(&mut store, entrypoint).ok_or_else(|| {
         anyhow::anyhow!("No such function '{}' in {}", entrypoint, wasm_module_name)
     })?;
     tracing::trace!("Calling Wasm entry point");
-    start.call(&mut store, &[], &mut vec![])?;
+    start.call_async(&mut store, &[], &mut vec![]).await?;
     tracing::trace!("Module execution complete");
-    Ok(())
+    Ok(memory_size(&instance, &mut store))
 }
 
-pub fn run_prepared_wasm_instance_if_present(
+/// The size in bytes of the instance's exported linear memories. Memories only
+/// grow, so after a run this is the most the run used.
+fn memory_size(instance: &Instance, store: &mut Store<WasiCtx>) -> u64 {
+    let memories: Vec<Memory> = instance
+        .exports(&mut *store)
+        .filter_map(|export| export.into_memory())
+        .collect();
+    memories.iter().map(|m| m.data_size(&*store) as u64).sum()
+}
+
+pub async fn run_prepared_wasm_instance_if_present(
     instance: Instance,
     mut store: Store<WasiCtx>,
     entrypoint: &str,
 ) -> RunWasmResult<(), Error> {
     match instance.get_func(&mut store, entrypoint) {
-        Some(func) => match func.call(&mut store, &[], &mut vec![]) {
+        Some(func) => match func.call_async(&mut store, &[], &mut vec![]).await {
             Ok(_) => RunWasmResult::Ok(()),
             Err(e) => RunWasmResult::WasmError(e),
         },
@@ -135,3 +212,27 @@ pub enum RunWasmResult<T, E> {
     WasmError(E),
     EntrypointNotFound,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn instantiate_http_importer(link_options: WasmLinkOptions) -> anyhow::Result<()> {
+        let wat = br#"(module (import "wasi_experimental_http" "close" (func (param i32) (result i32))))"#;
+        let engine = WasmModuleSource::new_engine(std::path::Path::new("no-such-cache.toml"), &Default::default())?;
+        let module = Module::new(&engine, wat)?;
+        let mut linker = Linker::new(&engine);
+        link_options.apply_to(&mut linker)?;
+        let mut store = new_store(WasiCtxBuilder::new().build(), &engine)?;
+        linker.instantiate_async(&mut store, &module).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn denied_http_functions_are_not_linked() {
+        instantiate_http_importer(WasmLinkOptions::none()).await.expect("HTTP functions should be linked");
+        instantiate_http_importer(WasmLinkOptions::none().with_http_denied(true))
+            .await
+            .expect_err("HTTP functions should not be linked");
+    }
+}
@@ -0,0 +1,159 @@
+//! Watch mode for local development (`wagi dev --watch`).
+//!
+//! Modules with a `build_command` in the modules.toml have their source files polled
+//! for changes. When a module's files change, its build command is run and, if the
+//! build succeeds, all handlers are reloaded and the server's routing table is
+//! swapped for the new one. A failed build leaves the previous modules serving.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::dispatcher::{LiveRoutingTable, RoutingTable};
+use crate::handler_loader::{self, BuildSettings, WasmHandlerConfiguration};
+use crate::wagi_config::WagiConfiguration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+type Snapshot = HashMap<PathBuf, SystemTime>;
+
+struct WatchedBuild {
+    module: String,
+    settings: BuildSettings,
+    snapshot: Snapshot,
+}
+
+/// Runs until the process exits, rebuilding and reloading modules as their sources change.
+pub async fn watch_and_rebuild(
+    configuration: WagiConfiguration,
+    handlers: WasmHandlerConfiguration,
+    routing_table: LiveRoutingTable,
+) {
+    let mut builds = watched_builds(&handlers);
+    if builds.is_empty() {
+        tracing::warn!("Watch mode is on, but no modules have a build_command; nothing will be rebuilt");
+        return;
+    }
+    println!("Watching {} module(s) for changes", builds.len());
+
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let changed: Vec<usize> = builds
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| snapshot(&b.settings.watch) != b.snapshot)
+            .map(|(i, _)| i)
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+
+        let mut all_succeeded = true;
+        for index in changed {
+            let build = &mut builds[index];
+            all_succeeded &= run_build(build).await;
+            // Re-snapshot after the build so its own outputs do not trigger another one
+            build.snapshot = snapshot(&build.settings.watch);
+        }
+
+        if all_succeeded {
+            match reload(&configuration, &routing_table).await {
+                Ok(reloaded_handlers) => {
+                    println!("Reloaded modules");
+                    builds = watched_builds(&reloaded_handlers);
+                }
+                Err(e) => tracing::error!(error = ?e, "Failed to reload modules after rebuild; continuing with previous modules"),
+            }
+        }
+    }
+}
+
+fn watched_builds(handlers: &WasmHandlerConfiguration) -> Vec<WatchedBuild> {
+    handlers
+        .entries
+        .iter()
+        .filter_map(|e| e.info.build.as_ref().map(|b| (e.info.name.clone(), b.clone())))
+        .map(|(module, settings)| WatchedBuild {
+            snapshot: snapshot(&settings.watch),
+            module,
+            settings,
+        })
+        .collect()
+}
+
+async fn run_build(build: &WatchedBuild) -> bool {
+    println!("Rebuilding {}: {}", build.module, build.settings.command);
+    let mut command = if cfg!(windows) {
+        let mut c = tokio::process::Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = tokio::process::Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    let status = command
+        .arg(&build.settings.command)
+        .current_dir(&build.settings.working_dir)
+        .status()
+        .await;
+    match status {
+        Ok(s) if s.success() => true,
+        Ok(s) => {
+            tracing::error!(module = %build.module, status = %s, "Build command failed");
+            false
+        }
+        Err(e) => {
+            tracing::error!(module = %build.module, error = %e, "Could not run build command");
+            false
+        }
+    }
+}
+
+async fn reload(configuration: &WagiConfiguration, routing_table: &LiveRoutingTable) -> anyhow::Result<WasmHandlerConfiguration> {
+    let handlers = handler_loader::load_handlers(configuration).await?;
+    // Keep the existing global context so that metrics survive reloads
+    let global_context = routing_table.current().global_context().clone();
+    let (handlers, new_table) = RoutingTable::build_on_blocking_thread(handlers, global_context).await?;
+    routing_table.replace(new_table);
+    Ok(handlers)
+}
+
+fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    let mut files = Snapshot::new();
+    for path in paths {
+        collect_modified_times(path, &mut files);
+    }
+    files
+}
+
+// Build output and VCS directories are skipped: they are large, and changes in
+// them are not source changes.
+fn collect_modified_times(path: &Path, files: &mut Snapshot) {
+    let metadata = match std::fs::metadata(path) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    if metadata.is_file() {
+        if let Ok(modified) = metadata.modified() {
+            files.insert(path.to_owned(), modified);
+        }
+        return;
+    }
+    let entries = match std::fs::read_dir(path) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let child = entry.path();
+        let is_skipped_dir = child.is_dir() && child
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n == "target" || n.starts_with('.'))
+            .unwrap_or(false);
+        if !is_skipped_dir {
+            collect_modified_times(&child, files);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+(module
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; Never returns, so the request only ends when it is abandoned or times out
+    (func $main (export "_start")
+        (loop $forever
+            br $forever
+        )
+    )
+)